@@ -0,0 +1,73 @@
+//! Wall-clock time to generate a matrix of forking scenarios offline, at a
+//! harder target than regtest's, mining in order and in parallel
+
+use std::process::{Command, Stdio};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// Number of scenarios, each forking from the anchor
+const N_SCENARIOS: usize = 8;
+
+/// Number of blocks in each scenario
+const DEPTH: usize = 4;
+
+/// Harder than regtest's target, so that mining dominates
+const BITS: &str = "1f00ffff";
+
+/// Blocks spec with `N_SCENARIOS` branches of `DEPTH` blocks
+fn matrix_spec() -> String {
+    let scenario = std::iter::once(r#"{"fork_from": "anchor"}"#)
+        .chain(std::iter::repeat_n("{}", DEPTH - 1));
+    let block_specs: Vec<_> = std::iter::repeat_n(scenario, N_SCENARIOS)
+        .flat_map(|scenario| scenario.clone())
+        .collect();
+    format!("[{}]", block_specs.join(", "))
+}
+
+/// Generate the matrix offline, mining on `mining_threads` threads
+fn gen_matrix(config: &std::path::Path, spec: &str, mining_threads: usize) {
+    let status = Command::new(env!("CARGO_BIN_EXE_bip300-enforcer-demo"))
+        .arg("--config")
+        .arg(config)
+        .args([
+            "--offline",
+            "--prev-blockhash",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "--height",
+            "200",
+            "--bits",
+            BITS,
+            "--seed",
+            "1",
+            "--deterministic",
+            "--start-time",
+            "1700000000",
+            "--mining-threads",
+            &mining_threads.to_string(),
+            spec,
+        ])
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn matrix_mining(c: &mut Criterion) {
+    let config = tempfile::NamedTempFile::new().unwrap();
+    let spec = matrix_spec();
+    let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut group = c.benchmark_group("matrix_mining");
+    group.sample_size(10);
+    group.bench_function("in_order", |b| {
+        b.iter(|| gen_matrix(config.path(), &spec, 1))
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| gen_matrix(config.path(), &spec, n_threads))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, matrix_mining);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Sets `GIT_HASH` to the commit that the crate is built from, if it is
+//! built from a git checkout, and `LONG_VERSION` to the crate version,
+//! followed by the commit if known, eg. `0.1.0 (0123456789ab)`.
+//! Generates the enforcer's gRPC client from the proto definitions in
+//! `proto/`.
+
+use std::{path::Path, process::Command};
+
+/// Commit that the crate is built from, if it is built from a git checkout
+fn git_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let git_hash = String::from_utf8(output.stdout).ok()?;
+    Some(git_hash.trim().to_owned())
+}
+
+/// Generate the enforcer's gRPC client with tonic. The server is only
+/// compiled for tests, which run a mock enforcer, and messages can be
+/// (de)serialized as JSON in tests, as by gRPC-gateway.
+fn compile_protos() {
+    // Use the vendored protoc, unless one is specified
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().unwrap();
+        std::env::set_var("PROTOC", protoc);
+    }
+    let well_known_types = protoc_bin_vendored::include_path().unwrap();
+    tonic_build::configure()
+        .build_server(true)
+        .server_mod_attribute(".", "#[cfg(test)]")
+        .type_attribute(
+            ".",
+            "#[cfg_attr(test, derive(serde::Deserialize, serde::Serialize), \
+             serde(default, rename_all = \"camelCase\"))]",
+        )
+        .compile_protos(
+            &["proto/cusf/mainchain/v1/validator.proto"],
+            &[Path::new("proto"), &well_known_types],
+        )
+        .unwrap();
+}
+
+fn main() {
+    compile_protos();
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let version = env!("CARGO_PKG_VERSION");
+    match git_hash() {
+        Some(git_hash) => {
+            println!("cargo:rustc-env=GIT_HASH={git_hash}");
+            println!("cargo:rustc-env=LONG_VERSION={version} ({git_hash})");
+        }
+        None => println!("cargo:rustc-env=LONG_VERSION={version}"),
+    }
+}
@@ -0,0 +1,606 @@
+use std::fmt::Display;
+
+use bitcoin::{
+    hashes::{sha256d, Hash as _},
+    hex::DisplayHex as _,
+    opcodes::all::OP_RETURN,
+    script::Instruction,
+    Amount, BlockHash, ScriptBuf, TxOut,
+};
+
+/// Magic bytes that follow `OP_RETURN` in an M1 output
+const M1_TAG: &[u8] = &[0xD5, 0xE0, 0xC4, 0xAF];
+/// Magic bytes that follow `OP_RETURN` in an M2 output
+const M2_TAG: &[u8] = &[0xD6, 0xE1, 0xC5, 0xBF];
+/// Magic bytes that follow `OP_RETURN` in an M3 output
+const M3_TAG: &[u8] = &[0xD4, 0x5A, 0xA9, 0x43];
+/// Magic bytes that follow `OP_RETURN` in an M4 output
+const M4_TAG: &[u8] = &[0xD7, 0x7D, 0x17, 0x76];
+/// Magic bytes that follow `OP_RETURN` in an M7 (BIP301) output
+const M7_TAG: &[u8] = &[0xD1, 0x61, 0x73, 0x68];
+/// Magic bytes that follow `OP_RETURN` in an M8 (BIP301) output
+const M8_TAG: &[u8] = &[0x00, 0xBF, 0x00];
+
+/// Name, magic bytes, and payload length of each message
+const MESSAGES: &[(&str, &[u8], PayloadLen)] = &[
+    ("M1", M1_TAG, PayloadLen::AtLeast(1)),
+    ("M2", M2_TAG, PayloadLen::Exactly(33)),
+    ("M3", M3_TAG, PayloadLen::Exactly(33)),
+    ("M4", M4_TAG, PayloadLen::AtLeast(1)),
+    ("M7", M7_TAG, PayloadLen::Exactly(33)),
+    ("M8", M8_TAG, PayloadLen::Exactly(65)),
+];
+
+/// Length of a payload, after the magic bytes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadLen {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl PayloadLen {
+    fn check(
+        self,
+        message: &'static str,
+        payload: &[u8],
+    ) -> Result<(), ParseError> {
+        let ok = match self {
+            Self::Exactly(len) => payload.len() == len,
+            Self::AtLeast(len) => payload.len() >= len,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(ParseError {
+                message,
+                len: payload.len(),
+                expected: self,
+            })
+        }
+    }
+}
+
+impl Display for PayloadLen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exactly(len) => write!(f, "exactly {len} bytes"),
+            Self::AtLeast(len) => write!(f, "at least {len} bytes"),
+        }
+    }
+}
+
+/// Output with the magic bytes of a BIP300/BIP301 message, but a payload
+/// that is too short or too long for it
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+#[error(
+    "malformed {message} message: payload is {len} bytes, but must be \
+     {expected}"
+)]
+pub struct ParseError {
+    /// Name of the message, eg. `M2`
+    pub message: &'static str,
+    /// Length of the payload, after the magic bytes
+    pub len: usize,
+    pub expected: PayloadLen,
+}
+
+/// BIP300/BIP301 message, carried by an `OP_RETURN` output. The magic bytes
+/// and payload follow `OP_RETURN` directly, without a push opcode.
+/// Hashes are encoded in their internal byte order, which is the reverse of
+/// the order that they are displayed in, eg. the M2 ack of the description
+/// `demo sidechain` in slot 255 is
+/// `6ad6e1c5bfff8a9b61fbdb68004c3a4684773def0f282c67565c508627e2c8fcf2955811aafc`,
+/// although the description hash is displayed as `fcaa1158…619b8a`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Bip300Message {
+    /// Propose a sidechain in a slot.
+    /// `6a d5e0c4af <slot> <description>`
+    M1Propose { slot: u8, description: Vec<u8> },
+    /// Ack the proposal of a sidechain, by the SHA256D hash of its
+    /// description.
+    /// `6a d6e1c5bf <slot> <description hash>`
+    M2Ack {
+        slot: u8,
+        description_hash: sha256d::Hash,
+    },
+    /// Propose a withdrawal bundle for a sidechain.
+    /// `6a d45aa943 <slot> <bundle hash>`
+    M3ProposeBundle { slot: u8, bundle_hash: [u8; 32] },
+    /// Ack withdrawal bundles. The encoding of the upvotes depends on the
+    /// version, and is not interpreted.
+    /// `6a d77d1776 <version> <upvotes>`
+    M4AckBundles { version: u8, upvotes: Vec<u8> },
+    /// Accept a BMM request, committing to a sidechain block.
+    /// `6a d1617368 <slot> <sidechain block hash>`
+    M7BmmAccept {
+        slot: u8,
+        sidechain_block_hash: [u8; 32],
+    },
+    /// Request BMM of a sidechain block on top of a mainchain block.
+    /// `6a 00bf00 <slot> <sidechain block hash> <prev mainchain block hash>`
+    M8BmmRequest {
+        slot: u8,
+        sidechain_block_hash: [u8; 32],
+        prev_mainchain_block_hash: BlockHash,
+    },
+}
+
+impl Bip300Message {
+    /// Name of the message, eg. `M2`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::M1Propose { .. } => "M1",
+            Self::M2Ack { .. } => "M2",
+            Self::M3ProposeBundle { .. } => "M3",
+            Self::M4AckBundles { .. } => "M4",
+            Self::M7BmmAccept { .. } => "M7",
+            Self::M8BmmRequest { .. } => "M8",
+        }
+    }
+
+    fn tag(&self) -> &'static [u8] {
+        match self {
+            Self::M1Propose { .. } => M1_TAG,
+            Self::M2Ack { .. } => M2_TAG,
+            Self::M3ProposeBundle { .. } => M3_TAG,
+            Self::M4AckBundles { .. } => M4_TAG,
+            Self::M7BmmAccept { .. } => M7_TAG,
+            Self::M8BmmRequest { .. } => M8_TAG,
+        }
+    }
+
+    /// Sidechain slot that the message is for, if any
+    pub fn slot(&self) -> Option<u8> {
+        match self {
+            Self::M1Propose { slot, .. }
+            | Self::M2Ack { slot, .. }
+            | Self::M3ProposeBundle { slot, .. }
+            | Self::M7BmmAccept { slot, .. }
+            | Self::M8BmmRequest { slot, .. } => Some(*slot),
+            Self::M4AckBundles { .. } => None,
+        }
+    }
+
+    /// `false` for messages that are only valid outside the coinbase tx
+    pub fn in_coinbase(&self) -> bool {
+        !matches!(self, Self::M8BmmRequest { .. })
+    }
+
+    /// Payload after the magic bytes and the slot, if any
+    pub fn data(&self) -> Vec<u8> {
+        match self {
+            Self::M1Propose {
+                slot: _,
+                description,
+            } => description.clone(),
+            Self::M2Ack {
+                slot: _,
+                description_hash,
+            } => description_hash.to_byte_array().to_vec(),
+            Self::M3ProposeBundle {
+                slot: _,
+                bundle_hash,
+            } => bundle_hash.to_vec(),
+            Self::M4AckBundles { version, upvotes } => {
+                std::iter::once(*version).chain(upvotes.clone()).collect()
+            }
+            Self::M7BmmAccept {
+                slot: _,
+                sidechain_block_hash,
+            } => sidechain_block_hash.to_vec(),
+            Self::M8BmmRequest {
+                slot: _,
+                sidechain_block_hash,
+                prev_mainchain_block_hash,
+            } => sidechain_block_hash
+                .iter()
+                .copied()
+                .chain(prev_mainchain_block_hash.to_byte_array())
+                .collect(),
+        }
+    }
+
+    pub fn to_txout(&self) -> TxOut {
+        let script_pubkey = ScriptBuf::from_bytes(
+            std::iter::once(OP_RETURN.to_u8())
+                .chain(self.tag().iter().copied())
+                .chain(self.slot())
+                .chain(self.data())
+                .collect(),
+        );
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        }
+    }
+
+    /// Parse the message carried by an output. `None` if the output does not
+    /// start with `OP_RETURN` and the magic bytes of a message.
+    pub fn parse(txout: &TxOut) -> Option<Result<Self, ParseError>> {
+        let script = txout.script_pubkey.as_bytes();
+        let data = script.strip_prefix(&[OP_RETURN.to_u8()])?;
+        let (name, tag, len) = MESSAGES
+            .iter()
+            .copied()
+            .find(|(_, tag, _)| data.starts_with(tag))?;
+        let payload = &data[tag.len()..];
+        if let Err(err) = len.check(name, payload) {
+            return Some(Err(err));
+        }
+        let hash = |idx: usize| -> [u8; 32] {
+            payload[idx..idx + 32].try_into().unwrap()
+        };
+        let res = match tag {
+            M1_TAG => Self::M1Propose {
+                slot: payload[0],
+                description: payload[1..].to_vec(),
+            },
+            M2_TAG => Self::M2Ack {
+                slot: payload[0],
+                description_hash: sha256d::Hash::from_byte_array(hash(1)),
+            },
+            M3_TAG => Self::M3ProposeBundle {
+                slot: payload[0],
+                bundle_hash: hash(1),
+            },
+            M4_TAG => Self::M4AckBundles {
+                version: payload[0],
+                upvotes: payload[1..].to_vec(),
+            },
+            M7_TAG => Self::M7BmmAccept {
+                slot: payload[0],
+                sidechain_block_hash: hash(1),
+            },
+            _ => Self::M8BmmRequest {
+                slot: payload[0],
+                sidechain_block_hash: hash(1),
+                prev_mainchain_block_hash: BlockHash::from_byte_array(hash(33)),
+            },
+        };
+        Some(Ok(res))
+    }
+
+    /// Name of the message whose magic bytes are pushed after `OP_RETURN`
+    /// in an output, rather than following it directly, if any. Such
+    /// outputs are not parsed as messages.
+    pub fn pushed_message_name(txout: &TxOut) -> Option<&'static str> {
+        let mut instructions = txout.script_pubkey.instructions();
+        match instructions.next()? {
+            Ok(Instruction::Op(OP_RETURN)) => (),
+            _ => return None,
+        }
+        let Ok(Instruction::PushBytes(push)) = instructions.next()? else {
+            return None;
+        };
+        MESSAGES
+            .iter()
+            .find(|(_, tag, _)| push.as_bytes().starts_with(tag))
+            .map(|(name, _, _)| *name)
+    }
+}
+
+impl Display for Bip300Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name();
+        match self {
+            Self::M1Propose { slot, description } => write!(
+                f,
+                "{name} proposing the sidechain `{}` in slot {slot}",
+                String::from_utf8_lossy(description)
+            ),
+            Self::M2Ack {
+                slot,
+                description_hash,
+            } => write!(
+                f,
+                "{name} acking the sidechain in slot {slot} with description \
+                 hash {description_hash}"
+            ),
+            Self::M3ProposeBundle { slot, bundle_hash } => write!(
+                f,
+                "{name} proposing bundle {} for slot {slot}",
+                bundle_hash.as_hex()
+            ),
+            Self::M4AckBundles { version, upvotes } => write!(
+                f,
+                "{name} acking bundles with version {version} upvotes {}",
+                upvotes.as_hex()
+            ),
+            Self::M7BmmAccept {
+                slot,
+                sidechain_block_hash,
+            } => write!(
+                f,
+                "{name} accepting BMM of sidechain block {} in slot {slot}",
+                sidechain_block_hash.as_hex()
+            ),
+            Self::M8BmmRequest {
+                slot,
+                sidechain_block_hash,
+                prev_mainchain_block_hash,
+            } => write!(
+                f,
+                "{name} requesting BMM of sidechain block {} in slot {slot} \
+                 on top of {prev_mainchain_block_hash}",
+                sidechain_block_hash.as_hex()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        hashes::{sha256d, Hash as _},
+        hex::DisplayHex as _,
+        Amount, BlockHash, ScriptBuf, TxOut,
+    };
+    use proptest::prelude::*;
+
+    use super::{Bip300Message, ParseError, PayloadLen, MESSAGES};
+
+    fn txout(script_pubkey_hex: &str) -> TxOut {
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_hex(script_pubkey_hex).unwrap(),
+        }
+    }
+
+    fn arb_message() -> impl Strategy<Value = Bip300Message> {
+        let bytes = || proptest::collection::vec(any::<u8>(), 0..80);
+        prop_oneof![
+            (any::<u8>(), bytes()).prop_map(|(slot, description)| {
+                Bip300Message::M1Propose { slot, description }
+            }),
+            (any::<u8>(), any::<[u8; 32]>()).prop_map(|(slot, hash)| {
+                Bip300Message::M2Ack {
+                    slot,
+                    description_hash: sha256d::Hash::from_byte_array(hash),
+                }
+            }),
+            (any::<u8>(), any::<[u8; 32]>()).prop_map(|(slot, bundle_hash)| {
+                Bip300Message::M3ProposeBundle { slot, bundle_hash }
+            }),
+            (any::<u8>(), bytes()).prop_map(|(version, upvotes)| {
+                Bip300Message::M4AckBundles { version, upvotes }
+            }),
+            (any::<u8>(), any::<[u8; 32]>()).prop_map(
+                |(slot, sidechain_block_hash)| Bip300Message::M7BmmAccept {
+                    slot,
+                    sidechain_block_hash,
+                }
+            ),
+            (any::<u8>(), any::<[u8; 32]>(), any::<[u8; 32]>()).prop_map(
+                |(slot, sidechain_block_hash, prev)| {
+                    Bip300Message::M8BmmRequest {
+                        slot,
+                        sidechain_block_hash,
+                        prev_mainchain_block_hash: BlockHash::from_byte_array(
+                            prev,
+                        ),
+                    }
+                }
+            ),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(message in arb_message()) {
+            let txout = message.to_txout();
+            prop_assert_eq!(txout.value, Amount::ZERO);
+            prop_assert_eq!(Bip300Message::parse(&txout), Some(Ok(message)));
+        }
+
+        /// Truncating a fixed-length message makes it malformed
+        #[test]
+        fn truncated_is_malformed(
+            message in arb_message(),
+            cut in 1usize..32,
+        ) {
+            prop_assume!(matches!(
+                message,
+                Bip300Message::M2Ack { .. }
+                    | Bip300Message::M3ProposeBundle { .. }
+                    | Bip300Message::M7BmmAccept { .. }
+                    | Bip300Message::M8BmmRequest { .. }
+            ));
+            let mut txout = message.to_txout();
+            let mut bytes = txout.script_pubkey.into_bytes();
+            bytes.truncate(bytes.len() - cut);
+            txout.script_pubkey = ScriptBuf::from_bytes(bytes);
+            let res = Bip300Message::parse(&txout);
+            prop_assert!(matches!(res, Some(Err(_))), "{res:?}");
+        }
+    }
+
+    /// Magic bytes of each message, as given in BIP300 (M1 to M4) and
+    /// BIP301 (M7 and M8). A change to these changes the generated outputs
+    /// on the wire.
+    #[test]
+    fn magic_bytes_match_spec() {
+        let spec = [
+            ("M1", "d5e0c4af"),
+            ("M2", "d6e1c5bf"),
+            ("M3", "d45aa943"),
+            ("M4", "d77d1776"),
+            ("M7", "d1617368"),
+            ("M8", "00bf00"),
+        ];
+        let tags: Vec<_> = MESSAGES
+            .iter()
+            .map(|(name, tag, _)| (*name, tag.to_lower_hex_string()))
+            .collect();
+        assert_eq!(
+            tags,
+            spec.map(|(name, tag)| (name, tag.to_owned())).to_vec()
+        );
+        // No message's magic bytes are a prefix of another's
+        for (name, tag, _) in MESSAGES {
+            for (other_name, other_tag, _) in MESSAGES {
+                assert!(
+                    name == other_name || !other_tag.starts_with(tag),
+                    "{name} and {other_name}"
+                );
+            }
+        }
+    }
+
+    /// M2 acks use the M2 magic bytes, not the M1 magic bytes that they
+    /// were once written with
+    #[test]
+    fn m2_vector() {
+        let txout = Bip300Message::M2Ack {
+            slot: 255,
+            description_hash: sha256d::Hash::hash(b"demo sidechain"),
+        }
+        .to_txout();
+        assert_eq!(
+            txout.script_pubkey.as_bytes().to_lower_hex_string(),
+            "6ad6e1c5bfff8a9b61fbdb68004c3a4684773def0f282c67565c508627e2c8fc\
+             f2955811aafc"
+        );
+        let data = &txout.script_pubkey.as_bytes()[1..];
+        assert!(!data.starts_with(super::M1_TAG));
+        assert_eq!(
+            Bip300Message::parse(&txout),
+            Some(Ok(Bip300Message::M2Ack {
+                slot: 255,
+                description_hash: sha256d::Hash::hash(b"demo sidechain"),
+            }))
+        );
+    }
+
+    #[test]
+    fn m1_vector() {
+        let txout = Bip300Message::M1Propose {
+            slot: 255,
+            description: b"demo sidechain".to_vec(),
+        }
+        .to_txout();
+        assert_eq!(
+            txout.script_pubkey.as_bytes().to_lower_hex_string(),
+            "6ad5e0c4afff64656d6f2073696465636861696e"
+        );
+    }
+
+    /// Bytes `start..start + 32`
+    fn seq(start: u8) -> [u8; 32] {
+        std::array::from_fn(|idx| start + idx as u8)
+    }
+
+    /// Script pubkeys of each message, as specified by BIP300 and BIP301:
+    /// `OP_RETURN`, the magic bytes, the slot (or M4 version) byte, and the
+    /// payload, with hashes in internal byte order
+    #[test]
+    fn script_pubkey_vectors() {
+        let vectors = [
+            (
+                Bip300Message::M1Propose {
+                    slot: 1,
+                    description: b"ab".to_vec(),
+                },
+                "6ad5e0c4af016162",
+            ),
+            (
+                Bip300Message::M2Ack {
+                    slot: 2,
+                    description_hash: sha256d::Hash::from_byte_array(seq(0x00)),
+                },
+                "6ad6e1c5bf02000102030405060708090a0b0c0d0e0f101112131415\
+                 161718191a1b1c1d1e1f",
+            ),
+            (
+                Bip300Message::M3ProposeBundle {
+                    slot: 3,
+                    bundle_hash: seq(0x20),
+                },
+                "6ad45aa94303202122232425262728292a2b2c2d2e2f303132333435\
+                 363738393a3b3c3d3e3f",
+            ),
+            (
+                Bip300Message::M4AckBundles {
+                    version: 1,
+                    upvotes: vec![0x00, 0xff],
+                },
+                "6ad77d17760100ff",
+            ),
+            (
+                Bip300Message::M7BmmAccept {
+                    slot: 7,
+                    sidechain_block_hash: seq(0x40),
+                },
+                "6ad161736807404142434445464748494a4b4c4d4e4f505152535455\
+                 565758595a5b5c5d5e5f",
+            ),
+            (
+                Bip300Message::M8BmmRequest {
+                    slot: 8,
+                    sidechain_block_hash: seq(0x60),
+                    prev_mainchain_block_hash: BlockHash::from_byte_array(seq(
+                        0x80,
+                    )),
+                },
+                "6a00bf0008606162636465666768696a6b6c6d6e6f70717273747576\
+                 7778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f909192\
+                 939495969798999a9b9c9d9e9f",
+            ),
+        ];
+        for (message, expected) in vectors {
+            let txout = message.to_txout();
+            assert_eq!(
+                txout.script_pubkey.as_bytes().to_lower_hex_string(),
+                expected,
+                "{}",
+                message.name()
+            );
+            assert_eq!(Bip300Message::parse(&txout), Some(Ok(message)));
+        }
+    }
+
+    /// Hashes are displayed in the reverse of the order that they are
+    /// encoded in
+    #[test]
+    fn hashes_display_reversed() {
+        let description_hash = sha256d::Hash::from_byte_array(seq(0x00));
+        assert_eq!(
+            description_hash.to_string(),
+            "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100"
+        );
+        let prev_mainchain_block_hash = BlockHash::from_byte_array(seq(0x80));
+        assert_eq!(
+            prev_mainchain_block_hash.to_string(),
+            "9f9e9d9c9b9a999897969594939291908f8e8d8c8b8a89888786858483828180"
+        );
+    }
+
+    #[test]
+    fn parse_non_messages() {
+        // Empty, not OP_RETURN, unknown magic bytes, and magic bytes pushed
+        // rather than following OP_RETURN directly
+        for hex in ["", "51", "6a", "6a01020304", "6a04d5e0c4af01"] {
+            assert_eq!(Bip300Message::parse(&txout(hex)), None, "{hex}");
+        }
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert_eq!(
+            Bip300Message::parse(&txout("6ad5e0c4af")),
+            Some(Err(ParseError {
+                message: "M1",
+                len: 0,
+                expected: PayloadLen::AtLeast(1),
+            }))
+        );
+        let m2_too_long = format!("6ad6e1c5bf{}", "00".repeat(34));
+        assert_eq!(
+            Bip300Message::parse(&txout(&m2_too_long)),
+            Some(Err(ParseError {
+                message: "M2",
+                len: 34,
+                expected: PayloadLen::Exactly(33),
+            }))
+        );
+    }
+}
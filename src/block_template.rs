@@ -0,0 +1,146 @@
+use anyhow::Context as _;
+use bip300301::jsonrpsee::{
+    self,
+    core::{client::ClientT as _, params::ArrayParams},
+    http_client::HttpClient,
+};
+use bitcoin::{Block, BlockHash, CompactTarget, ScriptBuf};
+use serde::{de::IgnoredAny, Deserialize};
+
+use crate::{errors::GenError, Anchor, WITNESS_COMMITMENT_HEADER};
+
+/// Subset of the response to the `getblocktemplate` RPC
+#[derive(Debug, Deserialize)]
+pub struct BlockTemplate {
+    #[serde(rename = "previousblockhash")]
+    pub prev_blockhash: BlockHash,
+    pub height: u32,
+    /// Compact target, in hex
+    bits: String,
+    /// Id to long poll for the next template with
+    pub longpollid: String,
+    /// Transactions from the node's mempool. Only their number is used.
+    transactions: Vec<IgnoredAny>,
+    /// Witness commitment output script for the template's transactions
+    default_witness_commitment: Option<ScriptBuf>,
+    /// Maximum sigop cost of a block
+    #[serde(rename = "sigoplimit")]
+    sigop_limit: Option<u64>,
+    /// Maximum serialized size of a block, in bytes
+    #[serde(rename = "sizelimit")]
+    size_limit: Option<u64>,
+    /// Maximum weight of a block, in weight units
+    #[serde(rename = "weightlimit")]
+    weight_limit: Option<u64>,
+}
+
+impl BlockTemplate {
+    /// Anchor for a block built on the template
+    pub fn anchor(&self) -> anyhow::Result<Anchor> {
+        let bits = u32::from_str_radix(&self.bits, 16).with_context(|| {
+            format!("invalid `bits` in block template: `{}`", self.bits)
+        })?;
+        Ok(Anchor {
+            height: self.height,
+            prev_blockhash: self.prev_blockhash,
+            target: CompactTarget::from_consensus(bits),
+        })
+    }
+
+    pub fn constraints(&self) -> TemplateConstraints {
+        TemplateConstraints {
+            prev_blockhash: self.prev_blockhash,
+            coinbase_only_witness_commitment: self
+                .default_witness_commitment
+                .clone()
+                .filter(|_| self.transactions.is_empty()),
+            sigop_limit: self.sigop_limit,
+            size_limit: self.size_limit,
+            weight_limit: self.weight_limit,
+        }
+    }
+}
+
+/// Constraints from the node's block template, that generated blocks which
+/// are expected to be valid are checked against
+#[derive(Clone, Debug)]
+pub struct TemplateConstraints {
+    prev_blockhash: BlockHash,
+    /// The template's default witness commitment, if the template has no
+    /// transactions, in which case it is the commitment for a block on the
+    /// template's parent with only a coinbase tx
+    coinbase_only_witness_commitment: Option<ScriptBuf>,
+    sigop_limit: Option<u64>,
+    size_limit: Option<u64>,
+    weight_limit: Option<u64>,
+}
+
+impl TemplateConstraints {
+    /// Check that a block is within the template's limits, and that its
+    /// witness commitment, if any, matches the template's default witness
+    /// commitment where it applies.
+    /// The outputs spent by non-coinbase txs are not known, so only their
+    /// legacy sigops are counted.
+    pub fn check(&self, block: &Block) -> Result<(), GenError> {
+        let block_hash = block.block_hash();
+        let check_limit = |limit, value, max: Option<u64>| match max {
+            Some(max) if value > max => Err(GenError::ExceedsTemplateLimit {
+                block_hash,
+                limit,
+                value,
+                max,
+            }),
+            Some(_) | None => Ok(()),
+        };
+        let () =
+            check_limit("weight", block.weight().to_wu(), self.weight_limit)?;
+        let () =
+            check_limit("size", block.total_size() as u64, self.size_limit)?;
+        if self.sigop_limit.is_some() {
+            let sigop_cost: usize = block
+                .txdata
+                .iter()
+                .map(|tx| tx.total_sigop_cost(|_| None))
+                .sum();
+            let () =
+                check_limit("sigop cost", sigop_cost as u64, self.sigop_limit)?;
+        }
+        if let (Some(expected), [coinbase_tx]) = (
+            &self.coinbase_only_witness_commitment,
+            block.txdata.as_slice(),
+        ) {
+            let commitment = coinbase_tx.output.iter().rev().find(|txout| {
+                txout.script_pubkey.as_bytes().get(2..6)
+                    == Some(WITNESS_COMMITMENT_HEADER.as_slice())
+            });
+            if let Some(commitment) = commitment
+                .filter(|_| block.header.prev_blockhash == self.prev_blockhash)
+            {
+                if commitment.script_pubkey != *expected {
+                    return Err(GenError::TemplateWitnessCommitment {
+                        block_hash,
+                        commitment: commitment.script_pubkey.clone(),
+                        expected: expected.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Request a block template with the segwit rule, which the node requires.
+/// With a `longpollid` from a previous template, the node responds once the
+/// template changes, eg. because its tip changed, rather than immediately.
+pub async fn request(
+    client: &HttpClient,
+    longpollid: Option<&str>,
+) -> Result<BlockTemplate, jsonrpsee::core::client::Error> {
+    let mut template_request = serde_json::json!({ "rules": ["segwit"] });
+    if let Some(longpollid) = longpollid {
+        template_request["longpollid"] = longpollid.into();
+    }
+    let mut params = ArrayParams::new();
+    let () = params.insert(template_request)?;
+    client.request("getblocktemplate", params).await
+}
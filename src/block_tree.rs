@@ -0,0 +1,161 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use bitcoin::BlockHash;
+
+use crate::{enforcer_checks::EnforcerState, Anchor};
+
+/// A block that the node is expected to store
+struct StoredBlock {
+    /// `None` for the root
+    parent: Option<BlockHash>,
+    /// Anchor for a child of the block
+    anchor: Anchor,
+    /// Enforcer state expected once the block is connected
+    enforcer_state: EnforcerState,
+    /// Order in which the block was stored
+    seq: usize,
+}
+
+/// Tracks the blocks that the node is expected to store, and which of them
+/// are invalidated, so that the node's tip can be predicted as blocks are
+/// submitted, invalidated, and reconsidered.
+/// All generated blocks have the same target, so the branch with the most
+/// work is the longest. Between branches of equal length, the node keeps its
+/// current tip if it is valid, and otherwise prefers the block it stored
+/// first.
+pub struct BlockTree {
+    blocks: HashMap<BlockHash, StoredBlock>,
+    /// Blocks invalidated with `invalidateblock`. Their descendants are also
+    /// invalid.
+    invalidated: HashSet<BlockHash>,
+    tip: BlockHash,
+}
+
+impl BlockTree {
+    /// `root` is the anchor for the first generated block
+    pub fn new(root: Anchor, enforcer_state: EnforcerState) -> Self {
+        let stored_block = StoredBlock {
+            parent: None,
+            anchor: root,
+            enforcer_state,
+            seq: 0,
+        };
+        Self {
+            blocks: HashMap::from([(root.prev_blockhash, stored_block)]),
+            invalidated: HashSet::new(),
+            tip: root.prev_blockhash,
+        }
+    }
+
+    fn get(&self, block_hash: &BlockHash) -> &StoredBlock {
+        &self.blocks[block_hash]
+    }
+
+    /// Hash and height of the node's expected tip
+    pub fn tip(&self) -> (BlockHash, u32) {
+        (self.tip, self.get(&self.tip).anchor.height - 1)
+    }
+
+    /// Anchor for a child of the node's expected tip
+    pub fn tip_anchor(&self) -> Anchor {
+        self.get(&self.tip).anchor
+    }
+
+    /// Enforcer state expected at the node's expected tip
+    pub fn tip_enforcer_state(&self) -> &EnforcerState {
+        &self.get(&self.tip).enforcer_state
+    }
+
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.blocks.contains_key(block_hash)
+    }
+
+    /// Ancestors of a stored block, starting with the block itself
+    fn ancestors(
+        &self,
+        block_hash: BlockHash,
+    ) -> impl Iterator<Item = BlockHash> + '_ {
+        std::iter::successors(Some(block_hash), |block_hash| {
+            self.blocks.get(block_hash)?.parent
+        })
+    }
+
+    /// `false` if the block, or any of its ancestors, was invalidated
+    pub fn is_valid(&self, block_hash: BlockHash) -> bool {
+        !self
+            .ancestors(block_hash)
+            .any(|ancestor| self.invalidated.contains(&ancestor))
+    }
+
+    /// Store a child of `parent`, with the anchor for a child of the new
+    /// block. If `connected` is `true`, the block becomes the tip.
+    pub fn insert(
+        &mut self,
+        parent: BlockHash,
+        child_anchor: Anchor,
+        enforcer_state: EnforcerState,
+        connected: bool,
+    ) {
+        let block_hash = child_anchor.prev_blockhash;
+        let stored_block = StoredBlock {
+            parent: Some(parent),
+            anchor: child_anchor,
+            enforcer_state,
+            seq: self.blocks.len(),
+        };
+        self.blocks.insert(block_hash, stored_block);
+        if connected {
+            self.tip = block_hash;
+        }
+    }
+
+    /// Move the tip to the highest valid block, unless the tip is valid and
+    /// at least as high
+    fn update_tip(&mut self) {
+        let height =
+            |block_hash: &BlockHash| self.get(block_hash).anchor.height;
+        let best = self
+            .blocks
+            .iter()
+            .filter(|(block_hash, _)| self.is_valid(**block_hash))
+            .max_by_key(|(_, stored_block)| {
+                (stored_block.anchor.height, Reverse(stored_block.seq))
+            });
+        if let Some((&best, _)) = best {
+            if !self.is_valid(self.tip) || height(&best) > height(&self.tip) {
+                self.tip = best;
+            }
+        }
+    }
+
+    /// Invalidate a stored block, with `invalidateblock`
+    pub fn invalidate(&mut self, block_hash: BlockHash) {
+        self.invalidated.insert(block_hash);
+        self.update_tip()
+    }
+
+    /// Reconsider a stored block, with `reconsiderblock`. Clears the invalid
+    /// mark from the block, its ancestors, and its descendants.
+    pub fn reconsider(&mut self, block_hash: BlockHash) {
+        let ancestors: HashSet<BlockHash> =
+            self.ancestors(block_hash).collect();
+        let reconsidered: Vec<BlockHash> = self
+            .invalidated
+            .iter()
+            .copied()
+            .filter(|invalidated| {
+                ancestors.contains(invalidated)
+                    || self
+                        .ancestors(*invalidated)
+                        .any(|ancestor| ancestor == block_hash)
+            })
+            .collect();
+        for block_hash in reconsidered {
+            self.invalidated.remove(&block_hash);
+        }
+        self.update_tip()
+    }
+}
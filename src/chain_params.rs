@@ -0,0 +1,258 @@
+use std::path::Path;
+
+use anyhow::Context as _;
+use bitcoin::{
+    address::NetworkUnchecked,
+    bech32::{self, Hrp},
+    constants::SUBSIDY_HALVING_INTERVAL,
+    Address, Amount, Script, ScriptBuf, WitnessProgram, WitnessVersion,
+};
+use serde::Deserialize;
+
+use crate::errors::GenError;
+
+/// Challenge script for the global signet
+const GLOBAL_SIGNET_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+/// Halving interval used by regtest
+const REGTEST_HALVING_INTERVAL: u32 = 150;
+
+/// Overrides for the built-in chain parameters, as read from a
+/// `--chain-params` file
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainParamsFile {
+    /// Number of blocks between subsidy halvings
+    #[serde(default)]
+    pub halving_interval: Option<u32>,
+    /// Human-readable part for bech32 addresses
+    #[serde(default)]
+    pub bech32_hrp: Option<String>,
+    /// Signet challenge script, as hex
+    #[serde(default)]
+    pub signet_challenge: Option<String>,
+}
+
+impl ChainParamsFile {
+    /// Read the file as TOML if its extension is `.toml`, and as JSON
+    /// otherwise
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::read_to_string(path).with_context(|| {
+            format!("failed to read chain params file `{}`", path.display())
+        })?;
+        let res: anyhow::Result<Self> =
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                toml::from_str(&file).map_err(anyhow::Error::from)
+            } else {
+                let mut deserializer =
+                    serde_json::Deserializer::from_str(&file);
+                serde_path_to_error::deserialize(&mut deserializer)
+                    .map_err(anyhow::Error::from)
+            };
+        res.with_context(|| {
+            format!("invalid chain params file `{}`", path.display())
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub network: bitcoin::Network,
+    pub halving_interval: u32,
+    pub bech32_hrp: Hrp,
+    /// Only set for signet
+    pub signet_challenge: Option<ScriptBuf>,
+}
+
+impl ChainParams {
+    /// Built-in parameters for the specified network
+    fn default_for(network: bitcoin::Network) -> anyhow::Result<Self> {
+        #[allow(clippy::wildcard_in_or_patterns)]
+        let halving_interval = match network {
+            bitcoin::Network::Regtest => REGTEST_HALVING_INTERVAL,
+            bitcoin::Network::Bitcoin
+            | bitcoin::Network::Testnet
+            | bitcoin::Network::Signet
+            | _ => SUBSIDY_HALVING_INTERVAL,
+        };
+        let signet_challenge = if network == bitcoin::Network::Signet {
+            Some(ScriptBuf::from_hex(GLOBAL_SIGNET_CHALLENGE)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            network,
+            halving_interval,
+            bech32_hrp: match network {
+                bitcoin::Network::Bitcoin => bech32::hrp::BC,
+                bitcoin::Network::Regtest => bech32::hrp::BCRT,
+                _ => bech32::hrp::TB,
+            },
+            signet_challenge,
+        })
+    }
+
+    /// Combine the built-in parameters for the network with the signet
+    /// challenge flag and chain params file, rejecting contradictory
+    /// combinations
+    pub fn new(
+        network: bitcoin::Network,
+        signet_challenge: Option<ScriptBuf>,
+        chain_params_file: Option<ChainParamsFile>,
+    ) -> anyhow::Result<Self> {
+        let mut res = Self::default_for(network)?;
+        if signet_challenge.is_some() && network != bitcoin::Network::Signet {
+            anyhow::bail!("a signet challenge requires `--network signet`");
+        }
+        if let Some(signet_challenge) = &signet_challenge {
+            res.signet_challenge = Some(signet_challenge.clone());
+        }
+        let Some(ChainParamsFile {
+            halving_interval,
+            bech32_hrp,
+            signet_challenge: file_signet_challenge,
+        }) = chain_params_file
+        else {
+            return Ok(res);
+        };
+        match network {
+            bitcoin::Network::Regtest | bitcoin::Network::Signet => (),
+            network => anyhow::bail!(
+                "custom chain params are only supported for regtest and \
+                 signet, not {network}"
+            ),
+        }
+        if let Some(halving_interval) = halving_interval {
+            if halving_interval == 0 {
+                anyhow::bail!("halving interval must be non-zero");
+            }
+            res.halving_interval = halving_interval;
+        }
+        if let Some(bech32_hrp) = bech32_hrp {
+            res.bech32_hrp = Hrp::parse(&bech32_hrp).with_context(|| {
+                format!("invalid bech32 HRP `{bech32_hrp}`")
+            })?;
+        }
+        if let Some(file_signet_challenge) = file_signet_challenge {
+            if network != bitcoin::Network::Signet {
+                anyhow::bail!("a signet challenge requires `--network signet`");
+            }
+            if signet_challenge.is_some() {
+                anyhow::bail!(
+                    "signet challenge specified by both `--signet-challenge` \
+                     and the chain params file"
+                );
+            }
+            let file_signet_challenge =
+                ScriptBuf::from_hex(&file_signet_challenge)
+                    .context("invalid signet challenge hex")?;
+            res.signet_challenge = Some(file_signet_challenge);
+        }
+        Ok(res)
+    }
+
+    pub fn block_subsidy(&self, height: u32) -> Amount {
+        let epoch = height / self.halving_interval;
+        Amount::from_int_btc(50)
+            .checked_div(1 << epoch.min(63))
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Witness commitments are required in every signet block, since the
+    /// signet block solution is located via the witness commitment
+    pub fn requires_witness_commitment(&self) -> bool {
+        self.network == bitcoin::Network::Signet
+    }
+
+    /// Check that the signet challenge can be satisfied by an empty signet
+    /// solution. Signing blocks for non-trivial challenges is not supported.
+    pub fn check_signet_challenge(&self) -> Result<(), GenError> {
+        match &self.signet_challenge {
+            Some(challenge)
+                if challenge != crate::unlocked_script().as_script() =>
+            {
+                Err(GenError::UnsupportedSignetChallenge {
+                    challenge: challenge.clone(),
+                })
+            }
+            Some(_) | None => Ok(()),
+        }
+    }
+
+    /// Encode a segwit script pubkey as an address, using the configured
+    /// HRP. Returns `None` if the script is not a segwit output.
+    pub fn address(&self, script_pubkey: &Script) -> Option<String> {
+        let witness_version = script_pubkey.witness_version()?;
+        let witness_program = &script_pubkey.as_bytes()[2..];
+        bech32::segwit::encode(
+            self.bech32_hrp,
+            witness_version.to_fe(),
+            witness_program,
+        )
+        .ok()
+    }
+
+    /// Parse an address, checking that it is valid for the network, and
+    /// return its script pubkey. Segwit addresses must use the configured
+    /// HRP.
+    pub fn script_pubkey(&self, address: &str) -> anyhow::Result<ScriptBuf> {
+        if let Ok((hrp, witness_version, witness_program)) =
+            bech32::segwit::decode(address)
+        {
+            if hrp != self.bech32_hrp {
+                anyhow::bail!(
+                    "address `{address}` has HRP `{hrp}`, but the network \
+                     uses `{}`",
+                    self.bech32_hrp
+                );
+            }
+            let witness_version = WitnessVersion::try_from(witness_version)?;
+            let witness_program =
+                WitnessProgram::new(witness_version, &witness_program)?;
+            return Ok(ScriptBuf::new_witness_program(&witness_program));
+        }
+        let address: Address<NetworkUnchecked> = address
+            .parse()
+            .with_context(|| format!("invalid address `{address}`"))?;
+        let address = address.require_network(self.network)?;
+        Ok(address.script_pubkey())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChainParamsFile;
+
+    #[test]
+    fn read_toml_and_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("chain_params.toml");
+        std::fs::write(
+            &toml_path,
+            "halving_interval = 210\nbech32_hrp = \"tb\"\n",
+        )
+        .unwrap();
+        let json_path = dir.path().join("chain_params.json");
+        std::fs::write(
+            &json_path,
+            r#"{"halving_interval": 210, "bech32_hrp": "tb"}"#,
+        )
+        .unwrap();
+        for path in [&toml_path, &json_path] {
+            let file = ChainParamsFile::read(path).unwrap();
+            assert_eq!(file.halving_interval, Some(210));
+            assert_eq!(file.bech32_hrp.as_deref(), Some("tb"));
+            assert_eq!(file.signet_challenge, None);
+        }
+        // JSON is not parsed as TOML
+        std::fs::copy(&json_path, &toml_path).unwrap();
+        let err = ChainParamsFile::read(&toml_path).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("invalid chain params file"),
+            "{err:#}"
+        );
+        std::fs::write(&toml_path, "halving_intervl = 210\n").unwrap();
+        let err = ChainParamsFile::read(&toml_path).unwrap_err();
+        assert!(format!("{err:#}").contains("halving_intervl"), "{err:#}");
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt::Display;
+
+use bitcoin::{
+    hashes::{sha256d, Hash as _},
+    opcodes::all::{OP_PUSHBYTES_36, OP_RETURN},
+    Amount, Block, ScriptBuf, TxOut, WitnessMerkleNode,
+};
+
+use crate::{
+    bip300_message::Bip300Message, errors::GenError, WITNESS_COMMITMENT_HEADER,
+    WITNESS_RESERVED_VALUE,
+};
+
+/// Message carried by a coinbase output
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CoinbaseMessage {
+    Bip300(Bip300Message),
+    WitnessCommitment,
+}
+
+impl Display for CoinbaseMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bip300(message) => message.fmt(f),
+            Self::WitnessCommitment => write!(f, "witness commitment"),
+        }
+    }
+}
+
+/// Coinbase outputs built by a [`CoinbaseBuilder`]
+#[derive(Clone, Debug)]
+pub struct CoinbaseOutputs {
+    pub txouts: Vec<TxOut>,
+    /// Index of each output that carries a message, and its message
+    pub messages: Vec<(usize, CoinbaseMessage)>,
+}
+
+/// Composes the outputs of a coinbase tx, in the order that they are added
+#[derive(Clone, Debug)]
+pub struct CoinbaseBuilder {
+    /// Block subsidy plus fees
+    max_value: Amount,
+    outputs: Vec<(TxOut, Option<CoinbaseMessage>)>,
+}
+
+impl CoinbaseBuilder {
+    /// `max_value` is the block subsidy plus fees, which the outputs must not
+    /// pay more than
+    pub fn new(max_value: Amount) -> Self {
+        Self {
+            max_value,
+            outputs: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, txout: TxOut, message: Option<CoinbaseMessage>) {
+        self.outputs.push((txout, message))
+    }
+
+    pub fn payout(&mut self, script_pubkey: ScriptBuf, value: Amount) {
+        let txout = TxOut {
+            value,
+            script_pubkey,
+        };
+        self.push(txout, None)
+    }
+
+    pub fn bip300_message(&mut self, message: Bip300Message) {
+        let txout = message.to_txout();
+        self.push(txout, Some(CoinbaseMessage::Bip300(message)))
+    }
+
+    pub fn m1(&mut self, slot: u8, description: Vec<u8>) {
+        self.bip300_message(Bip300Message::M1Propose { slot, description })
+    }
+
+    pub fn m2(&mut self, slot: u8, description: &[u8]) {
+        self.bip300_message(Bip300Message::M2Ack {
+            slot,
+            description_hash: sha256d::Hash::hash(description),
+        })
+    }
+
+    /// Commit to the witness root of the block's txs. Must be added last,
+    /// since the last commitment in a coinbase tx is the one that counts.
+    pub fn witness_commitment(&mut self, witness_root: WitnessMerkleNode) {
+        let witness_commitment = Block::compute_witness_commitment(
+            &witness_root,
+            &WITNESS_RESERVED_VALUE,
+        );
+        let script_pubkey = ScriptBuf::from_bytes(
+            [OP_RETURN.to_u8(), OP_PUSHBYTES_36.to_u8()]
+                .into_iter()
+                .chain(WITNESS_COMMITMENT_HEADER)
+                .chain(witness_commitment.to_byte_array())
+                .collect(),
+        );
+        let txout = TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        };
+        self.push(txout, Some(CoinbaseMessage::WitnessCommitment))
+    }
+
+    /// Check that the outputs do not pay more than the block subsidy plus
+    /// fees, and build them
+    pub fn build(self) -> Result<CoinbaseOutputs, GenError> {
+        let Self { max_value, outputs } = self;
+        let total_value =
+            outputs.iter().try_fold(Amount::ZERO, |total, (txout, _)| {
+                total.checked_add(txout.value)
+            });
+        match total_value {
+            Some(total_value) if total_value <= max_value => (),
+            Some(total_value) => {
+                return Err(GenError::CoinbaseOverpays {
+                    total_value,
+                    max_value,
+                })
+            }
+            None => return Err(GenError::CoinbaseValueOverflow),
+        }
+        let mut txouts = Vec::with_capacity(outputs.len());
+        let mut messages = Vec::new();
+        for (idx, (txout, message)) in outputs.into_iter().enumerate() {
+            txouts.push(txout);
+            if let Some(message) = message {
+                messages.push((idx, message));
+            }
+        }
+        Ok(CoinbaseOutputs { txouts, messages })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        hashes::{sha256d, Hash as _},
+        Amount, ScriptBuf, WitnessMerkleNode,
+    };
+
+    use super::{CoinbaseBuilder, CoinbaseMessage};
+    use crate::{bip300_message::Bip300Message, errors::GenError};
+
+    fn payout_script() -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51])
+    }
+
+    #[test]
+    fn outputs_in_order_added() {
+        let mut builder = CoinbaseBuilder::new(Amount::from_sat(100));
+        builder.m1(1, b"sidechain".to_vec());
+        builder.payout(payout_script(), Amount::from_sat(60));
+        builder.m2(1, b"sidechain");
+        builder.bip300_message(Bip300Message::M3ProposeBundle {
+            slot: 1,
+            bundle_hash: [3; 32],
+        });
+        builder.witness_commitment(WitnessMerkleNode::all_zeros());
+        let outputs = builder.build().unwrap();
+        assert_eq!(outputs.txouts.len(), 5);
+        assert_eq!(outputs.txouts[1].script_pubkey, payout_script());
+        assert_eq!(outputs.txouts[1].value, Amount::from_sat(60));
+        let names: Vec<_> = outputs
+            .messages
+            .iter()
+            .map(|(idx, message)| (*idx, message.to_string()[..2].to_owned()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (0, "M1".to_owned()),
+                (2, "M2".to_owned()),
+                (3, "M3".to_owned()),
+                (4, "wi".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_indices_match_txouts() {
+        let mut builder = CoinbaseBuilder::new(Amount::from_sat(100));
+        builder.payout(payout_script(), Amount::from_sat(100));
+        builder.m1(0, b"x".to_vec());
+        builder.payout(payout_script(), Amount::ZERO);
+        builder.m2(0, b"x");
+        builder.bip300_message(Bip300Message::M3ProposeBundle {
+            slot: 9,
+            bundle_hash: [5; 32],
+        });
+        builder.witness_commitment(WitnessMerkleNode::all_zeros());
+        let outputs = builder.build().unwrap();
+        let indices: Vec<_> =
+            outputs.messages.iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(indices, [1, 3, 4, 5]);
+        for (idx, message) in &outputs.messages {
+            let txout = &outputs.txouts[*idx];
+            match message {
+                CoinbaseMessage::Bip300(message) => {
+                    assert_eq!(
+                        Bip300Message::parse(txout),
+                        Some(Ok(message.clone()))
+                    );
+                    assert_eq!(&message.to_txout(), txout);
+                }
+                CoinbaseMessage::WitnessCommitment => {
+                    assert!(txout.script_pubkey.as_bytes()[6..].len() == 32)
+                }
+            }
+        }
+        assert_eq!(
+            outputs.messages[1].1,
+            CoinbaseMessage::Bip300(Bip300Message::M2Ack {
+                slot: 0,
+                description_hash: sha256d::Hash::hash(b"x"),
+            })
+        );
+    }
+
+    #[test]
+    fn build_rejects_overpaying_coinbase() {
+        let mut builder = CoinbaseBuilder::new(Amount::from_sat(100));
+        builder.payout(payout_script(), Amount::from_sat(60));
+        builder.payout(payout_script(), Amount::from_sat(41));
+        match builder.build() {
+            Err(GenError::CoinbaseOverpays {
+                total_value,
+                max_value,
+            }) => {
+                assert_eq!(total_value, Amount::from_sat(101));
+                assert_eq!(max_value, Amount::from_sat(100));
+            }
+            res => panic!("expected CoinbaseOverpays, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn build_rejects_overflowing_coinbase() {
+        let mut builder = CoinbaseBuilder::new(Amount::MAX);
+        builder.payout(payout_script(), Amount::MAX);
+        builder.payout(payout_script(), Amount::from_sat(1));
+        match builder.build() {
+            Err(GenError::CoinbaseValueOverflow) => (),
+            res => panic!("expected CoinbaseValueOverflow, got {res:?}"),
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    net::SocketAddr,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::cli::{GenerateArgs, Network, RpcCredentials};
+
+/// Placeholder for the RPC pass in the printed configuration
+const REDACTED: &str = "<redacted>";
+
+/// Defaults for connection options, the coinbase address, and the number of
+/// mining threads, read from a TOML config file
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mining_threads: Option<NonZeroUsize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    /// Deprecated, use `rpc_url`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_addr: Option<SocketAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_cacert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_cookie: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_insecure: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_pass: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_retries: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_timeout: Option<NonZeroU64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_wallet: Option<String>,
+}
+
+/// `true` if the arg was not set on the command line or via an environment
+/// variable
+pub fn is_unset(matches: &ArgMatches, id: &str) -> bool {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable) => false,
+        Some(ValueSource::DefaultValue) | Some(_) | None => true,
+    }
+}
+
+impl Config {
+    /// Default config file location
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|config_dir| {
+            config_dir.join(env!("CARGO_PKG_NAME")).join("config.toml")
+        })
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(path).with_context(|| {
+            format!("failed to read config file `{}`", path.display())
+        })?;
+        toml::from_str(&config).with_context(|| {
+            format!("invalid config file `{}`", path.display())
+        })
+    }
+
+    /// Load the config file at the specified path, or at the default path.
+    /// A missing config file is only an error if the path was specified.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        if let Some(path) = path {
+            return Self::read(path);
+        }
+        match Self::default_path() {
+            Some(default_path) if default_path.exists() => {
+                Self::read(&default_path)
+            }
+            Some(_) | None => Ok(Self::default()),
+        }
+    }
+
+    /// Apply config values to options that were not specified on the command
+    /// line or via environment variables
+    pub fn apply(self, args: &mut GenerateArgs, matches: &ArgMatches) {
+        let Self {
+            coinbase_address,
+            mining_threads,
+            network,
+            rpc_addr,
+            rpc_cacert,
+            rpc_cookie,
+            rpc_insecure,
+            rpc_pass,
+            rpc_retries,
+            rpc_timeout,
+            rpc_url,
+            rpc_user,
+            rpc_wallet,
+        } = self;
+        if let Some(coinbase_address) = coinbase_address {
+            if is_unset(matches, "coinbase_address") {
+                args.coinbase_address = Some(coinbase_address);
+            }
+        }
+        if let Some(mining_threads) = mining_threads {
+            if is_unset(matches, "mining_threads") {
+                args.mining_threads = Some(mining_threads);
+            }
+        }
+        if let Some(network) = network {
+            if is_unset(matches, "network") {
+                args.network = network;
+            }
+        }
+        let rpc_url_unset =
+            is_unset(matches, "rpc_url") && is_unset(matches, "rpc_addr");
+        if let Some(rpc_url) = rpc_url {
+            if rpc_url_unset {
+                args.rpc.rpc_url = rpc_url;
+            }
+        } else if let Some(rpc_addr) = rpc_addr {
+            if rpc_url_unset {
+                args.rpc.rpc_addr = Some(rpc_addr);
+            }
+        }
+        if let Some(rpc_retries) = rpc_retries {
+            if is_unset(matches, "rpc_retries") {
+                args.rpc.rpc_retries = rpc_retries;
+            }
+        }
+        if let Some(rpc_timeout) = rpc_timeout {
+            if is_unset(matches, "rpc_timeout") {
+                args.rpc.rpc_timeout = rpc_timeout.get();
+            }
+        }
+        if let Some(rpc_wallet) = rpc_wallet {
+            if is_unset(matches, "rpc_wallet") {
+                args.rpc.rpc_wallet = Some(rpc_wallet);
+            }
+        }
+        // `--rpc-cacert` and `--rpc-insecure` are mutually exclusive, so
+        // config values for both are ignored if either was specified
+        // explicitly
+        let tls_unset = is_unset(matches, "rpc_cacert")
+            && is_unset(matches, "rpc_insecure");
+        if tls_unset {
+            if let Some(rpc_cacert) = rpc_cacert {
+                args.rpc.rpc_cacert = Some(rpc_cacert);
+            } else if let Some(rpc_insecure) = rpc_insecure {
+                args.rpc.rpc_insecure = rpc_insecure;
+            }
+        }
+        // Cookie auth and user/pass auth are mutually exclusive, so config
+        // values for one are ignored if the other was specified explicitly
+        let cookie_unset = is_unset(matches, "rpc_cookie");
+        let user_pass_unset =
+            is_unset(matches, "rpc_user") && is_unset(matches, "rpc_pass");
+        if let Some(rpc_cookie) = rpc_cookie {
+            if cookie_unset
+                && user_pass_unset
+                && !args.rpc.rpc_auth.rpc_auth_env
+            {
+                args.rpc.rpc_auth.rpc_cookie = Some(rpc_cookie);
+            }
+        }
+        if cookie_unset && args.rpc.rpc_auth.rpc_cookie.is_none() {
+            if let Some(rpc_pass) = rpc_pass {
+                if is_unset(matches, "rpc_pass") {
+                    args.rpc.rpc_auth.rpc_pass = rpc_pass;
+                }
+            }
+            if let Some(rpc_user) = rpc_user {
+                if is_unset(matches, "rpc_user") {
+                    args.rpc.rpc_auth.rpc_user = rpc_user;
+                }
+            }
+        }
+    }
+
+    /// Effective configuration, after applying the config file
+    pub fn effective(args: &GenerateArgs) -> Self {
+        let (rpc_cookie, rpc_pass, rpc_user) =
+            match &args.rpc.rpc_auth.rpc_cookie {
+                Some(rpc_cookie) => (Some(rpc_cookie.clone()), None, None),
+                None => {
+                    let RpcCredentials { user, pass } = args.rpc.user_pass();
+                    (None, Some(pass), Some(user))
+                }
+            };
+        let mining_threads = args.mining_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+        });
+        Self {
+            coinbase_address: args.coinbase_address.clone(),
+            mining_threads: Some(mining_threads),
+            network: Some(args.network),
+            rpc_addr: None,
+            rpc_cacert: args.rpc.rpc_cacert.clone(),
+            rpc_cookie,
+            rpc_insecure: args.rpc.rpc_insecure.then_some(true),
+            rpc_pass,
+            rpc_retries: Some(args.rpc.rpc_retries),
+            rpc_timeout: NonZeroU64::new(args.rpc.rpc_timeout),
+            rpc_url: Some(args.rpc.endpoint(None)),
+            rpc_user,
+            rpc_wallet: args.rpc.rpc_wallet.clone(),
+        }
+    }
+
+    /// Configuration with the RPC pass replaced by a placeholder, for
+    /// printing
+    pub fn redacted(self) -> Self {
+        let rpc_pass = self.rpc_pass.map(|rpc_pass| {
+            if rpc_pass.is_empty() {
+                rpc_pass
+            } else {
+                REDACTED.to_owned()
+            }
+        });
+        Self { rpc_pass, ..self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory as _, FromArgMatches as _};
+
+    use super::Config;
+    use crate::cli::{Cli, GenerateArgs, Network};
+
+    /// Config setting every option that has a flag
+    const CONFIG: &str = r#"
+        coinbase_address = "bcrt1qconfig"
+        mining_threads = 3
+        network = "signet"
+        rpc_pass = "config-pass"
+        rpc_retries = 7
+        rpc_timeout = 30
+        rpc_url = "http://127.0.0.1:38332/"
+        rpc_user = "config-user"
+        rpc_wallet = "config-wallet"
+    "#;
+
+    /// Args parsed from the command line, with the config applied
+    fn parse(config: &str, args: &[&str]) -> GenerateArgs {
+        let matches = Cli::command()
+            .try_get_matches_from(
+                ["bip300-enforcer-demo"].iter().chain(args).chain(&["[]"]),
+            )
+            .unwrap();
+        let mut args = Cli::from_arg_matches(&matches).unwrap().args;
+        let config: Config = toml::from_str(config).unwrap();
+        let () = config.apply(&mut args, &matches);
+        args
+    }
+
+    #[test]
+    fn config_beats_defaults() {
+        let args = parse(CONFIG, &[]);
+        assert_eq!(args.coinbase_address.as_deref(), Some("bcrt1qconfig"));
+        assert_eq!(args.mining_threads.map(|n| n.get()), Some(3));
+        assert!(matches!(args.network, Network::Signet));
+        assert_eq!(args.rpc.rpc_auth.rpc_pass, "config-pass");
+        assert_eq!(args.rpc.rpc_retries, 7);
+        assert_eq!(args.rpc.rpc_timeout, 30);
+        assert_eq!(args.rpc.rpc_url.as_str(), "http://127.0.0.1:38332/");
+        assert_eq!(args.rpc.rpc_auth.rpc_user, "config-user");
+        assert_eq!(args.rpc.rpc_wallet.as_deref(), Some("config-wallet"));
+    }
+
+    #[test]
+    fn flags_beat_config() {
+        let args = parse(
+            CONFIG,
+            &[
+                "--coinbase-address",
+                "bcrt1qflag",
+                "--mining-threads",
+                "5",
+                "--network",
+                "regtest",
+                "--rpc-pass",
+                "flag-pass",
+                "--rpc-retries",
+                "1",
+                "--rpc-timeout",
+                "10",
+                "--rpc-url",
+                "http://127.0.0.1:18443",
+                "--rpc-user",
+                "flag-user",
+                "--rpc-wallet",
+                "flag-wallet",
+            ],
+        );
+        assert_eq!(args.coinbase_address.as_deref(), Some("bcrt1qflag"));
+        assert_eq!(args.mining_threads.map(|n| n.get()), Some(5));
+        assert!(matches!(args.network, Network::Regtest));
+        assert_eq!(args.rpc.rpc_auth.rpc_pass, "flag-pass");
+        assert_eq!(args.rpc.rpc_retries, 1);
+        assert_eq!(args.rpc.rpc_timeout, 10);
+        assert_eq!(args.rpc.rpc_url.as_str(), "http://127.0.0.1:18443/");
+        assert_eq!(args.rpc.rpc_auth.rpc_user, "flag-user");
+        assert_eq!(args.rpc.rpc_wallet.as_deref(), Some("flag-wallet"));
+    }
+
+    /// Flags for cookie auth exclude user/pass auth from the config, and
+    /// vice versa
+    #[test]
+    fn explicit_auth_excludes_config_auth() {
+        let config = r#"
+            rpc_cookie = "/config/.cookie"
+            rpc_user = "config-user"
+        "#;
+        let args = parse(config, &["--rpc-pass", "flag-pass"]);
+        assert_eq!(args.rpc.rpc_auth.rpc_cookie, None);
+        assert_eq!(args.rpc.rpc_auth.rpc_pass, "flag-pass");
+        let config = r#"rpc_user = "config-user""#;
+        let args = parse(config, &["--rpc-cookie", "/flag/.cookie"]);
+        assert_eq!(args.rpc.rpc_auth.rpc_user, "");
+    }
+
+    #[test]
+    fn effective_config() {
+        let args = parse(CONFIG, &["--rpc-user", "flag-user"]);
+        let effective = Config::effective(&args);
+        assert_eq!(effective.rpc_user.as_deref(), Some("flag-user"));
+        assert_eq!(effective.rpc_pass.as_deref(), Some("config-pass"));
+        assert_eq!(effective.mining_threads.map(|n| n.get()), Some(3));
+        let printed = toml::to_string(&effective.redacted()).unwrap();
+        assert!(printed.contains(r#"rpc_pass = "<redacted>""#), "{printed}");
+        assert!(!printed.contains("config-pass"), "{printed}");
+        assert!(
+            printed.contains(r#"coinbase_address = "bcrt1qconfig""#),
+            "{printed}"
+        );
+        // Round trips, apart from the redacted pass
+        let _: Config = toml::from_str(&printed).unwrap();
+    }
+
+    #[test]
+    fn empty_pass_not_redacted() {
+        let effective = Config::effective(&parse("", &[]));
+        assert_eq!(effective.redacted().rpc_pass.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn unknown_keys_rejected() {
+        let err = toml::from_str::<Config>("rpc_usr = \"user\"").unwrap_err();
+        assert!(err.to_string().contains("rpc_usr"), "{err}");
+    }
+}
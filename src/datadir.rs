@@ -0,0 +1,212 @@
+//! Discovery of RPC connection options from a Bitcoin Core datadir
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use clap::ArgMatches;
+use url::Url;
+
+use crate::{
+    cli::{GenerateArgs, Network, DEFAULT_RPC_URL},
+    config::is_unset,
+};
+
+impl Network {
+    /// Name of the network's section in `bitcoin.conf`
+    fn conf_section(self) -> &'static str {
+        match self {
+            Self::Mainnet => "main",
+            Self::Testnet => "test",
+            Self::Signet => "signet",
+            Self::Regtest => "regtest",
+        }
+    }
+
+    /// Subdirectory of the datadir used for the network
+    fn datadir_subdir(self) -> &'static str {
+        match self {
+            Self::Mainnet => "",
+            Self::Testnet => "testnet3",
+            Self::Signet => "signet",
+            Self::Regtest => "regtest",
+        }
+    }
+
+    fn default_rpc_port(self) -> u16 {
+        match self {
+            Self::Mainnet => 8332,
+            Self::Testnet => 18332,
+            Self::Signet => 38332,
+            Self::Regtest => 18443,
+        }
+    }
+}
+
+/// RPC settings read from `bitcoin.conf`
+#[derive(Debug, Default)]
+struct BitcoinConf {
+    rpcbind: Option<String>,
+    rpcport: Option<u16>,
+}
+
+impl BitcoinConf {
+    /// Parse the settings that apply to the specified network.
+    /// As in Bitcoin Core, settings outside of a network section only apply
+    /// to mainnet.
+    fn parse(conf: &str, network: Network) -> anyhow::Result<Self> {
+        let mut res = Self::default();
+        let mut section = Network::Mainnet.conf_section();
+        for (line_idx, line) in conf.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) =
+                line.strip_prefix('[').and_then(|s| s.strip_suffix(']'))
+            {
+                section = name.trim();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let (key_section, key) = match key.split_once('.') {
+                Some((key_section, key)) => (key_section, key),
+                None => (section, key),
+            };
+            if key_section != network.conf_section() {
+                continue;
+            }
+            match key {
+                // The first `rpcbind` is used, if there are several
+                "rpcbind" if res.rpcbind.is_none() => {
+                    res.rpcbind = Some(value.to_owned());
+                }
+                "rpcport" => {
+                    let rpcport = value.parse().with_context(|| {
+                        format!(
+                            "invalid `rpcport` on line {} of `bitcoin.conf`",
+                            line_idx + 1
+                        )
+                    })?;
+                    res.rpcport = Some(rpcport);
+                }
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    /// RPC URL for the node, using defaults for the network where not
+    /// specified
+    fn rpc_url(&self, network: Network) -> anyhow::Result<Url> {
+        let default_port = self.rpcport.unwrap_or(network.default_rpc_port());
+        let (host, port) = match self.rpcbind.as_deref() {
+            None => (IpAddr::V4(Ipv4Addr::LOCALHOST).to_string(), default_port),
+            Some(rpcbind) => {
+                if let Ok(addr) = rpcbind.parse::<SocketAddr>() {
+                    (addr.ip().to_string(), addr.port())
+                } else if let Ok(ip) = rpcbind
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                {
+                    (ip.to_string(), default_port)
+                } else {
+                    match rpcbind.rsplit_once(':') {
+                        Some((host, port)) => {
+                            let port = port.parse().with_context(|| {
+                                format!("invalid `rpcbind` `{rpcbind}`")
+                            })?;
+                            (host.to_owned(), port)
+                        }
+                        None => (rpcbind.to_owned(), default_port),
+                    }
+                }
+            }
+        };
+        // Connect to localhost if the node binds to all interfaces
+        let host = match host.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.to_string(),
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]"),
+            Ok(IpAddr::V4(_)) | Err(_) => host,
+        };
+        Url::parse(&format!("http://{host}:{port}/"))
+            .with_context(|| format!("invalid `rpcbind` host `{host}`"))
+    }
+}
+
+/// RPC connection options discovered from a Bitcoin Core datadir
+#[derive(Debug)]
+pub struct Datadir {
+    /// Cookie file location. The file only exists while the node is running.
+    cookie: PathBuf,
+    rpc_url: Url,
+}
+
+impl Datadir {
+    pub fn discover(path: &Path, network: Network) -> anyhow::Result<Self> {
+        if !path.is_dir() {
+            anyhow::bail!("Bitcoin datadir `{}` not found", path.display());
+        }
+        let conf_path = path.join("bitcoin.conf");
+        let conf = if conf_path.exists() {
+            let conf =
+                std::fs::read_to_string(&conf_path).with_context(|| {
+                    format!("failed to read `{}`", conf_path.display())
+                })?;
+            BitcoinConf::parse(&conf, network).with_context(|| {
+                format!("failed to parse `{}`", conf_path.display())
+            })?
+        } else {
+            BitcoinConf::default()
+        };
+        let cookie = path.join(network.datadir_subdir()).join(".cookie");
+        Ok(Self {
+            cookie,
+            rpc_url: conf.rpc_url(network)?,
+        })
+    }
+
+    /// Apply discovered values to connection options that were not
+    /// specified on the command line, via environment variables, or in the
+    /// config file
+    pub fn apply(
+        self,
+        args: &mut GenerateArgs,
+        matches: &ArgMatches,
+    ) -> anyhow::Result<()> {
+        let Self { cookie, rpc_url } = self;
+        let rpc = &mut args.rpc;
+        if is_unset(matches, "rpc_url")
+            && is_unset(matches, "rpc_addr")
+            && rpc.rpc_url == Url::parse(DEFAULT_RPC_URL).unwrap()
+        {
+            tracing::info!(%rpc_url, "Discovered RPC URL from datadir");
+            rpc.rpc_url = rpc_url;
+        }
+        let auth = &mut rpc.rpc_auth;
+        if auth.rpc_cookie.is_none()
+            && auth.rpc_user.is_empty()
+            && auth.rpc_pass.is_empty()
+            && !auth.rpc_auth_env
+        {
+            if !cookie.exists() {
+                anyhow::bail!(
+                    "RPC cookie file `{}` not found: is the node running?",
+                    cookie.display()
+                );
+            }
+            tracing::info!(
+                cookie = %cookie.display(),
+                "Discovered RPC cookie file from datadir"
+            );
+            auth.rpc_cookie = Some(cookie);
+        }
+        Ok(())
+    }
+}
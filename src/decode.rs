@@ -0,0 +1,198 @@
+use std::io::Read as _;
+
+use anyhow::Context as _;
+use bitcoin::{hex::DisplayHex as _, Block, BlockHash};
+use serde::Serialize;
+
+use crate::{bip300_message::Bip300Message, report::render_table};
+
+/// Version of the decoded block output. Must be incremented whenever the
+/// output changes in a way that is not backwards-compatible.
+pub const DECODE_VERSION: u32 = 1;
+
+/// BIP300/BIP301 message found in a block
+#[derive(Debug, Serialize)]
+pub struct DecodedMessage {
+    /// Index of the tx in the block, where the coinbase tx is 0
+    pub tx_index: usize,
+    pub output_index: usize,
+    /// Name of the message, eg. `M2`
+    pub message: &'static str,
+    pub slot: Option<u8>,
+    /// Payload after the magic bytes and the slot, as hex, in the order
+    /// that the bytes appear in the output
+    pub payload: String,
+    /// Hashes in the description are displayed in the usual reversed byte
+    /// order, so they may appear reversed with respect to the payload
+    pub description: String,
+}
+
+/// Output that resembles a BIP300/BIP301 message, but is not parsed as one,
+/// or a message that is ignored where it is
+#[derive(Debug, Serialize)]
+pub struct DecodeWarning {
+    pub tx_index: usize,
+    pub output_index: usize,
+    pub warning: String,
+}
+
+/// Height that a block commits to at the start of its coinbase scriptSig.
+/// Unlike [`Block::bip34_block_height`], heights pushed with `OP_1` to
+/// `OP_16` are accepted, as they are by nodes.
+pub fn bip34_height(block: &Block) -> Option<u64> {
+    let coinbase_tx = block.txdata.first()?;
+    let coinbase_txin = coinbase_tx.input.first()?;
+    let instruction = coinbase_txin.script_sig.instructions().next()?.ok()?;
+    instruction
+        .script_num()
+        .and_then(|height| u64::try_from(height).ok())
+}
+
+/// Block decoded by the `decode` subcommand
+#[derive(Debug, Serialize)]
+pub struct DecodedBlock {
+    pub version: u32,
+    pub hash: BlockHash,
+    /// Height committed to at the start of the coinbase scriptSig, if any,
+    /// as in BIP34
+    pub height: Option<u64>,
+    pub n_txs: usize,
+    pub messages: Vec<DecodedMessage>,
+    pub warnings: Vec<DecodeWarning>,
+}
+
+impl DecodedBlock {
+    pub fn new(block: &Block) -> Self {
+        let mut messages = Vec::new();
+        let mut warnings = Vec::new();
+        for (tx_index, tx) in block.txdata.iter().enumerate() {
+            let is_coinbase = tx_index == 0;
+            for (output_index, txout) in tx.output.iter().enumerate() {
+                let mut warn = |warning: String| {
+                    warnings.push(DecodeWarning {
+                        tx_index,
+                        output_index,
+                        warning,
+                    })
+                };
+                if let Some(name) = Bip300Message::pushed_message_name(txout) {
+                    warn(format!(
+                        "the magic bytes of an {name} message are pushed, \
+                         rather than following OP_RETURN directly, so the \
+                         output is not an {name} message"
+                    ));
+                }
+                let message = match Bip300Message::parse(txout) {
+                    None => continue,
+                    Some(Err(err)) => {
+                        warn(err.to_string());
+                        continue;
+                    }
+                    Some(Ok(message)) => message,
+                };
+                let name = message.name();
+                if message.in_coinbase() && !is_coinbase {
+                    warn(format!(
+                        "{name} messages are only valid in the coinbase tx"
+                    ));
+                } else if !message.in_coinbase() && is_coinbase {
+                    warn(format!(
+                        "{name} messages are not valid in the coinbase tx"
+                    ));
+                }
+                messages.push(DecodedMessage {
+                    tx_index,
+                    output_index,
+                    message: name,
+                    slot: message.slot(),
+                    payload: message.data().to_lower_hex_string(),
+                    description: message.to_string(),
+                });
+            }
+        }
+        Self {
+            version: DECODE_VERSION,
+            hash: block.block_hash(),
+            height: bip34_height(block),
+            n_txs: block.txdata.len(),
+            messages,
+            warnings,
+        }
+    }
+
+    /// Render the decoded block as plain text, with a table of its messages
+    pub fn render(&self) -> String {
+        const HEADER: [&str; 5] =
+            ["Tx", "Output", "Message", "Slot", "Payload"];
+        let height = match self.height {
+            Some(height) => height.to_string(),
+            None => "unknown (no BIP34 height in the coinbase)".to_owned(),
+        };
+        let mut res = format!(
+            "Block {}\nHeight: {height}\nTransactions: {}\n\n",
+            self.hash, self.n_txs
+        );
+        if self.messages.is_empty() {
+            res.push_str("No BIP300/BIP301 messages\n");
+        } else {
+            let rows: Vec<[String; 5]> = self
+                .messages
+                .iter()
+                .map(|message| {
+                    [
+                        message.tx_index.to_string(),
+                        message.output_index.to_string(),
+                        message.message.to_owned(),
+                        message
+                            .slot
+                            .map_or("-".to_owned(), |slot| slot.to_string()),
+                        message.payload.clone(),
+                    ]
+                })
+                .collect();
+            res.push_str(&render_table(HEADER, &rows));
+            res.push('\n');
+            for message in &self.messages {
+                res.push_str(&format!(
+                    "{}:{}: {}\n",
+                    message.tx_index, message.output_index, message.description
+                ));
+            }
+        }
+        if !self.warnings.is_empty() {
+            res.push_str("\nWarnings:\n");
+            for warning in &self.warnings {
+                res.push_str(&format!(
+                    "- {}:{}: {}\n",
+                    warning.tx_index, warning.output_index, warning.warning
+                ));
+            }
+        }
+        res
+    }
+}
+
+/// Decode a block from hex, or from stdin if `block_hex` is `-`, and print
+/// the BIP300/BIP301 messages in it
+pub fn decode_block(block_hex: &str, json: bool) -> anyhow::Result<()> {
+    let block_hex = if block_hex == "-" {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("failed to read block hex from stdin")?;
+        input
+    } else {
+        block_hex.to_owned()
+    };
+    // Quotes are trimmed, so that hex can be pasted from generated scripts
+    let block_hex = block_hex.trim().trim_matches(['"', '\'']);
+    let block: Block = bitcoin::consensus::encode::deserialize_hex(block_hex)
+        .context("invalid block hex")?;
+    let decoded = DecodedBlock::new(&block);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&decoded)?);
+    } else {
+        print!("{}", decoded.render());
+    }
+    Ok(())
+}
@@ -0,0 +1,607 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context as _;
+use bitcoin::{
+    hashes::{sha256d, Hash as _},
+    hex::DisplayHex as _,
+    BlockHash,
+};
+use serde::Serialize;
+use tonic::transport::{Channel, Endpoint};
+
+use crate::{
+    cli::Verdict,
+    proto::cusf::{
+        common::v1::ReverseHex,
+        mainchain::v1::{
+            get_ctip_response::Ctip,
+            get_sidechain_proposals_response::SidechainProposal,
+            validator_service_client::ValidatorServiceClient,
+            GetBlockFailuresRequest, GetBlockFailuresResponse,
+            GetChainTipRequest, GetChainTipResponse, GetCtipRequest,
+            GetSidechainProposalsRequest, GetSidechainProposalsResponse,
+            GetSidechainsRequest, GetSidechainsResponse,
+        },
+    },
+};
+
+/// gRPC service that enforcer state is read from, as defined in
+/// `proto/cusf/mainchain/v1/validator.proto`. Generated scripts send
+/// requests as JSON, as supported by gRPC-gateway, to
+/// `<enforcer URL>/<service>/<method>`, since they have no gRPC client.
+pub const VALIDATOR_SERVICE: &str = "cusf.mainchain.v1.ValidatorService";
+
+/// Method of [`VALIDATOR_SERVICE`] that returns the BIP300 failures that an
+/// enforcer in report-only mode recorded for a block. This method, and the
+/// failure identifiers in [`crate::report`], must be checked against the
+/// enforcer version under test.
+pub const BLOCK_FAILURES_METHOD: &str = "GetBlockFailures";
+
+/// Number of blocks that must ack a proposal for an unused sidechain slot
+/// for the sidechain to activate, as in BIP300
+pub const ACTIVATION_THRESHOLD: u32 = 1815;
+
+/// Number of attempts when waiting for the enforcer to reach the expected
+/// state, one second apart. The enforcer processes blocks asynchronously, so
+/// the state may lag behind the node.
+pub const ENFORCER_CHECK_ATTEMPTS: u32 = 10;
+
+/// Enforcer state that is expected after submitting a block, as predicted by
+/// the generator
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "check")]
+pub enum EnforcerCheck {
+    /// A sidechain proposal for the slot and description is pending, with
+    /// the specified number of acks
+    Proposal {
+        slot: u8,
+        /// SHA256D hash of the sidechain description, as consensus-encoded
+        /// hex
+        description_hash: String,
+        acks: u32,
+    },
+    /// The sidechain in the slot is active, or not
+    SidechainActive { slot: u8, active: bool },
+    /// The sidechain's treasury has no CTIP (critical treasury index
+    /// pointer), since generated blocks make no deposits
+    NoCtip { slot: u8 },
+    /// The block is not the enforcer's chain tip, since it was rejected
+    NotTip { block_hash: BlockHash },
+}
+
+impl EnforcerCheck {
+    /// Method of [`VALIDATOR_SERVICE`] that returns the state to check
+    pub fn method(&self) -> &'static str {
+        match self {
+            Self::Proposal { .. } => "GetSidechainProposals",
+            Self::SidechainActive { .. } => "GetSidechains",
+            Self::NoCtip { .. } => "GetCtip",
+            Self::NotTip { .. } => "GetChainTip",
+        }
+    }
+
+    /// Body of the request to [`Self::method`]
+    pub fn request_body(&self) -> serde_json::Value {
+        match self {
+            Self::NoCtip { slot } => {
+                serde_json::json!({ "sidechainNumber": slot })
+            }
+            Self::Proposal { .. }
+            | Self::SidechainActive { .. }
+            | Self::NotTip { .. } => serde_json::json!({}),
+        }
+    }
+}
+
+/// Pending sidechain proposal
+#[derive(Clone, Debug)]
+struct Proposal {
+    slot: u8,
+    description_hash: sha256d::Hash,
+    acks: u32,
+}
+
+impl Proposal {
+    /// `true` if the proposal has enough acks to activate the sidechain
+    fn activated(&self) -> bool {
+        self.acks >= ACTIVATION_THRESHOLD
+    }
+
+    /// Checks for the proposal's acks, or for the sidechain being active
+    /// once it activates, and for its treasury
+    fn checks(&self) -> Vec<EnforcerCheck> {
+        let mut checks = Vec::new();
+        if !self.activated() {
+            checks.push(EnforcerCheck::Proposal {
+                slot: self.slot,
+                description_hash: self
+                    .description_hash
+                    .to_byte_array()
+                    .to_lower_hex_string(),
+                acks: self.acks,
+            });
+        }
+        checks.push(EnforcerCheck::SidechainActive {
+            slot: self.slot,
+            active: self.activated(),
+        });
+        checks.push(EnforcerCheck::NoCtip { slot: self.slot });
+        checks
+    }
+}
+
+/// Tracks the enforcer state that is expected as generated blocks are
+/// submitted, in order
+#[derive(Clone, Debug, Default)]
+pub struct EnforcerState {
+    proposal: Option<Proposal>,
+}
+
+impl EnforcerState {
+    /// Update the state for an accepted block containing an M1 proposal,
+    /// and return the checks for the new state
+    pub fn m1_accepted(
+        &mut self,
+        slot: u8,
+        description: &[u8],
+    ) -> Vec<EnforcerCheck> {
+        let proposal = Proposal {
+            slot,
+            description_hash: sha256d::Hash::hash(description),
+            acks: 0,
+        };
+        let checks = proposal.checks();
+        self.proposal = Some(proposal);
+        checks
+    }
+
+    /// Checks for the current state
+    pub fn checks(&self) -> Vec<EnforcerCheck> {
+        self.proposal.iter().flat_map(Proposal::checks).collect()
+    }
+
+    /// Update the state for a submitted block, and return the checks for the
+    /// new state. `m2` is the slot and description hash that the block's M2
+    /// messages ack, if any. Acks are only counted once per block, and not
+    /// once the sidechain is active. The sidechain's activation status and
+    /// treasury are checked with its proposal.
+    /// Blocks stored on a side branch update the state of their branch, but
+    /// the enforcer does not see them until the branch is connected.
+    pub fn block_submitted(
+        &mut self,
+        block_hash: BlockHash,
+        verdict: Verdict,
+        m2: Option<(u8, sha256d::Hash)>,
+    ) -> Vec<EnforcerCheck> {
+        let mut checks = Vec::new();
+        if let (Some(proposal), Some((slot, description_hash))) =
+            (&mut self.proposal, m2)
+        {
+            if verdict != Verdict::Reject
+                && !proposal.activated()
+                && proposal.slot == slot
+                && proposal.description_hash == description_hash
+            {
+                proposal.acks += 1;
+            }
+            if verdict != Verdict::Inconclusive {
+                checks.extend(proposal.checks());
+            }
+        }
+        if verdict != Verdict::Accept {
+            checks.push(EnforcerCheck::NotTip { block_hash });
+        }
+        checks
+    }
+}
+
+/// A field of the enforcer's state that is not as expected
+#[derive(Clone, Debug, Serialize)]
+pub struct EnforcerMismatch {
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for EnforcerMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            field,
+            expected,
+            actual,
+        } = self;
+        write!(f, "{field}: expected {expected}, actual {actual}")
+    }
+}
+
+/// Client for the enforcer's gRPC API, used to check the enforcer's state
+/// when submitting blocks directly
+pub struct EnforcerClient {
+    client: ValidatorServiceClient<Channel>,
+    url: String,
+}
+
+impl EnforcerClient {
+    /// Client that connects to the enforcer on the first request. Connecting
+    /// and each request time out after `timeout`.
+    pub fn new(addr: SocketAddr, timeout: Duration) -> Self {
+        let url = format!("http://{addr}");
+        let channel = Endpoint::from_shared(url.clone())
+            .unwrap()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .connect_lazy();
+        Self {
+            client: ValidatorServiceClient::new(channel),
+            url,
+        }
+    }
+
+    /// Response to a request to a method of [`VALIDATOR_SERVICE`]. `None` if
+    /// the enforcer responds that the requested item was not found.
+    fn optional<Response>(
+        &self,
+        method: &str,
+        res: Result<tonic::Response<Response>, tonic::Status>,
+    ) -> anyhow::Result<Option<Response>> {
+        match res {
+            Ok(response) => Ok(Some(response.into_inner())),
+            Err(status) if status.code() == tonic::Code::NotFound => Ok(None),
+            Err(status) => Err(anyhow::Error::new(status).context(format!(
+                "`{method}` request to the enforcer at `{}` failed",
+                self.url
+            ))),
+        }
+    }
+
+    /// Like [`Self::optional`], but fails if the item was not found
+    fn required<Response>(
+        &self,
+        method: &str,
+        res: Result<tonic::Response<Response>, tonic::Status>,
+    ) -> anyhow::Result<Response> {
+        self.optional(method, res)?.with_context(|| {
+            format!(
+                "`{method}` request to the enforcer at `{}` found nothing",
+                self.url
+            )
+        })
+    }
+
+    /// Hash of the enforcer's chain tip, hex-encoded, if it has one
+    async fn chain_tip(&self) -> anyhow::Result<Option<String>> {
+        let res = self
+            .client
+            .clone()
+            .get_chain_tip(GetChainTipRequest {})
+            .await;
+        let GetChainTipResponse { block_header_info } =
+            self.required("GetChainTip", res)?;
+        Ok(block_header_info
+            .and_then(|info| info.block_hash)
+            .and_then(|block_hash| block_hash.hex))
+    }
+
+    /// Identifiers of the BIP300 failures that the enforcer recorded for a
+    /// block, in report-only mode. `None` if the enforcer has no record of
+    /// the block.
+    pub async fn block_failures(
+        &self,
+        block_hash: BlockHash,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let request = GetBlockFailuresRequest {
+            block_hash: Some(ReverseHex {
+                hex: Some(block_hash.to_string()),
+            }),
+        };
+        let res = self.client.clone().get_block_failures(request).await;
+        let response = self.optional(BLOCK_FAILURES_METHOD, res)?;
+        Ok(response.map(|GetBlockFailuresResponse { failures }| {
+            failures.into_iter().map(|failure| failure.reason).collect()
+        }))
+    }
+
+    /// Check that the enforcer is reachable, by requesting its chain tip
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let _: Option<String> = self.chain_tip().await?;
+        Ok(())
+    }
+
+    /// Compare the enforcer's state against a check, and return the fields
+    /// that do not match
+    async fn mismatches(
+        &self,
+        check: &EnforcerCheck,
+    ) -> anyhow::Result<Vec<EnforcerMismatch>> {
+        let mut mismatches = Vec::new();
+        match check {
+            EnforcerCheck::Proposal {
+                slot,
+                description_hash,
+                acks,
+            } => {
+                let res = self
+                    .client
+                    .clone()
+                    .get_sidechain_proposals(GetSidechainProposalsRequest {})
+                    .await;
+                let GetSidechainProposalsResponse {
+                    sidechain_proposals,
+                } = self.required(check.method(), res)?;
+                let proposals: Vec<_> = sidechain_proposals
+                    .iter()
+                    .filter(|proposal| {
+                        proposal.sidechain_number.unwrap_or_default()
+                            == u32::from(*slot)
+                    })
+                    .collect();
+                let proposal_hash = |proposal: &SidechainProposal| {
+                    proposal
+                        .description_sha256d_hash
+                        .as_ref()
+                        .and_then(|hash| hash.hex.clone())
+                };
+                let proposal = proposals.iter().find(|proposal| {
+                    proposal_hash(proposal).as_ref() == Some(description_hash)
+                });
+                match (proposal, proposals.first()) {
+                    (Some(proposal), _) => {
+                        let vote_count =
+                            proposal.vote_count.unwrap_or_default();
+                        if vote_count != *acks {
+                            mismatches.push(EnforcerMismatch {
+                                field: "acks",
+                                expected: acks.to_string(),
+                                actual: vote_count.to_string(),
+                            });
+                        }
+                    }
+                    (None, Some(proposal)) => {
+                        mismatches.push(EnforcerMismatch {
+                            field: "description_hash",
+                            expected: description_hash.clone(),
+                            actual: proposal_hash(proposal)
+                                .unwrap_or_else(|| "none".to_owned()),
+                        });
+                    }
+                    (None, None) => {
+                        mismatches.push(EnforcerMismatch {
+                            field: "proposal",
+                            expected: format!(
+                                "a proposal for sidechain {slot} with {acks} \
+                                 acks"
+                            ),
+                            actual: "no proposal".to_owned(),
+                        });
+                    }
+                }
+            }
+            EnforcerCheck::SidechainActive { slot, active } => {
+                let res = self
+                    .client
+                    .clone()
+                    .get_sidechains(GetSidechainsRequest {})
+                    .await;
+                let GetSidechainsResponse { sidechains } =
+                    self.required(check.method(), res)?;
+                let actual = sidechains.iter().any(|sidechain| {
+                    sidechain.sidechain_number.unwrap_or_default()
+                        == u32::from(*slot)
+                });
+                if actual != *active {
+                    let status = |active| {
+                        if active { "active" } else { "inactive" }.to_owned()
+                    };
+                    mismatches.push(EnforcerMismatch {
+                        field: "active",
+                        expected: status(*active),
+                        actual: status(actual),
+                    });
+                }
+            }
+            EnforcerCheck::NoCtip { slot } => {
+                let request = GetCtipRequest {
+                    sidechain_number: Some(u32::from(*slot)),
+                };
+                let res = self.client.clone().get_ctip(request).await;
+                // The enforcer may respond that an inactive sidechain has
+                // no treasury, rather than with no CTIP
+                let response = self.optional(check.method(), res)?;
+                if let Some(Ctip { txid, vout, .. }) =
+                    response.and_then(|response| response.ctip)
+                {
+                    let txid = txid
+                        .and_then(|txid| txid.hex)
+                        .unwrap_or_else(|| "unknown".to_owned());
+                    mismatches.push(EnforcerMismatch {
+                        field: "ctip",
+                        expected: "no CTIP".to_owned(),
+                        actual: format!("{txid}:{vout}"),
+                    });
+                }
+            }
+            EnforcerCheck::NotTip { block_hash } => {
+                let tip = self.chain_tip().await?;
+                if tip.as_deref() == Some(block_hash.to_string().as_str()) {
+                    mismatches.push(EnforcerMismatch {
+                        field: "tip",
+                        expected: format!("a block other than {block_hash}"),
+                        actual: block_hash.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Whether the enforcer connected a block that the node accepted, ie.
+    /// whether the block became the enforcer's tip, retrying while the
+    /// enforcer catches up
+    pub async fn block_verdict(
+        &self,
+        block_hash: BlockHash,
+    ) -> anyhow::Result<Verdict> {
+        let block_hash = block_hash.to_string();
+        let mut attempt = 1;
+        loop {
+            let tip = self.chain_tip().await?;
+            if tip.as_deref() == Some(block_hash.as_str()) {
+                return Ok(Verdict::Accept);
+            }
+            if attempt >= ENFORCER_CHECK_ATTEMPTS {
+                return Ok(Verdict::Reject);
+            }
+            attempt += 1;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Check the enforcer's state, retrying while the enforcer catches up,
+    /// and return the fields that still do not match after the last attempt
+    pub async fn check(
+        &self,
+        check: &EnforcerCheck,
+    ) -> anyhow::Result<Vec<EnforcerMismatch>> {
+        let mut attempt = 1;
+        loop {
+            let mismatches = self.mismatches(check).await?;
+            if mismatches.is_empty() || attempt >= ENFORCER_CHECK_ATTEMPTS {
+                return Ok(mismatches);
+            }
+            attempt += 1;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bitcoin::{
+        hashes::{sha256d, Hash as _},
+        BlockHash,
+    };
+    use serde_json::json;
+
+    use super::{
+        EnforcerCheck, EnforcerClient, EnforcerState, ACTIVATION_THRESHOLD,
+    };
+    use crate::{cli::Verdict, mock_rpc::MockEnforcerServer};
+
+    const SLOT: u8 = 3;
+
+    fn active_checks(checks: &[EnforcerCheck]) -> Vec<bool> {
+        checks
+            .iter()
+            .filter_map(|check| match check {
+                EnforcerCheck::SidechainActive { slot, active } => {
+                    assert_eq!(*slot, SLOT);
+                    Some(*active)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn proposal_acks(checks: &[EnforcerCheck]) -> Vec<u32> {
+        checks
+            .iter()
+            .filter_map(|check| match check {
+                EnforcerCheck::Proposal { acks, .. } => Some(*acks),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sidechain_activates_at_threshold() {
+        let description = b"sidechain";
+        let m2 = Some((SLOT, sha256d::Hash::hash(description)));
+        let mut state = EnforcerState::default();
+        let checks = state.m1_accepted(SLOT, description);
+        assert_eq!(proposal_acks(&checks), [0]);
+        assert_eq!(active_checks(&checks), [false]);
+        assert!(checks.iter().any(|check| matches!(
+            check,
+            EnforcerCheck::NoCtip { slot: SLOT }
+        )));
+        let block_hash = BlockHash::all_zeros();
+        for _ in 1..ACTIVATION_THRESHOLD {
+            let _ = state.block_submitted(block_hash, Verdict::Accept, m2);
+        }
+        // Rejected blocks do not ack
+        let checks = state.block_submitted(block_hash, Verdict::Reject, m2);
+        assert_eq!(proposal_acks(&checks), [ACTIVATION_THRESHOLD - 1]);
+        assert_eq!(active_checks(&checks), [false]);
+        let checks = state.block_submitted(block_hash, Verdict::Accept, m2);
+        assert!(proposal_acks(&checks).is_empty());
+        assert_eq!(active_checks(&checks), [true]);
+        // Acks after activation are not counted
+        let _ = state.block_submitted(block_hash, Verdict::Accept, m2);
+        assert_eq!(active_checks(&state.checks()), [true]);
+        assert_eq!(state.checks().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn activation_and_ctip_mismatches() {
+        let enforcer = MockEnforcerServer::start(|method, request| {
+            match method {
+                "GetSidechains" => Some(json!({
+                    "sidechains": [{ "sidechainNumber": SLOT }],
+                })),
+                "GetCtip" => match request["sidechainNumber"].as_u64()? {
+                    // An inactive sidechain has no treasury
+                    0 => None,
+                    _ => Some(json!({
+                        "ctip": {
+                            "txid": { "hex": "ab".repeat(32) },
+                            "vout": 1,
+                            "value": 1000,
+                            "sequenceNumber": 0,
+                        },
+                    })),
+                },
+                _ => panic!("unexpected method {method}"),
+            }
+        })
+        .await;
+        let client =
+            EnforcerClient::new(enforcer.addr(), Duration::from_secs(5));
+        let active = EnforcerCheck::SidechainActive {
+            slot: SLOT,
+            active: true,
+        };
+        assert!(client.mismatches(&active).await.unwrap().is_empty());
+        let inactive = EnforcerCheck::SidechainActive {
+            slot: SLOT,
+            active: false,
+        };
+        let mismatches = client.mismatches(&inactive).await.unwrap();
+        assert_eq!(
+            mismatches
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["active: expected inactive, actual active"]
+        );
+        let no_ctip = EnforcerCheck::NoCtip { slot: 0 };
+        assert!(client.mismatches(&no_ctip).await.unwrap().is_empty());
+        let no_ctip = EnforcerCheck::NoCtip { slot: SLOT };
+        let mismatches = client.mismatches(&no_ctip).await.unwrap();
+        assert_eq!(
+            mismatches
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [format!(
+                "ctip: expected no CTIP, actual {}:1",
+                "ab".repeat(32)
+            )]
+        );
+        assert_eq!(
+            enforcer.methods(),
+            ["GetSidechains", "GetSidechains", "GetCtip", "GetCtip"]
+        );
+    }
+}
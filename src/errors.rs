@@ -0,0 +1,308 @@
+use bip300301::jsonrpsee::{core::client::Error, http_client::transport};
+use bitcoin::{
+    locktime::absolute::ConversionError, Amount, BlockHash, ScriptBuf,
+};
+use url::Url;
+
+use crate::cli::FORK_FROM_ANCHOR;
+
+/// Error generating a block
+#[derive(Debug, thiserror::Error)]
+pub enum GenError {
+    #[error(
+        "coinbase outputs pay {total_value}, more than the block subsidy \
+         plus fees ({max_value}): lower the payout"
+    )]
+    CoinbaseOverpays {
+        total_value: Amount,
+        max_value: Amount,
+    },
+    #[error("total value of the coinbase outputs overflows")]
+    CoinbaseValueOverflow,
+    #[error("invalid coinbase lock time for height {height}")]
+    InvalidLockTime {
+        height: u32,
+        #[source]
+        source: ConversionError,
+    },
+    #[error("invalid address `{address}` for coinbase outputs")]
+    InvalidAddress {
+        address: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error(
+        "unsupported: non-trivial signet challenge `{}`; only `OP_TRUE` \
+         challenges can be satisfied",
+        challenge.to_hex_string()
+    )]
+    UnsupportedSignetChallenge { challenge: ScriptBuf },
+    #[error(
+        "cannot fund the node's wallet with {amount}: the block subsidy is \
+         zero from height {height}"
+    )]
+    ZeroSubsidy { amount: Amount, height: u32 },
+    #[error(transparent)]
+    Spec(#[from] SpecError),
+    #[error(
+        "block {index}: cannot apply `{method}` to {description}, since the \
+         node is not expected to store it"
+    )]
+    ActionOnUnstoredBlock {
+        index: usize,
+        method: &'static str,
+        description: String,
+    },
+    #[error(
+        "generated block {block_hash} breaks the node's block template: its \
+         {limit} is {value}, which exceeds the template's limit of {max}"
+    )]
+    ExceedsTemplateLimit {
+        block_hash: BlockHash,
+        /// Name of the limit, eg. `weight`
+        limit: &'static str,
+        value: u64,
+        max: u64,
+    },
+    #[error(
+        "generated block {block_hash} breaks the node's block template: it \
+         has witness commitment `{}`, rather than the template's default \
+         witness commitment `{}`",
+        commitment.to_hex_string(),
+        expected.to_hex_string()
+    )]
+    TemplateWitnessCommitment {
+        block_hash: BlockHash,
+        commitment: ScriptBuf,
+        expected: ScriptBuf,
+    },
+    #[error(
+        "no recorded block for the block generated at height {height}: the \
+         recording has fewer blocks than its blocks spec and setup generate"
+    )]
+    MissingRecordedBlock { height: u32 },
+    #[error(
+        "recorded block {block_hash} differs from the block generated from \
+         the recording at height {height}. The recording may be from an \
+         incompatible version."
+    )]
+    RecordedBlockMismatch { block_hash: BlockHash, height: u32 },
+    #[error(
+        "the recording has {n_blocks} more blocks than its blocks spec and \
+         setup generate"
+    )]
+    SurplusRecordedBlocks { n_blocks: usize },
+}
+
+/// RPC error code returned by the node while starting up
+pub const RPC_IN_WARMUP: i32 = -28;
+
+/// RPC error code returned by the node if it has no peers
+pub const RPC_CLIENT_NOT_CONNECTED: i32 = -9;
+
+/// RPC error code returned by the node while in IBD
+pub const RPC_CLIENT_IN_INITIAL_DOWNLOAD: i32 = -10;
+
+/// RPC error code returned by the node for RPC methods that it does not
+/// support
+pub const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+/// RPC error code returned by the node if the requested wallet is not loaded
+const RPC_WALLET_NOT_FOUND: i32 = -18;
+
+/// RPC error code returned by the node for wallet RPC requests to the root
+/// endpoint, if several wallets are loaded
+const RPC_WALLET_NOT_SPECIFIED: i32 = -19;
+
+/// Failed RPC request to the node, classified by what the user can do
+/// about it
+#[derive(Debug, thiserror::Error)]
+pub enum RpcError {
+    #[error(
+        "RPC credentials were rejected by `{rpc_url}`: check `--rpc-user` \
+         and `--rpc-pass`, or `--rpc-cookie`"
+    )]
+    CredentialsRejected { rpc_url: Url },
+    #[error(
+        "RPC request `{method}` to `{rpc_url}` was rejected with HTTP status \
+         {status_code}"
+    )]
+    HttpStatus {
+        rpc_url: Url,
+        method: String,
+        status_code: u16,
+    },
+    #[error(
+        "failed to connect to the node RPC server at `{rpc_url}` ({reason}): \
+         check that the node is running, and that `--rpc-url` is correct"
+    )]
+    Connect { rpc_url: Url, reason: String },
+    #[error(
+        "node at `{rpc_url}` is in initial block download: wait for it to \
+         sync, and retry"
+    )]
+    InitialBlockDownload { rpc_url: Url },
+    #[error(
+        "node at `{rpc_url}` is not connected to any peers ({message}): \
+         connect it to a peer, eg. with `addnode`, and retry"
+    )]
+    NoPeers { rpc_url: Url, message: String },
+    #[error(
+        "node at `{rpc_url}` is still starting up ({message}): retry shortly"
+    )]
+    Warmup { rpc_url: Url, message: String },
+    #[error(
+        "wallet not found on node at `{rpc_url}` ({message}): check \
+         `--rpc-wallet`, and that the wallet is loaded"
+    )]
+    WalletNotFound { rpc_url: Url, message: String },
+    #[error(
+        "RPC request `{method}` to `{rpc_url}` requires a wallet ({message}): \
+         specify one with `--rpc-wallet`"
+    )]
+    WalletNotSpecified {
+        rpc_url: Url,
+        method: String,
+        message: String,
+    },
+    #[error("RPC request `{method}` to `{rpc_url}` timed out")]
+    Timeout { rpc_url: Url, method: String },
+    #[error(
+        "invalid response to RPC request `{method}` to `{rpc_url}`: {reason}"
+    )]
+    InvalidResponse {
+        rpc_url: Url,
+        method: String,
+        reason: String,
+    },
+    #[error("RPC request `{method}` to `{rpc_url}` failed")]
+    Other {
+        rpc_url: Url,
+        method: String,
+        #[source]
+        source: Error,
+    },
+}
+
+impl RpcError {
+    /// Classify an error from an RPC request to `rpc_url`
+    pub fn new(rpc_url: &Url, method: &str, err: Error) -> Self {
+        let rpc_url = rpc_url.clone();
+        let method = method.to_owned();
+        match err {
+            Error::Transport(transport_err) => {
+                match transport_err.downcast_ref::<transport::Error>() {
+                    Some(transport::Error::Rejected {
+                        status_code: 401 | 403,
+                    }) => Self::CredentialsRejected { rpc_url },
+                    Some(transport::Error::Rejected { status_code }) => {
+                        Self::HttpStatus {
+                            rpc_url,
+                            method,
+                            status_code: *status_code,
+                        }
+                    }
+                    _ => Self::Connect {
+                        rpc_url,
+                        reason: transport_err.to_string(),
+                    },
+                }
+            }
+            Error::Call(err)
+                if err.code() == RPC_CLIENT_IN_INITIAL_DOWNLOAD =>
+            {
+                Self::InitialBlockDownload { rpc_url }
+            }
+            Error::Call(err) if err.code() == RPC_CLIENT_NOT_CONNECTED => {
+                Self::NoPeers {
+                    rpc_url,
+                    message: err.message().to_owned(),
+                }
+            }
+            Error::Call(err) if err.code() == RPC_IN_WARMUP => Self::Warmup {
+                rpc_url,
+                message: err.message().to_owned(),
+            },
+            Error::Call(err) if err.code() == RPC_WALLET_NOT_FOUND => {
+                Self::WalletNotFound {
+                    rpc_url,
+                    message: err.message().to_owned(),
+                }
+            }
+            Error::Call(err) if err.code() == RPC_WALLET_NOT_SPECIFIED => {
+                Self::WalletNotSpecified {
+                    rpc_url,
+                    method,
+                    message: err.message().to_owned(),
+                }
+            }
+            Error::RequestTimeout => Self::Timeout { rpc_url, method },
+            source => Self::Other {
+                rpc_url,
+                method,
+                source,
+            },
+        }
+    }
+}
+
+/// Blocks spec that parsed, but is invalid for the chain, or refers to
+/// labels that it does not define
+#[derive(Debug, thiserror::Error)]
+pub enum SpecError {
+    #[error("block {index}: invalid `payout_address`")]
+    InvalidPayoutAddress {
+        index: usize,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error(
+        "block {index}: `{anchor}` is reserved, and cannot be used as a \
+         label: rename the block",
+        anchor = FORK_FROM_ANCHOR
+    )]
+    ReservedLabel { index: usize },
+    #[error(
+        "block {index}: label `{label}` is not a valid script variable name: \
+         use only ASCII letters, digits, and underscores, not starting with \
+         a digit"
+    )]
+    InvalidLabel { index: usize, label: String },
+    #[error(
+        "block {index}: label `{label}` is already used by block \
+         {first_index}: give each block a unique label"
+    )]
+    DuplicateLabel {
+        index: usize,
+        first_index: usize,
+        label: String,
+    },
+    #[error(
+        "block {index}: `fork_from` refers to `{fork_from}`, but no earlier \
+         block has that label: label an earlier block `{fork_from}`, or fork \
+         from `{anchor}`",
+        anchor = FORK_FROM_ANCHOR
+    )]
+    UnknownForkFrom { index: usize, fork_from: String },
+    #[error(
+        "block {index}: `post_actions` refers to `{target}`, but neither this \
+         block nor an earlier block has that label"
+    )]
+    UnknownPostActionTarget { index: usize, target: String },
+}
+
+/// Error constructing a script
+#[derive(Debug, thiserror::Error)]
+pub enum ScriptError {
+    #[error(
+        "invalid script variable name `{name}`: must be upper-case ASCII \
+         letters, digits, and underscores, starting with a letter and \
+         containing an underscore"
+    )]
+    InvalidVarName { name: String },
+    #[error(
+        "invalid script variable name `{name}`: clashes with a settings \
+         variable, so choose another name"
+    )]
+    SettingsVarClash { name: String },
+}
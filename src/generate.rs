@@ -0,0 +1,837 @@
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Write as _},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use bitcoin::{Block, BlockHash};
+use clap::ValueEnum as _;
+
+use crate::{
+    block_description,
+    cli::{
+        self, BlockSpec, BlocksSpec, CurlOpts, ExecWrapper, GenerateArgs,
+        OutputFormat, RpcClient, RpcOpts, SpecFileBoundary, Verdict,
+    },
+    enforcer_checks::EnforcerCheck,
+    gen_anchored_blocks,
+    json_output::{JsonBlock, JsonOutput, JSON_OUTPUT_VERSION},
+    json_plan::JsonPlanBuilder,
+    makefile_builder::MakefileBuilder,
+    metrics::{self, Metrics},
+    posix_script_builder::OutputPosixScriptBuilder,
+    powershell_script_builder::PowerShellScriptBuilder,
+    script_builder::{
+        BlockSubmission, HexFilesDir, RenderOpts, RequestBody, RpcCall, RpcIds,
+        ScriptBuilder, ScriptMeta, ScriptVar, SubmissionCounts,
+    },
+    AnchorSource, BlockGenCtx, Clock, GeneratedAction, GeneratedBlocks,
+};
+
+/// Metadata for the script preamble. The node's tip is filled in once it
+/// is fetched.
+pub fn preamble_meta(
+    network: cli::Network,
+    deterministic: bool,
+    seed: u64,
+    blocks_spec: &BlocksSpec,
+) -> anyhow::Result<ScriptMeta> {
+    // Omitted in deterministic mode, so that output is reproducible
+    let generated_at = if deterministic {
+        None
+    } else {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        Some(now.as_secs())
+    };
+    Ok(ScriptMeta::new(
+        network.to_possible_value().unwrap().get_name().to_owned(),
+        seed,
+        generated_at,
+        serde_json::to_string(blocks_spec)?,
+    ))
+}
+
+/// Options for writing the output
+pub struct OutputOpts {
+    pub format: OutputFormat,
+    /// Write the output to this file, instead of stdout
+    pub path: Option<PathBuf>,
+    /// Directory to write block hex files to
+    pub hex_files_dir: Option<PathBuf>,
+    /// Directory to write JSON-RPC request bodies to
+    pub dump_requests_dir: Option<PathBuf>,
+}
+
+/// Options for rendering scripts
+#[derive(Clone)]
+pub struct ScriptOpts {
+    /// Metadata for the script preamble's header. If `None`, the preamble
+    /// is omitted.
+    pub preamble: Option<ScriptMeta>,
+    /// Check the node's responses to `submitblock` requests
+    pub checked: bool,
+    /// Check the tip after each `submitblock` request
+    pub check_tip: bool,
+    /// Check that the node's tip is the anchor block before submitting any
+    /// blocks
+    pub check_anchor: bool,
+    /// Skip blocks that are expected to be accepted, if the node already has
+    /// them
+    pub skip_known_blocks: bool,
+    /// Print a summary at the end of the script
+    pub summary: bool,
+    pub rpc_client: RpcClient,
+    pub network: bitcoin::Network,
+    /// Delay between block submissions, in milliseconds, unless overridden
+    /// by the block spec
+    pub delay_between_blocks: u64,
+    /// Maximum number of `submitblock` requests in each JSON-RPC batch
+    pub batch_size: usize,
+    /// Enforcer to check the state of after submitting blocks, if any
+    pub enforcer_addr: Option<SocketAddr>,
+    /// Command prefix to run node RPC commands with
+    pub exec_wrapper: Option<ExecWrapper>,
+    /// Generate a bash script, rather than a POSIX shell script
+    pub bash: bool,
+    /// Do not print progress messages before each step
+    pub quiet: bool,
+    /// Ids for JSON-RPC requests sent by the script
+    pub rpc_ids: RpcIds,
+    /// Connection options for curl
+    pub curl: CurlOpts,
+    /// Send request bodies as heredocs
+    pub heredoc_bodies: bool,
+    /// Parse JSON-RPC responses with jq only
+    pub assume_jq: bool,
+    /// Width to wrap commands at, or 0 to disable wrapping
+    pub wrap_width: usize,
+    pub render_opts: RenderOpts,
+}
+
+/// Maximum length of block hex to embed in generated scripts. Longer
+/// arguments exceed the limit on the length of a single argument on Linux.
+const MAX_EMBEDDED_HEX_LEN: usize = 100_000;
+
+/// Block hex files referenced by a generated script
+struct HexFiles {
+    dir: HexFilesDir,
+    /// Names of the files in `dir`, for blocks that are submitted from files
+    file_names: HashMap<BlockHash, String>,
+}
+
+impl HexFiles {
+    /// Write block hex files to `dir` if specified, in which case the hex of
+    /// every block is written. Otherwise, blocks that are too large to embed
+    /// are written to `blocks` next to the output file.
+    /// Files are numbered in submission order.
+    fn write(
+        generated_blocks: &GeneratedBlocks,
+        output: Option<&Path>,
+        dir: Option<&Path>,
+    ) -> anyhow::Result<Option<Self>> {
+        let setup_blocks = generated_blocks
+            .setup_blocks
+            .iter()
+            .map(|setup_block| &setup_block.block);
+        let blocks = generated_blocks
+            .blocks
+            .iter()
+            .map(|generated_block| &generated_block.block);
+        let hexes: Vec<_> = setup_blocks
+            .chain(blocks)
+            .map(|block| (block.block_hash(), metrics::block_hex(block)))
+            .collect();
+        let too_large = |hex: &str| hex.len() > MAX_EMBEDDED_HEX_LEN;
+        let (dir, all) = match (dir, output) {
+            (Some(dir), _) => (dir.to_owned(), true),
+            (None, _) if !hexes.iter().any(|(_, hex)| too_large(hex)) => {
+                return Ok(None)
+            }
+            (None, Some(output)) => (script_dir(output).join("blocks"), false),
+            (None, None) => anyhow::bail!(
+                "some blocks are too large to embed in the script; use \
+                 `--hex-files` or `--output` to write them to files"
+            ),
+        };
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!("failed to create directory `{}`", dir.display())
+        })?;
+        let mut file_names = HashMap::new();
+        for (idx, (block_hash, hex)) in hexes.iter().enumerate() {
+            if !all && !too_large(hex) {
+                continue;
+            }
+            let file_name = format!("{:04}-{block_hash}.hex", idx + 1);
+            let path = dir.join(&file_name);
+            std::fs::write(&path, hex).with_context(|| {
+                format!("failed to write `{}`", path.display())
+            })?;
+            file_names.insert(*block_hash, file_name);
+        }
+        tracing::info!(
+            "Wrote {} block hex files to `{}`",
+            file_names.len(),
+            dir.display()
+        );
+        let dir = dir.canonicalize().with_context(|| {
+            format!("failed to resolve `{}`", dir.display())
+        })?;
+        // Scripts written to stdout may be saved anywhere, so the directory
+        // can only be referenced relative to an output file
+        let dir = match output {
+            Some(output) => {
+                let script_dir = script_dir(output);
+                let script_dir =
+                    script_dir.canonicalize().with_context(|| {
+                        format!("failed to resolve `{}`", script_dir.display())
+                    })?;
+                match dir.strip_prefix(&script_dir) {
+                    Ok(relative_dir) => {
+                        HexFilesDir::ScriptRelative(relative_dir.to_owned())
+                    }
+                    Err(_) => HexFilesDir::Absolute(dir),
+                }
+            }
+            None => HexFilesDir::Absolute(dir),
+        };
+        Ok(Some(Self { dir, file_names }))
+    }
+}
+
+/// Directory containing the output file
+fn script_dir(output: &Path) -> &Path {
+    match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        Some(_) | None => Path::new("."),
+    }
+}
+
+/// Render a script with the builder, and return it with the request bodies
+/// that it sends
+fn render_script<Builder>(
+    mut script_builder: Builder,
+    script_opts: ScriptOpts,
+    seed: u64,
+    generated_blocks: &GeneratedBlocks,
+    spec_file_boundaries: &[SpecFileBoundary],
+    hex_files: Option<&HexFiles>,
+) -> (String, Vec<RequestBody>)
+where
+    Builder: ScriptBuilder,
+{
+    let () = build_script(
+        &mut script_builder,
+        script_opts,
+        seed,
+        generated_blocks,
+        spec_file_boundaries,
+        hex_files,
+    );
+    let request_bodies = script_builder.take_request_bodies();
+    (script_builder.finalize(), request_bodies)
+}
+
+/// Add the script's items to the builder, without finalizing it
+fn build_script<Builder>(
+    script_builder: &mut Builder,
+    script_opts: ScriptOpts,
+    seed: u64,
+    generated_blocks: &GeneratedBlocks,
+    spec_file_boundaries: &[SpecFileBoundary],
+    hex_files: Option<&HexFiles>,
+) where
+    Builder: ScriptBuilder,
+{
+    let ScriptOpts {
+        preamble,
+        checked,
+        check_tip,
+        check_anchor,
+        skip_known_blocks,
+        summary,
+        rpc_client: _,
+        network: _,
+        delay_between_blocks,
+        batch_size: _,
+        enforcer_addr,
+        exec_wrapper: _,
+        bash: _,
+        quiet: _,
+        rpc_ids: _,
+        curl: _,
+        heredoc_bodies: _,
+        assume_jq: _,
+        wrap_width: _,
+        render_opts: _,
+    } = script_opts;
+    if let Some(preamble) = preamble {
+        script_builder.preamble(&preamble);
+    }
+    if let Some(hex_files) = hex_files {
+        script_builder.hex_files_dir(&hex_files.dir);
+    }
+    if let Some(enforcer_addr) = enforcer_addr {
+        script_builder.enforcer_addr(enforcer_addr);
+    }
+    for generated_block in &generated_blocks.blocks {
+        if let Some(label) = &generated_block.block_spec.label {
+            script_builder.block_hash_var(
+                &ScriptVar::block_hash(label),
+                generated_block.block.block_hash(),
+            );
+        }
+    }
+    script_builder.comment(format!("Generated with seed {seed}"));
+    let first_block = generated_blocks
+        .setup_blocks
+        .first()
+        .map(|setup_block| &setup_block.block)
+        .or_else(|| {
+            generated_blocks
+                .blocks
+                .first()
+                .map(|generated_block| &generated_block.block)
+        });
+    if let Some(first_block) = first_block.filter(|_| check_anchor) {
+        let start_tip = ScriptVar::new("START_TIP").unwrap();
+        script_builder.command_capture(
+            &start_tip,
+            &RpcCall::new("getbestblockhash", ()),
+            "the node's tip",
+        );
+        script_builder.check_captured(
+            &start_tip,
+            &first_block.header.prev_blockhash.to_string(),
+            "the node's tip",
+        );
+    }
+    if !generated_blocks.setup_blocks.is_empty() {
+        script_builder.comment(generated_blocks.setup_comment.as_str());
+    }
+    // Setup blocks have no block spec, and must be accepted
+    let mut counts = SubmissionCounts::default();
+    let mut submitblock =
+        |script_builder: &mut Builder,
+         block: &Block,
+         height: u32,
+         block_spec: Option<&BlockSpec>,
+         expected: Verdict,
+         comment: Option<&str>,
+         enforcer_checks: &[EnforcerCheck]| {
+            let block_hash = block.block_hash();
+            let description = block_description(height, block_hash, block_spec);
+            let submission = BlockSubmission {
+                block,
+                height,
+                spec: block_spec,
+                expected,
+                description: &description,
+                hex_file: hex_files
+                    .and_then(|hex_files| hex_files.file_names.get(&block_hash))
+                    .map(String::as_str),
+            };
+            script_builder.block_comment(comment, &submission);
+            if skip_known_blocks
+                && matches!(expected, Verdict::Accept | Verdict::Inconclusive)
+            {
+                script_builder.skip_if_known(&submission);
+            }
+            let delay = block_spec
+                .and_then(|block_spec| block_spec.delay_ms)
+                .unwrap_or(delay_between_blocks);
+            if counts.n_blocks > 0 && delay > 0 {
+                script_builder.sleep(Duration::from_millis(delay));
+            }
+            let () = counts.add(expected);
+            if !checked {
+                return script_builder.submitblock(&submission);
+            }
+            script_builder.submitblock_checked(&submission);
+            if check_tip {
+                script_builder.check_tip(&submission);
+            }
+            if enforcer_addr.is_some() {
+                for check in enforcer_checks {
+                    script_builder.check_enforcer(check, &description);
+                }
+            }
+        };
+    for setup_block in &generated_blocks.setup_blocks {
+        submitblock(
+            script_builder,
+            &setup_block.block,
+            setup_block.height,
+            None,
+            Verdict::Accept,
+            None,
+            &setup_block.enforcer_checks,
+        );
+    }
+    if let Some(funded_wallet) = &generated_blocks.funded_wallet {
+        // Fails unless the address belongs to the node's wallet
+        script_builder.command_capture(
+            &ScriptVar::new("FUNDED_AMOUNT").unwrap(),
+            &RpcCall::new("getreceivedbyaddress", [&funded_wallet.address]),
+            "the amount received by the node's wallet",
+        );
+    }
+    let mut spec_file_boundaries = spec_file_boundaries.iter().peekable();
+    let mut idx = 0;
+    while let Some(generated_block) = generated_blocks.blocks.get(idx) {
+        while let Some(boundary) =
+            spec_file_boundaries.next_if(|boundary| boundary.index == idx)
+        {
+            script_builder.comment(format!(
+                "Blocks from spec file `{}`",
+                boundary.path.display()
+            ));
+        }
+        // Group consecutive empty blocks under a single comment, up to the
+        // next spec file boundary
+        let group_end = spec_file_boundaries
+            .peek()
+            .map_or(generated_blocks.blocks.len(), |boundary| boundary.index);
+        let n_empty = generated_blocks.blocks[idx..group_end]
+            .iter()
+            .take_while(|generated_block| generated_block.is_plain)
+            .count();
+        if n_empty >= 2 {
+            let last_block = &generated_blocks.blocks[idx + n_empty - 1];
+            script_builder.comment(format!(
+                "Generate {n_empty} empty blocks, at heights {} to {}",
+                generated_block.height, last_block.height
+            ));
+            for generated_block in &generated_blocks.blocks[idx..idx + n_empty]
+            {
+                submitblock(
+                    script_builder,
+                    &generated_block.block,
+                    generated_block.height,
+                    Some(&generated_block.block_spec),
+                    generated_block.expected_verdict,
+                    None,
+                    &generated_block.enforcer_checks,
+                );
+            }
+            idx += n_empty;
+            continue;
+        }
+        submitblock(
+            script_builder,
+            &generated_block.block,
+            generated_block.height,
+            Some(&generated_block.block_spec),
+            generated_block.expected_verdict,
+            Some(&generated_block.comment),
+            &generated_block.enforcer_checks,
+        );
+        for post_action in &generated_block.post_actions {
+            let GeneratedAction {
+                action,
+                block_hash,
+                description,
+                expected_tip,
+                comment,
+                enforcer_checks,
+            } = post_action;
+            script_builder.comment(comment.as_str());
+            let method = action.method();
+            script_builder.command_capture(
+                &ScriptVar::new("ACTION_RESULT").unwrap(),
+                &RpcCall::new(method, (block_hash,)),
+                &format!("`{method}` for {description}"),
+            );
+            if !checked {
+                continue;
+            }
+            let description = format!("{method} {description}");
+            if check_tip {
+                let action_tip = ScriptVar::new("ACTION_TIP").unwrap();
+                script_builder.command_capture(
+                    &action_tip,
+                    &RpcCall::new("getbestblockhash", ()),
+                    "the node's tip",
+                );
+                script_builder.check_captured(
+                    &action_tip,
+                    &expected_tip.to_string(),
+                    &format!("the node's tip after {description}"),
+                );
+            }
+            if enforcer_addr.is_some() {
+                for check in enforcer_checks {
+                    script_builder.check_enforcer(check, &description);
+                }
+            }
+        }
+        idx += 1;
+    }
+    if summary {
+        script_builder.summary(&counts);
+    }
+}
+
+/// Write each request body to a file in `dir`, numbered in the order the
+/// requests are sent, and named after the method, eg.
+/// `0001-submitblock.json`
+fn write_request_bodies(
+    dir: &Path,
+    request_bodies: &[RequestBody],
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| {
+        format!("failed to create directory `{}`", dir.display())
+    })?;
+    for (idx, RequestBody { method, body }) in request_bodies.iter().enumerate()
+    {
+        let path = dir.join(format!("{:04}-{method}.json", idx + 1));
+        std::fs::write(&path, body)
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+    }
+    tracing::info!(
+        "Wrote {} request bodies to `{}`",
+        request_bodies.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+fn render_json(
+    seed: u64,
+    generated_blocks: &GeneratedBlocks,
+) -> anyhow::Result<String> {
+    let setup_blocks =
+        generated_blocks.setup_blocks.iter().map(|setup_block| {
+            JsonBlock::new(
+                &setup_block.block,
+                setup_block.height,
+                None,
+                Verdict::Accept,
+                &generated_blocks.setup_comment,
+            )
+        });
+    let blocks = generated_blocks.blocks.iter().map(|generated_block| {
+        JsonBlock::new(
+            &generated_block.block,
+            generated_block.height,
+            Some(&generated_block.block_spec),
+            generated_block.expected_verdict,
+            &generated_block.comment,
+        )
+    });
+    let json_output = JsonOutput {
+        version: JSON_OUTPUT_VERSION,
+        seed,
+        blocks: setup_blocks.chain(blocks).collect(),
+    };
+    Ok(serde_json::to_string_pretty(&json_output)?)
+}
+
+/// Generate blocks, and render them as a script in the specified format
+pub async fn gen_script(
+    mut ctx: BlockGenCtx,
+    anchor_source: AnchorSource,
+    rpc: RpcOpts,
+    blocks_spec: BlocksSpec,
+    spec_file_boundaries: &[SpecFileBoundary],
+    output_opts: OutputOpts,
+    mut script_opts: ScriptOpts,
+) -> anyhow::Result<()> {
+    let (generated_blocks, node_tip) =
+        gen_anchored_blocks(&mut ctx, anchor_source, &rpc, blocks_spec).await?;
+    if let Some(meta) = &mut script_opts.preamble {
+        meta.node_tip = node_tip;
+        // Timings are omitted in deterministic mode, so that output is
+        // reproducible
+        if matches!(output_opts.format, OutputFormat::JsonPlan)
+            && matches!(ctx.clock, Clock::Now)
+        {
+            meta.metrics = Some(Metrics::get().summary());
+        }
+    }
+    let OutputOpts {
+        format,
+        path: output_path,
+        hex_files_dir,
+        dump_requests_dir,
+    } = output_opts;
+    let hex_files = match format {
+        OutputFormat::Script
+        | OutputFormat::PowerShell
+        | OutputFormat::Makefile => HexFiles::write(
+            &generated_blocks,
+            output_path.as_deref(),
+            hex_files_dir.as_deref(),
+        )?,
+        OutputFormat::Json | OutputFormat::JsonPlan => None,
+    };
+    let (output, request_bodies) = match format {
+        OutputFormat::Script => {
+            let new_script_builder = || {
+                let mut script_builder = OutputPosixScriptBuilder::new(
+                    rpc.clone(),
+                    script_opts.rpc_client,
+                    script_opts.network,
+                );
+                let () = script_builder.batch_size(script_opts.batch_size);
+                if let Some(ExecWrapper(exec_wrapper)) =
+                    &script_opts.exec_wrapper
+                {
+                    let () = script_builder.exec_wrapper(exec_wrapper.clone());
+                }
+                let () = script_builder.bash(script_opts.bash);
+                let () = script_builder.quiet(script_opts.quiet);
+                let () = script_builder.rpc_ids(script_opts.rpc_ids.clone());
+                let () = script_builder.curl_opts(script_opts.curl);
+                let () =
+                    script_builder.heredoc_bodies(script_opts.heredoc_bodies);
+                let () = script_builder.assume_jq(script_opts.assume_jq);
+                let () = script_builder.wrap_width(script_opts.wrap_width);
+                let () = script_builder.render_opts(script_opts.render_opts);
+                script_builder
+            };
+            // The script's prelude depends on its items, so the script is
+            // rendered twice: first discarding its items, to find the
+            // prelude, and then writing the items as they are rendered,
+            // rather than keeping the whole script in memory.
+            let mut prelude_builder = new_script_builder();
+            let mut script_builder = new_script_builder();
+            let () = prelude_builder.discard_items();
+            let () = build_script(
+                &mut prelude_builder,
+                script_opts.clone(),
+                ctx.seed,
+                &generated_blocks,
+                spec_file_boundaries,
+                hex_files.as_ref(),
+            );
+            let sink: Box<dyn std::io::Write> = match &output_path {
+                Some(output_path) => {
+                    let file = std::fs::File::create(output_path)
+                        .with_context(|| {
+                            format!(
+                                "failed to create `{}`",
+                                output_path.display()
+                            )
+                        })?;
+                    Box::new(BufWriter::new(file))
+                }
+                None => Box::new(BufWriter::new(std::io::stdout())),
+            };
+            let () =
+                script_builder.stream_items(prelude_builder.prelude(), sink);
+            let () = build_script(
+                &mut script_builder,
+                script_opts,
+                ctx.seed,
+                &generated_blocks,
+                spec_file_boundaries,
+                hex_files.as_ref(),
+            );
+            let request_bodies = script_builder.take_request_bodies();
+            let () = script_builder
+                .finish_stream()
+                .and_then(|mut sink| {
+                    let () = writeln!(sink)?;
+                    sink.flush()
+                })
+                .context("failed to write script")?;
+            (None, request_bodies)
+        }
+        OutputFormat::PowerShell => {
+            let mut script_builder = PowerShellScriptBuilder::new(rpc);
+            let () = script_builder.batch_size(script_opts.batch_size);
+            let () = script_builder.quiet(script_opts.quiet);
+            let () = script_builder.rpc_ids(script_opts.rpc_ids.clone());
+            let () = script_builder.render_opts(script_opts.render_opts);
+            let (output, request_bodies) = render_script(
+                script_builder,
+                script_opts,
+                ctx.seed,
+                &generated_blocks,
+                spec_file_boundaries,
+                hex_files.as_ref(),
+            );
+            (Some(output), request_bodies)
+        }
+        OutputFormat::Makefile => {
+            let mut script_builder = OutputPosixScriptBuilder::new(
+                rpc,
+                script_opts.rpc_client,
+                script_opts.network,
+            );
+            if let Some(ExecWrapper(exec_wrapper)) = &script_opts.exec_wrapper {
+                let () = script_builder.exec_wrapper(exec_wrapper.clone());
+            }
+            let () = script_builder.quiet(script_opts.quiet);
+            let () = script_builder.rpc_ids(script_opts.rpc_ids.clone());
+            let () = script_builder.curl_opts(script_opts.curl);
+            let () = script_builder.heredoc_bodies(script_opts.heredoc_bodies);
+            let () = script_builder.assume_jq(script_opts.assume_jq);
+            let () = script_builder.wrap_width(script_opts.wrap_width);
+            let () = script_builder.render_opts(script_opts.render_opts);
+            let (output, request_bodies) = render_script(
+                MakefileBuilder::new(script_builder),
+                script_opts,
+                ctx.seed,
+                &generated_blocks,
+                spec_file_boundaries,
+                hex_files.as_ref(),
+            );
+            (Some(output), request_bodies)
+        }
+        OutputFormat::Json => {
+            (Some(render_json(ctx.seed, &generated_blocks)?), Vec::new())
+        }
+        OutputFormat::JsonPlan => {
+            let (output, request_bodies) = render_script(
+                JsonPlanBuilder::new(&rpc),
+                script_opts,
+                ctx.seed,
+                &generated_blocks,
+                spec_file_boundaries,
+                hex_files.as_ref(),
+            );
+            (Some(output), request_bodies)
+        }
+    };
+    // Scripts are written as they are rendered
+    match (output, output_path) {
+        (None, _) => (),
+        (Some(output), Some(output_path)) => {
+            std::fs::write(&output_path, format!("{output}\n")).with_context(
+                || format!("failed to write `{}`", output_path.display()),
+            )?
+        }
+        (Some(output), None) => println!("{output}"),
+    }
+    // Blocks are only resumed from once they are written
+    if let Some(state_file) = &mut ctx.state_file {
+        for checkpoint in generated_blocks.checkpoints().into_iter().flatten() {
+            let () = state_file.advance(&checkpoint);
+        }
+        let () = state_file.save()?;
+    }
+    if let Some(dump_requests_dir) = dump_requests_dir {
+        let () = write_request_bodies(&dump_requests_dir, &request_bodies)?;
+    }
+    Ok(())
+}
+
+/// Check that the output format, RPC client and script options are
+/// supported together
+pub fn check_output_args(args: &GenerateArgs) -> anyhow::Result<()> {
+    if let (OutputFormat::PowerShell, RpcClient::BitcoinCli) =
+        (args.format, args.rpc_client)
+    {
+        anyhow::bail!(
+            "`--rpc-client bitcoin-cli` is not supported with `--format \
+             powershell`"
+        );
+    }
+    if let (OutputFormat::Json | OutputFormat::JsonPlan, Some(_)) =
+        (args.format, &args.hex_files)
+    {
+        anyhow::bail!(
+            "`--hex-files` is not supported with JSON output formats"
+        );
+    }
+    if let (
+        OutputFormat::PowerShell | OutputFormat::Json | OutputFormat::JsonPlan,
+        Some(_),
+    ) = (args.format, &args.exec_wrapper)
+    {
+        anyhow::bail!(
+            "`--exec-wrapper` is only supported with `--format script` and \
+             `--format makefile`"
+        );
+    }
+    if let (
+        OutputFormat::PowerShell
+        | OutputFormat::Json
+        | OutputFormat::JsonPlan
+        | OutputFormat::Makefile,
+        true,
+    ) = (args.format, args.bash)
+    {
+        anyhow::bail!("`--bash` is only supported with `--format script`");
+    }
+    if args.heredoc_bodies
+        && !matches!(
+            (args.format, args.rpc_client),
+            (
+                OutputFormat::Script | OutputFormat::Makefile,
+                RpcClient::Curl
+            )
+        )
+    {
+        anyhow::bail!(
+            "`--heredoc-bodies` is only supported with `--format script` or \
+             `--format makefile`, and `--rpc-client curl`"
+        );
+    }
+    if args.dump_requests.is_some()
+        && !matches!(
+            (args.format, args.rpc_client),
+            (
+                OutputFormat::Script | OutputFormat::Makefile,
+                RpcClient::Curl
+            )
+        )
+    {
+        anyhow::bail!(
+            "`--dump-requests` is only supported with `--format script` or \
+             `--format makefile`, and `--rpc-client curl`"
+        );
+    }
+    if args.assume_jq
+        && !matches!(
+            (args.format, args.rpc_client),
+            (
+                OutputFormat::Script | OutputFormat::Makefile,
+                RpcClient::Curl
+            )
+        )
+    {
+        anyhow::bail!(
+            "`--assume-jq` is only supported with `--format script` or \
+             `--format makefile`, and `--rpc-client curl`"
+        );
+    }
+    if let (
+        OutputFormat::PowerShell | OutputFormat::Json | OutputFormat::JsonPlan,
+        1..,
+    ) = (args.format, args.wrap_width)
+    {
+        anyhow::bail!(
+            "`--wrap-width` is only supported with `--format script` or \
+             `--format makefile`"
+        );
+    }
+    if let (RpcClient::BitcoinCli, "https") =
+        (args.rpc_client, args.rpc.rpc_url.scheme())
+    {
+        anyhow::bail!("HTTPS RPC URLs are not supported by bitcoin-cli");
+    }
+    if let (OutputFormat::PowerShell, Some(_)) =
+        (args.format, &args.rpc.rpc_cacert)
+    {
+        anyhow::bail!(
+            "`--rpc-cacert` is not supported with `--format powershell`. \
+             Add the CA certificate to the system's trusted CAs instead."
+        );
+    }
+    if let (RpcClient::BitcoinCli, 2..) =
+        (args.rpc_client, args.batch_size.get())
+    {
+        anyhow::bail!("`--batch-size` is not supported by bitcoin-cli");
+    }
+    if let (true, 2..) = (args.skip_known_blocks, args.batch_size.get()) {
+        anyhow::bail!(
+            "`--batch-size` is not supported with `--skip-known-blocks`"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use bitcoin::{hex::DisplayHex, Block, BlockHash};
+use serde::{Serialize, Serializer};
+
+use crate::{
+    cli::{BlockSpec, NodeAction, Verdict},
+    enforcer_checks::EnforcerMismatch,
+    script_builder::FailureClass,
+    submit_block_result::SubmitBlockResult,
+};
+
+/// Version of the JSON output envelope. Must be incremented whenever the
+/// output changes in a way that is not backwards-compatible.
+pub const JSON_OUTPUT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct JsonBlock<'a> {
+    pub hex: String,
+    pub hash: String,
+    pub height: u32,
+    /// `None` for setup blocks
+    pub spec: Option<&'a BlockSpec>,
+    pub expected_verdict: Verdict,
+    pub comment: &'a str,
+}
+
+impl<'a> JsonBlock<'a> {
+    pub fn new(
+        block: &Block,
+        height: u32,
+        spec: Option<&'a BlockSpec>,
+        expected_verdict: Verdict,
+        comment: &'a str,
+    ) -> Self {
+        Self {
+            hex: bitcoin::consensus::serialize(block).to_lower_hex_string(),
+            hash: block.block_hash().to_string(),
+            height,
+            spec,
+            expected_verdict,
+            comment,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonOutput<'a> {
+    pub version: u32,
+    /// Seed used for randomness in the run
+    pub seed: u64,
+    pub blocks: Vec<JsonBlock<'a>>,
+}
+
+/// Outcome of submitting a block directly
+#[derive(Debug)]
+pub enum SubmitOutcome {
+    Submitted(SubmitBlockResult),
+    /// Skipped in interactive mode
+    Skipped,
+}
+
+impl std::fmt::Display for SubmitOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Submitted(res) => res.fmt(f),
+            Self::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+impl Serialize for SubmitOutcome {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        /// Tagged like [`SubmitBlockResult`]
+        #[derive(Serialize)]
+        #[serde(rename_all = "snake_case", tag = "outcome")]
+        enum Skipped {
+            Skipped,
+        }
+        match self {
+            Self::Submitted(res) => res.serialize(serializer),
+            Self::Skipped => Skipped::Skipped.serialize(serializer),
+        }
+    }
+}
+
+/// Result of submitting a block directly, printed for each block in submit
+/// mode
+#[derive(Debug, Serialize)]
+pub struct SubmitResult<'a> {
+    /// Position of the block in the submission order, starting at 1
+    pub index: usize,
+    pub hash: BlockHash,
+    pub height: u32,
+    /// `None` for setup blocks, and blocks without a label
+    pub label: Option<&'a str>,
+    pub expected_verdict: Verdict,
+    #[serde(flatten)]
+    pub outcome: SubmitOutcome,
+    /// The node's tip after submitting the block. `None` if the tip was not
+    /// checked.
+    pub tip: Option<BlockHash>,
+    /// Fields of the enforcer's state that were not as expected after
+    /// submitting the block
+    pub enforcer_mismatches: Vec<EnforcerMismatch>,
+    /// `None` if the block was accepted or rejected as expected, and the
+    /// tip is as expected
+    pub failure: Option<FailureClass>,
+    /// Results of submitting the block to secondary nodes, in the order
+    /// they were specified
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub secondary_nodes: Vec<SecondaryResult>,
+}
+
+/// Result of submitting a block to a secondary node. Secondary nodes are
+/// not checked.
+#[derive(Debug, Serialize)]
+pub struct SecondaryResult {
+    /// RPC endpoint of the node, without credentials
+    pub node: String,
+    /// `None` if the request failed
+    #[serde(flatten)]
+    pub outcome: Option<SubmitOutcome>,
+    /// Why the request failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl std::fmt::Display for SecondaryResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            node,
+            outcome,
+            error,
+        } = self;
+        match (outcome, error) {
+            (Some(outcome), _) => write!(f, "{node}: {outcome}"),
+            (None, Some(error)) => write!(f, "{node}: error: {error}"),
+            (None, None) => write!(f, "{node}: -"),
+        }
+    }
+}
+
+/// Result of an action on the node after submitting a block directly,
+/// printed after the block's result in submit mode
+#[derive(Debug, Serialize)]
+pub struct ActionResult {
+    pub action: NodeAction,
+    /// Hash of the block that the action was applied to
+    pub hash: BlockHash,
+    /// The node's tip after the action. `None` if the tip was not checked.
+    pub tip: Option<BlockHash>,
+    /// Fields of the enforcer's state that were not as expected after the
+    /// action
+    pub enforcer_mismatches: Vec<EnforcerMismatch>,
+    /// `None` if the tip and the enforcer's state are as expected
+    pub failure: Option<FailureClass>,
+}
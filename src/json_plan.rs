@@ -0,0 +1,576 @@
+use std::{
+    collections::BTreeMap, net::SocketAddr, path::PathBuf, time::Duration,
+};
+
+use bitcoin::BlockHash;
+use serde::Serialize;
+
+use crate::{
+    cli::{BlockSpec, RpcCredentials, RpcOpts, Verdict},
+    enforcer_checks::{EnforcerCheck, VALIDATOR_SERVICE},
+    script_builder::{
+        BlockSubmission, HexFilesDir, RpcCall, ScriptBuilder, ScriptMeta,
+        ScriptVar, SubmissionCounts,
+    },
+};
+
+/// Version of the JSON plan format. Must be incremented whenever the
+/// format changes in a way that is not backwards-compatible.
+const JSON_PLAN_VERSION: u32 = 1;
+
+/// How to authenticate with the node
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum PlanAuth {
+    /// Read the user and pass from a cookie file
+    Cookie {
+        path: PathBuf,
+    },
+    /// Read the user and pass from the `RPC_USER` and `RPC_PASS`
+    /// environment variables
+    Env,
+    UserPass {
+        user: String,
+        pass: String,
+    },
+}
+
+/// Connection settings, kept separate from the steps so that the same plan
+/// can be run against different nodes
+#[derive(Debug, Serialize)]
+struct PlanConnection {
+    /// RPC endpoint URL, without credentials
+    url: String,
+    /// Wallet to send wallet RPC requests to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wallet: Option<String>,
+    auth: PlanAuth,
+    timeout_secs: u64,
+    /// PEM file with the CA certificate(s) to verify the node's TLS
+    /// certificate with, instead of the system's trusted CAs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cacert: Option<PathBuf>,
+    /// If `true`, the node's TLS certificate is not verified
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    insecure: bool,
+    /// Base URL for requests to the enforcer's gRPC service, without a
+    /// trailing slash. Requests are sent to `<enforcer_url>/<method>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enforcer_url: Option<String>,
+}
+
+impl PlanConnection {
+    fn new(rpc: &RpcOpts) -> Self {
+        let auth = match &rpc.rpc_auth.rpc_cookie {
+            Some(cookie_path) => PlanAuth::Cookie {
+                path: cookie_path.clone(),
+            },
+            None if rpc.rpc_auth.rpc_auth_env => PlanAuth::Env,
+            None => {
+                let RpcCredentials { user, pass } = rpc.user_pass();
+                PlanAuth::UserPass { user, pass }
+            }
+        };
+        Self {
+            url: rpc.endpoint(None).to_string(),
+            wallet: rpc.rpc_wallet.clone(),
+            auth,
+            timeout_secs: rpc.rpc_timeout,
+            cacert: rpc.rpc_cacert.clone(),
+            insecure: rpc.rpc_insecure,
+            enforcer_url: None,
+        }
+    }
+}
+
+/// Metadata for a submitted block
+#[derive(Debug, Serialize)]
+struct PlanBlock {
+    height: u32,
+    hash: BlockHash,
+    /// `None` for setup blocks
+    spec: Option<BlockSpec>,
+    /// Reasons for which the block is invalid
+    invalid_reasons: Vec<&'static str>,
+    /// Number of transactions, including the coinbase
+    n_txs: usize,
+    n_coinbase_outputs: usize,
+    /// One-line summary, as printed by generated scripts
+    summary: String,
+}
+
+/// Expected outcome of an RPC request
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Expectation {
+    /// The node accepts or rejects the submitted block, or stores it on a
+    /// side branch (`inconclusive`)
+    Submitblock { verdict: Verdict },
+    /// The submitted block does or does not become the tip
+    Tip {
+        block_hash: BlockHash,
+        verdict: Verdict,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum PlanStep {
+    Comment {
+        comment: String,
+    },
+    Sleep {
+        sleep_ms: u128,
+    },
+    Rpc {
+        rpc: RpcCall,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        block: Option<Box<PlanBlock>>,
+        /// If not set, the response is not checked
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expect: Option<Expectation>,
+        /// Name to store the response's result under, for later steps to
+        /// reference
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<ScriptVar>,
+        /// Describes the stored result in error messages
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+    },
+    /// Request the header of a block with `getblockheader`, and skip the
+    /// next `skip_next_steps` steps if the node has the block
+    SkipIfKnown {
+        skip_if_known: BlockHash,
+        skip_next_steps: usize,
+    },
+    /// Check that a result stored by an earlier step is equal to a value
+    Check {
+        check_output: ScriptVar,
+        equals: String,
+        /// Describes the stored result in error messages
+        description: String,
+    },
+    /// Request to the enforcer's gRPC service
+    Enforcer {
+        enforcer_method: &'static str,
+        /// JSON body of the request
+        enforcer_request: serde_json::Value,
+        /// Identifies the block in error messages
+        description: String,
+        expect: EnforcerCheck,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct JsonPlan {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    header: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<ScriptMeta>,
+    connection: PlanConnection,
+    /// Hashes of labelled blocks, by the names that steps refer to them by,
+    /// as for results stored by steps
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    block_hashes: BTreeMap<String, BlockHash>,
+    steps: Vec<PlanStep>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<SubmissionCounts>,
+}
+
+/// Renders an ordered list of steps as JSON, for test harnesses to run
+/// directly
+#[derive(Debug)]
+pub struct JsonPlanBuilder {
+    header: Option<String>,
+    meta: Option<ScriptMeta>,
+    connection: PlanConnection,
+    block_hashes: BTreeMap<String, BlockHash>,
+    steps: Vec<PlanStep>,
+    /// Index of the `SkipIfKnown` step for the current block, if any
+    skip_step: Option<usize>,
+    summary: Option<SubmissionCounts>,
+}
+
+impl JsonPlanBuilder {
+    pub fn new(rpc: &RpcOpts) -> Self {
+        Self {
+            header: None,
+            meta: None,
+            connection: PlanConnection::new(rpc),
+            block_hashes: BTreeMap::new(),
+            steps: Vec::new(),
+            skip_step: None,
+            summary: None,
+        }
+    }
+
+    /// Add a step, which is skipped by the current block's `SkipIfKnown`
+    /// step, if any
+    fn push_block_step(&mut self, step: PlanStep) {
+        if let Some(PlanStep::SkipIfKnown {
+            skip_next_steps, ..
+        }) = self.skip_step.and_then(|idx| self.steps.get_mut(idx))
+        {
+            *skip_next_steps += 1;
+        }
+        self.steps.push(step);
+    }
+
+    fn submitblock_step(
+        submission: &BlockSubmission,
+        checked: bool,
+    ) -> PlanStep {
+        let BlockSubmission {
+            block,
+            height,
+            spec,
+            expected,
+            description: _,
+            hex_file: _,
+        } = *submission;
+        let block_meta = PlanBlock {
+            height,
+            hash: block.block_hash(),
+            spec: spec.cloned(),
+            invalid_reasons: spec
+                .map(BlockSpec::reasons_invalid)
+                .unwrap_or_default(),
+            n_txs: block.txdata.len(),
+            n_coinbase_outputs: submission.n_coinbase_outputs(),
+            summary: submission.summary(),
+        };
+        PlanStep::Rpc {
+            rpc: RpcCall::submitblock(block),
+            block: Some(Box::new(block_meta)),
+            expect: checked
+                .then_some(Expectation::Submitblock { verdict: expected }),
+            output: None,
+            description: None,
+        }
+    }
+}
+
+impl ScriptBuilder for JsonPlanBuilder {
+    /// Set the plan's header, and its metadata
+    fn preamble(&mut self, meta: &ScriptMeta) {
+        self.header = Some(meta.to_string());
+        self.meta = Some(meta.clone());
+    }
+
+    fn comment<S>(&mut self, comment: S)
+    where
+        String: From<S>,
+    {
+        self.steps.push(PlanStep::Comment {
+            comment: comment.into(),
+        });
+    }
+
+    /// Ends the steps that are skipped by [`Self::skip_if_known`]
+    fn block_comment(
+        &mut self,
+        comment: Option<&str>,
+        submission: &BlockSubmission,
+    ) {
+        self.skip_step = None;
+        self.comment(submission.comment_with_context(comment))
+    }
+
+    /// Block hex is always embedded in the plan
+    fn hex_files_dir(&mut self, _dir: &HexFilesDir) {}
+
+    fn enforcer_addr(&mut self, addr: SocketAddr) {
+        self.connection.enforcer_url =
+            Some(format!("http://{addr}/{VALIDATOR_SERVICE}"));
+    }
+
+    fn block_hash_var(&mut self, var: &ScriptVar, block_hash: BlockHash) {
+        self.block_hashes.insert(var.name().to_owned(), block_hash);
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.push_block_step(PlanStep::Sleep {
+            sleep_ms: duration.as_millis(),
+        });
+    }
+
+    fn skip_if_known(&mut self, submission: &BlockSubmission) {
+        self.skip_step = Some(self.steps.len());
+        self.steps.push(PlanStep::SkipIfKnown {
+            skip_if_known: submission.block.block_hash(),
+            skip_next_steps: 0,
+        });
+    }
+
+    fn submitblock(&mut self, submission: &BlockSubmission) {
+        self.push_block_step(Self::submitblock_step(submission, false));
+    }
+
+    fn submitblock_checked(&mut self, submission: &BlockSubmission) {
+        self.push_block_step(Self::submitblock_step(submission, true));
+    }
+
+    fn check_tip(&mut self, submission: &BlockSubmission) {
+        self.push_block_step(PlanStep::Rpc {
+            rpc: RpcCall::new("getbestblockhash", ()),
+            block: None,
+            expect: Some(Expectation::Tip {
+                block_hash: submission.block.block_hash(),
+                verdict: submission.expected,
+            }),
+            output: None,
+            description: None,
+        });
+    }
+
+    fn command_capture(
+        &mut self,
+        var: &ScriptVar,
+        call: &RpcCall,
+        description: &str,
+    ) {
+        self.steps.push(PlanStep::Rpc {
+            rpc: call.clone(),
+            block: None,
+            expect: None,
+            output: Some(var.clone()),
+            description: Some(description.to_owned()),
+        });
+    }
+
+    fn check_captured(
+        &mut self,
+        var: &ScriptVar,
+        expected: &str,
+        description: &str,
+    ) {
+        self.steps.push(PlanStep::Check {
+            check_output: var.clone(),
+            equals: expected.to_owned(),
+            description: description.to_owned(),
+        });
+    }
+
+    fn check_enforcer(&mut self, check: &EnforcerCheck, description: &str) {
+        self.push_block_step(PlanStep::Enforcer {
+            enforcer_method: check.method(),
+            enforcer_request: check.request_body(),
+            description: description.to_owned(),
+            expect: check.clone(),
+        });
+    }
+
+    /// Adds steps capturing the node's tip and block count, and the counts
+    /// to the plan's `summary` object
+    fn summary(&mut self, counts: &SubmissionCounts) {
+        self.skip_step = None;
+        self.command_capture(
+            &ScriptVar::new("FINAL_TIP").unwrap(),
+            &RpcCall::new("getbestblockhash", ()),
+            "the node's tip",
+        );
+        self.command_capture(
+            &ScriptVar::new("FINAL_HEIGHT").unwrap(),
+            &RpcCall::new("getblockcount", ()),
+            "the node's block count",
+        );
+        self.summary = Some(*counts);
+    }
+
+    fn finalize(self) -> String {
+        let Self {
+            header,
+            meta,
+            connection,
+            block_hashes,
+            steps,
+            skip_step: _,
+            summary,
+        } = self;
+        let plan = JsonPlan {
+            version: JSON_PLAN_VERSION,
+            header,
+            meta,
+            connection,
+            block_hashes,
+            steps,
+            summary,
+        };
+        serde_json::to_string_pretty(&plan).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser as _;
+    use serde_json::{json, Value};
+
+    use super::JsonPlanBuilder;
+    use crate::{
+        cli::{BlockSpec, RpcOpts, Verdict},
+        script_builder::{
+            BlockSubmission, ScriptBuilder as _, SubmissionCounts,
+        },
+    };
+
+    fn rpc_opts(args: &[&str]) -> RpcOpts {
+        let args = [
+            "bip300-enforcer-demo",
+            "--rpc-url",
+            "http://127.0.0.1:18443",
+        ]
+        .into_iter()
+        .chain(args.iter().copied());
+        RpcOpts::try_parse_from(args).unwrap()
+    }
+
+    fn finalize(plan_builder: JsonPlanBuilder) -> Value {
+        serde_json::from_str(&plan_builder.finalize()).unwrap()
+    }
+
+    #[test]
+    fn connection() {
+        let cases = [
+            (
+                &["--rpc-user", "u", "--rpc-pass", "p"][..],
+                json!({
+                    "url": "http://127.0.0.1:18443/",
+                    "auth": { "type": "user_pass", "user": "u", "pass": "p" },
+                    "timeout_secs": 120,
+                }),
+            ),
+            (
+                &["--rpc-auth-env", "--rpc-wallet", "w", "--rpc-timeout", "5"]
+                    [..],
+                json!({
+                    "url": "http://127.0.0.1:18443/",
+                    "wallet": "w",
+                    "auth": { "type": "env" },
+                    "timeout_secs": 5,
+                }),
+            ),
+            (
+                &["--rpc-cookie", "/tmp/.cookie", "--rpc-insecure"][..],
+                json!({
+                    "url": "http://127.0.0.1:18443/",
+                    "auth": { "type": "cookie", "path": "/tmp/.cookie" },
+                    "timeout_secs": 120,
+                    "insecure": true,
+                }),
+            ),
+        ];
+        for (args, expected) in cases {
+            let plan = finalize(JsonPlanBuilder::new(&rpc_opts(args)));
+            assert_eq!(plan["connection"], expected, "{args:?}");
+        }
+        let mut plan_builder =
+            JsonPlanBuilder::new(&rpc_opts(&["--rpc-cacert", "/etc/ca.pem"]));
+        plan_builder.enforcer_addr("127.0.0.1:50051".parse().unwrap());
+        let plan = finalize(plan_builder);
+        assert_eq!(plan["connection"]["cacert"], "/etc/ca.pem");
+        assert_eq!(
+            plan["connection"]["enforcer_url"],
+            "http://127.0.0.1:50051/cusf.mainchain.v1.ValidatorService"
+        );
+    }
+
+    /// Steps added for a block after `skip_if_known` are skipped if the node
+    /// has the block, up to the next block comment
+    #[test]
+    fn block_steps() {
+        let block =
+            bitcoin::constants::genesis_block(bitcoin::Network::Regtest);
+        let block_hash = block.block_hash().to_string();
+        let spec = BlockSpec {
+            label: Some("a".to_owned()),
+            duplicate_m2: true,
+            ..BlockSpec::default()
+        };
+        let submission = BlockSubmission {
+            block: &block,
+            height: 0,
+            spec: Some(&spec),
+            expected: Verdict::Reject,
+            description: "block a",
+            hex_file: None,
+        };
+        let mut plan_builder = JsonPlanBuilder::new(&rpc_opts(&[]));
+        plan_builder.skip_if_known(&submission);
+        plan_builder.sleep(Duration::from_millis(5));
+        plan_builder.submitblock_checked(&submission);
+        plan_builder.check_tip(&submission);
+        plan_builder.block_comment(None, &submission);
+        plan_builder.submitblock(&submission);
+        let plan = finalize(plan_builder);
+        let steps = plan["steps"].as_array().unwrap();
+        assert_eq!(steps.len(), 6, "{plan}");
+        assert_eq!(
+            steps[0],
+            json!({ "skip_if_known": block_hash, "skip_next_steps": 3 })
+        );
+        assert_eq!(steps[1], json!({ "sleep_ms": 5 }));
+        let hex = bitcoin::consensus::encode::serialize_hex(&block);
+        assert_eq!(
+            steps[2]["rpc"],
+            json!({ "method": "submitblock", "params": [hex] })
+        );
+        assert_eq!(steps[2]["block"]["hash"], block_hash);
+        assert_eq!(steps[2]["block"]["spec"], json!(spec));
+        assert_eq!(
+            steps[2]["block"]["invalid_reasons"],
+            json!(["duplicate_m2"])
+        );
+        assert_eq!(
+            steps[2]["expect"],
+            json!({ "submitblock": { "verdict": "reject" } })
+        );
+        assert_eq!(
+            steps[3],
+            json!({
+                "rpc": { "method": "getbestblockhash", "params": [] },
+                "expect": {
+                    "tip": { "block_hash": block_hash, "verdict": "reject" }
+                },
+            })
+        );
+        assert!(steps[4]["comment"].is_string(), "{}", steps[4]);
+        // Not checked
+        assert_eq!(steps[5]["rpc"]["method"], "submitblock");
+        assert!(steps[5].get("expect").is_none(), "{}", steps[5]);
+    }
+
+    #[test]
+    fn summary() {
+        let mut plan_builder = JsonPlanBuilder::new(&rpc_opts(&[]));
+        let mut counts = SubmissionCounts::default();
+        counts.add(Verdict::Accept);
+        counts.add(Verdict::Reject);
+        plan_builder.summary(&counts);
+        let plan = finalize(plan_builder);
+        assert_eq!(
+            plan["steps"],
+            json!([
+                {
+                    "rpc": { "method": "getbestblockhash", "params": [] },
+                    "output": "FINAL_TIP",
+                    "description": "the node's tip",
+                },
+                {
+                    "rpc": { "method": "getblockcount", "params": [] },
+                    "output": "FINAL_HEIGHT",
+                    "description": "the node's block count",
+                },
+            ])
+        );
+        assert_eq!(
+            plan["summary"],
+            json!({ "n_blocks": 2, "n_expect_accept": 1, "n_expect_reject": 1 })
+        );
+        // Empty fields are omitted
+        assert!(plan.get("block_hashes").is_none(), "{plan}");
+        assert!(plan.get("header").is_none(), "{plan}");
+    }
+}
@@ -1,61 +1,306 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
     net::SocketAddr,
-    time::{Duration, SystemTime},
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
 };
 
-use bip300301::{client::BlockTemplate, MainClient as _};
+use anyhow::Context as _;
+use base64::Engine as _;
+use bip300301::jsonrpsee::{
+    self,
+    core::{client::ClientT as _, params::ArrayParams},
+    http_client::{HeaderMap, HeaderValue, HttpClient, HttpClientBuilder},
+};
 use bitcoin::{
     absolute::LockTime,
     block::Header,
-    constants::{COINBASE_MATURITY, SUBSIDY_HALVING_INTERVAL},
+    constants::COINBASE_MATURITY,
     hashes::{sha256d, Hash as _},
-    opcodes::{all::OP_RETURN, OP_TRUE},
-    transaction, Address, Amount, Block, BlockHash, CompactTarget, OutPoint,
-    ScriptBuf, Sequence, Target, Transaction, TxIn, TxMerkleNode, TxOut,
-    Witness,
+    opcodes::OP_TRUE,
+    transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf,
+    Sequence, Target, Transaction, TxIn, TxMerkleNode, Witness,
+    WitnessMerkleNode, Wtxid,
 };
-use clap::Parser;
+use clap::{CommandFactory as _, FromArgMatches as _};
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+use url::Url;
 
+mod bip300_message;
+mod block_template;
+mod block_tree;
+mod chain_params;
 mod cli;
+mod coinbase_builder;
+mod config;
+mod datadir;
+mod decode;
+mod enforcer_checks;
+mod errors;
+mod generate;
+mod json_output;
+mod json_plan;
+mod makefile_builder;
+mod metrics;
+mod mine;
+#[cfg(test)]
+mod mock_rpc;
 mod posix_script_builder;
+mod powershell_script_builder;
+mod proto;
+mod recording;
+mod regtest_node;
+mod replay;
+mod report;
+mod rollback;
+mod run;
+mod script_builder;
+mod selftest;
+mod setup_cache;
+mod spec_error;
+mod state_file;
+mod submit;
+mod submit_block_result;
+mod tip_notifications;
+mod tls;
 
-use cli::{BlockSpec, BlocksSpec, Cli, RpcAuth};
-use posix_script_builder::OutputPosixScriptBuilder;
+use bip300_message::Bip300Message;
+use block_template::TemplateConstraints;
+use block_tree::BlockTree;
+use chain_params::ChainParams;
+use cli::{
+    BlockSpec, BlocksSpec, Cli, Command, Network, NoPeersAction, NodeAction,
+    OutputFormat, RpcOpts, SidechainDescription, Verdict, VersionedBlocksSpec,
+    FORK_FROM_ANCHOR,
+};
+use coinbase_builder::{CoinbaseBuilder, CoinbaseOutputs};
+use enforcer_checks::{EnforcerCheck, EnforcerClient, EnforcerState};
+use errors::{
+    GenError, RpcError, SpecError, RPC_CLIENT_IN_INITIAL_DOWNLOAD,
+    RPC_CLIENT_NOT_CONNECTED, RPC_IN_WARMUP, RPC_METHOD_NOT_FOUND,
+};
+use recording::{RecordedBlockMeta, RecordingManifest};
+use run::{run, Mode};
+use script_builder::NodeTip;
+use setup_cache::{CachedBlock, CachedSetup, SetupCache, SetupRequirements};
+use state_file::{Checkpoint, StateFile};
 
 /// Script with no spend requirements
 fn unlocked_script() -> ScriptBuf {
     ScriptBuf::builder().push_opcode(OP_TRUE).into_script()
 }
 
-fn block_subsidy(network: bitcoin::Network, height: u32) -> Amount {
-    #[allow(clippy::wildcard_in_or_patterns)]
-    let halving_interval = match network {
-        bitcoin::Network::Regtest => 150,
-        bitcoin::Network::Bitcoin | bitcoin::Network::Testnet | _ => {
-            SUBSIDY_HALVING_INTERVAL
+/// P2WSH script pubkey for [`unlocked_script`].
+/// Constructed directly rather than via `Address`, so that it does not
+/// depend on the network.
+fn unlocked_script_pubkey() -> ScriptBuf {
+    ScriptBuf::new_p2wsh(&unlocked_script().wscript_hash())
+}
+
+/// Witness reserved value used in coinbase witnesses
+const WITNESS_RESERVED_VALUE: [u8; 32] = [0; 32];
+
+/// Magic bytes for the witness commitment output in a coinbase tx
+const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
+/// Witness root of a block's txs, other than the coinbase tx, which is
+/// committed to with the wtxid of the coinbase tx taken to be zero
+fn witness_root(txs: &[Transaction]) -> WitnessMerkleNode {
+    let hashes = std::iter::once(Wtxid::all_zeros().to_raw_hash())
+        .chain(txs.iter().map(|tx| tx.compute_wtxid().to_raw_hash()));
+    bitcoin::merkle_tree::calculate_root(hashes).unwrap().into()
+}
+
+/// Source of block timestamps
+#[derive(Clone, Copy, Debug)]
+enum Clock {
+    /// Use the current system time
+    Now,
+    /// Timestamps increase by one second per block, so that runs are
+    /// reproducible
+    Deterministic { next_time: u32 },
+}
+
+impl Clock {
+    fn next_time(&mut self) -> u32 {
+        match self {
+            Self::Now => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32,
+            Self::Deterministic { next_time } => {
+                let res = *next_time;
+                *next_time += 1;
+                res
+            }
         }
-    };
-    let epoch = height / halving_interval;
-    Amount::from_int_btc(50) / (1 << epoch)
+    }
+}
+
+/// Sidechain that M1 and M2 messages refer to
+#[derive(Clone)]
+struct Sidechain {
+    slot: u8,
+    /// Description, unless overridden by the block spec
+    description: Vec<u8>,
+}
+
+/// Identifies a block across the passes of [`gen_blocks`]. The unmined
+/// header is not used, since its timestamp differs between passes under
+/// [`Clock::Now`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct PreminedKey {
+    height: u32,
+    /// Mined hash of the parent
+    prev_blockhash: BlockHash,
+    merkle_root: TxMerkleNode,
+}
+
+impl PreminedKey {
+    fn new(height: u32, header: &Header) -> Self {
+        Self {
+            height,
+            prev_blockhash: header.prev_blockhash,
+            merkle_root: header.merkle_root,
+        }
+    }
+}
+
+/// How [`gen_block`] finds a nonce for each block
+#[derive(Clone, Debug, Default)]
+enum Mining {
+    /// Mine each block as it is generated
+    #[default]
+    Inline,
+    /// Leave blocks unmined, recording their heights and headers, so that
+    /// the blocks can be mined in parallel with [`mine_in_parallel`]
+    Record(Vec<(u32, Header)>),
+    /// Use headers mined by [`mine_in_parallel`], including their
+    /// timestamps. Blocks that were not mined in parallel are mined inline.
+    Premined(HashMap<PreminedKey, MinedHeader>),
+    /// Use blocks recorded by a previous run, in order, instead of mining
+    /// them. Each recorded block must match the generated block, other than
+    /// its header.
+    Replay(VecDeque<Block>),
+}
+
+/// State shared by all blocks generated in a run
+#[derive(Clone)]
+struct BlockGenCtx {
+    chain_params: ChainParams,
+    clock: Clock,
+    /// Address that coinbase outputs pay to, unless overridden by the block
+    /// spec. Must be valid for the chain.
+    coinbase_address: Option<String>,
+    sidechain: Sidechain,
+    /// Seed used to initialize `rng`
+    seed: u64,
+    /// All randomness in a run is drawn from this RNG, so that runs are
+    /// reproducible from the seed
+    rng: StdRng,
+    /// Enforcer state expected after the blocks generated so far
+    enforcer_state: EnforcerState,
+    /// Funding for the node's wallet in setup blocks, if any
+    wallet_funding: Option<WalletFunding>,
+    /// Constraints from the node's block template, if blocks are anchored
+    /// on one
+    template_constraints: Option<TemplateConstraints>,
+    mining: Mining,
+    /// Number of threads to mine independent branches on
+    mining_threads: NonZeroUsize,
+    /// File that the run's progress is saved to, if any. If the run is
+    /// resumed from it, no setup blocks are generated, and blocks are
+    /// anchored on the block that the previous run stopped at.
+    state_file: Option<StateFile>,
+    /// Directory to record the generated blocks to, if any
+    record_dir: Option<PathBuf>,
+    /// File that submitted setup blocks are cached in, if any. If it has
+    /// setup blocks for the run's requirements that the node still has, no
+    /// setup blocks are generated.
+    setup_cache: Option<SetupCache>,
+}
+
+impl BlockGenCtx {
+    fn new(
+        chain_params: ChainParams,
+        clock: Clock,
+        coinbase_address: Option<String>,
+        sidechain: Sidechain,
+        seed: u64,
+    ) -> Self {
+        Self {
+            chain_params,
+            clock,
+            coinbase_address,
+            sidechain,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            enforcer_state: EnforcerState::default(),
+            wallet_funding: None,
+            template_constraints: None,
+            mining: Mining::Inline,
+            mining_threads: std::thread::available_parallelism()
+                .unwrap_or(NonZeroUsize::MIN),
+            state_file: None,
+            record_dir: None,
+            setup_cache: None,
+        }
+    }
+
+    /// Sidechain description used in M1 and M2 messages for the block spec
+    fn sidechain_description<'a>(
+        &'a self,
+        block_spec: &'a BlockSpec,
+    ) -> &'a [u8] {
+        block_spec
+            .description
+            .as_ref()
+            .map_or(self.sidechain.description.as_slice(), |description| {
+                description.as_bytes()
+            })
+    }
 }
 
 fn gen_block(
+    ctx: &mut BlockGenCtx,
     prev_blockhash: BlockHash,
     target: CompactTarget,
     height: u32,
-    coinbase_txouts: Vec<TxOut>,
+    mut coinbase: CoinbaseBuilder,
     mut txs: Vec<Transaction>,
-) -> anyhow::Result<Block> {
+) -> Result<Block, GenError> {
+    let coinbase_witness = if ctx.chain_params.requires_witness_commitment() {
+        let () = coinbase.witness_commitment(witness_root(&txs));
+        Witness::from_slice(&[WITNESS_RESERVED_VALUE])
+    } else {
+        Witness::new()
+    };
+    let CoinbaseOutputs {
+        txouts: coinbase_txouts,
+        messages,
+    } = coinbase.build()?;
+    for (idx, message) in messages {
+        tracing::debug!("Coinbase output {idx} carries {message}");
+    }
     let coinbase_txin = TxIn {
         previous_output: OutPoint::null(),
-        script_sig: ScriptBuf::builder().push_int(height as i64).into_script(),
+        // Extra nonce ensures that blocks are unique across runs
+        script_sig: ScriptBuf::builder()
+            .push_int(height as i64)
+            .push_slice(ctx.rng.gen::<[u8; 8]>())
+            .into_script(),
         // FIXME: Verify that this is correct
         sequence: Sequence::MAX,
-        witness: Witness::new(),
+        witness: coinbase_witness,
     };
     let coinbase_tx = Transaction {
         version: transaction::Version::TWO,
-        lock_time: LockTime::from_height(height + COINBASE_MATURITY)?,
+        lock_time: LockTime::from_height(height + COINBASE_MATURITY)
+            .map_err(|source| GenError::InvalidLockTime { height, source })?,
         input: vec![coinbase_txin],
         output: coinbase_txouts,
     };
@@ -66,10 +311,7 @@ fn gen_block(
         version: bitcoin::block::Version::NO_SOFT_FORK_SIGNALLING,
         prev_blockhash,
         merkle_root: TxMerkleNode::all_zeros(),
-        time: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32,
+        time: ctx.clock.next_time(),
         bits: target,
         nonce: 0,
     };
@@ -78,189 +320,2597 @@ fn gen_block(
         txdata: txs,
     };
     block.header.merkle_root = block.compute_merkle_root().unwrap();
-    let target = Target::from_compact(target);
-    let mut nonce = block.header.nonce;
-    let mut header_bytes = bitcoin::consensus::serialize(&block.header);
+    let premined = match &mut ctx.mining {
+        Mining::Inline => None,
+        Mining::Record(headers) => {
+            headers.push((height, block.header));
+            return Ok(block);
+        }
+        Mining::Premined(premined) => premined
+            .get(&PreminedKey::new(height, &block.header))
+            .copied(),
+        Mining::Replay(recorded_blocks) => {
+            let recorded_block = recorded_blocks
+                .pop_front()
+                .ok_or(GenError::MissingRecordedBlock { height })?;
+            if recorded_block.header.prev_blockhash != prev_blockhash
+                || recorded_block.txdata != block.txdata
+            {
+                return Err(GenError::RecordedBlockMismatch {
+                    block_hash: recorded_block.block_hash(),
+                    height,
+                });
+            }
+            let () = metrics::record_mined(
+                height,
+                recorded_block.block_hash(),
+                0,
+                Duration::ZERO,
+            );
+            return Ok(recorded_block);
+        }
+    };
+    let MinedHeader {
+        header,
+        nonce_attempts,
+        mining,
+    } = premined.unwrap_or_else(|| mine_header(block.header));
+    block.header = header;
+    tracing::debug!(
+        height,
+        hash = %block.block_hash(),
+        target = %block.header.bits.to_consensus(),
+        nonce_attempts,
+        "Mined block"
+    );
+    let () = metrics::record_mined(
+        height,
+        block.block_hash(),
+        nonce_attempts,
+        mining,
+    );
+    Ok(block)
+}
+
+/// Header mined by [`mine_header`]
+#[derive(Clone, Copy, Debug)]
+struct MinedHeader {
+    header: Header,
+    /// Number of nonces tried, including the one found
+    nonce_attempts: u64,
+    /// Time taken to mine the header
+    mining: Duration,
+}
+
+/// Try nonces from the header's nonce upwards, until the header's hash meets
+/// its target
+fn mine_header(header: Header) -> MinedHeader {
+    let mining_start = Instant::now();
+    let target = header.target();
+    let mut nonce = header.nonce;
+    let mut nonce_attempts = 1;
+    let mut header_bytes = bitcoin::consensus::serialize(&header);
     loop {
         let header_hash = sha256d::Hash::hash(&header_bytes).to_byte_array();
         if Target::from_le_bytes(header_hash) < target {
             break;
         }
         nonce += 1;
-        let nonce_bytes = nonce.to_be_bytes();
-        header_bytes[76] = nonce_bytes[0];
-        header_bytes[77] = nonce_bytes[1];
-        header_bytes[78] = nonce_bytes[2];
-        header_bytes[79] = nonce_bytes[3];
-    }
-    block.header = bitcoin::consensus::deserialize(&header_bytes).unwrap();
-    assert!(block.header.validate_pow(target).is_ok());
-    Ok(block)
+        nonce_attempts += 1;
+        // The nonce is the last field of the header, encoded little-endian
+        header_bytes[76..80].copy_from_slice(&nonce.to_le_bytes());
+    }
+    let header: Header =
+        bitcoin::consensus::deserialize(&header_bytes).unwrap();
+    assert!(header.validate_pow(target).is_ok());
+    MinedHeader {
+        header,
+        nonce_attempts,
+        mining: mining_start.elapsed(),
+    }
+}
+
+/// Headers waiting to be mined by [`mine_in_parallel`]
+struct MiningQueue {
+    /// Heights and headers whose parents are mined, with their parent's
+    /// mined hash, and their hashes as recorded
+    ready: Vec<(u32, Header, BlockHash)>,
+    /// Heights and headers whose parents are not mined yet, by the unmined
+    /// hash of their parent
+    waiting: HashMap<BlockHash, Vec<(u32, Header)>>,
+    /// Number of headers not mined yet
+    remaining: usize,
+    /// Mined headers, by the key of their unmined header
+    mined: HashMap<PreminedKey, MinedHeader>,
 }
 
-fn m1_txout(sidechain_number: u8, description: Vec<u8>) -> TxOut {
-    let script_pubkey = ScriptBuf::from_bytes(
-        std::iter::once(OP_RETURN.to_u8())
-            .chain([0xD5, 0xE0, 0xC4, 0xAF, sidechain_number])
-            .chain(description)
-            .collect(),
+/// Mine headers recorded with [`Mining::Record`] on `n_threads` threads, for
+/// [`Mining::Premined`]. Each header is mined once its parent is, so that
+/// independent branches are mined in parallel, while each branch is mined
+/// in order. Mined headers are the same as if they were mined in order.
+fn mine_in_parallel(
+    headers: Vec<(u32, Header)>,
+    n_threads: usize,
+) -> HashMap<PreminedKey, MinedHeader> {
+    let unmined_hashes: HashSet<BlockHash> = headers
+        .iter()
+        .map(|(_, header)| header.block_hash())
+        .collect();
+    let mut queue = MiningQueue {
+        ready: Vec::new(),
+        waiting: HashMap::new(),
+        remaining: headers.len(),
+        mined: HashMap::new(),
+    };
+    for (height, header) in headers {
+        if unmined_hashes.contains(&header.prev_blockhash) {
+            queue
+                .waiting
+                .entry(header.prev_blockhash)
+                .or_default()
+                .push((height, header));
+        } else {
+            queue.ready.push((height, header, header.block_hash()));
+        }
+    }
+    let queue = std::sync::Mutex::new(queue);
+    let queue_changed = std::sync::Condvar::new();
+    let mine_ready = || loop {
+        let (height, header, recorded_hash) = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if let Some(ready) = queue.ready.pop() {
+                    break ready;
+                }
+                if queue.remaining == 0 {
+                    return;
+                }
+                queue = queue_changed.wait(queue).unwrap();
+            }
+        };
+        let mined_header = mine_header(header);
+        let mut queue = queue.lock().unwrap();
+        let children = queue.waiting.remove(&recorded_hash).unwrap_or_default();
+        for (child_height, mut child) in children {
+            let child_recorded_hash = child.block_hash();
+            child.prev_blockhash = mined_header.header.block_hash();
+            queue.ready.push((child_height, child, child_recorded_hash));
+        }
+        queue
+            .mined
+            .insert(PreminedKey::new(height, &header), mined_header);
+        queue.remaining -= 1;
+        queue_changed.notify_all();
+    };
+    std::thread::scope(|scope| {
+        for _ in 0..n_threads {
+            scope.spawn(mine_ready);
+        }
+    });
+    queue.into_inner().unwrap().mined
+}
+
+const DEMO_SIDECHAIN_SLOT: u8 = 0xFF;
+const DEMO_SIDECHAIN_DESCRIPTION: &[u8] = b"demo sidechain";
+
+/// Chain state that generated blocks are anchored on
+#[derive(Clone, Copy, Debug)]
+struct Anchor {
+    /// Height of the first block to generate
+    height: u32,
+    prev_blockhash: BlockHash,
+    target: CompactTarget,
+}
+
+impl Anchor {
+    /// Anchor for a child of the specified block
+    fn child_of(block: &Block, height: u32) -> Self {
+        Self {
+            height: height + 1,
+            prev_blockhash: block.block_hash(),
+            target: block.header.target().to_compact_lossy(),
+        }
+    }
+}
+
+/// Construct an RPC client for the node. If a wallet is specified,
+/// requests are sent to that wallet's endpoint.
+fn rpc_client(
+    rpc: &RpcOpts,
+    wallet: Option<&str>,
+) -> anyhow::Result<HttpClient> {
+    let credentials = rpc.credentials()?;
+    let endpoint = rpc.endpoint(wallet);
+    // Resolve the host now, so that failures are reported before any
+    // requests are made
+    let _: Vec<SocketAddr> =
+        endpoint.socket_addrs(|| None).with_context(|| {
+            format!("failed to resolve RPC host in `{endpoint}`")
+        })?;
+    let auth = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", credentials.user, credentials.pass));
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "authorization",
+        HeaderValue::from_str(&format!("Basic {auth}"))?,
     );
-    TxOut {
-        value: Amount::ZERO,
-        script_pubkey,
+    let mut client_builder = HttpClientBuilder::default()
+        .request_timeout(Duration::from_secs(rpc.rpc_timeout))
+        .set_headers(headers);
+    if let Some(tls_config) = tls::client_config(rpc)? {
+        client_builder = client_builder.with_custom_cert_store(tls_config);
+    }
+    let client =
+        client_builder.build(endpoint.as_str()).with_context(|| {
+            format!("failed to construct RPC client for `{endpoint}`")
+        })?;
+    Ok(client)
+}
+
+/// Delay between `getblocktemplate` requests while waiting for the node to
+/// connect to a peer, with `--on-no-peers retry`
+const NO_PEERS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay before the first retry of a failed RPC request, doubled after each
+/// retry
+const RPC_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Maximum delay between retries of a failed RPC request
+const RPC_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// `true` if an RPC request that failed with the error may succeed if
+/// retried: the connection failed or timed out, the server was unavailable,
+/// or the node was still starting up
+fn is_retryable(err: &jsonrpsee::core::client::Error) -> bool {
+    use jsonrpsee::{core::client::Error, http_client::transport};
+    /// HTTP status for a server that is temporarily unavailable, eg. because
+    /// its work queue is full
+    const SERVICE_UNAVAILABLE: u16 = 503;
+    match err {
+        Error::Transport(transport_err) => {
+            match transport_err.downcast_ref::<transport::Error>() {
+                Some(transport::Error::Rejected { status_code }) => {
+                    *status_code == SERVICE_UNAVAILABLE
+                }
+                _ => true,
+            }
+        }
+        Error::RequestTimeout => true,
+        Error::Call(err) => err.code() == RPC_IN_WARMUP,
+        _ => false,
     }
 }
 
-fn m2_txout(sidechain_number: u8, description: &[u8]) -> TxOut {
-    let script_pubkey = ScriptBuf::from_bytes(
-        std::iter::once(OP_RETURN.to_u8())
-            .chain([0xD5, 0xE0, 0xC4, 0xAF, sidechain_number])
-            .chain(sha256d::Hash::hash(description).to_byte_array())
-            .collect(),
+/// Send an RPC request, retrying up to `retries` times with exponential
+/// backoff if it fails with a transient error. `request` is passed the
+/// attempt number, starting from 1. The time spent waiting on each attempt
+/// is recorded in the run's metrics.
+async fn retry_rpc<T, F, Fut>(
+    retries: u32,
+    rpc_url: &Url,
+    method: &str,
+    mut request: F,
+) -> Result<T, jsonrpsee::core::client::Error>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, jsonrpsee::core::client::Error>>,
+{
+    let mut backoff = RPC_RETRY_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let start = Instant::now();
+        let res = request(attempt).await;
+        let () = metrics::record_rpc(method, start.elapsed());
+        match res {
+            Err(err) if attempt <= retries && is_retryable(&err) => {
+                tracing::warn!(
+                    "RPC request `{method}` to `{rpc_url}` failed (attempt \
+                     {attempt} of {}), retrying in {backoff:?}: {err}",
+                    retries + 1
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RPC_RETRY_MAX_BACKOFF);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Fetch a block template from the node, to anchor generated blocks on.
+/// If the node cannot provide a template because it is in IBD or does not
+/// support `getblocktemplate`, blocks are anchored on the node's tip
+/// instead, without template constraints. If it cannot because it has no
+/// peers, `--on-no-peers` decides.
+async fn fetch_anchor(
+    rpc: &RpcOpts,
+) -> anyhow::Result<(Anchor, Option<TemplateConstraints>)> {
+    use jsonrpsee::core::client::Error;
+    let client = rpc_client(rpc, None)?;
+    let rpc_url = rpc.endpoint(None);
+    let wait_start = Instant::now();
+    let template = loop {
+        let request_start = Instant::now();
+        let err = match retry_rpc(
+            rpc.rpc_retries,
+            &rpc_url,
+            "getblocktemplate",
+            |_| block_template::request(&client, None),
+        )
+        .await
+        {
+            Ok(template) => {
+                tracing::debug!(
+                    elapsed = ?request_start.elapsed(),
+                    "RPC request `getblocktemplate` completed"
+                );
+                break template;
+            }
+            Err(Error::Call(err)) => err,
+            Err(err) => {
+                return Err(
+                    RpcError::new(&rpc_url, "getblocktemplate", err).into()
+                )
+            }
+        };
+        match (err.code(), rpc.on_no_peers) {
+            (RPC_CLIENT_NOT_CONNECTED, NoPeersAction::Retry) => {
+                let waited = wait_start.elapsed();
+                if waited >= rpc.no_peers_timeout {
+                    return Err(RpcError::new(
+                        &rpc_url,
+                        "getblocktemplate",
+                        Error::Call(err),
+                    ))
+                    .with_context(|| {
+                        format!(
+                            "node did not connect to a peer within \
+                             {:?}",
+                            rpc.no_peers_timeout
+                        )
+                    });
+                }
+                let delay =
+                    NO_PEERS_RETRY_INTERVAL.min(rpc.no_peers_timeout - waited);
+                tracing::warn!(
+                    "Node at `{rpc_url}` is not connected to any peers ({}), \
+                     retrying `getblocktemplate` in {delay:?}",
+                    err.message()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            (RPC_CLIENT_NOT_CONNECTED, NoPeersAction::Abort) => {
+                anyhow::bail!(
+                    "node at `{rpc_url}` is not connected to any peers ({}), \
+                     so it cannot provide a block template. Connect it to a \
+                     peer, eg. with `addnode`, or pass `--on-no-peers retry` \
+                     to wait for it to connect, `--on-no-peers fallback` to \
+                     anchor blocks on its tip instead, or `--offline` to \
+                     generate blocks without a node.",
+                    err.message()
+                )
+            }
+            (RPC_CLIENT_NOT_CONNECTED, NoPeersAction::Fallback)
+            | (RPC_CLIENT_IN_INITIAL_DOWNLOAD | RPC_METHOD_NOT_FOUND, _) => {
+                tracing::warn!(
+                    "`getblocktemplate` failed ({}): anchoring on the node's \
+                     tip instead. Mempool transactions and the template's \
+                     limits are not considered, and the target is the tip's, \
+                     which may be wrong after a difficulty adjustment.",
+                    err.message()
+                );
+                let anchor =
+                    fetch_tip_anchor(&client, &rpc_url, rpc.rpc_retries)
+                        .await?;
+                return Ok((anchor, None));
+            }
+            (_, _) => {
+                return Err(RpcError::new(
+                    &rpc_url,
+                    "getblocktemplate",
+                    Error::Call(err),
+                )
+                .into())
+            }
+        }
+    };
+    let anchor = template.anchor()?;
+    tracing::info!(
+        height = anchor.height,
+        prev_blockhash = %anchor.prev_blockhash,
+        target = %anchor.target.to_consensus(),
+        "Received block template"
     );
-    TxOut {
-        value: Amount::ZERO,
-        script_pubkey,
+    Ok((anchor, Some(template.constraints())))
+}
+
+/// Anchor on the node's tip, with `getbestblockhash` and `getblockheader`,
+/// for nodes that cannot provide a block template
+async fn fetch_tip_anchor(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+) -> Result<Anchor, RpcError> {
+    let tip: BlockHash =
+        retry_rpc(rpc_retries, rpc_url, "getbestblockhash", |_| {
+            client.request("getbestblockhash", ArrayParams::new())
+        })
+        .await
+        .map_err(|err| RpcError::new(rpc_url, "getbestblockhash", err))?;
+    let BlockHeaderInfo {
+        height,
+        prev_blockhash: _,
+        bits,
+    } = fetch_block_header(client, rpc_url, rpc_retries, tip).await?;
+    let bits = u32::from_str_radix(&bits, 16).map_err(|_| {
+        RpcError::InvalidResponse {
+            rpc_url: rpc_url.clone(),
+            method: "getblockheader".to_owned(),
+            reason: format!(
+                "invalid `bits` in header of block {tip}: `{bits}`"
+            ),
+        }
+    })?;
+    let anchor = Anchor {
+        height: height + 1,
+        prev_blockhash: tip,
+        target: CompactTarget::from_consensus(bits),
+    };
+    tracing::info!(
+        height = anchor.height,
+        prev_blockhash = %anchor.prev_blockhash,
+        target = %anchor.target.to_consensus(),
+        "Anchored on the node's tip"
+    );
+    Ok(anchor)
+}
+
+/// Subset of the response to the `getblockchaininfo` RPC
+#[derive(Debug, Deserialize)]
+struct BlockchainInfo {
+    chain: String,
+    #[serde(rename = "initialblockdownload")]
+    initial_block_download: bool,
+}
+
+/// Fetch the node's chain and IBD status with `getblockchaininfo`, and check
+/// that the node is not on mainnet, unless `--i-really-want-mainnet` was
+/// specified
+async fn fetch_blockchain_info(
+    rpc: &RpcOpts,
+) -> anyhow::Result<BlockchainInfo> {
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(rpc, None)?;
+    let blockchain_info: BlockchainInfo =
+        retry_rpc(rpc.rpc_retries, &rpc_url, "getblockchaininfo", |_| {
+            client.request("getblockchaininfo", ArrayParams::new())
+        })
+        .await
+        .map_err(|err| RpcError::new(&rpc_url, "getblockchaininfo", err))?;
+    if blockchain_info.chain == bitcoin::Network::Bitcoin.to_core_arg()
+        && !rpc.i_really_want_mainnet
+    {
+        anyhow::bail!(
+            "node at `{rpc_url}` is on mainnet, but generated blocks are \
+             meant for test networks. Pass `--i-really-want-mainnet` to \
+             proceed anyway."
+        );
     }
+    Ok(blockchain_info)
 }
 
-const DEMO_SIDECHAIN_SLOT: u8 = 0xFF;
-const DEMO_SIDECHAIN_DESCRIPTION: &[u8] = b"demo sidechain";
+/// Check that the chain reported by a node's `getblockchaininfo` is the
+/// specified network
+fn check_chain(
+    rpc_url: &Url,
+    chain: &str,
+    network: bitcoin::Network,
+) -> anyhow::Result<()> {
+    match bitcoin::Network::from_core_arg(chain) {
+        Ok(node_network) if node_network == network => Ok(()),
+        Ok(_) | Err(_) => anyhow::bail!(
+            "node at `{rpc_url}` is on chain `{chain}`, but `--network` \
+             specifies chain `{}`. Pass `--skip-network-check` to ignore.",
+            network.to_core_arg()
+        ),
+    }
+}
 
-/// Generate initial setup blocks that ensure proposals exist, etc
-async fn gen_setup_blocks(
+/// Check that the node is not on mainnet, unless `--i-really-want-mainnet`
+/// was specified, and that it is on the specified network, unless
+/// `--skip-network-check` was specified. Warns if the node is in initial
+/// block download.
+/// Returns the node's chain, as reported by `getblockchaininfo`.
+async fn check_node(
+    rpc: &RpcOpts,
     network: bitcoin::Network,
-    rpc_addr: SocketAddr,
-    rpc_auth: RpcAuth,
-    blocks_spec: &BlocksSpec,
-) -> anyhow::Result<Vec<Block>> {
-    const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
-    let mut blocks = Vec::new();
-    let client = bip300301::client(
-        rpc_addr,
-        &rpc_auth.rpc_pass,
-        Some(REQUEST_TIMEOUT),
-        &rpc_auth.rpc_user,
-    )?;
-    let BlockTemplate {
-        mut height,
-        prev_blockhash,
-        target,
-        ..
-    } = client.get_block_template(Default::default()).await?;
-    let mut prev_blockhash =
-        BlockHash::from_byte_array(*prev_blockhash.as_ref());
-    let mut target = CompactTarget::from_consensus(target.to_consensus());
-    let addr = Address::p2wsh(&unlocked_script(), network);
-    let coinbase_value = block_subsidy(network, height);
-    let coinbase_txout = TxOut {
-        value: coinbase_value,
-        script_pubkey: addr.script_pubkey(),
+) -> anyhow::Result<String> {
+    let rpc_url = rpc.endpoint(None);
+    let BlockchainInfo {
+        chain,
+        initial_block_download,
+    } = fetch_blockchain_info(rpc).await?;
+    // Nodes in isolated environments may never leave IBD, so blocks are
+    // anchored on the node's tip rather than failing
+    if initial_block_download {
+        tracing::warn!(
+            "node at `{rpc_url}` is in initial block download: blocks will \
+             be anchored on its current tip, which may be behind the network"
+        );
+    }
+    if !rpc.skip_network_check {
+        let () = check_chain(&rpc_url, &chain, network)?;
+    }
+    Ok(chain)
+}
+
+/// Subset of the response to the `getnetworkinfo` RPC
+#[derive(Debug, Deserialize)]
+struct NetworkInfo {
+    version: u32,
+    subversion: String,
+}
+
+/// Oldest node version that is known to report `submitblock` results as
+/// expected. Older versions report some results differently, eg. for
+/// duplicate blocks, or blocks stored on a side branch.
+const MIN_TESTED_NODE_VERSION: u32 = 250000;
+
+/// Results of the preflight checks
+#[derive(Debug)]
+struct Preflight {
+    /// Chain, as reported by `getblockchaininfo`
+    chain: String,
+    /// Node version string, as reported by `getnetworkinfo`
+    subversion: String,
+}
+
+/// Check the node with [`check_node`], warn if its version has known
+/// `submitblock` quirks, and check that the enforcer is reachable, if
+/// `enforcer_addr` is specified.
+/// Run before generating or submitting blocks, unless `--skip-preflight` is
+/// specified.
+async fn preflight(
+    rpc: &RpcOpts,
+    network: bitcoin::Network,
+    enforcer_addr: Option<SocketAddr>,
+) -> anyhow::Result<Preflight> {
+    let chain = check_node(rpc, network).await?;
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(rpc, None)?;
+    let NetworkInfo {
+        version,
+        subversion,
+    } = retry_rpc(rpc.rpc_retries, &rpc_url, "getnetworkinfo", |_| {
+        client.request("getnetworkinfo", ArrayParams::new())
+    })
+    .await
+    .map_err(|err| RpcError::new(&rpc_url, "getnetworkinfo", err))?;
+    if version < MIN_TESTED_NODE_VERSION {
+        tracing::warn!(
+            "node at `{rpc_url}` is running `{subversion}`, which is older \
+             than {}.{}: `submitblock` results may differ from the expected \
+             verdicts",
+            MIN_TESTED_NODE_VERSION / 10000,
+            MIN_TESTED_NODE_VERSION / 100 % 100
+        );
+    }
+    if let Some(enforcer_addr) = enforcer_addr {
+        let enforcer_client = EnforcerClient::new(
+            enforcer_addr,
+            Duration::from_secs(rpc.rpc_timeout),
+        );
+        let () = enforcer_client.ping().await.with_context(|| {
+            format!(
+                "enforcer at `{enforcer_addr}` is not reachable: check that \
+                 it is running, and that `--enforcer-addr` is its \
+                 gRPC-gateway address. Pass `--skip-preflight` to skip."
+            )
+        })?;
+    }
+    Ok(Preflight { chain, subversion })
+}
+
+/// Run the preflight checks, and check that a block template can be
+/// fetched, without generating any blocks
+async fn dry_run(
+    rpc: &RpcOpts,
+    network: bitcoin::Network,
+    enforcer_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let rpc_url = rpc.endpoint(None);
+    let Preflight { chain, subversion } =
+        preflight(rpc, network, enforcer_addr).await?;
+    let (anchor, _) = fetch_anchor(rpc).await?;
+    if let Some(wallet) = &rpc.rpc_wallet {
+        let wallet_client = rpc_client(rpc, Some(wallet))?;
+        let _: serde_json::Value = wallet_client
+            .request("getwalletinfo", ArrayParams::new())
+            .await
+            .map_err(|err| RpcError::new(&rpc_url, "getwalletinfo", err))?;
+    }
+    println!("Connected to node RPC server at `{rpc_url}`");
+    println!("Chain: {chain}");
+    println!("Node version: {subversion}");
+    if let Some(enforcer_addr) = enforcer_addr {
+        println!("Enforcer: {enforcer_addr}");
+    }
+    if let Some(wallet) = &rpc.rpc_wallet {
+        println!("Wallet: {wallet}");
+    }
+    println!("Height: {}", anchor.height - 1);
+    println!("Best block hash: {}", anchor.prev_blockhash);
+    println!("Target: {:08x}", anchor.target.to_consensus());
+    Ok(())
+}
+
+/// Where the chain state that generated blocks are anchored on comes from
+#[derive(Clone, Copy, Debug)]
+enum AnchorSource {
+    /// Specified on the command line, in offline mode
+    Offline(Anchor),
+    /// Fetched from the node with `getblocktemplate`
+    Node {
+        /// If `true`, the plain setup block is first mined by the node
+        setup_via_generatetoaddress: bool,
+        /// Amount to fund the node's wallet with in setup blocks, if any
+        fund_node_wallet: Option<Amount>,
+    },
+    /// The anchor of a recording that is replayed
+    Recorded {
+        anchor: Anchor,
+        /// Setup blocks generated by the recorded run
+        setup: Setup,
+        /// If `false`, the node's tip must be the block that the recorded
+        /// blocks build on
+        offline: bool,
+    },
+}
+
+/// Funding for the node's wallet, paid by setup blocks
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct WalletFunding {
+    /// Address from the node's wallet
+    address: String,
+    /// Minimum amount to pay to the address
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    amount: Amount,
+}
+
+/// Funding paid to the node's wallet by generated setup blocks
+#[derive(Clone, Debug)]
+struct FundedWallet {
+    address: String,
+    /// Total amount paid to the address, which is at least the requested
+    /// amount
+    amount: Amount,
+    /// Number of setup blocks paying to the address
+    n_blocks: usize,
+}
+
+/// Fetch a new address from the node's wallet, to fund with setup blocks
+async fn fetch_funding_address(
+    rpc: &RpcOpts,
+    chain_params: &ChainParams,
+) -> anyhow::Result<String> {
+    let wallet = rpc.rpc_wallet.as_deref();
+    let rpc_url = rpc.endpoint(wallet);
+    let client = rpc_client(rpc, wallet)?;
+    let address: String = client
+        .request("getnewaddress", ArrayParams::new())
+        .await
+        .map_err(|err| RpcError::new(&rpc_url, "getnewaddress", err))?;
+    // Checks that the address is valid for the chain
+    let _: ScriptBuf =
+        chain_params.script_pubkey(&address).with_context(|| {
+            format!("invalid address `{address}` from `getnewaddress`")
+        })?;
+    Ok(address)
+}
+
+/// Amount in BTC, as returned by wallet RPCs
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct BtcAmount(#[serde(with = "bitcoin::amount::serde::as_btc")] Amount);
+
+/// Check that the node's wallet received the funding paid by setup blocks,
+/// once they are submitted and the funds are mature, and print the wallet's
+/// balance
+async fn check_wallet_funding(
+    rpc: &RpcOpts,
+    address: &str,
+    amount: Amount,
+) -> anyhow::Result<()> {
+    let wallet = rpc.rpc_wallet.as_deref();
+    let rpc_url = rpc.endpoint(wallet);
+    let client = rpc_client(rpc, wallet)?;
+    let mut params = ArrayParams::new();
+    let () = params.insert(address)?;
+    let BtcAmount(received) = client
+        .request("getreceivedbyaddress", params)
+        .await
+        .map_err(|err| RpcError::new(&rpc_url, "getreceivedbyaddress", err))?;
+    if received < amount {
+        anyhow::bail!(
+            "the node's wallet received {received} at `{address}`, rather \
+             than {amount}: check that the setup blocks were accepted"
+        );
+    }
+    let BtcAmount(balance) = client
+        .request("getbalance", ArrayParams::new())
+        .await
+        .map_err(|err| RpcError::new(&rpc_url, "getbalance", err))?;
+    eprintln!(
+        "Funded the node's wallet with {received} at {address}. Wallet \
+         balance: {balance}"
+    );
+    Ok(())
+}
+
+/// Mine the plain setup block on the node with `generatetoaddress`, paying
+/// to the unlocked script, instead of generating it locally
+async fn mine_setup_block(
+    rpc: &RpcOpts,
+    chain_params: &ChainParams,
+) -> anyhow::Result<()> {
+    let rpc_url = rpc.endpoint(None);
+    let Some(address) = chain_params.address(&unlocked_script_pubkey()) else {
+        anyhow::bail!(
+            "`--setup-via-generatetoaddress` requires an address for setup \
+             block outputs, but none is available on this network"
+        );
     };
+    let client = rpc_client(rpc, None)?;
+    let mut params = ArrayParams::new();
+    let () = params.insert(1)?;
+    let () = params.insert(&address)?;
+    let block_hashes: Vec<BlockHash> = client
+        .request("generatetoaddress", params)
+        .await
+        .map_err(|err| RpcError::new(&rpc_url, "generatetoaddress", err))?;
+    tracing::info!(
+        ?block_hashes,
+        %address,
+        "Mined setup block with `generatetoaddress`"
+    );
+    Ok(())
+}
+
+/// A block generated during setup
+struct SetupBlock {
+    block: Block,
+    height: u32,
+    /// Enforcer state expected after submitting the block
+    enforcer_checks: Vec<EnforcerCheck>,
+}
+
+/// Generate a setup block that pays the block subsidy to `script_pubkey`,
+/// and advance the anchor past it
+fn gen_subsidy_block(
+    ctx: &mut BlockGenCtx,
+    anchor: &mut Anchor,
+    script_pubkey: ScriptBuf,
+) -> Result<SetupBlock, GenError> {
+    let subsidy = ctx.chain_params.block_subsidy(anchor.height);
+    let mut coinbase = CoinbaseBuilder::new(subsidy);
+    let () = coinbase.payout(script_pubkey, subsidy);
     let block = gen_block(
-        prev_blockhash,
-        target,
-        height,
-        vec![coinbase_txout],
+        ctx,
+        anchor.prev_blockhash,
+        anchor.target,
+        anchor.height,
+        coinbase,
         Vec::new(),
     )?;
-    prev_blockhash = block.block_hash();
-    height = block.bip34_block_height()? as u32;
-    target = block.header.target().to_compact_lossy();
-    blocks.push(block);
+    *anchor = Anchor::child_of(&block, anchor.height);
+    Ok(SetupBlock {
+        block,
+        height: anchor.height - 1,
+        enforcer_checks: Vec::new(),
+    })
+}
+
+/// Which setup blocks to generate
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Setup {
+    All,
+    /// The plain setup block was already mined by the node, and is not
+    /// generated
+    PlainBlockMined,
+    /// The setup blocks were submitted by a previous run, which is resumed
+    Done,
+}
+
+/// Description of the sidechain proposed by the M1 setup block: the first
+/// description in a block spec that requires the M1 message, or else the
+/// sidechain's
+fn m1_description(ctx: &BlockGenCtx, blocks_spec: &BlocksSpec) -> Vec<u8> {
+    blocks_spec
+        .0
+        .iter()
+        .filter(|block_spec| block_spec.requires_m1())
+        .find_map(|block_spec| block_spec.description.as_ref())
+        .map_or(ctx.sidechain.description.clone(), |description| {
+            description.as_bytes().to_vec()
+        })
+}
+
+/// Generate initial setup blocks that ensure proposals exist, fund the
+/// node's wallet, etc.
+fn gen_setup_blocks(
+    ctx: &mut BlockGenCtx,
+    mut anchor: Anchor,
+    blocks_spec: &BlocksSpec,
+    setup: Setup,
+) -> Result<(Vec<SetupBlock>, Option<FundedWallet>), GenError> {
+    let mut blocks = Vec::new();
+    if setup == Setup::Done {
+        return Ok((blocks, None));
+    }
+    if setup == Setup::All {
+        blocks.push(gen_subsidy_block(
+            ctx,
+            &mut anchor,
+            unlocked_script_pubkey(),
+        )?);
+    }
+    let funded_wallet = match ctx.wallet_funding.clone() {
+        Some(WalletFunding { address, amount }) => {
+            let script_pubkey = coinbase_script_pubkey(ctx, &address)?;
+            let mut funded = Amount::ZERO;
+            let mut n_blocks = 0;
+            while funded < amount {
+                let subsidy = ctx.chain_params.block_subsidy(anchor.height);
+                if subsidy == Amount::ZERO {
+                    return Err(GenError::ZeroSubsidy {
+                        amount,
+                        height: anchor.height,
+                    });
+                }
+                blocks.push(gen_subsidy_block(
+                    ctx,
+                    &mut anchor,
+                    script_pubkey.clone(),
+                )?);
+                funded += subsidy;
+                n_blocks += 1;
+            }
+            // Coinbase outputs can be spent once they have
+            // `COINBASE_MATURITY` blocks on top of them
+            for _ in 0..COINBASE_MATURITY {
+                blocks.push(gen_subsidy_block(
+                    ctx,
+                    &mut anchor,
+                    unlocked_script_pubkey(),
+                )?);
+            }
+            Some(FundedWallet {
+                address,
+                amount: funded,
+                n_blocks,
+            })
+        }
+        None => None,
+    };
     if blocks_spec.requires_m1() {
-        let value_txout = TxOut {
-            value: coinbase_value,
-            script_pubkey: addr.script_pubkey(),
-        };
-        let m1_txout =
-            m1_txout(DEMO_SIDECHAIN_SLOT, DEMO_SIDECHAIN_DESCRIPTION.to_vec());
-        let coinbase_txouts = vec![value_txout, m1_txout];
-        let block =
-            gen_block(prev_blockhash, target, height, coinbase_txouts, vec![])?;
-        blocks.push(block);
+        let subsidy = ctx.chain_params.block_subsidy(anchor.height);
+        let mut coinbase = CoinbaseBuilder::new(subsidy);
+        let () = coinbase.payout(unlocked_script_pubkey(), subsidy);
+        let description = m1_description(ctx, blocks_spec);
+        let enforcer_checks = ctx
+            .enforcer_state
+            .m1_accepted(ctx.sidechain.slot, &description);
+        let () = coinbase.m1(ctx.sidechain.slot, description);
+        let block = gen_block(
+            ctx,
+            anchor.prev_blockhash,
+            anchor.target,
+            anchor.height,
+            coinbase,
+            vec![],
+        )?;
+        blocks.push(SetupBlock {
+            block,
+            height: anchor.height,
+            enforcer_checks,
+        });
     }
-    Ok(blocks)
+    Ok((blocks, funded_wallet))
 }
 
-/// Generate a comment for the block generated by a block spec
-fn gen_comment(block_spec: &BlockSpec) -> String {
-    let mut comment = vec![format!(
+/// Generate a comment for the block generated by a block spec, paying to
+/// the specified address, if any
+fn gen_comment(block_spec: &BlockSpec, payout_address: Option<&str>) -> String {
+    let BlockSpec {
+        label,
+        description: _,
+        payout_address: _,
+        expect,
+        delay_ms: _,
+        fork_from: _,
+        post_actions: _,
+        duplicate_m2,
+    } = block_spec;
+    let header = format!(
         "Generate a block with {} invalid conditions:",
         block_spec.n_reasons_invalid()
-    )];
-    let BlockSpec { duplicate_m2 } = block_spec;
+    );
+    let mut comment = match label {
+        Some(label) => vec![format!("Block '{label}': {header}")],
+        None => vec![header],
+    };
     if *duplicate_m2 {
         comment.push("- 1 duplicate M2 message in coinbase outputs".to_owned());
     }
+    if let Some(payout_address) = payout_address {
+        comment.push(format!("Coinbase pays to {payout_address}"));
+    }
+    match expect {
+        Some(Verdict::Accept) => {
+            comment.push("Expected to be accepted by the node".to_owned())
+        }
+        Some(Verdict::Reject) => {
+            comment.push("Expected to be rejected by the node".to_owned())
+        }
+        Some(Verdict::Inconclusive) => comment.push(
+            "Expected to be stored by the node, but not connected".to_owned(),
+        ),
+        None => (),
+    }
     comment.join("\n")
 }
 
-/// Generate coinbase txouts and txs from a block spec.
-fn gen_txs(block_spec: &BlockSpec) -> (Vec<TxOut>, Vec<Transaction>) {
-    let mut coinbase_txouts = Vec::new();
-    let mut txs = Vec::new();
-    let BlockSpec { duplicate_m2 } = block_spec;
+/// Generate coinbase outputs and txs from a block spec, with the coinbase
+/// paying the block subsidy to `script_pubkey`.
+fn gen_txs(
+    ctx: &BlockGenCtx,
+    block_spec: &BlockSpec,
+    height: u32,
+    script_pubkey: ScriptBuf,
+) -> (CoinbaseBuilder, Vec<Transaction>) {
+    // No fees, since none of the txs pay any
+    let subsidy = ctx.chain_params.block_subsidy(height);
+    let mut coinbase = CoinbaseBuilder::new(subsidy);
+    let txs = Vec::new();
+    let BlockSpec {
+        label: _,
+        description: _,
+        payout_address: _,
+        expect: _,
+        delay_ms: _,
+        fork_from: _,
+        post_actions: _,
+        duplicate_m2,
+    } = block_spec;
+    let description = ctx.sidechain_description(block_spec);
     if *duplicate_m2 {
-        let m2_txout =
-            m2_txout(DEMO_SIDECHAIN_SLOT, DEMO_SIDECHAIN_DESCRIPTION);
-        coinbase_txouts.push(m2_txout.clone());
-        coinbase_txouts.push(m2_txout);
+        let () = coinbase.m2(ctx.sidechain.slot, description);
+        let () = coinbase.m2(ctx.sidechain.slot, description);
     }
-    (coinbase_txouts, txs)
+    let () = coinbase.payout(script_pubkey, subsidy);
+    (coinbase, txs)
 }
 
-async fn gen_script(
+/// A block generated from a block spec
+struct GeneratedBlock {
+    block: Block,
+    height: u32,
+    block_spec: BlockSpec,
+    /// Expected response from the node, given the branch that the block
+    /// extends
+    expected_verdict: Verdict,
+    /// `true` IFF the block is empty, and extends the node's chain without
+    /// forking, so that it needs no comment of its own
+    is_plain: bool,
+    comment: String,
+    /// Enforcer state expected after submitting the block
+    enforcer_checks: Vec<EnforcerCheck>,
+    /// Actions on the node after submitting the block
+    post_actions: Vec<GeneratedAction>,
+    /// Anchor for the next block from the spec, unless it forks, after the
+    /// block's actions
+    next_anchor: Anchor,
+}
+
+/// Action on the node after submitting a block, resolved to the block that
+/// it applies to
+struct GeneratedAction {
+    action: NodeAction,
+    block_hash: BlockHash,
+    /// Identifies the block that the action applies to in messages
+    description: String,
+    /// The node's expected tip after the action
+    expected_tip: BlockHash,
+    comment: String,
+    /// Enforcer state expected after the action
+    enforcer_checks: Vec<EnforcerCheck>,
+}
+
+/// A block that later blocks can fork from, with the state after it
+struct ForkPoint {
+    /// Anchor for a child of the block
+    anchor: Anchor,
+    enforcer_state: EnforcerState,
+    /// Identifies the block in comments
+    description: String,
+}
+
+/// All blocks generated in a run
+struct GeneratedBlocks {
+    setup_comment: String,
+    setup_blocks: Vec<SetupBlock>,
+    /// Funding paid to the node's wallet by setup blocks, if any
+    funded_wallet: Option<FundedWallet>,
+    blocks: Vec<GeneratedBlock>,
+}
+
+impl GeneratedBlocks {
+    /// Progress made by each block, setup blocks first, for the state file.
+    /// `None` for setup blocks other than the last, since a run can only be
+    /// resumed once all of its setup blocks are submitted.
+    fn checkpoints(&self) -> Vec<Option<Checkpoint>> {
+        let n_setup_blocks = self.setup_blocks.len();
+        let setup_checkpoints =
+            self.setup_blocks
+                .iter()
+                .enumerate()
+                .map(|(idx, setup_block)| {
+                    (idx + 1 == n_setup_blocks).then(|| Checkpoint {
+                        n_blocks: 0,
+                        anchor: Anchor::child_of(
+                            &setup_block.block,
+                            setup_block.height,
+                        ),
+                        spendable_coinbases: self
+                            .setup_blocks
+                            .iter()
+                            .flat_map(|setup_block| {
+                                spendable_coinbases(&setup_block.block)
+                            })
+                            .collect(),
+                    })
+                });
+        let checkpoints =
+            self.blocks
+                .iter()
+                .enumerate()
+                .map(|(idx, generated_block)| {
+                    let spendable_coinbases = match generated_block
+                        .expected_verdict
+                    {
+                        Verdict::Accept => {
+                            spendable_coinbases(&generated_block.block)
+                        }
+                        Verdict::Reject | Verdict::Inconclusive => Vec::new(),
+                    };
+                    Some(Checkpoint {
+                        n_blocks: idx + 1,
+                        anchor: generated_block.next_anchor,
+                        spendable_coinbases,
+                    })
+                });
+        setup_checkpoints.chain(checkpoints).collect()
+    }
+
+    /// Blocks to record, setup blocks first, with their metadata
+    fn recorded_blocks(
+        &self,
+    ) -> impl Iterator<Item = (&Block, RecordedBlockMeta)> + '_ {
+        let setup_blocks = self.setup_blocks.iter().map(|setup_block| {
+            let meta = RecordedBlockMeta {
+                hash: setup_block.block.block_hash(),
+                height: setup_block.height,
+                spec: None,
+                expect: Verdict::Accept,
+            };
+            (&setup_block.block, meta)
+        });
+        let blocks = self.blocks.iter().map(|generated_block| {
+            let meta = RecordedBlockMeta {
+                hash: generated_block.block.block_hash(),
+                height: generated_block.height,
+                spec: Some(generated_block.block_spec.clone()),
+                expect: generated_block.expected_verdict,
+            };
+            (&generated_block.block, meta)
+        });
+        setup_blocks.chain(blocks)
+    }
+}
+
+/// Coinbase outputs in the block that anyone can spend
+fn spendable_coinbases(block: &Block) -> Vec<OutPoint> {
+    let coinbase_tx = &block.txdata[0];
+    let txid = coinbase_tx.compute_txid();
+    coinbase_tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, txout)| txout.script_pubkey == unlocked_script_pubkey())
+        .map(|(vout, _)| OutPoint::new(txid, vout as u32))
+        .collect()
+}
+
+/// Check a generated block that is expected to be valid against the
+/// constraints from the node's block template, if any
+fn check_template_constraints(
+    ctx: &BlockGenCtx,
+    block: &Block,
+) -> Result<(), GenError> {
+    match &ctx.template_constraints {
+        Some(template_constraints) => template_constraints.check(block),
+        None => Ok(()),
+    }
+}
+
+/// Script pubkey for coinbase outputs paying to `address`
+fn coinbase_script_pubkey(
+    ctx: &BlockGenCtx,
+    address: &str,
+) -> Result<ScriptBuf, GenError> {
+    ctx.chain_params.script_pubkey(address).map_err(|err| {
+        GenError::InvalidAddress {
+            address: address.to_owned(),
+            source: err.into(),
+        }
+    })
+}
+
+/// Generate setup blocks and blocks from the spec.
+/// If the spec forks, the blocks are first generated without mining them,
+/// to find which branches are independent, so that the branches can be
+/// mined in parallel. The generated blocks are the same either way.
+fn gen_blocks(
+    ctx: &mut BlockGenCtx,
+    anchor: Anchor,
+    blocks_spec: BlocksSpec,
+    setup: Setup,
+) -> Result<GeneratedBlocks, GenError> {
+    let n_threads = ctx.mining_threads.get();
+    let forks = blocks_spec
+        .0
+        .iter()
+        .any(|block_spec| block_spec.fork_from.is_some());
+    if matches!(ctx.mining, Mining::Inline) && n_threads > 1 && forks {
+        let mut record_ctx = ctx.clone();
+        record_ctx.mining = Mining::Record(Vec::new());
+        let _: GeneratedBlocks = gen_blocks_in_order(
+            &mut record_ctx,
+            anchor,
+            blocks_spec.clone(),
+            setup,
+        )?;
+        let Mining::Record(headers) = record_ctx.mining else {
+            unreachable!()
+        };
+        ctx.mining = Mining::Premined(mine_in_parallel(headers, n_threads));
+    }
+    let res = gen_blocks_in_order(ctx, anchor, blocks_spec, setup);
+    let mining = std::mem::take(&mut ctx.mining);
+    if let (Ok(_), Mining::Replay(remaining)) = (&res, mining) {
+        if !remaining.is_empty() {
+            return Err(GenError::SurplusRecordedBlocks {
+                n_blocks: remaining.len(),
+            });
+        }
+    }
+    res
+}
+
+/// Generate setup blocks and blocks from the spec, in order, mining each
+/// block as specified by `ctx.mining`
+fn gen_blocks_in_order(
+    ctx: &mut BlockGenCtx,
+    anchor: Anchor,
+    blocks_spec: BlocksSpec,
+    setup: Setup,
+) -> Result<GeneratedBlocks, GenError> {
+    let () = ctx.chain_params.check_signet_challenge()?;
+    // Blocks may fork from the anchor, before any setup blocks
+    let mut fork_points = HashMap::from([(
+        FORK_FROM_ANCHOR.to_owned(),
+        ForkPoint {
+            anchor,
+            enforcer_state: ctx.enforcer_state.clone(),
+            description: format!(
+                "the anchor block {} at height {}",
+                anchor.prev_blockhash,
+                anchor.height - 1
+            ),
+        },
+    )]);
+    let (setup_blocks, funded_wallet) =
+        gen_setup_blocks(ctx, anchor, &blocks_spec, setup)?;
+    let mut tree = BlockTree::new(anchor, ctx.enforcer_state.clone());
+    for setup_block in &setup_blocks {
+        let () = check_template_constraints(ctx, &setup_block.block)?;
+        tree.insert(
+            setup_block.block.header.prev_blockhash,
+            Anchor::child_of(&setup_block.block, setup_block.height),
+            ctx.enforcer_state.clone(),
+            true,
+        );
+    }
+    let mut next = tree.tip_anchor();
+    // Label of the block that the current branch forked from, if any
+    let mut branch: Option<String> = None;
+    let mut setup_comment = match ctx
+        .chain_params
+        .address(&unlocked_script_pubkey())
+    {
+        Some(address) => format!("Mine some setup blocks, paying to {address}"),
+        None => "Mine some setup blocks".to_owned(),
+    };
+    if let Some(FundedWallet {
+        address,
+        amount,
+        n_blocks,
+    }) = &funded_wallet
+    {
+        setup_comment.push_str(&format!(
+            "\nFund the node's wallet: {n_blocks} blocks pay {amount} to \
+             {address}, followed by {COINBASE_MATURITY} blocks so that the \
+             funds mature"
+        ));
+    }
+    let mut blocks = Vec::new();
+    for (index, block_spec) in blocks_spec.0.into_iter().enumerate() {
+        let mut fork_comment = Vec::new();
+        if let Some(fork_from) = &block_spec.fork_from {
+            let fork_point = fork_points.get(fork_from).ok_or_else(|| {
+                SpecError::UnknownForkFrom {
+                    index,
+                    fork_from: fork_from.clone(),
+                }
+            })?;
+            next = fork_point.anchor;
+            ctx.enforcer_state = fork_point.enforcer_state.clone();
+            fork_comment.push(format!(
+                "Fork point: builds on {}, starting a competing branch",
+                fork_point.description
+            ));
+            branch = Some(fork_from.clone());
+        } else if let Some(branch) = &branch {
+            fork_comment
+                .push(format!("Extends the branch forked from '{branch}'"));
+        }
+        let height = next.height;
+        let (best_tip_hash, best_height) = tree.tip();
+        let expected_verdict = match block_spec.expect {
+            Some(verdict) => verdict,
+            None if !tree.is_valid(next.prev_blockhash) => {
+                fork_comment.push(
+                    "Expected to be rejected, since it builds on an \
+                     invalidated block"
+                        .to_owned(),
+                );
+                Verdict::Reject
+            }
+            None => match block_spec.expected_verdict() {
+                Verdict::Accept if height <= best_height => {
+                    fork_comment.push(
+                        "Expected to be stored, but not connected, since its \
+                         branch is not longer than the node's chain"
+                            .to_owned(),
+                    );
+                    Verdict::Inconclusive
+                }
+                verdict => verdict,
+            },
+        };
+        if expected_verdict == Verdict::Accept
+            && next.prev_blockhash != best_tip_hash
+        {
+            fork_comment.push(format!(
+                "Expected to reorg the node onto this branch, replacing the \
+                 tip {best_tip_hash} at height {best_height}"
+            ));
+        }
+        let is_plain = block_spec.is_empty() && fork_comment.is_empty();
+        let payout_address = block_spec
+            .payout_address
+            .as_deref()
+            .or(ctx.coinbase_address.as_deref());
+        let comment = std::iter::once(gen_comment(&block_spec, payout_address))
+            .chain(fork_comment)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let script_pubkey = match payout_address {
+            Some(payout_address) => {
+                coinbase_script_pubkey(ctx, payout_address)?
+            }
+            None => unlocked_script_pubkey(),
+        };
+        let (coinbase, txs) = gen_txs(ctx, &block_spec, height, script_pubkey);
+        let block = gen_block(
+            ctx,
+            next.prev_blockhash,
+            next.target,
+            height,
+            coinbase,
+            txs,
+        )?;
+        // Blocks that are expected to be rejected may break constraints
+        if expected_verdict != Verdict::Reject {
+            let () = check_template_constraints(ctx, &block)?;
+        }
+        let parent = next.prev_blockhash;
+        next = Anchor::child_of(&block, height);
+        // Expected acks are derived from the coinbase outputs, rather than
+        // the block spec, so that they match what the enforcer parses
+        let m2 = block.txdata[0].output.iter().find_map(|txout| {
+            match Bip300Message::parse(txout) {
+                Some(Ok(Bip300Message::M2Ack {
+                    slot,
+                    description_hash,
+                })) => Some((slot, description_hash)),
+                _ => None,
+            }
+        });
+        let enforcer_checks = ctx.enforcer_state.block_submitted(
+            next.prev_blockhash,
+            expected_verdict,
+            m2,
+        );
+        if expected_verdict != Verdict::Reject {
+            tree.insert(
+                parent,
+                next,
+                ctx.enforcer_state.clone(),
+                expected_verdict == Verdict::Accept,
+            );
+        }
+        if let Some(label) = &block_spec.label {
+            fork_points.insert(
+                label.clone(),
+                ForkPoint {
+                    anchor: next,
+                    enforcer_state: ctx.enforcer_state.clone(),
+                    description: block_description(
+                        height,
+                        next.prev_blockhash,
+                        Some(&block_spec),
+                    ),
+                },
+            );
+        }
+        let mut post_actions = Vec::new();
+        for post_action in &block_spec.post_actions {
+            let (action, block_hash, description) = match post_action.action() {
+                (action, None) => (
+                    action,
+                    next.prev_blockhash,
+                    block_description(
+                        height,
+                        next.prev_blockhash,
+                        Some(&block_spec),
+                    ),
+                ),
+                (action, Some(label)) => {
+                    let fork_point =
+                        fork_points.get(label).ok_or_else(|| {
+                            SpecError::UnknownPostActionTarget {
+                                index,
+                                target: label.to_owned(),
+                            }
+                        })?;
+                    (
+                        action,
+                        fork_point.anchor.prev_blockhash,
+                        fork_point.description.clone(),
+                    )
+                }
+            };
+            if !tree.contains(&block_hash) {
+                return Err(GenError::ActionOnUnstoredBlock {
+                    index,
+                    method: action.method(),
+                    description,
+                });
+            }
+            let (prev_tip, _) = tree.tip();
+            match action {
+                NodeAction::Invalidate => tree.invalidate(block_hash),
+                NodeAction::Reconsider => tree.reconsider(block_hash),
+            }
+            let (expected_tip, tip_height) = tree.tip();
+            let mut comment =
+                vec![format!("Apply `{}` to {description}", action.method())];
+            let enforcer_checks = if expected_tip == prev_tip {
+                comment.push(format!(
+                    "Expected to leave the node's tip at {expected_tip}"
+                ));
+                Vec::new()
+            } else {
+                comment.push(format!(
+                    "Expected to move the node's tip to {expected_tip} at \
+                     height {tip_height}, and following blocks to build on it"
+                ));
+                next = tree.tip_anchor();
+                ctx.enforcer_state = tree.tip_enforcer_state().clone();
+                branch = None;
+                ctx.enforcer_state.checks()
+            };
+            post_actions.push(GeneratedAction {
+                action,
+                block_hash,
+                description,
+                expected_tip,
+                comment: comment.join("\n"),
+                enforcer_checks,
+            });
+        }
+        blocks.push(GeneratedBlock {
+            block,
+            height,
+            block_spec,
+            expected_verdict,
+            is_plain,
+            comment,
+            enforcer_checks,
+            post_actions,
+            next_anchor: next,
+        });
+    }
+    Ok(GeneratedBlocks {
+        setup_comment,
+        setup_blocks,
+        funded_wallet,
+        blocks,
+    })
+}
+
+/// Identifies a block in messages, by its label, if any, height, and hash
+fn block_description(
+    height: u32,
+    block_hash: BlockHash,
+    block_spec: Option<&BlockSpec>,
+) -> String {
+    match block_spec.and_then(|block_spec| block_spec.label.as_ref()) {
+        Some(label) => {
+            format!("block \"{label}\" at height {height} ({block_hash})")
+        }
+        None => format!("block at height {height} ({block_hash})"),
+    }
+}
+
+/// Subset of the response to the `getblockheader` RPC
+#[derive(Debug, Deserialize)]
+struct BlockHeaderInfo {
+    height: u32,
+    /// `None` for the genesis block
+    #[serde(rename = "previousblockhash")]
+    prev_blockhash: Option<BlockHash>,
+    /// Compact target, in hex
+    bits: String,
+}
+
+async fn fetch_block_header(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    block_hash: BlockHash,
+) -> Result<BlockHeaderInfo, RpcError> {
+    retry_rpc(rpc_retries, rpc_url, "getblockheader", |_| async move {
+        let mut params = ArrayParams::new();
+        let () = params.insert(block_hash)?;
+        client.request("getblockheader", params).await
+    })
+    .await
+    .map_err(|err| RpcError::new(rpc_url, "getblockheader", err))
+}
+
+/// RPC error code returned by the node for invalid parameters, eg. a height
+/// above its tip
+const RPC_INVALID_PARAMETER: i32 = -8;
+
+/// Hash of the block at the height in the node's active chain, or `None` if
+/// the chain is not that long
+async fn fetch_block_hash(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    height: u32,
+) -> Result<Option<BlockHash>, RpcError> {
+    let res = retry_rpc(rpc_retries, rpc_url, "getblockhash", |_| async move {
+        let mut params = ArrayParams::new();
+        let () = params.insert(height)?;
+        client.request("getblockhash", params).await
+    })
+    .await;
+    match res {
+        Ok(block_hash) => Ok(Some(block_hash)),
+        Err(jsonrpsee::core::client::Error::Call(err))
+            if err.code() == RPC_INVALID_PARAMETER =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(RpcError::new(rpc_url, "getblockhash", err)),
+    }
+}
+
+/// Setup blocks cached by a previous run for the requirements, if the node
+/// can build on them: they must still be in the node's active chain, and
+/// the node's wallet must have received any funding that they paid. Cached
+/// setup blocks that the node's chain reorged past are removed from the
+/// cache.
+async fn find_cached_setup(
+    rpc: &RpcOpts,
+    setup_cache: &mut SetupCache,
     network: bitcoin::Network,
-    rpc_addr: SocketAddr,
-    rpc_auth: RpcAuth,
+    requirements: &SetupRequirements,
+) -> anyhow::Result<Option<CachedSetup>> {
+    let Some(cached_setup) = setup_cache.get(network, requirements).cloned()
+    else {
+        return Ok(None);
+    };
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(rpc, None)?;
+    for CachedBlock { hash, height } in &cached_setup.blocks {
+        let active_hash =
+            fetch_block_hash(&client, &rpc_url, rpc.rpc_retries, *height)
+                .await?;
+        if active_hash != Some(*hash) {
+            eprintln!(
+                "Cached setup block {hash} at height {height} is no longer in \
+                 the node's active chain, so setup blocks will be generated \
+                 again"
+            );
+            let () = setup_cache.remove(network, requirements);
+            let () = setup_cache.save()?;
+            return Ok(None);
+        }
+    }
+    if let (Some(address), Some(amount)) =
+        (&cached_setup.funded_address, requirements.wallet_funding)
+    {
+        let () = check_wallet_funding(rpc, address, amount).await.context(
+            "failed to check the funding paid by cached setup blocks",
+        )?;
+    }
+    Ok(Some(cached_setup))
+}
+
+/// Generate blocks anchored on the node's current block template, or on the
+/// specified anchor in offline mode. The node's tip is also returned, if the
+/// anchor was fetched from the node.
+/// If the run is resumed from a state file, blocks are instead anchored on
+/// the block that the previous run stopped at, which must be the node's tip.
+/// If a recording is replayed, blocks are anchored as they were recorded,
+/// and the node's tip, unless in offline mode, must be the recorded anchor.
+/// With `--record`, the generated blocks are recorded.
+async fn gen_anchored_blocks(
+    ctx: &mut BlockGenCtx,
+    anchor_source: AnchorSource,
+    rpc: &RpcOpts,
     blocks_spec: BlocksSpec,
-) -> anyhow::Result<()> {
-    let mut posix_script_builder =
-        OutputPosixScriptBuilder::new(rpc_addr, rpc_auth.clone());
-    let setup_blocks =
-        gen_setup_blocks(network, rpc_addr, rpc_auth, &blocks_spec).await?;
-    let mut height = setup_blocks.last().unwrap().bip34_block_height()? as u32;
-    let mut prev_blockhash = setup_blocks.last().unwrap().block_hash();
-    let mut target = setup_blocks
-        .last()
-        .unwrap()
-        .header
-        .target()
-        .to_compact_lossy();
-    posix_script_builder.comment("Mine some setup blocks");
-    for block in setup_blocks {
-        posix_script_builder.submitblock(&block);
-    }
-    for block_spec in blocks_spec.0.into_iter() {
-        let comment = gen_comment(&block_spec);
-        posix_script_builder.comment(comment);
-        let (mut coinbase_txouts, txs) = gen_txs(&block_spec);
-        let addr = Address::p2wsh(&unlocked_script(), network);
-        let coinbase_value_txout = TxOut {
-            value: block_subsidy(network, height),
-            script_pubkey: addr.script_pubkey(),
+) -> anyhow::Result<(GeneratedBlocks, Option<NodeTip>)> {
+    let resumed_anchor = ctx
+        .state_file
+        .as_ref()
+        .and_then(StateFile::resumed)
+        .map(|resumed| resumed.anchor());
+    // Requirements that the generated setup blocks are cached for, if any
+    let mut cached_requirements = None;
+    let (anchor, setup, node_tip) = match (anchor_source, resumed_anchor) {
+        (
+            AnchorSource::Recorded {
+                anchor,
+                setup,
+                offline: true,
+            },
+            _,
+        ) => (anchor, setup, None),
+        (
+            AnchorSource::Recorded {
+                anchor: recorded_anchor,
+                setup,
+                offline: false,
+            },
+            _,
+        ) => {
+            let (anchor, template_constraints) = fetch_anchor(rpc).await?;
+            if anchor.prev_blockhash != recorded_anchor.prev_blockhash {
+                anyhow::bail!(
+                    "cannot replay the recording: the node's tip is {} at \
+                     height {}, but the recorded blocks build on {} at height \
+                     {}",
+                    anchor.prev_blockhash,
+                    anchor.height - 1,
+                    recorded_anchor.prev_blockhash,
+                    recorded_anchor.height - 1
+                );
+            }
+            ctx.template_constraints = template_constraints;
+            let node_tip = NodeTip {
+                hash: anchor.prev_blockhash,
+                height: anchor.height - 1,
+            };
+            (recorded_anchor, setup, Some(node_tip))
+        }
+        (AnchorSource::Offline(_), Some(resumed_anchor)) => {
+            (resumed_anchor, Setup::Done, None)
+        }
+        (AnchorSource::Offline(anchor), None) => (anchor, Setup::All, None),
+        (AnchorSource::Node { .. }, Some(resumed_anchor)) => {
+            let (anchor, template_constraints) = fetch_anchor(rpc).await?;
+            if anchor.prev_blockhash != resumed_anchor.prev_blockhash {
+                anyhow::bail!(
+                    "cannot resume from the state file: the node's tip is {} \
+                     at height {}, rather than {} at height {}, where the \
+                     previous run stopped",
+                    anchor.prev_blockhash,
+                    anchor.height - 1,
+                    resumed_anchor.prev_blockhash,
+                    resumed_anchor.height - 1
+                );
+            }
+            ctx.template_constraints = template_constraints;
+            let node_tip = NodeTip {
+                hash: anchor.prev_blockhash,
+                height: anchor.height - 1,
+            };
+            (anchor, Setup::Done, Some(node_tip))
+        }
+        (
+            AnchorSource::Node {
+                setup_via_generatetoaddress,
+                fund_node_wallet,
+            },
+            None,
+        ) => {
+            let requirements = SetupRequirements {
+                m1: blocks_spec.requires_m1().then(|| {
+                    let description = m1_description(ctx, &blocks_spec);
+                    (ctx.sidechain.slot, sha256d::Hash::hash(&description))
+                }),
+                wallet_funding: fund_node_wallet,
+            };
+            let network = ctx.chain_params.network;
+            let cached_setup = match &mut ctx.setup_cache {
+                Some(setup_cache) => {
+                    find_cached_setup(rpc, setup_cache, network, &requirements)
+                        .await?
+                }
+                None => None,
+            };
+            if let Some(cached_setup) = cached_setup {
+                let (anchor, template_constraints) = fetch_anchor(rpc).await?;
+                ctx.template_constraints = template_constraints;
+                let last_block = cached_setup
+                    .blocks
+                    .last()
+                    .map_or(cached_setup.prev_blockhash, |cached_block| {
+                        cached_block.hash
+                    });
+                eprintln!(
+                    "Reusing {} cached setup blocks, ending with {last_block}",
+                    cached_setup.blocks.len()
+                );
+                let node_tip = NodeTip {
+                    hash: anchor.prev_blockhash,
+                    height: anchor.height - 1,
+                };
+                (anchor, Setup::Done, Some(node_tip))
+            } else {
+                if ctx.setup_cache.is_some() {
+                    cached_requirements = Some(requirements);
+                }
+                if let Some(amount) = fund_node_wallet {
+                    let address =
+                        fetch_funding_address(rpc, &ctx.chain_params).await?;
+                    ctx.wallet_funding =
+                        Some(WalletFunding { address, amount });
+                }
+                if setup_via_generatetoaddress {
+                    let () = mine_setup_block(rpc, &ctx.chain_params).await?;
+                }
+                let (anchor, template_constraints) = fetch_anchor(rpc).await?;
+                ctx.template_constraints = template_constraints;
+                let node_tip = NodeTip {
+                    hash: anchor.prev_blockhash,
+                    height: anchor.height - 1,
+                };
+                let setup = if setup_via_generatetoaddress {
+                    Setup::PlainBlockMined
+                } else {
+                    Setup::All
+                };
+                (anchor, setup, Some(node_tip))
+            }
+        }
+    };
+    let generated_blocks = gen_blocks(ctx, anchor, blocks_spec, setup)?;
+    if let (Some(setup_cache), Some(requirements), Some(first_setup_block)) = (
+        &mut ctx.setup_cache,
+        cached_requirements,
+        generated_blocks.setup_blocks.first(),
+    ) {
+        setup_cache.set_pending(CachedSetup {
+            network: ctx.chain_params.network,
+            prev_blockhash: first_setup_block.block.header.prev_blockhash,
+            requirements,
+            funded_address: ctx
+                .wallet_funding
+                .as_ref()
+                .map(|wallet_funding| wallet_funding.address.clone()),
+            blocks: generated_blocks
+                .setup_blocks
+                .iter()
+                .map(|setup_block| CachedBlock {
+                    hash: setup_block.block.block_hash(),
+                    height: setup_block.height,
+                })
+                .collect(),
+        });
+    }
+    if let Some(record_dir) = &ctx.record_dir {
+        let manifest = RecordingManifest {
+            version: recording::RECORDING_VERSION,
+            network: ctx.chain_params.network,
+            seed: ctx.seed,
+            height: anchor.height,
+            prev_blockhash: anchor.prev_blockhash,
+            target: anchor.target,
+            setup,
+            wallet_funding: ctx.wallet_funding.clone(),
+            coinbase_address: ctx.coinbase_address.clone(),
+            sidechain_slot: ctx.sidechain.slot,
+            sidechain_description: SidechainDescription::try_from(
+                ctx.sidechain.description.clone(),
+            )
+            .map_err(anyhow::Error::msg)?,
+            n_blocks: generated_blocks.setup_blocks.len()
+                + generated_blocks.blocks.len(),
         };
-        coinbase_txouts.push(coinbase_value_txout);
-        let block =
-            gen_block(prev_blockhash, target, height, coinbase_txouts, txs)?;
-        posix_script_builder.submitblock(&block);
-        height += 1;
-        prev_blockhash = block.block_hash();
-        target = block.header.target().to_compact_lossy();
-    }
-    println!("{}", posix_script_builder.finalize());
+        let () = recording::record(
+            record_dir,
+            &manifest,
+            generated_blocks.recorded_blocks(),
+        )?;
+    }
+    Ok((generated_blocks, node_tip))
+}
+
+/// Refuse `--network mainnet`, unless `--i-really-want-mainnet` was
+/// specified
+fn check_mainnet_network(
+    network: Network,
+    i_really_want_mainnet: bool,
+) -> anyhow::Result<()> {
+    if let (Network::Mainnet, false) = (network, i_really_want_mainnet) {
+        anyhow::bail!(
+            "`--network mainnet` was specified, but generated blocks are \
+             meant for test networks. Pass `--i-really-want-mainnet` to \
+             proceed anyway."
+        );
+    }
+    Ok(())
+}
+
+/// Refuse to embed RPC credentials for a mainnet node in a generated
+/// script, unless `--embed-mainnet-credentials` was specified
+fn check_mainnet_credentials(
+    mainnet: bool,
+    mode: &Mode,
+    format: OutputFormat,
+    rpc: &RpcOpts,
+) -> anyhow::Result<()> {
+    if mainnet
+        && matches!(mode, Mode::Generate)
+        && !matches!(format, OutputFormat::Json)
+        && rpc.embeds_credentials()
+        && !rpc.rpc_auth.embed_mainnet_credentials
+    {
+        anyhow::bail!(
+            "refusing to embed RPC credentials for a mainnet node in the \
+             generated script. Pass `--rpc-auth-env` or `--rpc-cookie` to \
+             read them at run time, or `--embed-mainnet-credentials` to embed \
+             them anyway."
+        );
+    }
+    Ok(())
+}
+
+/// Initialize logging to stderr. `RUST_LOG`, if set, overrides the verbosity
+/// flag.
+fn init_tracing(verbose: u8) -> anyhow::Result<()> {
+    let level = match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let env_filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env()?;
+    tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_writer(std::io::stderr)
+        .init();
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-    gen_script(
-        cli.network.into(),
-        cli.rpc_addr,
-        cli.rpc_auth,
-        cli.blocks_spec,
-    )
-    .await
+    let matches = Cli::command().get_matches();
+    let Cli {
+        command,
+        args,
+        verbose,
+    } = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let () = init_tracing(verbose)?;
+    match command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+        Some(Command::Schema) => {
+            let schema = schemars::schema_for!(VersionedBlocksSpec);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            Ok(())
+        }
+        Some(Command::Report {
+            recording,
+            enforcer_addr,
+            json,
+        }) => report::report_failures(&recording, enforcer_addr, json).await,
+        Some(Command::Decode { block, json }) => {
+            decode::decode_block(&block, json)
+        }
+        Some(Command::Submit { args, submit_args }) => {
+            let matches = matches.subcommand_matches("submit").unwrap();
+            run(*args, matches, Mode::Submit(submit_args), None, None).await
+        }
+        Some(Command::Mine { args, mine_args }) => {
+            let matches = matches.subcommand_matches("mine").unwrap();
+            run(*args, matches, Mode::Mine(mine_args), None, None).await
+        }
+        Some(Command::Rollback { args, to }) => {
+            let matches = matches.subcommand_matches("rollback").unwrap();
+            run(*args, matches, Mode::Rollback(to), None, None).await
+        }
+        Some(Command::Replay {
+            args,
+            recording,
+            submit,
+            submit_args,
+        }) => {
+            let matches = matches.subcommand_matches("replay").unwrap();
+            let mode = if submit {
+                Mode::Submit(submit_args)
+            } else {
+                Mode::Generate
+            };
+            run(*args, matches, mode, Some(recording), None).await
+        }
+        Some(Command::Selftest {
+            args,
+            submit_args,
+            bitcoind_path,
+        }) => {
+            let matches = matches.subcommand_matches("selftest").unwrap();
+            let mode = Mode::Submit(submit_args);
+            run(*args, matches, mode, None, Some(bitcoind_path)).await
+        }
+        None => run(args, &matches, Mode::Generate, None, None).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Instant};
+
+    use bitcoin::{
+        block::Header, hashes::Hash as _, transaction, Amount, Block,
+        BlockHash, CompactTarget, OutPoint, Sequence, Transaction, TxIn, TxOut,
+        Txid, Witness,
+    };
+    use clap::Parser as _;
+    use proptest::prelude::*;
+    use serde_json::json;
+    use url::Url;
+
+    use super::{
+        check_chain, check_mainnet_credentials, check_mainnet_network,
+        check_node, fetch_block_hash, fetch_block_header,
+        fetch_blockchain_info, gen_block, gen_blocks_in_order, gen_txs,
+        mine_in_parallel, rpc_client, unlocked_script, unlocked_script_pubkey,
+        Anchor, BlockGenCtx, Clock, CoinbaseBuilder, GenError, Mining, Mode,
+        RpcError, Setup, Sidechain, SpecError, DEMO_SIDECHAIN_DESCRIPTION,
+        DEMO_SIDECHAIN_SLOT, RPC_RETRY_INITIAL_BACKOFF,
+        WITNESS_COMMITMENT_HEADER,
+    };
+    use crate::{
+        block_template::BlockTemplate,
+        chain_params::ChainParams,
+        cli::{
+            BlockSpec, BlocksSpec, Network, OutputFormat, RpcOpts,
+            FORK_FROM_ANCHOR,
+        },
+        decode,
+        errors::RPC_IN_WARMUP,
+        metrics::Metrics,
+        mock_rpc::MockRpcServer,
+    };
+
+    /// Easiest target that nodes accept, as on regtest
+    const EASY_TARGET: u32 = 0x207fffff;
+
+    /// Tx spending an arbitrary, non-null outpoint to up to 3 outputs
+    fn arb_tx() -> impl Strategy<Value = Transaction> {
+        (
+            any::<[u8; 32]>(),
+            0u32..1000,
+            proptest::collection::vec(1u64..1_000_000, 1..4),
+        )
+            .prop_map(|(txid, vout, values)| Transaction {
+                version: transaction::Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint {
+                        txid: Txid::from_byte_array(txid),
+                        vout,
+                    },
+                    script_sig: bitcoin::ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_slice(&[
+                        unlocked_script().into_bytes()
+                    ]),
+                }],
+                output: values
+                    .into_iter()
+                    .map(|value| TxOut {
+                        value: Amount::from_sat(value),
+                        script_pubkey: unlocked_script_pubkey(),
+                    })
+                    .collect(),
+            })
+    }
+
+    fn arb_network() -> impl Strategy<Value = bitcoin::Network> {
+        prop_oneof![
+            Just(bitcoin::Network::Regtest),
+            Just(bitcoin::Network::Signet),
+        ]
+    }
+
+    /// Chains reported by `getblockchaininfo`, and their networks
+    const CHAINS: [(&str, Network); 4] = [
+        ("main", Network::Mainnet),
+        ("test", Network::Testnet),
+        ("signet", Network::Signet),
+        ("regtest", Network::Regtest),
+    ];
+
+    #[test]
+    fn check_chain_matches_network() {
+        let rpc_url = "http://127.0.0.1:8332".parse().unwrap();
+        for (chain, network) in CHAINS {
+            let network = network.into();
+            assert_eq!(bitcoin::Network::to_core_arg(network), chain);
+            assert!(check_chain(&rpc_url, chain, network).is_ok(), "{chain}");
+        }
+    }
+
+    #[test]
+    fn check_chain_rejects_mismatch() {
+        let rpc_url = "http://127.0.0.1:8332".parse().unwrap();
+        for (chain, _) in CHAINS.iter().chain(&[("testnet3", Network::Testnet)])
+        {
+            for (expected_chain, network) in CHAINS {
+                if *chain == expected_chain {
+                    continue;
+                }
+                let err = check_chain(&rpc_url, chain, network.into())
+                    .unwrap_err()
+                    .to_string();
+                assert_eq!(
+                    err,
+                    format!(
+                        "node at `http://127.0.0.1:8332/` is on chain \
+                         `{chain}`, but `--network` specifies chain \
+                         `{expected_chain}`. Pass `--skip-network-check` to \
+                         ignore."
+                    )
+                );
+            }
+        }
+    }
+
+    fn rpc_opts(rpc_url: &Url, args: &[&str]) -> RpcOpts {
+        let rpc_url = rpc_url.as_str();
+        let args = ["bip300-enforcer-demo", "--rpc-url", rpc_url]
+            .into_iter()
+            .chain(args.iter().copied());
+        RpcOpts::try_parse_from(args).unwrap()
+    }
+
+    /// Node that reports `chain` from `getblockchaininfo`
+    async fn node_on_chain(chain: &'static str) -> MockRpcServer {
+        MockRpcServer::start(move |method, _| match method {
+            "getblockchaininfo" => Ok(json!({
+                "chain": chain,
+                "initialblockdownload": false,
+            })),
+            _ => Err((-32601, "Method not found".to_owned())),
+        })
+        .await
+    }
+
+    #[test]
+    fn mainnet_network_requires_override() {
+        let err = check_mainnet_network(Network::Mainnet, false).unwrap_err();
+        assert!(err.to_string().contains("--i-really-want-mainnet"), "{err}");
+        assert!(check_mainnet_network(Network::Mainnet, true).is_ok());
+        for network in [Network::Testnet, Network::Signet, Network::Regtest] {
+            assert!(check_mainnet_network(network, false).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn mainnet_node_requires_override() {
+        let node = node_on_chain("main").await;
+        let err = fetch_blockchain_info(&rpc_opts(&node.url(), &[]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is on mainnet"), "{err:#}");
+        // Refused before the network is checked
+        let err =
+            check_node(&rpc_opts(&node.url(), &[]), Network::Mainnet.into())
+                .await
+                .unwrap_err();
+        assert!(err.to_string().contains("is on mainnet"), "{err:#}");
+        let rpc = rpc_opts(&node.url(), &["--i-really-want-mainnet"]);
+        let blockchain_info = fetch_blockchain_info(&rpc).await.unwrap();
+        assert_eq!(blockchain_info.chain, "main");
+        let chain = check_node(&rpc, Network::Mainnet.into()).await.unwrap();
+        assert_eq!(chain, "main");
+        let err = check_node(&rpc, Network::Regtest.into()).await.unwrap_err();
+        assert!(err.to_string().contains("is on chain `main`"), "{err:#}");
+        assert_eq!(node.methods(), ["getblockchaininfo"; 5]);
+
+        let node = node_on_chain("regtest").await;
+        let rpc = rpc_opts(&node.url(), &[]);
+        let chain = check_node(&rpc, Network::Regtest.into()).await.unwrap();
+        assert_eq!(chain, "regtest");
+    }
+
+    #[test]
+    fn mainnet_credentials_require_override() {
+        let rpc_url: Url = "http://127.0.0.1:8332".parse().unwrap();
+        let embedded =
+            rpc_opts(&rpc_url, &["--rpc-user", "u", "--rpc-pass", "p"]);
+        let check = |mainnet, mode, format, rpc: &RpcOpts| {
+            check_mainnet_credentials(mainnet, &mode, format, rpc)
+        };
+        let err = check(true, Mode::Generate, OutputFormat::Script, &embedded)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("--embed-mainnet-credentials"),
+            "{err}"
+        );
+        assert!(check(
+            true,
+            Mode::Generate,
+            OutputFormat::PowerShell,
+            &embedded
+        )
+        .is_err());
+        let allowed = [
+            // Not mainnet
+            (
+                false,
+                Mode::Generate,
+                OutputFormat::Script,
+                embedded.clone(),
+            ),
+            // No credentials in the output
+            (true, Mode::Generate, OutputFormat::Json, embedded.clone()),
+            (
+                true,
+                Mode::Generate,
+                OutputFormat::Script,
+                rpc_opts(&rpc_url, &["--rpc-auth-env"]),
+            ),
+            (
+                true,
+                Mode::Generate,
+                OutputFormat::Script,
+                rpc_opts(&rpc_url, &[]),
+            ),
+            (
+                true,
+                Mode::Rollback(BlockHash::all_zeros()),
+                OutputFormat::Script,
+                embedded.clone(),
+            ),
+            (
+                true,
+                Mode::Generate,
+                OutputFormat::Script,
+                rpc_opts(
+                    &rpc_url,
+                    &[
+                        "--rpc-user",
+                        "u",
+                        "--i-really-want-mainnet",
+                        "--embed-mainnet-credentials",
+                    ],
+                ),
+            ),
+        ];
+        for (mainnet, mode, format, rpc) in allowed {
+            assert!(check(mainnet, mode, format, &rpc).is_ok(), "{rpc:?}");
+        }
+        // The override requires `--i-really-want-mainnet`
+        let args = [
+            "bip300-enforcer-demo",
+            "--rpc-user",
+            "u",
+            "--embed-mainnet-credentials",
+        ];
+        assert!(RpcOpts::try_parse_from(args).is_err());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn gen_block_invariants(
+            network in arb_network(),
+            height in 1u32..1_000_000,
+            prev_blockhash in any::<[u8; 32]>(),
+            txs in proptest::collection::vec(arb_tx(), 0..5),
+            duplicate_m2 in any::<bool>(),
+            seed in any::<u64>(),
+        ) {
+            let chain_params = ChainParams::new(network, None, None).unwrap();
+            let subsidy = chain_params.block_subsidy(height);
+            let mut ctx = BlockGenCtx::new(
+                chain_params,
+                Clock::Deterministic {
+                    next_time: 1_700_000_000,
+                },
+                None,
+                Sidechain {
+                    slot: DEMO_SIDECHAIN_SLOT,
+                    description: DEMO_SIDECHAIN_DESCRIPTION.to_vec(),
+                },
+                seed,
+            );
+            let block_spec = BlockSpec {
+                duplicate_m2,
+                ..BlockSpec::default()
+            };
+            let (coinbase, _) =
+                gen_txs(&ctx, &block_spec, height, unlocked_script_pubkey());
+            let block = gen_block(
+                &mut ctx,
+                BlockHash::from_byte_array(prev_blockhash),
+                CompactTarget::from_consensus(EASY_TARGET),
+                height,
+                coinbase,
+                txs.clone(),
+            )
+            .unwrap();
+            let target = block.header.target();
+            prop_assert!(block.header.validate_pow(target).is_ok());
+            prop_assert_eq!(
+                target,
+                CompactTarget::from_consensus(EASY_TARGET).into()
+            );
+            prop_assert_eq!(
+                Some(block.header.merkle_root),
+                block.compute_merkle_root()
+            );
+            prop_assert_eq!(decode::bip34_height(&block), Some(height.into()));
+            if height > 16 {
+                prop_assert_eq!(
+                    block.bip34_block_height(),
+                    Ok(u64::from(height))
+                );
+            }
+            prop_assert!(block.txdata[0].is_coinbase());
+            prop_assert!(block.txdata[1..].iter().all(|tx| !tx.is_coinbase()));
+            prop_assert_eq!(&block.txdata[1..], txs.as_slice());
+            // No fees, since the txs' inputs are unknown
+            let coinbase_value = block.txdata[0]
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .try_fold(Amount::ZERO, Amount::checked_add);
+            prop_assert!(coinbase_value.is_some_and(|value| value <= subsidy));
+            if network == bitcoin::Network::Signet {
+                prop_assert!(block.check_witness_commitment());
+                // The last commitment is the one that counts, so the
+                // commitment must be the last output
+                let last_txout = block.txdata[0].output.last().unwrap();
+                prop_assert_eq!(
+                    &last_txout.script_pubkey.as_bytes()[2..6],
+                    WITNESS_COMMITMENT_HEADER.as_slice()
+                );
+            }
+        }
+    }
+
+    /// Hash that is distinct for each `n`
+    fn block_hash(n: u8) -> BlockHash {
+        BlockHash::from_byte_array([n; 32])
+    }
+
+    /// Blocks spec with `n_scenarios` independent branches of `depth` blocks,
+    /// each forking from the anchor
+    fn matrix_spec(n_scenarios: usize, depth: usize) -> BlocksSpec {
+        let block_specs = (0..n_scenarios)
+            .flat_map(|_| {
+                let fork = BlockSpec {
+                    fork_from: Some(FORK_FROM_ANCHOR.to_owned()),
+                    ..BlockSpec::default()
+                };
+                std::iter::once(fork)
+                    .chain(std::iter::repeat_n(BlockSpec::default(), depth - 1))
+            })
+            .collect();
+        BlocksSpec(block_specs)
+    }
+
+    fn regtest_ctx(next_time: u32, mining: Mining) -> BlockGenCtx {
+        let chain_params =
+            ChainParams::new(bitcoin::Network::Regtest, None, None).unwrap();
+        let mut ctx = BlockGenCtx::new(
+            chain_params,
+            Clock::Deterministic { next_time },
+            None,
+            Sidechain {
+                slot: DEMO_SIDECHAIN_SLOT,
+                description: DEMO_SIDECHAIN_DESCRIPTION.to_vec(),
+            },
+            0,
+        );
+        ctx.mining = mining;
+        ctx
+    }
+
+    /// Generate the spec on regtest, returning the generated blocks
+    fn gen_matrix(
+        ctx: &mut BlockGenCtx,
+        blocks_spec: &BlocksSpec,
+        bits: u32,
+    ) -> Result<Vec<Block>, GenError> {
+        let anchor = Anchor {
+            height: 1,
+            prev_blockhash: block_hash(0),
+            target: CompactTarget::from_consensus(bits),
+        };
+        let generated_blocks =
+            gen_blocks_in_order(ctx, anchor, blocks_spec.clone(), Setup::Done)?;
+        Ok(generated_blocks
+            .blocks
+            .into_iter()
+            .map(|generated_block| generated_block.block)
+            .collect())
+    }
+
+    /// Record the spec's headers, and mine them on `n_threads` threads
+    fn premine_matrix(
+        next_time: u32,
+        blocks_spec: &BlocksSpec,
+        bits: u32,
+        n_threads: usize,
+    ) -> Mining {
+        let mut record_ctx = regtest_ctx(next_time, Mining::Record(Vec::new()));
+        let _: Vec<Block> =
+            gen_matrix(&mut record_ctx, blocks_spec, bits).unwrap();
+        let Mining::Record(headers) = record_ctx.mining else {
+            unreachable!()
+        };
+        Mining::Premined(mine_in_parallel(headers, n_threads))
+    }
+
+    /// Under `Clock::Now`, the second pass of `gen_blocks` takes other
+    /// timestamps than the first, which must not stop it from using the
+    /// premined headers
+    #[test]
+    fn premined_despite_clock_change() {
+        const RECORD_TIME: u32 = 1_700_000_000;
+        let blocks_spec = matrix_spec(8, 3);
+        let premined =
+            premine_matrix(RECORD_TIME, &blocks_spec, EASY_TARGET, 4);
+        let Mining::Premined(premined_headers) = &premined else {
+            unreachable!()
+        };
+        assert_eq!(premined_headers.len(), 24);
+        let mined_headers: HashSet<Header> = premined_headers
+            .values()
+            .map(|mined_header| mined_header.header)
+            .collect();
+        let mut ctx = regtest_ctx(RECORD_TIME + 600, premined);
+        let blocks = gen_matrix(&mut ctx, &blocks_spec, EASY_TARGET).unwrap();
+        assert_eq!(blocks.len(), 24);
+        for block in &blocks {
+            assert!(mined_headers.contains(&block.header), "{block:?}");
+        }
+        // Same as mining in order, at the timestamps of the first pass
+        let mut inline_ctx = regtest_ctx(RECORD_TIME, Mining::Inline);
+        let inline_blocks =
+            gen_matrix(&mut inline_ctx, &blocks_spec, EASY_TARGET).unwrap();
+        assert_eq!(blocks, inline_blocks);
+    }
+
+    /// Nonces tried until the header's hash meets its target, counting up
+    /// from a nonce of 0, independently of `mine_header`
+    fn count_nonce_attempts(mut header: Header) -> u64 {
+        let target = header.target();
+        header.nonce = 0;
+        let mut attempts = 1;
+        while header.validate_pow(target).is_err() {
+            header.nonce += 1;
+            attempts += 1;
+        }
+        attempts
+    }
+
+    /// Hashes tried for each block, as reported in the metrics
+    fn reported_hashes(blocks: &[Block]) -> Vec<u64> {
+        let metrics = serde_json::to_value(Metrics::get()).unwrap();
+        let reported = metrics["blocks"].as_array().unwrap();
+        blocks
+            .iter()
+            .map(|block| {
+                let hash = block.block_hash().to_string();
+                reported
+                    .iter()
+                    .find(|block_metrics| block_metrics["hash"] == hash)
+                    .and_then(|block_metrics| block_metrics["hashes"].as_u64())
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// The hashes tried that are reported for each block are the nonces
+    /// actually tried, whether the block is mined inline or in parallel
+    #[test]
+    fn reported_hashes_are_nonce_attempts() {
+        const TIME: u32 = 1_700_000_000;
+        let blocks_spec = matrix_spec(4, 4);
+        let mut inline_ctx = regtest_ctx(TIME, Mining::Inline);
+        let inline_blocks =
+            gen_matrix(&mut inline_ctx, &blocks_spec, EASY_TARGET).unwrap();
+        let expected: Vec<u64> = inline_blocks
+            .iter()
+            .map(|block| count_nonce_attempts(block.header))
+            .collect();
+        // Some blocks are only found after several attempts, so that nonces
+        // past the first are checked
+        assert!(
+            expected.iter().any(|attempts| *attempts > 1),
+            "{expected:?}"
+        );
+        assert_eq!(reported_hashes(&inline_blocks), expected);
+        let premined = premine_matrix(TIME, &blocks_spec, EASY_TARGET, 2);
+        let Mining::Premined(premined_headers) = &premined else {
+            unreachable!()
+        };
+        for mined_header in premined_headers.values() {
+            assert_eq!(
+                mined_header.nonce_attempts,
+                count_nonce_attempts(mined_header.header)
+            );
+        }
+        let mut ctx = regtest_ctx(TIME, premined);
+        let blocks = gen_matrix(&mut ctx, &blocks_spec, EASY_TARGET).unwrap();
+        assert_eq!(blocks, inline_blocks);
+        assert_eq!(reported_hashes(&blocks), expected);
+    }
+
+    /// Error from `getblockheader` on a node that fails it with `code`,
+    /// retrying twice, and the number of requests made
+    async fn block_header_error(code: i32) -> (RpcError, usize) {
+        let node = MockRpcServer::start(move |_, _| {
+            Err((code, format!("error {code}")))
+        })
+        .await;
+        let client = rpc_client(&rpc_opts(&node.url(), &[]), None).unwrap();
+        let err = fetch_block_header(&client, &node.url(), 2, block_hash(1))
+            .await
+            .unwrap_err();
+        (err, node.methods().len())
+    }
+
+    #[tokio::test]
+    async fn rpc_error_variants() {
+        let (err, n_requests) = block_header_error(-18).await;
+        assert!(matches!(err, RpcError::WalletNotFound { .. }), "{err:?}");
+        assert_eq!(n_requests, 1);
+        let (err, n_requests) = block_header_error(-10).await;
+        assert!(
+            matches!(err, RpcError::InitialBlockDownload { .. }),
+            "{err:?}"
+        );
+        assert_eq!(n_requests, 1);
+        // Not retryable
+        let (err, n_requests) = block_header_error(-32601).await;
+        match &err {
+            RpcError::Other { method, .. } => {
+                assert_eq!(method, "getblockheader")
+            }
+            err => panic!("expected RpcError::Other, got {err:?}"),
+        }
+        assert_eq!(n_requests, 1);
+        // Retried while the node is warming up
+        let (err, n_requests) = block_header_error(-28).await;
+        assert!(matches!(err, RpcError::Warmup { .. }), "{err:?}");
+        assert_eq!(n_requests, 3);
+    }
+
+    /// Node that fails the first `failures` requests while warming up, and
+    /// then returns the hash of block 1
+    async fn warming_up_node(failures: usize) -> MockRpcServer {
+        let requests = std::sync::atomic::AtomicUsize::new(0);
+        MockRpcServer::start(move |_, _| {
+            let n = requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if n < failures {
+                Err((RPC_IN_WARMUP, "Loading block index...".to_owned()))
+            } else {
+                Ok(json!(block_hash(1)))
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn rpc_retried_until_success() {
+        let node = warming_up_node(2).await;
+        let client = rpc_client(&rpc_opts(&node.url(), &[]), None).unwrap();
+        let start = Instant::now();
+        let res = fetch_block_hash(&client, &node.url(), 2, 1).await.unwrap();
+        assert_eq!(res, Some(block_hash(1)));
+        assert_eq!(node.methods(), ["getblockhash"; 3]);
+        // Backed off before each retry
+        assert!(
+            start.elapsed() >= RPC_RETRY_INITIAL_BACKOFF * 3,
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_retries_exhausted() {
+        let node = warming_up_node(3).await;
+        let client = rpc_client(&rpc_opts(&node.url(), &[]), None).unwrap();
+        let err = fetch_block_hash(&client, &node.url(), 2, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RpcError::Warmup { .. }), "{err:?}");
+        assert_eq!(node.methods(), ["getblockhash"; 3]);
+    }
+
+    #[test]
+    fn gen_error_variants() {
+        let mut ctx = regtest_ctx(1_700_000_000, Mining::Inline);
+        let target = CompactTarget::from_consensus(EASY_TARGET);
+        let mut coinbase = CoinbaseBuilder::new(Amount::from_sat(1));
+        let () = coinbase.payout(unlocked_script_pubkey(), Amount::from_sat(2));
+        let err =
+            gen_block(&mut ctx, block_hash(0), target, 1, coinbase, vec![])
+                .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                GenError::CoinbaseOverpays { total_value, max_value }
+                    if total_value == Amount::from_sat(2)
+                        && max_value == Amount::from_sat(1)
+            ),
+            "{err:?}"
+        );
+
+        let mut ctx =
+            regtest_ctx(1_700_000_000, Mining::Replay(Vec::new().into()));
+        let blocks_spec = BlocksSpec(vec![BlockSpec::default()]);
+        let err = gen_matrix(&mut ctx, &blocks_spec, EASY_TARGET).unwrap_err();
+        assert!(
+            matches!(err, GenError::MissingRecordedBlock { height: 1 }),
+            "{err:?}"
+        );
+
+        let mut ctx = regtest_ctx(1_700_000_000, Mining::Inline);
+        let blocks_spec = BlocksSpec(vec![BlockSpec {
+            fork_from: Some("missing".to_owned()),
+            ..BlockSpec::default()
+        }]);
+        let err = gen_matrix(&mut ctx, &blocks_spec, EASY_TARGET).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                GenError::Spec(SpecError::UnknownForkFrom {
+                    index: 0,
+                    fork_from,
+                }) if fork_from == "missing"
+            ),
+            "{err:?}"
+        );
+
+        let ctx = regtest_ctx(1_700_000_000, Mining::Inline);
+        let blocks_spec = BlocksSpec(vec![
+            BlockSpec {
+                label: Some("_first".to_owned()),
+                ..BlockSpec::default()
+            },
+            BlockSpec {
+                label: Some("double-ack".to_owned()),
+                ..BlockSpec::default()
+            },
+        ]);
+        let err = blocks_spec.validate(&ctx.chain_params).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                SpecError::InvalidLabel { index: 1, label }
+                    if label == "double-ack"
+            ),
+            "{err:?}"
+        );
+
+        let blocks_spec = BlocksSpec(vec![
+            BlockSpec {
+                label: Some("a".to_owned()),
+                ..BlockSpec::default()
+            },
+            BlockSpec::default(),
+            BlockSpec {
+                label: Some("a".to_owned()),
+                ..BlockSpec::default()
+            },
+        ]);
+        let err = blocks_spec.validate(&ctx.chain_params).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                SpecError::DuplicateLabel {
+                    index: 2,
+                    first_index: 0,
+                    label,
+                } if label == "a"
+            ),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "block 2: label `a` is already used by block 0: give each block \
+             a unique label"
+        );
+
+        let mut ctx = regtest_ctx(1_700_000_000, Mining::Inline);
+        let template: BlockTemplate = serde_json::from_value(json!({
+            "previousblockhash": block_hash(0),
+            "height": 1,
+            "bits": "207fffff",
+            "longpollid": "1",
+            "transactions": [],
+            "weightlimit": 100,
+        }))
+        .unwrap();
+        ctx.template_constraints = Some(template.constraints());
+        let blocks_spec = BlocksSpec(vec![BlockSpec::default()]);
+        let err = gen_matrix(&mut ctx, &blocks_spec, EASY_TARGET).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                GenError::ExceedsTemplateLimit {
+                    limit: "weight",
+                    max: 100,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+    }
 }
@@ -0,0 +1,353 @@
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use bitcoin::BlockHash;
+
+use crate::{
+    enforcer_checks::EnforcerCheck,
+    posix_script_builder::{quote, OutputPosixScriptBuilder, PosixScriptParts},
+    script_builder::{
+        render_items, BlockSubmission, Comment, FailureClass, HexFilesDir,
+        RenderOpts, RequestBody, RpcCall, ScriptBuilder, ScriptItem,
+        ScriptMeta, ScriptVar, SubmissionCounts,
+    },
+};
+
+/// Make variable containing the prelude of the POSIX shell script, which
+/// each recipe evaluates first
+const PRELUDE_VAR: &str = "SCRIPT_PRELUDE";
+
+/// Name of the target for setup blocks, and any steps before them
+const SETUP_TARGET: &str = "setup";
+
+/// Name of the target for the summary
+const SUMMARY_TARGET: &str = "summary";
+
+/// Escape `$` in text that is expanded by make
+fn escape_make(s: &str) -> String {
+    s.replace('$', "$$")
+}
+
+/// Render a block label as part of a target name, as lower-case ASCII
+/// letters and digits, separated by `-`
+fn target_slug(label: &str) -> String {
+    label
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Makefile target, submitting one scenario block, or the setup blocks
+#[derive(Debug)]
+struct Target {
+    name: String,
+    /// Index of the target's first item in the POSIX shell script
+    start: usize,
+}
+
+/// Renders a Makefile with a target for the setup blocks, and a target for
+/// each scenario block, which depends on the previous target. Recipes are
+/// rendered by the POSIX shell script builder, and run with `.ONESHELL`,
+/// so GNU make is required.
+#[derive(Debug)]
+pub struct MakefileBuilder {
+    inner: OutputPosixScriptBuilder,
+    /// Header comment. If not set, the Makefile is emitted without one.
+    preamble: Option<Comment>,
+    targets: Vec<Target>,
+    /// Number of items in the POSIX shell script after its last step, not
+    /// including comments that follow it. Comments before a block's step
+    /// belong to the block's target.
+    steps_end: usize,
+}
+
+impl MakefileBuilder {
+    pub fn new(inner: OutputPosixScriptBuilder) -> Self {
+        Self {
+            inner,
+            preamble: None,
+            targets: vec![Target {
+                name: SETUP_TARGET.to_owned(),
+                start: 0,
+            }],
+            steps_end: 0,
+        }
+    }
+
+    /// Record the end of a step in the POSIX shell script
+    fn step_end(&mut self) {
+        self.steps_end = self.inner.n_items();
+    }
+}
+
+impl ScriptBuilder for MakefileBuilder {
+    /// Set the Makefile's header comment. The POSIX shell script is
+    /// rendered without a preamble, since recipes are run with `set -eu`.
+    fn preamble(&mut self, meta: &ScriptMeta) {
+        self.preamble = Some(Comment(meta.to_string()));
+    }
+
+    fn comment<S>(&mut self, comment: S)
+    where
+        String: From<S>,
+    {
+        self.inner.comment(comment)
+    }
+
+    /// Each scenario block starts a new target, named after its position
+    /// and label, eg. `block-07-duplicate-m2`
+    fn block_comment(
+        &mut self,
+        comment: Option<&str>,
+        submission: &BlockSubmission,
+    ) {
+        if let Some(spec) = submission.spec {
+            let mut name = format!("block-{:02}", self.targets.len());
+            if let Some(slug) = spec
+                .label
+                .as_deref()
+                .map(target_slug)
+                .filter(|slug| !slug.is_empty())
+            {
+                name.push('-');
+                name.push_str(&slug);
+            }
+            let start = self.steps_end;
+            self.targets.push(Target { name, start });
+        }
+        self.inner.block_comment(comment, submission)
+    }
+
+    /// Relative directories are resolved relative to the Makefile, rather
+    /// than the current directory
+    fn hex_files_dir(&mut self, dir: &HexFilesDir) {
+        match dir {
+            HexFilesDir::ScriptRelative(dir) => self.inner.variable(
+                "BLOCKS_DIR",
+                format!("\"$MAKEFILE_DIR\"/{}", quote(&dir.to_string_lossy())),
+            ),
+            HexFilesDir::Absolute(_) => self.inner.hex_files_dir(dir),
+        }
+    }
+
+    fn enforcer_addr(&mut self, addr: SocketAddr) {
+        self.inner.enforcer_addr(addr)
+    }
+
+    /// Declared in the prelude that each recipe evaluates, so that any
+    /// target can reference them
+    fn block_hash_var(&mut self, var: &ScriptVar, block_hash: BlockHash) {
+        self.inner.block_hash_var(var, block_hash)
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        let () = self.inner.sleep(duration);
+        self.step_end()
+    }
+
+    fn skip_if_known(&mut self, submission: &BlockSubmission) {
+        let () = self.inner.skip_if_known(submission);
+        self.step_end()
+    }
+
+    fn submitblock(&mut self, submission: &BlockSubmission) {
+        let () = self.inner.submitblock(submission);
+        self.step_end()
+    }
+
+    fn submitblock_checked(&mut self, submission: &BlockSubmission) {
+        let () = self.inner.submitblock_checked(submission);
+        self.step_end()
+    }
+
+    fn check_tip(&mut self, submission: &BlockSubmission) {
+        let () = self.inner.check_tip(submission);
+        self.step_end()
+    }
+
+    fn command_capture(
+        &mut self,
+        var: &ScriptVar,
+        call: &RpcCall,
+        description: &str,
+    ) {
+        let () = self.inner.command_capture(var, call, description);
+        self.step_end()
+    }
+
+    fn check_captured(
+        &mut self,
+        var: &ScriptVar,
+        expected: &str,
+        description: &str,
+    ) {
+        let () = self.inner.check_captured(var, expected, description);
+        self.step_end()
+    }
+
+    fn check_enforcer(&mut self, check: &EnforcerCheck, description: &str) {
+        let () = self.inner.check_enforcer(check, description);
+        self.step_end()
+    }
+
+    /// The summary is its own target, which depends on the last block's
+    /// target
+    fn summary(&mut self, counts: &SubmissionCounts) {
+        self.targets.push(Target {
+            name: SUMMARY_TARGET.to_owned(),
+            start: self.steps_end,
+        });
+        let () = self.inner.summary(counts);
+        self.step_end()
+    }
+
+    /// make strips leading whitespace from each recipe line, including the
+    /// lines of heredoc bodies, so it is stripped from the bodies too
+    fn take_request_bodies(&mut self) -> Vec<RequestBody> {
+        self.inner
+            .take_request_bodies()
+            .into_iter()
+            .map(|RequestBody { method, body }| RequestBody {
+                method,
+                body: body
+                    .split('\n')
+                    .map(str::trim_start)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            })
+            .collect()
+    }
+
+    fn finalize(self) -> String {
+        let Self {
+            inner,
+            preamble,
+            targets,
+            steps_end: _,
+        } = self;
+        let PosixScriptParts {
+            prelude,
+            mut items,
+            render_opts,
+        } = inner.into_parts();
+        let blank_line = render_opts.blank_line();
+        let mut res = match preamble {
+            Some(comment) => render_opts.comment_line(&Comment(format!(
+                "{}\n{}",
+                comment.0,
+                FailureClass::doc()
+            ))),
+            None => String::new(),
+        };
+        res.push_str(&render_opts.comment_line(&Comment(
+            "Run `make` to submit all blocks, or `make <target>` to submit the \
+             blocks up to\nand including a target. Set `NO_DEPS=1` to submit \
+             a single target's blocks,\neg. `make block-01 NO_DEPS=1`. \
+             Requires GNU make. If a recipe fails, make\nreports its exit \
+             code, eg. `Error 20`, and exits with status 2."
+                .to_owned(),
+        )));
+        res.push_str(blank_line);
+        res.push_str(&format!(
+            "SHELL := /bin/sh\n\
+             .SHELLFLAGS := -eu -c\n\
+             .ONESHELL:\n\
+             .SILENT:\n\
+             {blank_line}\
+             export MAKEFILE_DIR := $(patsubst %/,%,$(dir $(abspath \
+             $(lastword $(MAKEFILE_LIST)))))\n{blank_line}"
+        ));
+        res.push_str(&format!(
+            "define {PRELUDE_VAR}\n{}\nendef\nexport {PRELUDE_VAR}\n\
+             {blank_line}",
+            escape_make(prelude.trim_end())
+        ));
+        // Targets are split off from the end, so that each split is at an
+        // index into the remaining items
+        let mut recipes = Vec::with_capacity(targets.len());
+        for target in targets.iter().rev() {
+            let target_items = items.split_off(target.start.min(items.len()));
+            recipes.push(target_items);
+        }
+        recipes.reverse();
+        let last_target = &targets[targets.len() - 1].name;
+        res.push_str(&format!(".PHONY: all\nall: {last_target}\n"));
+        let mut prev_target: Option<&str> = None;
+        for (target, target_items) in targets.iter().zip(recipes) {
+            let deps = match prev_target {
+                Some(prev_target) => {
+                    format!(" $(if $(NO_DEPS),,{prev_target})")
+                }
+                None => String::new(),
+            };
+            res.push_str(&format!(
+                "{blank_line}.PHONY: {name}\n{name}:{deps}\n",
+                name = target.name
+            ));
+            res.push_str(&format!("\teval \"$${PRELUDE_VAR}\"\n"));
+            res.push_str(&render_recipe(target_items, render_opts));
+            prev_target = Some(&target.name);
+        }
+        res.trim_end().to_owned()
+    }
+}
+
+/// Render POSIX shell script items as recipe lines. Blank lines are
+/// omitted, since make ignores them.
+fn render_recipe(
+    items: VecDeque<ScriptItem>,
+    render_opts: RenderOpts,
+) -> String {
+    render_items(items, render_opts)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| format!("\t{}\n", escape_make(line)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::{escape_make, render_recipe, target_slug};
+    use crate::script_builder::{Comment, RenderOpts, ScriptItem};
+
+    #[test]
+    fn escape() {
+        assert_eq!(escape_make("no dollars"), "no dollars");
+        assert_eq!(
+            escape_make("\"$RPC_URL\" $(id) $$"),
+            "\"$$RPC_URL\" $$(id) $$$$"
+        );
+    }
+
+    #[test]
+    fn recipe_lines() {
+        let items = VecDeque::from([
+            ScriptItem::Comment(Comment("Tip is $tip".to_owned())),
+            ScriptItem::Snippet("tip=$(rpc getbestblockhash)".to_owned()),
+            ScriptItem::Snippet(
+                "if [ \"$tip\" = x ]; then\n    exit 1\nfi".to_owned(),
+            ),
+        ]);
+        let recipe = render_recipe(items, RenderOpts::default());
+        assert_eq!(
+            recipe,
+            "\t# Tip is $$tip\n\
+             \ttip=$$(rpc getbestblockhash)\n\
+             \tif [ \"$$tip\" = x ]; then\n\
+             \t    exit 1\n\
+             \tfi\n"
+        );
+        // Blank lines between items are omitted
+        assert!(recipe.lines().all(|line| line.starts_with('\t')));
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(target_slug("double_ack"), "double-ack");
+        assert_eq!(target_slug("_Second__2_"), "second-2");
+        assert_eq!(target_slug("___"), "");
+    }
+}
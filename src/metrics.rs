@@ -0,0 +1,292 @@
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use bitcoin::{hex::DisplayHex as _, Block, BlockHash};
+use serde::{Serialize, Serializer};
+
+/// Version of the metrics JSON. Must be incremented whenever the metrics
+/// change in a way that is not backwards-compatible.
+pub const METRICS_VERSION: u32 = 1;
+
+/// Metrics accumulated over the run. Recorded globally, since blocks are
+/// mined, serialized, and submitted in many places.
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+fn serialize_secs<S>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Time spent on a generated block
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BlockMetrics {
+    pub hash: BlockHash,
+    pub height: u32,
+    /// Number of header hashes tried while mining the block
+    pub hashes: u64,
+    #[serde(rename = "mining_secs", serialize_with = "serialize_secs")]
+    pub mining: Duration,
+    /// Time spent serializing the block as hex, for scripts, hex files, and
+    /// `submitblock` requests
+    #[serde(rename = "serializing_secs", serialize_with = "serialize_secs")]
+    pub serializing: Duration,
+    /// Time spent waiting on the node to respond to `submitblock` requests
+    /// for the block, including retries
+    #[serde(rename = "rpc_secs", serialize_with = "serialize_secs")]
+    pub rpc: Duration,
+}
+
+impl BlockMetrics {
+    pub fn total(&self) -> Duration {
+        self.mining + self.serializing + self.rpc
+    }
+}
+
+/// Requests sent with an RPC method
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RpcMetrics {
+    /// Number of attempts, including retries
+    pub requests: u64,
+    /// Time spent waiting on responses
+    #[serde(rename = "secs", serialize_with = "serialize_secs")]
+    pub time: Duration,
+}
+
+/// Totals over the run
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MetricsSummary {
+    pub n_blocks: usize,
+    pub hashes: u64,
+    #[serde(rename = "mining_secs", serialize_with = "serialize_secs")]
+    pub mining: Duration,
+    #[serde(rename = "serializing_secs", serialize_with = "serialize_secs")]
+    pub serializing: Duration,
+    /// Requests to the node, for any method
+    pub rpc_requests: u64,
+    /// Time spent waiting on the node, for any method
+    #[serde(rename = "rpc_secs", serialize_with = "serialize_secs")]
+    pub rpc: Duration,
+    /// Block with the most time spent on it, if any
+    pub slowest_block: Option<BlockMetrics>,
+}
+
+/// Mining and RPC timings for a run
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    blocks: Vec<BlockMetrics>,
+    /// Index of each block in `blocks`
+    block_idxs: BTreeMap<BlockHash, usize>,
+    /// Requests by method
+    rpc: BTreeMap<String, RpcMetrics>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            block_idxs: BTreeMap::new(),
+            rpc: BTreeMap::new(),
+        }
+    }
+
+    /// Metrics recorded so far
+    pub fn get() -> Self {
+        METRICS.lock().unwrap().clone()
+    }
+
+    /// Metrics for a block, if it was mined in this run
+    fn block_mut(
+        &mut self,
+        block_hash: &BlockHash,
+    ) -> Option<&mut BlockMetrics> {
+        let idx = *self.block_idxs.get(block_hash)?;
+        Some(&mut self.blocks[idx])
+    }
+
+    pub fn summary(&self) -> MetricsSummary {
+        let rpc = self.rpc.values();
+        MetricsSummary {
+            n_blocks: self.blocks.len(),
+            hashes: self.blocks.iter().map(|block| block.hashes).sum(),
+            mining: self.blocks.iter().map(|block| block.mining).sum(),
+            serializing: self
+                .blocks
+                .iter()
+                .map(|block| block.serializing)
+                .sum(),
+            rpc_requests: rpc.clone().map(|rpc| rpc.requests).sum(),
+            rpc: rpc.map(|rpc| rpc.time).sum(),
+            slowest_block: self
+                .blocks
+                .iter()
+                .max_by_key(|block| block.total())
+                .copied(),
+        }
+    }
+
+    /// Render the summary as a plain text table, with per-block averages,
+    /// and a row for each RPC method
+    pub fn table(&self) -> String {
+        let MetricsSummary {
+            n_blocks,
+            hashes,
+            mining,
+            serializing,
+            rpc_requests,
+            rpc,
+            slowest_block,
+        } = self.summary();
+        let per_block = |total: Duration| match u32::try_from(n_blocks) {
+            Ok(n_blocks) if n_blocks > 0 => format!("{:?}", total / n_blocks),
+            Ok(_) | Err(_) => "-".to_owned(),
+        };
+        let block_rpc = self.blocks.iter().map(|block| block.rpc).sum();
+        let mut rows = vec![
+            [
+                "Hashes tried".to_owned(),
+                hashes.to_string(),
+                match n_blocks {
+                    0 => "-".to_owned(),
+                    _ => (hashes / n_blocks as u64).to_string(),
+                },
+            ],
+            [
+                "Mining".to_owned(),
+                format!("{mining:?}"),
+                per_block(mining),
+            ],
+            [
+                "Serializing".to_owned(),
+                format!("{serializing:?}"),
+                per_block(serializing),
+            ],
+            [
+                "Submitting".to_owned(),
+                format!("{block_rpc:?}"),
+                per_block(block_rpc),
+            ],
+        ];
+        for (method, RpcMetrics { requests, time }) in &self.rpc {
+            rows.push([
+                format!("RPC `{method}` ({requests} requests)"),
+                format!("{time:?}"),
+                "".to_owned(),
+            ]);
+        }
+        rows.push([
+            format!("RPC total ({rpc_requests} requests)"),
+            format!("{rpc:?}"),
+            "".to_owned(),
+        ]);
+        let header = ["Metric", "Total", "Per block"].map(str::to_owned);
+        let mut widths = header.clone().map(|cell| cell.len());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let mut table = String::new();
+        for row in std::iter::once(&header).chain(&rows) {
+            let line: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect();
+            table.push_str(line.join("  ").trim_end());
+            table.push('\n');
+        }
+        table.push_str(&format!("\n{n_blocks} blocks"));
+        if let Some(slowest_block) = slowest_block {
+            let BlockMetrics {
+                hash,
+                height,
+                hashes,
+                mining,
+                serializing,
+                rpc,
+            } = slowest_block;
+            table.push_str(&format!(
+                ", slowest {hash} at height {height}: {:?} ({hashes} hashes, \
+                 {mining:?} mining, {serializing:?} serializing, {rpc:?} \
+                 submitting)",
+                slowest_block.total()
+            ));
+        }
+        table.push('\n');
+        table
+    }
+}
+
+impl Serialize for Metrics {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        /// Raw metrics, as written by `--metrics-json`
+        #[derive(Serialize)]
+        struct MetricsJson<'a> {
+            version: u32,
+            summary: MetricsSummary,
+            blocks: &'a [BlockMetrics],
+            rpc: &'a BTreeMap<String, RpcMetrics>,
+        }
+        MetricsJson {
+            version: METRICS_VERSION,
+            summary: self.summary(),
+            blocks: &self.blocks,
+            rpc: &self.rpc,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Record a mined block, and the hashes tried and time taken to mine it
+pub fn record_mined(
+    height: u32,
+    block_hash: BlockHash,
+    hashes: u64,
+    mining: Duration,
+) {
+    let mut metrics = METRICS.lock().unwrap();
+    let idx = metrics.blocks.len();
+    metrics.blocks.push(BlockMetrics {
+        hash: block_hash,
+        height,
+        hashes,
+        mining,
+        serializing: Duration::ZERO,
+        rpc: Duration::ZERO,
+    });
+    metrics.block_idxs.insert(block_hash, idx);
+}
+
+/// Record an attempt at an RPC request, and the time spent waiting on it
+pub fn record_rpc(method: &str, time: Duration) {
+    let mut metrics = METRICS.lock().unwrap();
+    let rpc_metrics = metrics.rpc.entry(method.to_owned()).or_default();
+    rpc_metrics.requests += 1;
+    rpc_metrics.time += time;
+}
+
+/// Record the time spent submitting a block, including retries
+pub fn record_submitted(block_hash: &BlockHash, time: Duration) {
+    if let Some(block) = METRICS.lock().unwrap().block_mut(block_hash) {
+        block.rpc += time;
+    }
+}
+
+/// Serialize a block as hex, recording the time taken
+pub fn block_hex(block: &Block) -> String {
+    let start = std::time::Instant::now();
+    let hex = bitcoin::consensus::serialize(block).to_lower_hex_string();
+    let serializing = start.elapsed();
+    if let Some(block) = METRICS.lock().unwrap().block_mut(&block.block_hash())
+    {
+        block.serializing += serializing;
+    }
+    hex
+}
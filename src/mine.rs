@@ -0,0 +1,300 @@
+use std::time::Instant;
+
+use bip300301::jsonrpsee::{self, http_client::HttpClient};
+use url::Url;
+
+use crate::{
+    block_description,
+    block_template::{self, BlockTemplate},
+    cli::{BlocksSpec, GenerateArgs, MineArgs, RpcOpts, Verdict},
+    errors::RpcError,
+    gen_blocks, rpc_client,
+    script_builder::FailureClass,
+    submit::{submitblock, unexpected_outcome},
+    submit_block_result::SubmitBlockResult,
+    Anchor, AnchorSource, BlockGenCtx, Setup,
+};
+
+/// Request a block template, long polling if `longpollid` is specified.
+/// Long poll requests that time out before the template changes are retried.
+pub async fn fetch_long_poll_template(
+    client: &HttpClient,
+    rpc_url: &Url,
+    longpollid: Option<&str>,
+) -> Result<BlockTemplate, RpcError> {
+    loop {
+        match block_template::request(client, longpollid).await {
+            Ok(template) => return Ok(template),
+            Err(jsonrpsee::core::client::Error::RequestTimeout)
+                if longpollid.is_some() => {}
+            Err(err) => {
+                return Err(RpcError::new(rpc_url, "getblocktemplate", err))
+            }
+        }
+    }
+}
+
+/// Anchor for the next mined block, from a block template. Warns if the
+/// node's tip is not the tip that mining was tracking.
+fn track_template(
+    anchor: Option<Anchor>,
+    template: &BlockTemplate,
+) -> anyhow::Result<Anchor> {
+    let node_anchor = template.anchor()?;
+    if let Some(anchor) = anchor
+        .filter(|anchor| anchor.prev_blockhash != node_anchor.prev_blockhash)
+    {
+        tracing::warn!(
+            "The node's tip is {} at height {}, rather than {} at height {}. \
+             Mining from the node's tip.",
+            node_anchor.prev_blockhash,
+            node_anchor.height - 1,
+            anchor.prev_blockhash,
+            anchor.height - 1,
+        );
+    }
+    Ok(node_anchor)
+}
+
+/// Counts of blocks mined by [`mine_blocks`]
+#[derive(Debug, Default)]
+struct MineSummary {
+    n_blocks: u64,
+    n_accepted: u64,
+    n_rejected: u64,
+    /// Blocks from the blocks spec
+    n_injected: u64,
+    /// Blocks that were not accepted or rejected as expected
+    n_unexpected: u64,
+    /// Failed requests to the node
+    n_errors: u64,
+}
+
+/// Mine blocks continuously, one every `interval`, until interrupted with
+/// ctrl-c, and print a summary. Blocks are empty, except for every
+/// `inject_every`th block, which is the next block from the blocks spec. The
+/// block template is long polled between blocks, so that mining continues
+/// promptly from the node's new tip if it changes, eg. because another
+/// miner extended the chain, rather than from a stale parent.
+/// In offline mode, each block builds on the last accepted block instead.
+/// Returns the class of the first failure, if any.
+pub async fn mine_blocks(
+    mut ctx: BlockGenCtx,
+    anchor_source: AnchorSource,
+    rpc: RpcOpts,
+    blocks_spec: BlocksSpec,
+    mine_args: MineArgs,
+) -> anyhow::Result<Option<FailureClass>> {
+    let MineArgs {
+        interval,
+        inject_every,
+    } = mine_args;
+    // Spawned, so that ctrl-c is handled from now on, rather than from when
+    // the handler is first polled
+    let mut ctrl_c = tokio::spawn(tokio::signal::ctrl_c());
+    let mut anchor = match anchor_source {
+        AnchorSource::Offline(anchor) => Some(anchor),
+        AnchorSource::Node { .. } => None,
+        // Recordings are not replayed when mining
+        AnchorSource::Recorded { .. } => unreachable!(),
+    };
+    let fetch_template = anchor.is_none();
+    // Id to long poll the block template with, if the last template request
+    // succeeded
+    let mut longpollid: Option<String> = None;
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(&rpc, None)?;
+    let mut injected_specs = blocks_spec.0.into_iter().cycle();
+    let mut summary = MineSummary::default();
+    let mut first_failure = None;
+    let start = Instant::now();
+    // Rounds in which a block was generated, used to schedule injected
+    // blocks
+    let mut n_rounds = 0u64;
+    'mine: loop {
+        if ctrl_c.is_finished() {
+            break;
+        }
+        // While long polling, the anchor is already up to date
+        let synced = !fetch_template
+            || longpollid.is_some()
+            || match fetch_long_poll_template(&client, &rpc_url, None).await {
+                Ok(template) => {
+                    anchor = Some(track_template(anchor, &template)?);
+                    ctx.template_constraints = Some(template.constraints());
+                    longpollid = Some(template.longpollid);
+                    true
+                }
+                Err(err) => {
+                    summary.n_errors += 1;
+                    tracing::warn!(
+                        "Failed to fetch a block template, retrying in \
+                         {interval:?}: {err:#}"
+                    );
+                    false
+                }
+            };
+        if let (true, Some(current_anchor)) = (synced, anchor) {
+            n_rounds += 1;
+            let block_spec = inject_every
+                .filter(|inject_every| {
+                    n_rounds.is_multiple_of(inject_every.get())
+                })
+                .and_then(|_| injected_specs.next());
+            let injected = block_spec.is_some();
+            let generated_blocks = gen_blocks(
+                &mut ctx,
+                current_anchor,
+                BlocksSpec(vec![block_spec.unwrap_or_default()]),
+                Setup::All,
+            )?;
+            let setup_blocks =
+                generated_blocks.setup_blocks.iter().map(|setup_block| {
+                    (
+                        &setup_block.block,
+                        setup_block.height,
+                        None,
+                        Verdict::Accept,
+                    )
+                });
+            let blocks =
+                generated_blocks.blocks.iter().map(|generated_block| {
+                    (
+                        &generated_block.block,
+                        generated_block.height,
+                        Some(&generated_block.block_spec),
+                        generated_block.expected_verdict,
+                    )
+                });
+            for (block, height, block_spec, expected) in
+                setup_blocks.chain(blocks)
+            {
+                let block_hash = block.block_hash();
+                let description =
+                    block_description(height, block_hash, block_spec);
+                let res = match submitblock(&client, block).await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        summary.n_errors += 1;
+                        let err = RpcError::new(&rpc_url, "submitblock", err);
+                        tracing::warn!(
+                            "Failed to submit {description}, retrying in \
+                             {interval:?}: {err:#}"
+                        );
+                        break;
+                    }
+                };
+                summary.n_blocks += 1;
+                if injected && block_spec.is_some() {
+                    summary.n_injected += 1;
+                }
+                println!("[{}] {description}: {res}", summary.n_blocks);
+                match res {
+                    SubmitBlockResult::Accepted => {
+                        summary.n_accepted += 1;
+                        anchor = Some(Anchor::child_of(block, height));
+                    }
+                    SubmitBlockResult::Duplicate { .. }
+                    | SubmitBlockResult::Inconclusive
+                    | SubmitBlockResult::Rejected { .. } => {
+                        summary.n_rejected += 1
+                    }
+                }
+                if let Some(failure) =
+                    unexpected_outcome(expected, &description, &res)
+                {
+                    summary.n_unexpected += 1;
+                    first_failure.get_or_insert(failure);
+                }
+            }
+        }
+        let deadline = tokio::time::Instant::now() + interval;
+        // Long poll until the next block is due, following the node's tip
+        while let Some(current_longpollid) = longpollid.as_deref() {
+            let template = tokio::select! {
+                _ = &mut ctrl_c => break 'mine,
+                res = tokio::time::timeout_at(
+                    deadline,
+                    fetch_long_poll_template(
+                        &client,
+                        &rpc_url,
+                        Some(current_longpollid),
+                    ),
+                ) => match res {
+                    Ok(template) => template,
+                    Err(_elapsed) => break,
+                },
+            };
+            match template {
+                Ok(template) => {
+                    anchor = Some(track_template(anchor, &template)?);
+                    ctx.template_constraints = Some(template.constraints());
+                    longpollid = Some(template.longpollid);
+                }
+                Err(err) => {
+                    summary.n_errors += 1;
+                    tracing::warn!(
+                        "Failed to long poll the block template, fetching \
+                         it again before the next block: {err:#}"
+                    );
+                    longpollid = None;
+                }
+            }
+        }
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            () = tokio::time::sleep_until(deadline) => (),
+        }
+    }
+    let MineSummary {
+        n_blocks,
+        n_accepted,
+        n_rejected,
+        n_injected,
+        n_unexpected,
+        n_errors,
+    } = summary;
+    println!();
+    println!(
+        "Mined {n_blocks} blocks in {:.1?}: {n_accepted} accepted, \
+         {n_rejected} rejected, {n_injected} injected, {n_unexpected} \
+         unexpected, {n_errors} failed requests",
+        start.elapsed()
+    );
+    if let Some(anchor) = anchor {
+        println!(
+            "Tip: {} at height {}",
+            anchor.prev_blockhash,
+            anchor.height - 1
+        );
+    }
+    Ok(first_failure)
+}
+
+/// Check the args and blocks spec for `mine`
+pub fn check_mine_args(
+    args: &GenerateArgs,
+    mine_args: &MineArgs,
+    blocks_spec: &BlocksSpec,
+) -> anyhow::Result<()> {
+    if mine_args.inject_every.is_none() && !blocks_spec.0.is_empty() {
+        anyhow::bail!("`--inject-every` is required with a blocks spec");
+    }
+    if blocks_spec
+        .0
+        .iter()
+        .any(|block_spec| !block_spec.post_actions.is_empty())
+    {
+        anyhow::bail!("`post_actions` are not supported when mining");
+    }
+    if args.fund_node_wallet.is_some() {
+        anyhow::bail!("`--fund-node-wallet` is not supported when mining");
+    }
+    if args.state_file.is_some() {
+        anyhow::bail!("`--state-file` is not supported when mining");
+    }
+    if args.record.is_some() {
+        anyhow::bail!("`--record` is not supported when mining");
+    }
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! Servers for tests, standing in for a node's JSON-RPC endpoint and an
+//! enforcer's gRPC endpoint
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader,
+    },
+    net::{TcpListener, TcpStream},
+};
+use tonic::transport::{server::TcpIncoming, Server};
+use url::Url;
+
+use crate::proto::cusf::mainchain::v1::{
+    validator_service_server::{ValidatorService, ValidatorServiceServer},
+    GetBlockFailuresRequest, GetBlockFailuresResponse, GetChainTipRequest,
+    GetChainTipResponse, GetCtipRequest, GetCtipResponse,
+    GetSidechainProposalsRequest, GetSidechainProposalsResponse,
+    GetSidechainsRequest, GetSidechainsResponse,
+};
+
+/// Result of an RPC request, or its error code and message
+pub type MockResponse = Result<Value, (i32, String)>;
+
+/// JSON body to respond to a request with, given the request's JSON body, or
+/// `None` to never respond, eg. to a long poll request that never completes
+type Responder = dyn Fn(&Value) -> Option<Value> + Send + Sync;
+
+/// JSON-RPC server that responds to each request with the response that a
+/// handler returns for its method and params. Stops when dropped.
+pub struct MockRpcServer {
+    addr: SocketAddr,
+    /// Method of each request received, in order
+    methods: Arc<Mutex<Vec<String>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockRpcServer {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Value) -> MockResponse + Send + Sync + 'static,
+    {
+        Self::start_pending(move |method, params| Some(handler(method, params)))
+            .await
+    }
+
+    /// Like [`Self::start`], but requests that the handler returns `None` for
+    /// are never responded to
+    pub async fn start_pending<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Option<MockResponse> + Send + Sync + 'static,
+    {
+        let methods = Arc::new(Mutex::new(Vec::new()));
+        let responder: Arc<Responder> = Arc::new({
+            let methods = methods.clone();
+            move |request: &Value| match request {
+                Value::Array(requests) => requests
+                    .iter()
+                    .map(|request| respond(&handler, &methods, request))
+                    .collect::<Option<_>>()
+                    .map(Value::Array),
+                request => respond(&handler, &methods, request),
+            }
+        });
+        let (addr, task) = serve(responder).await;
+        Self {
+            addr,
+            methods,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        format!("http://{}/", self.addr).parse().unwrap()
+    }
+
+    /// Method of each request received so far, in order
+    pub fn methods(&self) -> Vec<String> {
+        self.methods.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockRpcServer {
+    fn drop(&mut self) {
+        self.task.abort()
+    }
+}
+
+/// gRPC server for tests, standing in for an enforcer. Responds to each
+/// request with the message that a handler returns for its method and
+/// request, both as gRPC-gateway JSON, or with `NOT_FOUND` if the handler
+/// returns `None`. Stops when dropped.
+pub struct MockEnforcerServer {
+    addr: SocketAddr,
+    /// Method of each request received, in order
+    methods: Arc<Mutex<Vec<String>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockEnforcerServer {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Option<Value> + Send + Sync + 'static,
+    {
+        let methods = Arc::new(Mutex::new(Vec::new()));
+        let service = MockValidatorService {
+            handler: Box::new(handler),
+            methods: methods.clone(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming =
+            TcpIncoming::from_listener(listener, true, None).unwrap();
+        let task = tokio::spawn(async move {
+            Server::builder()
+                .add_service(ValidatorServiceServer::new(service))
+                .serve_with_incoming(incoming)
+                .await
+                .unwrap()
+        });
+        Self {
+            addr,
+            methods,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Method of each request received so far, in order
+    pub fn methods(&self) -> Vec<String> {
+        self.methods.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockEnforcerServer {
+    fn drop(&mut self) {
+        self.task.abort()
+    }
+}
+
+/// Handler for a [`MockEnforcerServer`]'s requests, given each request's
+/// method and JSON body
+type EnforcerHandler = dyn Fn(&str, &Value) -> Option<Value> + Send + Sync;
+
+struct MockValidatorService {
+    handler: Box<EnforcerHandler>,
+    methods: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockValidatorService {
+    // `tonic::Status` is large, but is the error type of each service method
+    #[allow(clippy::result_large_err)]
+    fn respond<Request, Response>(
+        &self,
+        method: &str,
+        request: tonic::Request<Request>,
+    ) -> Result<tonic::Response<Response>, tonic::Status>
+    where
+        Request: Serialize,
+        Response: DeserializeOwned,
+    {
+        self.methods.lock().unwrap().push(method.to_owned());
+        let request = serde_json::to_value(request.into_inner()).unwrap();
+        match (self.handler)(method, &request) {
+            Some(response) => Ok(tonic::Response::new(
+                serde_json::from_value(response).unwrap(),
+            )),
+            None => Err(tonic::Status::not_found("not found")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ValidatorService for MockValidatorService {
+    async fn get_chain_tip(
+        &self,
+        request: tonic::Request<GetChainTipRequest>,
+    ) -> Result<tonic::Response<GetChainTipResponse>, tonic::Status> {
+        self.respond("GetChainTip", request)
+    }
+
+    async fn get_ctip(
+        &self,
+        request: tonic::Request<GetCtipRequest>,
+    ) -> Result<tonic::Response<GetCtipResponse>, tonic::Status> {
+        self.respond("GetCtip", request)
+    }
+
+    async fn get_sidechain_proposals(
+        &self,
+        request: tonic::Request<GetSidechainProposalsRequest>,
+    ) -> Result<tonic::Response<GetSidechainProposalsResponse>, tonic::Status>
+    {
+        self.respond("GetSidechainProposals", request)
+    }
+
+    async fn get_sidechains(
+        &self,
+        request: tonic::Request<GetSidechainsRequest>,
+    ) -> Result<tonic::Response<GetSidechainsResponse>, tonic::Status> {
+        self.respond("GetSidechains", request)
+    }
+
+    async fn get_block_failures(
+        &self,
+        request: tonic::Request<GetBlockFailuresRequest>,
+    ) -> Result<tonic::Response<GetBlockFailuresResponse>, tonic::Status> {
+        self.respond("GetBlockFailures", request)
+    }
+}
+
+fn respond(
+    handler: &impl Fn(&str, &Value) -> Option<MockResponse>,
+    methods: &Mutex<Vec<String>>,
+    request: &Value,
+) -> Option<Value> {
+    let method = request["method"].as_str().unwrap_or_default();
+    methods.lock().unwrap().push(method.to_owned());
+    let params = request.get("params").cloned().unwrap_or(json!([]));
+    let response = match handler(method, &params)? {
+        Ok(result) => json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": { "code": code, "message": message },
+        }),
+    };
+    Some(response)
+}
+
+/// Accept connections on a local port, and serve each with `responder`
+async fn serve(
+    responder: Arc<Responder>,
+) -> (SocketAddr, tokio::task::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let task = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_connection(stream, responder.clone()));
+        }
+    });
+    (addr, task)
+}
+
+/// Serve HTTP/1.1 requests on a connection until it is closed
+async fn serve_connection(stream: TcpStream, responder: Arc<Responder>) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        // The request line, eg. `POST / HTTP/1.1`, is followed by headers
+        let mut request_line = true;
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            match stream.read_line(&mut line).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if request_line {
+                request_line = false;
+            } else if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        let Some(response) = responder(&request) else {
+            // Held until the client gives up
+            return std::future::pending().await;
+        };
+        let response = response.to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{response}",
+            response.len()
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
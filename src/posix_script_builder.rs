@@ -1,137 +1,2416 @@
-use std::{collections::VecDeque, fmt::Display, net::SocketAddr};
+use std::{collections::VecDeque, io::Write, net::SocketAddr, time::Duration};
 
-use bitcoin::{hex::DisplayHex, Block};
+use bitcoin::BlockHash;
 use serde::Serialize;
 
-use crate::cli::RpcAuth;
+use crate::{
+    cli::{
+        wallet_endpoint_path, CurlOpts, RpcClient, RpcCredentials, RpcOpts,
+        Verdict,
+    },
+    enforcer_checks::{
+        EnforcerCheck, ENFORCER_CHECK_ATTEMPTS, VALIDATOR_SERVICE,
+    },
+    script_builder::{
+        indent, render_items, verdict_arg, Arg, BatchedSubmitblock,
+        BlockSubmission, Command, Comment, FailureClass, Heredoc, HexFilesDir,
+        ItemRenderer, RenderOpts, RequestBody, RpcCall, RpcIds, RpcRequest,
+        ScriptBuilder, ScriptItem, ScriptMeta, ScriptVar, Stdin,
+        SubmissionCounts, WALLET_METHODS,
+    },
+};
 
-#[derive(Debug)]
-struct Command {
-    command: String,
-    args: Vec<String>,
+/// Quote a string as a single argument for a POSIX shell. The string is
+/// enclosed in single quotes, within which no characters are special, and
+/// single quotes in the string are rendered as `'"'"'`.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'"'"'"#))
 }
 
-impl Display for Command {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::iter::once(self.command.clone())
-            .chain(self.args.clone())
-            .collect::<Vec<_>>()
-            .join(" ")
-            .fmt(f)
+/// Render a string as a single word for a POSIX shell, quoting it only if
+/// it contains characters that are special to the shell
+fn shell_word(s: &str) -> String {
+    let is_plain = |c: char| {
+        c.is_ascii_alphanumeric()
+            || matches!(
+                c,
+                '_' | '-' | '.' | '/' | ':' | '=' | '@' | '%' | '+' | ','
+            )
+    };
+    if !s.is_empty() && s.chars().all(is_plain) {
+        s.to_owned()
+    } else {
+        quote(s)
     }
 }
 
-#[derive(Debug)]
-struct Comment(String);
+/// Render a command argument as a single word for a POSIX shell
+fn render_arg(arg: &Arg) -> String {
+    match arg {
+        Arg::Literal(arg) => arg.clone(),
+        Arg::Quoted(arg) => quote(arg),
+        Arg::Variable(name) => format!("\"${name}\""),
+        Arg::FilePath { dir_var, file_name } => {
+            format!("\"${dir_var}\"/{}", quote(file_name))
+        }
+        Arg::Body(value) => quote(&serde_json::to_string(value).unwrap()),
+        Arg::Opt { name, value } => format!("-{name}={}", render_arg(value)),
+    }
+}
+
+// Messages are printed with `printf '%s'` rather than `echo`, since some
+// shells interpret backslash escapes in `echo` arguments
+
+/// Shell function that prints the result of a JSON-RPC response, using jq
+/// if available, and grep/sed otherwise. The grep/sed fallback only parses
+/// string and numeric results. `null` results are printed as empty strings.
+/// Fails if the response is an error.
+/// Usage: `rpc_result <response> <description>`
+const RPC_RESULT_FN: &str = r#"rpc_result() {
+    if command -v jq >/dev/null 2>&1; then
+        error=$(printf '%s' "$1" | jq -c '.error // empty')
+        result=$(printf '%s' "$1" | jq -r '.result // empty')
+    else
+        error=$(printf '%s' "$1" | grep -o '"error": *{[^}]*}' || true)
+        result=$(printf '%s' "$1" | sed -n -e 's/.*"result": *"\([^"]*\)".*/\1/p' -e 's/.*"result": *\(-\{0,1\}[0-9][0-9.]*\).*/\1/p')
+    fi
+    if [ -n "$error" ]; then
+        printf 'RPC request failed for %s: %s\n' "$2" "$error" >&2
+        exit {exit_rpc_error}
+    fi
+    printf '%s' "$result"
+}"#;
+
+/// Shell function that prints the result of a JSON-RPC response, using jq,
+/// which is required. `null` results are printed as empty strings. Fails if
+/// the response is an error.
+/// Usage: `rpc_result <response> <description>`
+const RPC_RESULT_JQ_FN: &str = r#"rpc_result() {
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check RPC responses\n' >&2
+        exit 1
+    fi
+    if ! printf '%s' "$1" | jq -e '.error == null' >/dev/null; then
+        printf 'RPC request failed for %s: %s\n' "$2" "$(printf '%s' "$1" | jq -c '.error')" >&2
+        exit {exit_rpc_error}
+    fi
+    printf '%s' "$(printf '%s' "$1" | jq -r '.result // empty')"
+}"#;
+
+/// Shell function that prints a batch of JSON-RPC results, using jq. Batch
+/// responses cannot be reliably parsed with grep/sed, so jq is required.
+/// Fails if there is no response with the specified id, or if the response
+/// is an error.
+/// Usage: `batch_result <responses> <id> <description>`
+const BATCH_RESULT_FN: &str = r#"batch_result() {
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check batched RPC responses\n' >&2
+        exit 1
+    fi
+    batch_response=$(printf '%s' "$1" | jq -c --argjson id "$2" '.[] | select(.id == $id)' 2>/dev/null || true)
+    if [ -z "$batch_response" ]; then
+        printf 'no RPC response for %s\n' "$3" >&2
+        exit {exit_rpc_error}
+    fi
+    rpc_result "$batch_response" "$3"
+}"#;
+
+/// Shell function that sends a JSON-RPC request body to a URL with curl,
+/// and prints the response body. The body is read from stdin if it is
+/// `@-`. Fails if curl fails, or if the HTTP status is not a success,
+/// unless the response is a JSON-RPC error, which is left to the caller to
+/// report. On HTTP errors, the status and response body are printed to
+/// stderr, since eg. a 401 response has an empty body.
+/// `{curl}` is replaced by the curl command, `{tls}` by TLS options,
+/// `{curl_opts}` by connection options, and `{max_time}` by the RPC
+/// timeout.
+/// Usage: `rpc_post <url> <body>`
+const RPC_POST_FN: &str = r#"rpc_post() {
+    rpc_status=0
+    rpc_output=$({curl} "$1" --silent --show-error{tls} \
+        {curl_opts} \
+        -H 'Content-Type: application/json' \
+        --user "$RPC_AUTH" \
+        --max-time {max_time} \
+        --write-out '\n%{http_code}' \
+        --data-binary "$2") || rpc_status=$?
+    rpc_http_status=$(printf '%s\n' "$rpc_output" | tail -n 1)
+    rpc_body=$(printf '%s\n' "$rpc_output" | sed '$d')
+    case "$rpc_http_status" in
+        2??|000) ;;
+        *)
+            case "$rpc_body" in
+                *'"error":{'*) rpc_status=0 ;;
+                *)
+                    printf 'HTTP status %s from %s\n' "$rpc_http_status" "$1" >&2
+                    if [ -n "$rpc_body" ]; then
+                        printf 'Response body: %s\n' "$rpc_body" >&2
+                    fi
+                    if [ "$rpc_status" -eq 0 ]; then
+                        rpc_status=22
+                    fi
+                    ;;
+            esac
+            ;;
+    esac
+    printf '%s\n' "$rpc_body"
+    return "$rpc_status"
+}"#;
+
+/// Shell function that sends a JSON-RPC request to the node, and prints the
+/// response body. Each argument after the method is a JSON-encoded param.
+/// Requests are sent to `$RPC_URL`, or to the path following `--path`,
+/// relative to `$RPC_URL`.
+/// `{id}` is replaced by the default request id.
+/// Usage: `rpc [--id <id>] [--path <path>] <method> [<param>...]`
+const RPC_FN: &str = r#"rpc() {
+    rpc_request_id={id}
+    if [ "$1" = --id ]; then
+        rpc_request_id=$2
+        shift 2
+    fi
+    rpc_url=$RPC_URL
+    if [ "$1" = --path ]; then
+        rpc_url="${RPC_URL%/}$2"
+        shift 2
+    fi
+    rpc_method=$1
+    shift
+    rpc_params=
+    for rpc_param in "$@"; do
+        rpc_params="${rpc_params:+$rpc_params,}$rpc_param"
+    done
+    rpc_post "$rpc_url" "$(printf '{"jsonrpc":"2.0","id":"%s","method":"%s","params":[%s]}' "$rpc_request_id" "$rpc_method" "$rpc_params")"
+}"#;
+
+/// Shell function that sends a JSON-RPC request to the node with a single
+/// string param read from a file, and prints the response body. The
+/// request is streamed to curl, so that large params are not passed as
+/// arguments.
+/// `{id}` is replaced by the default request id.
+/// Usage: `rpc_hex_file [--id <id>] <method> <file>`
+const RPC_HEX_FILE_FN: &str = r#"rpc_hex_file() {
+    rpc_request_id={id}
+    if [ "$1" = --id ]; then
+        rpc_request_id=$2
+        shift 2
+    fi
+    {
+        printf '{"jsonrpc":"2.0","id":"%s","method":"%s","params":["' "$rpc_request_id" "$1"
+        cat "$2"
+        printf '"]}'
+    } | rpc_post "$RPC_URL" @-
+}"#;
+
+/// Shell function that sends a batch of JSON-RPC requests for the same
+/// method to the node, and prints the response body. Each argument after
+/// the method is a comma-separated list of JSON-encoded params for one
+/// request. Requests are numbered from 0, in order.
+/// Usage: `rpc_batch <method> [<params>...]`
+const RPC_BATCH_FN: &str = r#"rpc_batch() {
+    rpc_method=$1
+    shift
+    rpc_requests=
+    rpc_id=0
+    for rpc_params in "$@"; do
+        rpc_request=$(printf '{"jsonrpc":"2.0","id":%d,"method":"%s","params":[%s]}' "$rpc_id" "$rpc_method" "$rpc_params")
+        rpc_requests="${rpc_requests:+$rpc_requests,}$rpc_request"
+        rpc_id=$((rpc_id + 1))
+    done
+    rpc_post "$RPC_URL" "[$rpc_requests]"
+}"#;
+
+/// Shell function that checks the result of a `submitblock` request, which
+/// is normalized as by `SubmitBlockResult::parse`. Invalid blocks on a side
+/// branch are not fully validated, so `inconclusive` is not a failure for
+/// blocks that are expected to be rejected.
+/// Usage: `check_submitblock <result> <accept|reject|inconclusive>
+/// <description>`
+const CHECK_SUBMITBLOCK_FN: &str = r#"check_submitblock() {
+    case "$1" in
+        '') submit_result=accepted ;;
+        duplicate) submit_result=duplicate ;;
+        duplicate-inconclusive) submit_result=duplicate-inconclusive ;;
+        inconclusive) submit_result=inconclusive ;;
+        *) submit_result=rejected ;;
+    esac
+    case "$2:$submit_result" in
+        accept:accepted|reject:rejected|reject:inconclusive|reject:duplicate-inconclusive|inconclusive:inconclusive)
+            ;;
+        accept:rejected)
+            printf 'expected %s to be accepted, but it was rejected: %s\n' "$3" "$1" >&2
+            exit {exit_unexpected_reject}
+            ;;
+        accept:inconclusive)
+            printf 'expected %s to be accepted, but it was stored on a side branch\n' "$3" >&2
+            exit {exit_unexpected_reject}
+            ;;
+        accept:duplicate*|inconclusive:duplicate*)
+            printf 'expected %s to be submitted for the first time, but the node already had it: %s\n' "$3" "$1" >&2
+            exit {exit_unexpected_reject}
+            ;;
+        reject:accepted)
+            printf 'expected %s to be rejected, but it was accepted\n' "$3" >&2
+            exit {exit_unexpected_accept}
+            ;;
+        reject:duplicate)
+            printf 'expected %s to be rejected, but the node already had it as a valid block\n' "$3" >&2
+            exit {exit_unexpected_accept}
+            ;;
+        inconclusive:accepted)
+            printf 'expected %s to be stored on a side branch, but it became the tip\n' "$3" >&2
+            exit {exit_unexpected_accept}
+            ;;
+        inconclusive:rejected)
+            printf 'expected %s to be stored on a side branch, but it was rejected: %s\n' "$3" "$1" >&2
+            exit {exit_unexpected_reject}
+            ;;
+    esac
+}"#;
+
+/// Shell function that checks the tip after submitting a block. Accepted
+/// blocks must become the tip, and rejected or side branch blocks must not.
+/// Usage: `check_tip <tip> <accept|reject|inconclusive> <block hash>
+/// <description>`
+const CHECK_TIP_FN: &str = r#"check_tip() {
+    case "$2" in
+        accept)
+            if [ "$1" != "$3" ]; then
+                printf 'expected %s to become the tip, but the tip is %s\n' "$4" "$1" >&2
+                exit {exit_tip_mismatch}
+            fi
+            ;;
+        reject)
+            if [ "$1" = "$3" ]; then
+                printf 'expected %s to be rejected, but it became the tip\n' "$4" >&2
+                exit {exit_tip_mismatch}
+            fi
+            ;;
+        inconclusive)
+            if [ "$1" = "$3" ]; then
+                printf 'expected %s to be stored on a side branch, but it became the tip\n' "$4" >&2
+                exit {exit_tip_mismatch}
+            fi
+            ;;
+    esac
+}"#;
+
+/// Shell function that checks whether a `getblockheader` response has a
+/// result, ie. whether the node has the block, using jq if available, and
+/// grep otherwise.
+/// Usage: `block_known <response>`
+const BLOCK_KNOWN_FN: &str = r#"block_known() {
+    if command -v jq >/dev/null 2>&1; then
+        printf '%s' "$1" | jq -e '.result != null' >/dev/null
+    else
+        printf '%s' "$1" | grep -q '"result": *{'
+    fi
+}"#;
 
-impl Display for Comment {
+/// Shell function that checks whether a `getblockheader` response has a
+/// result, ie. whether the node has the block, using jq, which is
+/// required.
+/// Usage: `block_known <response>`
+const BLOCK_KNOWN_JQ_FN: &str = r#"block_known() {
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check RPC responses\n' >&2
+        exit 1
+    fi
+    printf '%s' "$1" | jq -e '.result != null' >/dev/null
+}"#;
+
+/// Shell function that checks a value captured by an earlier step.
+/// Usage: `check_captured <value> <expected> <description>`
+const CHECK_CAPTURED_FN: &str = r#"check_captured() {
+    if [ "$1" != "$2" ]; then
+        printf 'expected %s to be %s, but it is %s\n' "$3" "$2" "$1" >&2
+        exit {exit_check_failed}
+    fi
+}"#;
+
+/// Shell function that sends a request to the enforcer's gRPC-gateway
+/// endpoint, and prints the response body.
+/// `{service}` is replaced by the gRPC service, `{curl_opts}` by connection
+/// options, and `{max_time}` by the RPC timeout.
+/// Usage: `enforcer_rpc <method> [<JSON body, `{}` if omitted>]`
+const ENFORCER_RPC_FN: &str = r#"enforcer_rpc() {
+    curl "${ENFORCER_URL%/}/{service}/$1" --silent --show-error --fail \
+        {curl_opts} \
+        -H 'Content-Type: application/json' \
+        --max-time {max_time} \
+        --data-binary "${2:-"{}"}"
+}"#;
+
+/// Shell function that checks that the enforcer has a pending sidechain
+/// proposal with the expected number of acks, retrying while the enforcer
+/// catches up. Skipped if `ENFORCER_CHECKS` is `0`.
+/// `{attempts}` is replaced by the number of attempts.
+/// Usage: `check_enforcer_proposal <slot> <description hash> <acks> <description>`
+const CHECK_ENFORCER_PROPOSAL_FN: &str = r#"check_enforcer_proposal() {
+    if [ "$ENFORCER_CHECKS" = 0 ]; then
+        return 0
+    fi
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check the enforcer state\n' >&2
+        exit 1
+    fi
+    attempt=1
+    while :; do
+        response=$(enforcer_rpc GetSidechainProposals) || exit {exit_connection}
+        acks=$(printf '%s' "$response" | jq -r --argjson slot "$1" --arg hash "$2" '[.sidechainProposals[]? | select(.sidechainNumber == $slot and .descriptionSha256dHash.hex == $hash) | .voteCount // 0][0] // empty')
+        if [ "$acks" = "$3" ]; then
+            return 0
+        fi
+        if [ "$attempt" -ge {attempts} ]; then
+            printf 'expected the enforcer to have a proposal for sidechain %s with %s acks after %s, but found: %s\n' "$1" "$3" "$4" "${acks:-no proposal}" >&2
+            exit {exit_enforcer_mismatch}
+        fi
+        attempt=$((attempt + 1))
+        sleep 1
+    done
+}"#;
+
+/// Shell function that checks whether the sidechain in a slot is active,
+/// retrying while the enforcer catches up. Skipped if `ENFORCER_CHECKS` is
+/// `0`.
+/// `{attempts}` is replaced by the number of attempts.
+/// Usage: `check_enforcer_active <slot> <true|false> <description>`
+const CHECK_ENFORCER_ACTIVE_FN: &str = r#"check_enforcer_active() {
+    if [ "$ENFORCER_CHECKS" = 0 ]; then
+        return 0
+    fi
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check the enforcer state\n' >&2
+        exit 1
+    fi
+    attempt=1
+    while :; do
+        response=$(enforcer_rpc GetSidechains) || exit {exit_connection}
+        active=$(printf '%s' "$response" | jq -r --argjson slot "$1" '[.sidechains[]? | select((.sidechainNumber // 0) == $slot)] | length > 0')
+        if [ "$active" = "$2" ]; then
+            return 0
+        fi
+        if [ "$attempt" -ge {attempts} ]; then
+            printf 'expected sidechain %s to be active: %s after %s, but found: %s\n' "$1" "$2" "$3" "$active" >&2
+            exit {exit_enforcer_mismatch}
+        fi
+        attempt=$((attempt + 1))
+        sleep 1
+    done
+}"#;
+
+/// Shell function that checks that a sidechain's treasury has no CTIP.
+/// Skipped if `ENFORCER_CHECKS` is `0`. An HTTP error response counts as no
+/// CTIP, since the enforcer may have no treasury for an inactive sidechain.
+/// Usage: `check_enforcer_no_ctip <slot> <description>`
+const CHECK_ENFORCER_NO_CTIP_FN: &str = r#"check_enforcer_no_ctip() {
+    if [ "$ENFORCER_CHECKS" = 0 ]; then
+        return 0
+    fi
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check the enforcer state\n' >&2
+        exit 1
+    fi
+    status=0
+    response=$(enforcer_rpc GetCtip "{\"sidechainNumber\": $1}") || status=$?
+    # curl exits with 22 on an HTTP error response
+    if [ "$status" = 22 ]; then
+        response='{}'
+    elif [ "$status" != 0 ]; then
+        exit {exit_connection}
+    fi
+    ctip=$(printf '%s' "$response" | jq -r 'if .ctip then "\(.ctip.txid.hex):\(.ctip.vout // 0)" else empty end' 2>/dev/null)
+    if [ -n "$ctip" ]; then
+        printf 'expected sidechain %s to have no CTIP after %s, but found: %s\n' "$1" "$2" "$ctip" >&2
+        exit {exit_enforcer_mismatch}
+    fi
+}"#;
+
+/// Shell function that checks that a rejected block is not the enforcer's
+/// chain tip. Skipped if `ENFORCER_CHECKS` is `0`.
+/// Usage: `check_enforcer_not_tip <block hash> <description>`
+const CHECK_ENFORCER_NOT_TIP_FN: &str = r#"check_enforcer_not_tip() {
+    if [ "$ENFORCER_CHECKS" = 0 ]; then
+        return 0
+    fi
+    if ! command -v jq >/dev/null 2>&1; then
+        printf 'jq is required to check the enforcer state\n' >&2
+        exit 1
+    fi
+    response=$(enforcer_rpc GetChainTip) || exit {exit_connection}
+    enforcer_tip=$(printf '%s' "$response" | jq -r '.blockHeaderInfo.blockHash.hex // empty')
+    if [ "$enforcer_tip" = "$1" ]; then
+        printf 'expected %s to be rejected, but it became the enforcer tip\n' "$2" >&2
+        exit {exit_enforcer_mismatch}
+    fi
+}"#;
+
+/// Shell function that prints the enforcer's tip to stderr, as part of the
+/// summary. Skipped if `ENFORCER_CHECKS` is `0`, or if jq is unavailable.
+const PRINT_ENFORCER_TIP_FN: &str = r#"print_enforcer_tip() {
+    if [ "$ENFORCER_CHECKS" = 0 ] || ! command -v jq >/dev/null 2>&1; then
+        return 0
+    fi
+    response=$(enforcer_rpc GetChainTip) || exit {exit_connection}
+    enforcer_tip=$(printf '%s' "$response" | jq -r '.blockHeaderInfo.blockHash.hex // empty')
+    printf '  Enforcer tip:            %s\n' "$enforcer_tip" >&2
+}"#;
+
+/// Shell snippet that prints a summary of progress when the script exits:
+/// the number of completed steps, the last submitted block, and the step
+/// that failed, if any, and the class of failure, if the exit code has
+/// one. Each step sets `step` and `step_label` before it starts, and
+/// `steps_done` once it completes.
+/// `{n_steps}` is replaced by the number of steps, `{exit_cases}` by a
+/// `case` branch for each class of failure, and `{extra}` by additional
+/// statements for the handler.
+const SUMMARY_TRAP: &str = r#"n_steps={n_steps}
+step=0
+step_label=
+steps_done=0
+last_block=
+on_exit() {
+    exit_status=$?
+    printf 'Completed %s of %s steps\n' "$steps_done" "$n_steps" >&2
+    if [ -n "$last_block" ]; then
+        printf 'Last submitted block: %s\n' "$last_block" >&2
+    fi
+    if [ "$exit_status" -ne 0 ] && [ "$step" -gt "$steps_done" ]; then
+        printf 'Failed at step %s: %s\n' "$step" "$step_label" >&2
+    fi
+    case "$exit_status" in
+{exit_cases}
+    esac{extra}
+}
+trap on_exit EXIT"#;
+
+/// Additional statements for the summary trap in bash scripts: the elapsed
+/// time, and the line of the command that failed, as recorded by an `ERR`
+/// trap
+const BASH_SUMMARY_TRAP_EXTRA: &str = r#"
+    if [ "$exit_status" -ne 0 ] && [ -n "${failed_line:-}" ]; then
+        printf 'Failed command at line %s: %s\n' "$failed_line" "$failed_command" >&2
+    fi
+    printf 'Elapsed: %ss\n' "$SECONDS" >&2"#;
+
+/// Shell functions used by a script
+#[derive(Clone, Copy, Debug, Default)]
+struct FunctionsUsed {
+    /// `true` IFF the script uses `rpc_post` directly
+    rpc_post: bool,
+    /// `true` IFF the script uses `rpc`
+    rpc: bool,
+    /// `true` IFF the script uses `rpc_hex_file`
+    rpc_hex_file: bool,
+    /// `true` IFF the script uses `rpc_batch`
+    rpc_batch: bool,
+    /// `true` IFF the script uses `batch_result`
+    batch_result: bool,
+    /// `true` IFF the script uses `rpc_result`
+    rpc_result: bool,
+    /// `true` IFF the script uses `check_submitblock`
+    check_submitblock: bool,
+    /// `true` IFF the script uses `check_tip`
+    check_tip: bool,
+    /// `true` IFF the script uses `check_captured`
+    check_captured: bool,
+    /// `true` IFF the script uses `block_known`
+    block_known: bool,
+    /// `true` IFF the script uses `check_enforcer_proposal`
+    check_enforcer_proposal: bool,
+    /// `true` IFF the script uses `check_enforcer_active`
+    check_enforcer_active: bool,
+    /// `true` IFF the script uses `check_enforcer_no_ctip`
+    check_enforcer_no_ctip: bool,
+    /// `true` IFF the script uses `check_enforcer_not_tip`
+    check_enforcer_not_tip: bool,
+    /// `true` IFF the script uses `print_enforcer_tip`
+    print_enforcer_tip: bool,
+}
+
+/// What a script's prelude depends on, other than its settings. Found by
+/// rendering the script with its items discarded, so that the prelude can
+/// be written before the items are streamed.
+#[derive(Clone, Copy, Debug)]
+pub struct PosixScriptPrelude {
+    uses: FunctionsUsed,
+    n_steps: usize,
+}
+
+/// Items streamed to a sink as they are added to a script
+struct ItemStream {
+    prelude: PosixScriptPrelude,
+    /// `true` once the prelude was written
+    started: bool,
+    item_renderer: ItemRenderer,
+    sink: Box<dyn Write>,
+    /// First error writing to the sink. Nothing is written after it.
+    error: Option<std::io::Error>,
+}
+
+impl ItemStream {
+    fn write(&mut self, s: &str) {
+        if self.error.is_none() {
+            if let Err(err) = self.sink.write_all(s.as_bytes()) {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    /// Push an item, and write the items that are rendered
+    fn push(&mut self, item: ScriptItem) {
+        let rendered = self.item_renderer.push(item);
+        self.write(&rendered)
+    }
+}
+
+impl std::fmt::Debug for ItemStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0
-            .lines()
-            .map(|line| format!("# {line}"))
-            .collect::<Vec<_>>()
-            .join("\n")
-            .fmt(f)
+        f.debug_struct("ItemStream")
+            .field("prelude", &self.prelude)
+            .field("started", &self.started)
+            .finish_non_exhaustive()
     }
 }
 
+/// What happens to items as they are added to a script
 #[derive(Debug)]
-enum ScriptItem {
-    Command(Command),
-    Comment(Comment),
+enum Items {
+    /// Kept until the script is finalized
+    Buffered(VecDeque<ScriptItem>),
+    /// Dropped, when the script is only rendered to find its prelude
+    Discarded,
+    /// Written to a sink, after the prelude
+    Streamed(ItemStream),
+}
+
+/// POSIX shell script, split into parts
+#[derive(Debug)]
+pub struct PosixScriptParts {
+    /// Declarations of the settings variables, and the shell functions used
+    /// by the items
+    pub prelude: String,
+    pub items: VecDeque<ScriptItem>,
+    /// Options that the prelude was rendered with
+    pub render_opts: RenderOpts,
 }
 
 #[derive(Debug)]
 pub struct OutputPosixScriptBuilder {
-    rpc_addr: SocketAddr,
-    rpc_auth: RpcAuth,
-    script: VecDeque<ScriptItem>,
+    rpc: RpcOpts,
+    rpc_client: RpcClient,
+    /// Used to select the chain for bitcoin-cli
+    network: bitcoin::Network,
+    /// Header comment for the preamble. If not set, the script is emitted
+    /// without a shebang or shell options.
+    preamble: Option<Comment>,
+    /// Shell variables assigned at the top of the script, as names and
+    /// default values
+    variables: Vec<(String, String)>,
+    /// Shell variables holding the hashes of labelled blocks, assigned after
+    /// the settings variables. Unlike settings, they cannot be overridden.
+    block_hashes: Vec<(ScriptVar, BlockHash)>,
+    items: Items,
+    /// Number of items added to the script
+    n_items: usize,
+    /// Maximum number of `submitblock` requests to send in each JSON-RPC
+    /// batch
+    batch_size: usize,
+    /// `submitblock` requests waiting to be sent
+    batch: Vec<BatchedSubmitblock>,
+    /// Command prefix to run node RPC commands with, if not empty
+    exec_wrapper: Vec<String>,
+    /// `true` if the script is a bash script, rather than a POSIX shell
+    /// script
+    bash: bool,
+    /// Number of steps in the script, if steps are tracked
+    n_steps: usize,
+    /// `true` if progress messages are not printed before each step
+    quiet: bool,
+    /// Ids for requests sent with curl
+    rpc_ids: RpcIds,
+    curl_opts: CurlOpts,
+    /// `true` if request bodies are sent to `rpc_post` as heredocs
+    heredoc_bodies: bool,
+    /// Width to wrap commands at, or 0 to disable wrapping
+    wrap_width: usize,
+    /// `true` if JSON-RPC responses are parsed with jq only, rather than
+    /// falling back to grep/sed if jq is not available
+    assume_jq: bool,
+    render_opts: RenderOpts,
+    /// JSON-RPC request bodies sent by the script with curl, in order
+    request_bodies: Vec<RequestBody>,
+    /// `true` if steps are skipped when `$skip_block` is set, ie. the steps
+    /// for a block that the node may already have
+    skip_guard: bool,
+    /// Shell functions used by the script so far
+    uses: FunctionsUsed,
 }
 
 impl OutputPosixScriptBuilder {
-    pub fn new(rpc_addr: SocketAddr, rpc_auth: RpcAuth) -> Self {
-        Self {
-            rpc_addr,
-            rpc_auth,
-            script: VecDeque::new(),
+    pub fn new(
+        rpc: RpcOpts,
+        rpc_client: RpcClient,
+        network: bitcoin::Network,
+    ) -> Self {
+        let mut res = Self {
+            rpc,
+            rpc_client,
+            network,
+            preamble: None,
+            variables: Vec::new(),
+            block_hashes: Vec::new(),
+            items: Items::Buffered(VecDeque::new()),
+            n_items: 0,
+            batch_size: 1,
+            batch: Vec::new(),
+            exec_wrapper: Vec::new(),
+            bash: false,
+            n_steps: 0,
+            quiet: false,
+            rpc_ids: RpcIds::default(),
+            curl_opts: CurlOpts::default(),
+            heredoc_bodies: false,
+            wrap_width: 0,
+            assume_jq: false,
+            render_opts: RenderOpts::default(),
+            request_bodies: Vec::new(),
+            skip_guard: false,
+            uses: FunctionsUsed::default(),
+        };
+        let () = res.declare_rpc_variables();
+        res
+    }
+
+    /// Send up to `batch_size` consecutive `submitblock` requests in each
+    /// JSON-RPC batch request. Batches are split by any other step.
+    /// Only supported by curl.
+    pub fn batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Run node RPC commands (curl or bitcoin-cli) with the specified command
+    /// prefix, eg. `docker exec -i bitcoind`. Arguments are passed through
+    /// the prefix as-is, so they are only quoted for the script's shell.
+    pub fn exec_wrapper(&mut self, exec_wrapper: Vec<String>) {
+        self.exec_wrapper = exec_wrapper;
+    }
+
+    /// Generate a bash script, rather than a POSIX shell script. Bash
+    /// scripts also fail on errors in pipelines, and report the failed
+    /// command and elapsed time on exit.
+    pub fn bash(&mut self, bash: bool) {
+        self.bash = bash;
+    }
+
+    /// Do not print progress messages before each step
+    pub fn quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Set the ids for JSON-RPC requests sent with curl. bitcoin-cli sets
+    /// its own request ids.
+    pub fn rpc_ids(&mut self, rpc_ids: RpcIds) {
+        self.rpc_ids = rpc_ids;
+    }
+
+    /// Send request bodies with curl as heredocs, with the JSON
+    /// pretty-printed, rather than as arguments to the RPC functions.
+    /// Block hex read from hex files is not affected.
+    pub fn heredoc_bodies(&mut self, heredoc_bodies: bool) {
+        self.heredoc_bodies = heredoc_bodies;
+    }
+
+    /// Wrap commands longer than `wrap_width` across lines, breaking only
+    /// between arguments. A width of 0 disables wrapping.
+    pub fn wrap_width(&mut self, wrap_width: usize) {
+        self.wrap_width = wrap_width;
+    }
+
+    /// Parse JSON-RPC responses with jq only, rather than checking whether
+    /// jq is available, and falling back to grep/sed
+    pub fn assume_jq(&mut self, assume_jq: bool) {
+        self.assume_jq = assume_jq;
+    }
+
+    /// Set the connection options for curl
+    pub fn curl_opts(&mut self, curl_opts: CurlOpts) {
+        self.curl_opts = curl_opts;
+    }
+
+    /// Set whether comments are omitted, and whether the output is compact
+    pub fn render_opts(&mut self, render_opts: RenderOpts) {
+        self.render_opts = render_opts;
+    }
+
+    /// Command word(s) to run `command` with the exec wrapper, if any
+    fn exec_wrapped(&self, command: &str) -> String {
+        self.exec_wrapper
+            .iter()
+            .map(|word| shell_word(word))
+            .chain(std::iter::once(command.to_owned()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Declare a shell variable at the top of the script. The default value
+    /// is used unless the variable is set in the environment, and must be a
+    /// shell word, eg. as quoted by [`quote`].
+    pub fn variable(&mut self, name: &str, default: String) {
+        debug_assert!(
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid shell variable name `{name}`"
+        );
+        self.variables.push((name.to_owned(), default));
+    }
+
+    /// Declare variables for the RPC connection settings used by the
+    /// selected RPC client
+    fn declare_rpc_variables(&mut self) {
+        let endpoint = self.rpc.endpoint(None);
+        match self.rpc_client {
+            RpcClient::Curl => {
+                self.variable("RPC_URL", quote(endpoint.as_str()));
+                if let Some(cacert) = &self.rpc.rpc_cacert {
+                    self.variable(
+                        "RPC_CACERT",
+                        quote(&cacert.to_string_lossy()),
+                    );
+                }
+                let auth = match &self.rpc.rpc_auth.rpc_cookie {
+                    Some(cookie_path) => format!(
+                        "\"$(cat {})\"",
+                        quote(&cookie_path.to_string_lossy())
+                    ),
+                    None if self.rpc.rpc_auth.rpc_auth_env => {
+                        "\"$RPC_USER:$RPC_PASS\"".to_owned()
+                    }
+                    None => {
+                        let RpcCredentials { user, pass } =
+                            self.rpc.user_pass();
+                        quote(&format!("{user}:{pass}"))
+                    }
+                };
+                self.variable("RPC_AUTH", auth);
+            }
+            RpcClient::BitcoinCli => {
+                self.variable(
+                    "RPC_HOST",
+                    quote(endpoint.host_str().unwrap_or_default()),
+                );
+                if let Some(port) = endpoint.port_or_known_default() {
+                    self.variable("RPC_PORT", port.to_string());
+                }
+                match &self.rpc.rpc_auth.rpc_cookie {
+                    Some(cookie_path) => self.variable(
+                        "RPC_COOKIE_FILE",
+                        quote(&cookie_path.to_string_lossy()),
+                    ),
+                    // Read from the environment
+                    None if self.rpc.rpc_auth.rpc_auth_env => (),
+                    None => {
+                        let RpcCredentials { user, pass } =
+                            self.rpc.user_pass();
+                        if !user.is_empty() || !pass.is_empty() {
+                            self.variable("RPC_USER", quote(&user));
+                            self.variable("RPC_PASS", quote(&pass));
+                        }
+                    }
+                }
+            }
         }
     }
 
-    pub fn command<S>(&mut self, command: S, args: Vec<String>)
-    where
-        String: From<S>,
-    {
-        self.script.push_back(ScriptItem::Command(Command {
-            command: command.into(),
+    /// curl options for verifying the node's TLS certificate, with a leading
+    /// space if not empty
+    fn curl_tls_args(&self) -> &'static str {
+        if self.rpc.rpc_cacert.is_some() {
+            " --cacert \"$RPC_CACERT\""
+        } else if self.rpc.rpc_insecure {
+            " --insecure"
+        } else {
+            ""
+        }
+    }
+
+    /// curl options for connecting and retrying. If `fail_with_body` is
+    /// `true`, `--fail-with-body` is included unless disabled.
+    fn curl_opts_args(&self, fail_with_body: bool) -> String {
+        let CurlOpts {
+            curl_connect_timeout,
+            curl_retry,
+            no_curl_fail_with_body,
+        } = self.curl_opts;
+        let mut args = format!("--connect-timeout {curl_connect_timeout}");
+        if curl_retry > 0 {
+            args.push_str(&format!(
+                " --retry {curl_retry} --retry-connrefused"
+            ));
+        }
+        if fail_with_body && !no_curl_fail_with_body {
+            args.push_str(" --fail-with-body");
+        }
+        args
+    }
+
+    /// Command to send an RPC request to the node, using the selected RPC
+    /// client
+    fn rpc_command(&mut self, call: &RpcCall) -> Command {
+        match self.rpc_client {
+            RpcClient::Curl => self.curl_command(call),
+            RpcClient::BitcoinCli => self.bitcoin_cli_command(call),
+        }
+    }
+
+    /// Render a command, wrapped at the wrap width
+    fn render_command(&self, command: &Command) -> String {
+        command.render(self.wrap_width, render_arg)
+    }
+
+    /// Wallet to send the RPC request to, if any
+    fn wallet(&self, call: &RpcCall) -> Option<&str> {
+        self.rpc
+            .rpc_wallet
+            .as_deref()
+            .filter(|_| WALLET_METHODS.contains(&call.method.as_str()))
+    }
+
+    /// Command to send an RPC request to the node, using the `rpc` shell
+    /// function. Params are JSON-encoded.
+    /// Wallet RPC requests are sent to the wallet endpoint, if a wallet was
+    /// specified.
+    fn curl_command(&mut self, call: &RpcCall) -> Command {
+        if self.heredoc_bodies {
+            let id = self
+                .rpc_ids
+                .next()
+                .unwrap_or_else(|| self.rpc_ids.default_id().to_owned());
+            let body = self.request_body(
+                &call.method,
+                &RpcRequest::new(id, call),
+                true,
+            );
+            let url = match self.wallet(call) {
+                // Parameter expansion, followed by the quoted path
+                Some(wallet) => Arg::Literal(format!(
+                    "\"${{RPC_URL%/}}\"{}",
+                    quote(&wallet_endpoint_path(wallet))
+                )),
+                None => Arg::Variable("RPC_URL".to_owned()),
+            };
+            return self.rpc_post_heredoc(url, body);
+        }
+        self.uses.rpc = true;
+        let id = self.rpc_ids.next();
+        let _: String = self.request_body(
+            &call.method,
+            &RpcRequest::new(
+                id.clone()
+                    .unwrap_or_else(|| self.rpc_ids.default_id().to_owned()),
+                call,
+            ),
+            false,
+        );
+        let mut args = Vec::new();
+        if let Some(id) = id {
+            args.push(Arg::literal("--id"));
+            args.push(Arg::Quoted(id));
+        }
+        if let Some(wallet) = self.wallet(call) {
+            args.push(Arg::literal("--path"));
+            args.push(Arg::Quoted(wallet_endpoint_path(wallet)));
+        }
+        args.push(Arg::literal(call.method.as_str()));
+        args.extend(call.params.iter().cloned().map(Arg::Body));
+        Command {
+            command: "rpc".to_owned(),
             args,
-        }))
+            stdin: None,
+        }
     }
 
-    pub fn comment<S>(&mut self, comment: S)
+    /// JSON-RPC request body, exactly as sent by curl, which is recorded
+    /// for `--dump-requests`. Bodies sent as heredocs are pretty-printed,
+    /// and the heredoc adds a trailing newline. Other bodies are built by
+    /// the shell functions in the same compact form as `serde_json`.
+    /// Returns the body, without the heredoc's trailing newline.
+    fn request_body<Body>(
+        &mut self,
+        method: &str,
+        body: &Body,
+        heredoc: bool,
+    ) -> String
     where
-        String: From<S>,
+        Body: Serialize,
     {
-        self.script
-            .push_back(ScriptItem::Comment(Comment(comment.into())))
-    }
-
-    pub fn finalize(self) -> String {
-        let mut res = "".to_owned();
-        let mut iter = self.script.into_iter().peekable();
-        while let Some(script_item) = iter.next() {
-            match script_item {
-                ScriptItem::Comment(comment) => {
-                    res.push_str(&comment.to_string());
-                    match iter.peek() {
-                        Some(ScriptItem::Comment(_)) => {
-                            res.push_str("\n\n");
-                        }
-                        Some(ScriptItem::Command(_)) | None => {
-                            res.push('\n');
-                        }
-                    }
+        let (body, sent) = if heredoc {
+            let body = serde_json::to_string_pretty(body).unwrap();
+            let sent = format!("{body}\n");
+            (body, sent)
+        } else {
+            let body = serde_json::to_string(body).unwrap();
+            (body.clone(), body)
+        };
+        self.request_bodies.push(RequestBody {
+            method: method.to_owned(),
+            body: sent,
+        });
+        body
+    }
+
+    /// `rpc_post` command to send a request body, as a heredoc
+    fn rpc_post_heredoc(&mut self, url: Arg, body: String) -> Command {
+        self.uses.rpc_post = true;
+        Command {
+            command: "rpc_post".to_owned(),
+            args: vec![url, Arg::literal("@-")],
+            stdin: Some(Stdin::Heredoc(Heredoc::new(body))),
+        }
+    }
+
+    /// bitcoin-cli command to send an RPC request to the node. Params are
+    /// passed as positional arguments. If no credentials were specified,
+    /// bitcoin-cli reads the node's cookie file.
+    fn bitcoin_cli_command(&self, call: &RpcCall) -> Command {
+        let var_arg = |name: &str| Arg::Variable(name.to_owned());
+        let mut args = vec![
+            Arg::opt("chain", Arg::literal(self.network.to_core_arg())),
+            Arg::opt("rpcconnect", var_arg("RPC_HOST")),
+        ];
+        let mut push_var_arg = |name: &str, opt: &str| {
+            if self.variables.iter().any(|(var_name, _)| var_name == name) {
+                args.push(Arg::opt(opt, var_arg(name)));
+            }
+        };
+        push_var_arg("RPC_PORT", "rpcport");
+        push_var_arg("RPC_COOKIE_FILE", "rpccookiefile");
+        if self.rpc.rpc_auth.rpc_auth_env {
+            args.push(Arg::opt("rpcuser", var_arg("RPC_USER")));
+            args.push(Arg::opt("rpcpassword", var_arg("RPC_PASS")));
+        } else {
+            push_var_arg("RPC_USER", "rpcuser");
+            push_var_arg("RPC_PASS", "rpcpassword");
+        }
+        if let Some(wallet) = self.wallet(call) {
+            args.push(Arg::opt("rpcwallet", Arg::quoted(wallet)));
+        }
+        args.push(Arg::opt(
+            "rpcclienttimeout",
+            Arg::Literal(self.rpc.rpc_timeout.to_string()),
+        ));
+        args.push(Arg::literal(call.method.as_str()));
+        // bitcoin-cli parses params as JSON, unless they are invalid JSON,
+        // so strings are passed as-is
+        args.extend(call.params.iter().map(|param| match param {
+            serde_json::Value::String(param) => Arg::quoted(param.as_str()),
+            param => Arg::Quoted(param.to_string()),
+        }));
+        Command {
+            command: self.exec_wrapped("bitcoin-cli"),
+            args,
+            stdin: None,
+        }
+    }
+
+    /// Command to send a `submitblock` request for the block, reading the
+    /// block's hex from its hex file, if any
+    fn submitblock_command(&mut self, submission: &BlockSubmission) -> Command {
+        let Some(hex_file) = submission.hex_file else {
+            return self.rpc_command(&RpcCall::submitblock(submission.block));
+        };
+        let hex_file = Arg::FilePath {
+            dir_var: "BLOCKS_DIR".to_owned(),
+            file_name: hex_file.to_owned(),
+        };
+        match self.rpc_client {
+            RpcClient::Curl => {
+                self.uses.rpc_hex_file = true;
+                let id = self.rpc_ids.next();
+                let _: String = self.request_body(
+                    "submitblock",
+                    &RpcRequest::new(
+                        id.clone().unwrap_or_else(|| {
+                            self.rpc_ids.default_id().to_owned()
+                        }),
+                        &RpcCall::submitblock(submission.block),
+                    ),
+                    false,
+                );
+                let mut args = Vec::new();
+                if let Some(id) = id {
+                    args.push(Arg::literal("--id"));
+                    args.push(Arg::Quoted(id));
+                }
+                args.extend([Arg::literal("submitblock"), hex_file]);
+                Command {
+                    command: "rpc_hex_file".to_owned(),
+                    args,
+                    stdin: None,
                 }
-                ScriptItem::Command(command) => {
-                    res.push_str(&command.to_string());
-                    if iter.peek().is_some() {
-                        res.push_str("\n\n");
-                    } else {
-                        res.push('\n');
+            }
+            RpcClient::BitcoinCli => {
+                let Command {
+                    command,
+                    mut args,
+                    stdin: _,
+                } = self.bitcoin_cli_command(&RpcCall::new("submitblock", ()));
+                // Read the param from stdin, before the method
+                args.insert(args.len() - 1, Arg::literal("-stdin"));
+                Command {
+                    command,
+                    args,
+                    stdin: Some(Stdin::File(hex_file)),
+                }
+            }
+        }
+    }
+
+    /// Shell statements that send an RPC request to the node, and assign
+    /// its result to the shell variable `var`, exiting if the request
+    /// fails. `null` results are assigned as empty strings.
+    /// `description` is used in error messages.
+    fn capture_result(
+        &mut self,
+        command: Command,
+        var: &str,
+        description: &str,
+    ) -> String {
+        let description = quote(description);
+        match self.rpc_client {
+            RpcClient::Curl => {
+                self.uses.rpc_result = true;
+                format!(
+                    "response=$({}) || exit {}\n\
+                     {var}=$(rpc_result \"$response\" {description}) || exit",
+                    self.render_command(&command),
+                    FailureClass::Connection.exit_code()
+                )
+            }
+            RpcClient::BitcoinCli => format!(
+                "{var}=$({}) || exit {}",
+                self.render_command(&command),
+                FailureClass::Connection.exit_code()
+            ),
+        }
+    }
+
+    /// Statements that are skipped if `$skip_block` is set, if steps are
+    /// guarded
+    fn guarded(&self, body: &str) -> String {
+        if self.skip_guard {
+            format!("if [ \"$skip_block\" = 0 ]; then\n{}\nfi", indent(body))
+        } else {
+            body.to_owned()
+        }
+    }
+
+    /// Add statements to the script as a step. If the script has a
+    /// preamble, steps are numbered, and the summary trap reports progress
+    /// through them. Unless the script is quiet, the label is printed to
+    /// stderr before the step. `submitted` describes the last block
+    /// submitted by the step, if any.
+    fn push_step(
+        &mut self,
+        label: &str,
+        body: String,
+        submitted: Option<&str>,
+    ) {
+        let mut lines = Vec::new();
+        if self.preamble.is_none() {
+            if !self.quiet {
+                lines.push(format!("printf '>>> %s\\n' {} >&2", quote(label)));
+            }
+            lines.push(body.trim_end().to_owned());
+            let snippet = self.guarded(&lines.join("\n"));
+            self.push_item(ScriptItem::Snippet(snippet));
+            return;
+        }
+        self.n_steps += 1;
+        let n_step = self.n_steps;
+        lines.push(format!("step={n_step} step_label={}", quote(label)));
+        let mut step_lines = Vec::new();
+        if !self.quiet {
+            step_lines.push(
+                "printf '>>> [%s/%s] %s\\n' \"$step\" \"$n_steps\" \
+                 \"$step_label\" >&2"
+                    .to_owned(),
+            );
+        }
+        step_lines.push(body.trim_end().to_owned());
+        lines.push(self.guarded(&step_lines.join("\n")));
+        let mut done = format!("steps_done={n_step}");
+        if let Some(submitted) = submitted {
+            done.push_str(&format!(" last_block={}", quote(submitted)));
+        }
+        lines.push(done);
+        self.push_item(ScriptItem::Snippet(lines.join("\n")));
+    }
+
+    /// `submitblock` request, without checking the response. The script
+    /// still fails if the request fails, eg. on HTTP errors.
+    fn submitblock_unchecked_step(
+        &mut self,
+        submitblock: Command,
+        description: &str,
+        summary: &str,
+    ) {
+        self.push_step(
+            &format!("submitting {summary}"),
+            format!(
+                "{} || exit {}",
+                self.render_command(&submitblock),
+                FailureClass::Connection.exit_code()
+            ),
+            Some(description),
+        )
+    }
+
+    /// `submitblock` request, failing the script if the node returns an
+    /// error, or if the block is not accepted or rejected as expected
+    fn submitblock_checked_step(
+        &mut self,
+        submitblock: Command,
+        expected: Verdict,
+        description: &str,
+        summary: &str,
+    ) {
+        let snippet = [
+            self.capture_result(submitblock, "result", description),
+            format!(
+                "check_submitblock \"$result\" {} {}",
+                verdict_arg(expected),
+                quote(description)
+            ),
+        ]
+        .join("\n");
+        self.uses.check_submitblock = true;
+        self.push_step(
+            &format!("submitting {summary}"),
+            snippet,
+            Some(description),
+        )
+    }
+
+    /// Add a `submitblock` request to the pending batch, and send the batch
+    /// if it is full
+    fn push_batch(&mut self, batched: BatchedSubmitblock) {
+        self.batch.push(batched);
+        if self.batch.len() >= self.batch_size {
+            let () = self.flush_batch();
+        }
+    }
+
+    /// Send the pending `submitblock` requests. A single pending request is
+    /// sent on its own.
+    fn flush_batch(&mut self) {
+        let batch = std::mem::take(&mut self.batch);
+        if !batch.is_empty() {
+            let contexts: Vec<_> = batch
+                .iter()
+                .map(|batched| batched.context.as_str())
+                .collect();
+            self.push_item(ScriptItem::Comment(Comment(contexts.join("\n"))));
+        }
+        match batch.as_slice() {
+            [] => (),
+            [BatchedSubmitblock {
+                call,
+                expected: None,
+                description,
+                summary,
+                context: _,
+            }] => {
+                let submitblock = self.rpc_command(call);
+                self.submitblock_unchecked_step(
+                    submitblock,
+                    description,
+                    summary,
+                )
+            }
+            [BatchedSubmitblock {
+                call,
+                expected: Some(expected),
+                description,
+                summary,
+                context: _,
+            }] => {
+                let submitblock = self.rpc_command(call);
+                self.submitblock_checked_step(
+                    submitblock,
+                    *expected,
+                    description,
+                    summary,
+                )
+            }
+            [first, .., last] => {
+                let requests: Vec<_> = batch
+                    .iter()
+                    .enumerate()
+                    .map(|(id, batched)| RpcRequest::new(id, &batched.call))
+                    .collect();
+                let body = self.request_body(
+                    &first.call.method,
+                    &requests,
+                    self.heredoc_bodies,
+                );
+                let command = if self.heredoc_bodies {
+                    self.rpc_post_heredoc(
+                        Arg::Variable("RPC_URL".to_owned()),
+                        body,
+                    )
+                } else {
+                    self.uses.rpc_batch = true;
+                    let mut args =
+                        vec![Arg::literal(first.call.method.as_str())];
+                    // Each request's params, comma-separated
+                    args.extend(batch.iter().map(|batched| {
+                        let params: Vec<_> = batched
+                            .call
+                            .params
+                            .iter()
+                            .map(|param| serde_json::to_string(param).unwrap())
+                            .collect();
+                        Arg::Quoted(params.join(","))
+                    }));
+                    Command {
+                        command: "rpc_batch".to_owned(),
+                        args,
+                        stdin: None,
                     }
+                };
+                let checks: Vec<_> = batch
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(id, batched)| {
+                        let expected = batched.expected?;
+                        let description = quote(&batched.description);
+                        Some(format!(
+                            "result=$(batch_result \"$response\" {id} \
+                             {description}) || exit\n\
+                             check_submitblock \"$result\" {} {description}",
+                            verdict_arg(expected)
+                        ))
+                    })
+                    .collect();
+                let label = format!(
+                    "submitting a batch of {} blocks, ending with {}",
+                    batch.len(),
+                    last.description
+                );
+                if checks.is_empty() {
+                    return self.push_step(
+                        &label,
+                        self.render_command(&command),
+                        Some(&last.description),
+                    );
                 }
+                self.uses.rpc_result = true;
+                self.uses.batch_result = true;
+                self.uses.check_submitblock = true;
+                let snippet = std::iter::once(format!(
+                    "response=$({}) || exit {}",
+                    self.render_command(&command),
+                    FailureClass::Connection.exit_code()
+                ))
+                .chain(checks)
+                .collect::<Vec<_>>()
+                .join("\n");
+                self.push_step(&label, snippet, Some(&last.description))
+            }
+        }
+    }
+
+    /// Declarations of the settings variables, and of the variables holding
+    /// block hashes, each followed by a blank line, if there are any
+    fn render_variables(&self) -> String {
+        let mut res = String::new();
+        if !self.variables.is_empty() {
+            res.push_str(&self.render_opts.comment_line(&Comment(
+                "Settings, which can be overridden by setting these variables \
+                 in the\nenvironment"
+                    .to_owned(),
+            )));
+            for (name, default) in &self.variables {
+                res.push_str(&format!("{name}=${{{name}:-{default}}}\n"));
             }
+            res.push_str(self.render_opts.blank_line());
+        }
+        if !self.block_hashes.is_empty() {
+            res.push_str(&self.render_opts.comment_line(&Comment(
+                "Hashes of labelled blocks".to_owned(),
+            )));
+            for (var, block_hash) in &self.block_hashes {
+                res.push_str(&format!("{}={block_hash}\n", var.name()));
+            }
+            res.push_str(self.render_opts.blank_line());
         }
         res
     }
 
-    /// Use curl to send an RPC request to the node
-    pub fn curl_rpc<Params>(&mut self, method: &str, params: Params)
+    /// Shell functions in `uses`, with comments describing them
+    fn functions(&self, uses: &FunctionsUsed) -> Vec<(&'static str, String)> {
+        let mut functions = Vec::new();
+        if uses.rpc_post || uses.rpc || uses.rpc_hex_file || uses.rpc_batch {
+            functions.push((
+                "Send a JSON-RPC request body, and print the response",
+                RPC_POST_FN
+                    .replace("{curl}", &self.exec_wrapped("curl"))
+                    .replace("{tls}", self.curl_tls_args())
+                    .replace("{curl_opts}", &self.curl_opts_args(true))
+                    .replace("{max_time}", &self.rpc.rpc_timeout.to_string()),
+            ));
+        }
+        if uses.rpc {
+            functions.push((
+                "Send a JSON-RPC request to the node, and print the response",
+                RPC_FN.replace("{id}", &quote(self.rpc_ids.default_id())),
+            ));
+        }
+        if uses.rpc_hex_file {
+            functions.push((
+                "Send a JSON-RPC request to the node, with a param read from a \
+                 file",
+                RPC_HEX_FILE_FN.replace("{id}", &quote(self.rpc_ids.default_id())),
+            ));
+        }
+        if uses.rpc_batch {
+            functions.push((
+                "Send a batch of JSON-RPC requests to the node, and print the \
+                 responses",
+                RPC_BATCH_FN.to_owned(),
+            ));
+        }
+        if uses.rpc_result {
+            functions.push((
+                "Print the result of a JSON-RPC response",
+                if self.assume_jq {
+                    RPC_RESULT_JQ_FN
+                } else {
+                    RPC_RESULT_FN
+                }
+                .to_owned(),
+            ));
+        }
+        if uses.batch_result {
+            functions.push((
+                "Print the result of a JSON-RPC response in a batch",
+                BATCH_RESULT_FN.to_owned(),
+            ));
+        }
+        if uses.check_submitblock {
+            functions.push((
+                "Check the result of a `submitblock` request",
+                CHECK_SUBMITBLOCK_FN.to_owned(),
+            ));
+        }
+        if uses.check_tip {
+            functions.push((
+                "Check that a submitted block did or did not become the tip",
+                CHECK_TIP_FN.to_owned(),
+            ));
+        }
+        if uses.block_known {
+            functions.push((
+                "Check whether a `getblockheader` response has a result",
+                if self.assume_jq {
+                    BLOCK_KNOWN_JQ_FN
+                } else {
+                    BLOCK_KNOWN_FN
+                }
+                .to_owned(),
+            ));
+        }
+        if uses.check_captured {
+            functions.push((
+                "Check a value captured by an earlier step",
+                CHECK_CAPTURED_FN.to_owned(),
+            ));
+        }
+        if uses.check_enforcer_proposal
+            || uses.check_enforcer_active
+            || uses.check_enforcer_no_ctip
+            || uses.check_enforcer_not_tip
+            || uses.print_enforcer_tip
+        {
+            functions.push((
+                "Send a request to the enforcer, and print the response",
+                ENFORCER_RPC_FN
+                    .replace("{service}", VALIDATOR_SERVICE)
+                    .replace("{curl_opts}", &self.curl_opts_args(false))
+                    .replace("{max_time}", &self.rpc.rpc_timeout.to_string()),
+            ));
+        }
+        if uses.check_enforcer_proposal {
+            functions.push((
+                "Check that the enforcer has a sidechain proposal with the \
+                 expected acks",
+                CHECK_ENFORCER_PROPOSAL_FN.replace(
+                    "{attempts}",
+                    &ENFORCER_CHECK_ATTEMPTS.to_string(),
+                ),
+            ));
+        }
+        if uses.check_enforcer_active {
+            functions.push((
+                "Check whether a sidechain is active",
+                CHECK_ENFORCER_ACTIVE_FN.replace(
+                    "{attempts}",
+                    &ENFORCER_CHECK_ATTEMPTS.to_string(),
+                ),
+            ));
+        }
+        if uses.check_enforcer_no_ctip {
+            functions.push((
+                "Check that a sidechain's treasury has no CTIP",
+                CHECK_ENFORCER_NO_CTIP_FN.to_owned(),
+            ));
+        }
+        if uses.check_enforcer_not_tip {
+            functions.push((
+                "Check that a rejected block is not the enforcer's tip",
+                CHECK_ENFORCER_NOT_TIP_FN.to_owned(),
+            ));
+        }
+        if uses.print_enforcer_tip {
+            functions.push((
+                "Print the enforcer's tip",
+                PRINT_ENFORCER_TIP_FN.to_owned(),
+            ));
+        }
+        functions
+            .into_iter()
+            .map(|(comment, function)| {
+                (comment, FailureClass::substitute(&function))
+            })
+            .collect()
+    }
+
+    /// Number of items in the script so far. Pending `submitblock` requests
+    /// are sent first.
+    pub fn n_items(&mut self) -> usize {
+        let () = self.flush_batch();
+        self.n_items
+    }
+
+    /// Add an item to the script. If the script is streamed, the prelude is
+    /// written before the first item.
+    fn push_item(&mut self, item: ScriptItem) {
+        self.n_items += 1;
+        let () = self.start_stream();
+        match &mut self.items {
+            Items::Buffered(items) => items.push_back(item),
+            Items::Discarded => (),
+            Items::Streamed(item_stream) => item_stream.push(item),
+        }
+    }
+
+    /// Write the prelude of a streamed script, if it was not written yet
+    fn start_stream(&mut self) {
+        let Items::Streamed(ItemStream {
+            prelude,
+            started: false,
+            ..
+        }) = &self.items
+        else {
+            return;
+        };
+        let (head, head_items) = self.head(*prelude);
+        let Items::Streamed(item_stream) = &mut self.items else {
+            unreachable!()
+        };
+        item_stream.started = true;
+        item_stream.write(&head);
+        for item in head_items {
+            item_stream.push(item);
+        }
+    }
+
+    /// Discard items as they are added, so that the script is only rendered
+    /// to find its prelude, with [`Self::prelude`]
+    pub fn discard_items(&mut self) {
+        self.items = Items::Discarded;
+    }
+
+    /// What the script's prelude depends on, given the items so far
+    pub fn prelude(&mut self) -> PosixScriptPrelude {
+        let () = self.flush_batch();
+        PosixScriptPrelude {
+            uses: self.uses,
+            n_steps: self.n_steps,
+        }
+    }
+
+    /// Write the script to `sink` as items are added, rather than keeping it
+    /// in memory until it is finalized. `prelude` must be found by rendering
+    /// the same script with [`Self::discard_items`] first, since the prelude
+    /// is written before the items. Finish the script with
+    /// [`Self::finish_stream`].
+    pub fn stream_items(
+        &mut self,
+        prelude: PosixScriptPrelude,
+        sink: Box<dyn Write>,
+    ) {
+        self.items = Items::Streamed(ItemStream {
+            prelude,
+            started: false,
+            item_renderer: ItemRenderer::new(self.render_opts),
+            sink,
+            error: None,
+        });
+    }
+
+    /// Write the rest of a streamed script, and return the sink
+    pub fn finish_stream(mut self) -> std::io::Result<Box<dyn Write>> {
+        let () = self.flush_batch();
+        // The prelude is written even if there are no items
+        let () = self.start_stream();
+        let Items::Streamed(mut item_stream) = self.items else {
+            panic!("script is not streamed");
+        };
+        debug_assert_eq!(
+            self.n_steps, item_stream.prelude.n_steps,
+            "streamed script differs from the script that its prelude was \
+             found with"
+        );
+        let rest = item_stream.item_renderer.finish();
+        item_stream.item_renderer = ItemRenderer::new(self.render_opts);
+        item_stream.write(&rest);
+        match item_stream.error {
+            Some(err) => Err(err),
+            None => Ok(item_stream.sink),
+        }
+    }
+
+    /// Text before the items, ie. the shebang and header comment, if there
+    /// is a preamble, and the settings variables, followed by the items
+    /// that precede the script's own items: the functions used by the
+    /// script, and the summary trap, if there is a preamble
+    fn head(&self, prelude: PosixScriptPrelude) -> (String, Vec<ScriptItem>) {
+        let PosixScriptPrelude { uses, n_steps } = prelude;
+        let blank_line = self.render_opts.blank_line();
+        let mut head = match (&self.preamble, self.bash) {
+            (Some(comment), false) => format!(
+                "#!/bin/sh\n{}set -eu\n{blank_line}",
+                self.render_opts.comment_line(comment)
+            ),
+            (Some(comment), true) => format!(
+                "#!/usr/bin/env bash\n{}set -euo pipefail\n\
+                 trap 'failed_line=$LINENO failed_command=$BASH_COMMAND' \
+                 ERR\n{blank_line}",
+                self.render_opts.comment_line(comment)
+            ),
+            (None, _) => "".to_owned(),
+        };
+        head.push_str(&self.render_variables());
+        let mut head_items = Vec::new();
+        for (comment, function) in self.functions(&uses) {
+            head_items.push(ScriptItem::Comment(Comment(comment.to_owned())));
+            head_items.push(ScriptItem::Snippet(function));
+        }
+        if self.preamble.is_some() {
+            let extra = if self.bash {
+                BASH_SUMMARY_TRAP_EXTRA
+            } else {
+                ""
+            };
+            let exit_cases: Vec<_> = FailureClass::ALL
+                .iter()
+                .map(|class| {
+                    format!(
+                        "        {}) printf 'Exit code %s: %s\\n' \
+                         \"$exit_status\" {} >&2 ;;",
+                        class.exit_code(),
+                        quote(class.description())
+                    )
+                })
+                .collect();
+            let summary_trap = SUMMARY_TRAP
+                .replace("{n_steps}", &n_steps.to_string())
+                .replace("{exit_cases}", &exit_cases.join("\n"))
+                .replace("{extra}", extra);
+            head_items.push(ScriptItem::Comment(Comment(
+                "Print a summary of progress when the script exits".to_owned(),
+            )));
+            head_items.push(ScriptItem::Snippet(summary_trap));
+        }
+        (head, head_items)
+    }
+
+    /// Split the script into its prelude and items, for output formats that
+    /// embed POSIX shell scripts. The preamble, if any, is ignored.
+    /// The script must be buffered.
+    pub fn into_parts(mut self) -> PosixScriptParts {
+        let () = self.flush_batch();
+        let Items::Buffered(items) =
+            std::mem::replace(&mut self.items, Items::Discarded)
+        else {
+            panic!("script is not buffered");
+        };
+        let mut prelude = self.render_variables();
+        let functions = self
+            .functions(&self.uses)
+            .into_iter()
+            .flat_map(|(comment, function)| {
+                [
+                    ScriptItem::Comment(Comment(comment.to_owned())),
+                    ScriptItem::Snippet(function),
+                ]
+            })
+            .collect();
+        prelude.push_str(&render_items(functions, self.render_opts));
+        PosixScriptParts {
+            prelude,
+            items,
+            render_opts: self.render_opts,
+        }
+    }
+}
+
+impl ScriptBuilder for OutputPosixScriptBuilder {
+    /// Prepend a preamble to the script: a shebang, the specified header
+    /// comment followed by the exit codes, and `set -eu`, so that the
+    /// script exits on the first failure or unset variable. Scripts with a
+    /// preamble also print a summary of their progress on exit.
+    fn preamble(&mut self, meta: &ScriptMeta) {
+        self.preamble =
+            Some(Comment(format!("{meta}\n{}", FailureClass::doc())));
+    }
+
+    fn comment<S>(&mut self, comment: S)
     where
-        Params: Serialize,
+        String: From<S>,
     {
-        let request = serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": "bip347-enforcer-test",
-            "method": method,
-            "params": params
-        });
-        let args = vec![
-            format!("'{}'", &self.rpc_addr),
-            "-H".to_owned(),
-            "'Content-Type: application/json'".to_owned(),
-            "--user".to_owned(),
-            format!("'{}:{}'", self.rpc_auth.rpc_user, self.rpc_auth.rpc_pass),
-            "--data-binary".to_owned(),
-            format!("'{}'", serde_json::to_string(&request).unwrap()),
+        let () = self.flush_batch();
+        self.push_item(ScriptItem::Comment(Comment(comment.into())))
+    }
+
+    /// Blocks that are sent in a batch are commented on when the batch is
+    /// sent, so that comments do not split batches.
+    /// Ends the steps that are skipped by [`Self::skip_if_known`].
+    fn block_comment(
+        &mut self,
+        comment: Option<&str>,
+        submission: &BlockSubmission,
+    ) {
+        self.skip_guard = false;
+        if submission.hex_file.is_some() || self.batch_size == 1 {
+            self.comment(submission.comment_with_context(comment))
+        } else if let Some(comment) = comment {
+            self.comment(comment)
+        }
+    }
+
+    /// Pause the script. Fractional seconds are rounded up to whole seconds
+    /// if not supported by `sleep`.
+    fn sleep(&mut self, duration: Duration) {
+        let () = self.flush_batch();
+        let millis = duration.as_millis();
+        let snippet = if millis.is_multiple_of(1000) {
+            format!("sleep {}", millis / 1000)
+        } else {
+            format!(
+                "sleep {}.{:03} 2>/dev/null || sleep {}",
+                millis / 1000,
+                millis % 1000,
+                millis.div_ceil(1000)
+            )
+        };
+        let snippet = self.guarded(&snippet);
+        self.push_item(ScriptItem::Snippet(snippet));
+    }
+
+    fn hex_files_dir(&mut self, dir: &HexFilesDir) {
+        let dir = match dir {
+            HexFilesDir::ScriptRelative(dir) => format!(
+                "\"$(dirname \"$0\")\"/{}",
+                quote(&dir.to_string_lossy())
+            ),
+            HexFilesDir::Absolute(dir) => quote(&dir.to_string_lossy()),
+        };
+        self.variable("BLOCKS_DIR", dir);
+    }
+
+    fn enforcer_addr(&mut self, addr: SocketAddr) {
+        self.variable("ENFORCER_URL", quote(&format!("http://{addr}")));
+        self.variable("ENFORCER_CHECKS", "1".to_owned());
+    }
+
+    fn block_hash_var(&mut self, var: &ScriptVar, block_hash: BlockHash) {
+        self.block_hashes.push((var.clone(), block_hash));
+    }
+
+    /// With curl, the `getblockheader` response is checked for a result.
+    /// bitcoin-cli fails if the node does not have the block.
+    fn skip_if_known(&mut self, submission: &BlockSubmission) {
+        let () = self.flush_batch();
+        self.skip_guard = false;
+        let getblockheader = self.rpc_command(&RpcCall::new(
+            "getblockheader",
+            [submission.block.block_hash().to_string()],
+        ));
+        let getblockheader = self.render_command(&getblockheader);
+        let condition = match self.rpc_client {
+            RpcClient::Curl => {
+                self.uses.block_known = true;
+                format!(
+                    "response=$({getblockheader}) || exit {}\n\
+                     if block_known \"$response\"; then",
+                    FailureClass::Connection.exit_code()
+                )
+            }
+            RpcClient::BitcoinCli => {
+                format!("if {getblockheader} >/dev/null 2>&1; then")
+            }
+        };
+        let snippet = format!(
+            "skip_block=0\n\
+             {condition}\n    \
+                 skip_block=1\n    \
+                 printf 'skipping %s, which the node already has\\n' {} >&2\n\
+             fi",
+            quote(submission.description)
+        );
+        self.push_step(
+            &format!(
+                "checking whether the node already has {}",
+                submission.description
+            ),
+            snippet,
+            None,
+        );
+        self.skip_guard = true;
+    }
+
+    fn submitblock(&mut self, submission: &BlockSubmission) {
+        if submission.hex_file.is_some() || self.batch_size == 1 {
+            let () = self.flush_batch();
+            let submitblock = self.submitblock_command(submission);
+            self.submitblock_unchecked_step(
+                submitblock,
+                submission.description,
+                &submission.summary(),
+            )
+        } else {
+            self.push_batch(BatchedSubmitblock::new(submission, false))
+        }
+    }
+
+    fn submitblock_checked(&mut self, submission: &BlockSubmission) {
+        let BlockSubmission {
+            block: _,
+            height: _,
+            spec: _,
+            expected,
+            description,
+            hex_file,
+        } = *submission;
+        if hex_file.is_some() || self.batch_size == 1 {
+            let () = self.flush_batch();
+            let submitblock = self.submitblock_command(submission);
+            self.submitblock_checked_step(
+                submitblock,
+                expected,
+                description,
+                &submission.summary(),
+            )
+        } else {
+            self.push_batch(BatchedSubmitblock::new(submission, true))
+        }
+    }
+
+    fn check_tip(&mut self, submission: &BlockSubmission) {
+        let () = self.flush_batch();
+        let BlockSubmission {
+            block,
+            height: _,
+            spec: _,
+            expected,
+            description,
+            hex_file: _,
+        } = *submission;
+        let getbestblockhash =
+            self.rpc_command(&RpcCall::new("getbestblockhash", ()));
+        let snippet = [
+            self.capture_result(getbestblockhash, "tip", description),
+            format!(
+                "check_tip \"$tip\" {} {} {}",
+                verdict_arg(expected),
+                block.block_hash(),
+                quote(description)
+            ),
+        ]
+        .join("\n");
+        self.uses.check_tip = true;
+        self.push_step(
+            &format!("checking the tip after {description}"),
+            snippet,
+            None,
+        )
+    }
+
+    fn command_capture(
+        &mut self,
+        var: &ScriptVar,
+        call: &RpcCall,
+        description: &str,
+    ) {
+        let () = self.flush_batch();
+        let command = self.rpc_command(call);
+        let snippet = self.capture_result(command, var.name(), description);
+        self.push_step(&format!("requesting {description}"), snippet, None)
+    }
+
+    fn check_captured(
+        &mut self,
+        var: &ScriptVar,
+        expected: &str,
+        description: &str,
+    ) {
+        let () = self.flush_batch();
+        let snippet = format!(
+            "check_captured \"${}\" {} {}",
+            var.name(),
+            quote(expected),
+            quote(description)
+        );
+        self.uses.check_captured = true;
+        self.push_step(&format!("checking {description}"), snippet, None)
+    }
+
+    fn check_enforcer(&mut self, check: &EnforcerCheck, description: &str) {
+        let () = self.flush_batch();
+        let snippet = match check {
+            EnforcerCheck::Proposal {
+                slot,
+                description_hash,
+                acks,
+            } => {
+                self.uses.check_enforcer_proposal = true;
+                format!(
+                    "check_enforcer_proposal {slot} {description_hash} {acks} \
+                     {}",
+                    quote(description)
+                )
+            }
+            EnforcerCheck::SidechainActive { slot, active } => {
+                self.uses.check_enforcer_active = true;
+                format!(
+                    "check_enforcer_active {slot} {active} {}",
+                    quote(description)
+                )
+            }
+            EnforcerCheck::NoCtip { slot } => {
+                self.uses.check_enforcer_no_ctip = true;
+                format!("check_enforcer_no_ctip {slot} {}", quote(description))
+            }
+            EnforcerCheck::NotTip { block_hash } => {
+                self.uses.check_enforcer_not_tip = true;
+                format!(
+                    "check_enforcer_not_tip {block_hash} {}",
+                    quote(description)
+                )
+            }
+        };
+        self.push_step(
+            &format!("checking the enforcer state after {description}"),
+            snippet,
+            None,
+        )
+    }
+
+    fn summary(&mut self, counts: &SubmissionCounts) {
+        let SubmissionCounts {
+            n_blocks,
+            n_expect_accept,
+            n_expect_reject,
+        } = *counts;
+        self.skip_guard = false;
+        let final_tip = ScriptVar::new("FINAL_TIP").unwrap();
+        let final_height = ScriptVar::new("FINAL_HEIGHT").unwrap();
+        self.command_capture(
+            &final_tip,
+            &RpcCall::new("getbestblockhash", ()),
+            "the node's tip",
+        );
+        self.command_capture(
+            &final_height,
+            &RpcCall::new("getblockcount", ()),
+            "the node's block count",
+        );
+        let mut lines = vec![
+            "printf 'Summary:\\n' >&2".to_owned(),
+            format!("printf '  Blocks submitted:        %s\\n' {n_blocks} >&2"),
+            format!(
+                "printf '  Expected to be accepted: %s\\n' {n_expect_accept} \
+                 >&2"
+            ),
+            format!(
+                "printf '  Expected to be rejected: %s\\n' {n_expect_reject} \
+                 >&2"
+            ),
+            format!(
+                "printf '  Node tip:                %s (height %s)\\n' \
+                 \"${}\" \"${}\" >&2",
+                final_tip.name(),
+                final_height.name()
+            ),
         ];
-        let () = self.command("curl", args);
+        if self
+            .variables
+            .iter()
+            .any(|(var_name, _)| var_name == "ENFORCER_URL")
+        {
+            self.uses.print_enforcer_tip = true;
+            lines.push("print_enforcer_tip".to_owned());
+        }
+        self.push_step("printing a summary", lines.join("\n"), None)
+    }
+
+    /// Pending `submitblock` requests are flushed first, so that their
+    /// batch is included
+    fn take_request_bodies(&mut self) -> Vec<RequestBody> {
+        let () = self.flush_batch();
+        std::mem::take(&mut self.request_bodies)
+    }
+
+    /// Render a buffered script. A discarded script is rendered without its
+    /// items.
+    fn finalize(mut self) -> String {
+        let () = self.flush_batch();
+        let items = match std::mem::replace(&mut self.items, Items::Discarded) {
+            Items::Buffered(items) => items,
+            Items::Discarded => VecDeque::new(),
+            Items::Streamed(_) => {
+                panic!("streamed scripts are finished with `finish_stream`")
+            }
+        };
+        let prelude = self.prelude();
+        let (mut res, head_items) = self.head(prelude);
+        let items = head_items.into_iter().chain(items).collect();
+        res.push_str(&render_items(items, self.render_opts));
+        res
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+    use serde_json::json;
+
+    use super::{
+        render_arg, OutputPosixScriptBuilder, BLOCK_KNOWN_FN,
+        BLOCK_KNOWN_JQ_FN, RPC_RESULT_FN, RPC_RESULT_JQ_FN,
+    };
+    use crate::{
+        cli::{CurlOpts, RpcClient, RpcOpts, Verdict},
+        metrics,
+        script_builder::{
+            Arg, BlockSubmission, FailureClass, ScriptBuilder as _,
+        },
+    };
 
-    /// RPC request for `submitblock`
-    pub fn submitblock(&mut self, block: &Block) {
-        self.curl_rpc(
-            "submitblock",
-            [bitcoin::consensus::serialize(block).to_lower_hex_string()],
+    /// String with single and double quotes, `$`, spaces and newlines
+    const AWKWARD: &str = "it's \"$HOME\" \\n\n$(id)";
+
+    /// Print `rendered` with a POSIX shell, with `DIR` set to `/tmp/a b`
+    fn sh_printf(rendered: &str) -> String {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("DIR='/tmp/a b'; printf '%s' {rendered}"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(render_arg(&Arg::literal("-v")), "-v");
+        // Literals are not quoted, even if they should be
+        assert_eq!(render_arg(&Arg::literal("$a b")), "$a b");
+    }
+
+    #[test]
+    fn quoted() {
+        let rendered = render_arg(&Arg::quoted(AWKWARD));
+        assert_eq!(rendered, "'it'\"'\"'s \"$HOME\" \\n\n$(id)'");
+        assert_eq!(sh_printf(&rendered), AWKWARD);
+        assert_eq!(render_arg(&Arg::quoted("")), "''");
+    }
+
+    #[test]
+    fn nested_quotes() {
+        // Single quote inside double quotes, and the reverse
+        for (s, expected) in [
+            ("\"it's\"", "'\"it'\"'\"'s\"'"),
+            ("'say \"hi\"'", "''\"'\"'say \"hi\"'\"'\"''"),
+        ] {
+            let rendered = render_arg(&Arg::quoted(s));
+            assert_eq!(rendered, expected);
+            assert_eq!(sh_printf(&rendered), s);
+            let body = json!([s]);
+            let rendered = render_arg(&Arg::Body(body.clone()));
+            let printed: serde_json::Value =
+                serde_json::from_str(&sh_printf(&rendered)).unwrap();
+            assert_eq!(printed, body);
+        }
+    }
+
+    #[test]
+    fn variable() {
+        let rendered = render_arg(&Arg::Variable("DIR".to_owned()));
+        assert_eq!(rendered, "\"$DIR\"");
+        assert_eq!(sh_printf(&rendered), "/tmp/a b");
+    }
+
+    #[test]
+    fn file_path() {
+        let rendered = render_arg(&Arg::FilePath {
+            dir_var: "DIR".to_owned(),
+            file_name: AWKWARD.to_owned(),
+        });
+        assert_eq!(rendered, "\"$DIR\"/'it'\"'\"'s \"$HOME\" \\n\n$(id)'");
+        assert_eq!(sh_printf(&rendered), format!("/tmp/a b/{AWKWARD}"));
+    }
+
+    #[test]
+    fn body() {
+        let value = json!([AWKWARD, 1, {"a": null}]);
+        let rendered = render_arg(&Arg::Body(value.clone()));
+        assert_eq!(
+            rendered,
+            r#"'["it'"'"'s \"$HOME\" \\n\n$(id)",1,{"a":null}]'"#
+        );
+        let printed = sh_printf(&rendered);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&printed).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn opt() {
+        let rendered =
+            render_arg(&Arg::opt("rpcpassword", Arg::quoted(AWKWARD)));
+        assert_eq!(rendered, "-rpcpassword='it'\"'\"'s \"$HOME\" \\n\n$(id)'");
+        assert_eq!(sh_printf(&rendered), format!("-rpcpassword={AWKWARD}"));
+        let rendered = render_arg(&Arg::opt(
+            "rpcconnect",
+            Arg::Variable("DIR".to_owned()),
+        ));
+        assert_eq!(rendered, "-rpcconnect=\"$DIR\"");
+        assert_eq!(sh_printf(&rendered), "-rpcconnect=/tmp/a b");
+    }
+
+    /// Three distinct regtest blocks, descriptions of them, and their hex
+    fn three_blocks() -> Vec<(bitcoin::Block, String, String)> {
+        (0..3)
+            .map(|n| {
+                let mut block = bitcoin::constants::genesis_block(
+                    bitcoin::Network::Regtest,
+                );
+                block.header.nonce += n;
+                let hex = metrics::block_hex(&block);
+                (block, format!("block {n}"), hex)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_of_three() {
+        let rpc = RpcOpts::try_parse_from([
+            "bip300-enforcer-demo",
+            "--rpc-url",
+            "http://127.0.0.1:18443",
+        ])
+        .unwrap();
+        let mut script_builder = OutputPosixScriptBuilder::new(
+            rpc,
+            RpcClient::Curl,
+            bitcoin::Network::Regtest,
+        );
+        script_builder.batch_size(3);
+        script_builder.quiet(true);
+        let blocks = three_blocks();
+        for (block, description, _) in &blocks {
+            script_builder.submitblock_checked(&BlockSubmission {
+                block,
+                height: 0,
+                spec: None,
+                expected: Verdict::Accept,
+                description,
+                hex_file: None,
+            });
+        }
+        let hexes: Vec<_> = blocks.iter().map(|(_, _, hex)| hex).collect();
+        // Taking the request bodies sends the batch
+        let request_bodies = script_builder.take_request_bodies();
+        let [request_body] = request_bodies.as_slice() else {
+            panic!("expected 1 request body, got {request_bodies:?}");
+        };
+        assert_eq!(request_body.method, "submitblock");
+        let body: serde_json::Value =
+            serde_json::from_str(&request_body.body).unwrap();
+        let expected_body: Vec<_> = hexes
+            .iter()
+            .enumerate()
+            .map(|(id, hex)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": "submitblock",
+                    "params": [hex],
+                })
+            })
+            .collect();
+        assert_eq!(body, json!(expected_body));
+        let script = script_builder.finalize();
+        let expected_step = format!(
+            "response=$(rpc_batch submitblock '\"{}\"' '\"{}\"' '\"{}\"') \
+             || exit 10\n\
+             result=$(batch_result \"$response\" 0 'block 0') || exit\n\
+             check_submitblock \"$result\" accept 'block 0'\n\
+             result=$(batch_result \"$response\" 1 'block 1') || exit\n\
+             check_submitblock \"$result\" accept 'block 1'\n\
+             result=$(batch_result \"$response\" 2 'block 2') || exit\n\
+             check_submitblock \"$result\" accept 'block 2'\n",
+            hexes[0], hexes[1], hexes[2]
+        );
+        assert!(script.contains(&expected_step), "{script}");
+    }
+
+    /// Script submitting the genesis block, with the RPC and curl options
+    fn script_with_rpc_opts(args: &[&str], curl_opts: CurlOpts) -> String {
+        let rpc = RpcOpts::try_parse_from(
+            std::iter::once("bip300-enforcer-demo").chain(args.iter().copied()),
+        )
+        .unwrap();
+        let mut script_builder = OutputPosixScriptBuilder::new(
+            rpc,
+            RpcClient::Curl,
+            bitcoin::Network::Regtest,
+        );
+        script_builder.curl_opts(curl_opts);
+        let (block, description, _) = three_blocks().remove(0);
+        script_builder.submitblock_checked(&BlockSubmission {
+            block: &block,
+            height: 0,
+            spec: None,
+            expected: Verdict::Accept,
+            description: &description,
+            hex_file: None,
+        });
+        script_builder.finalize()
+    }
+
+    #[test]
+    fn rpc_url_schemes() {
+        let curl = "rpc_output=$(curl \"$1\" --silent --show-error";
+        for (args, url, tls) in [
+            (&["--rpc-url", "http://127.0.0.1:18443"][..], "http", ""),
+            (
+                &[
+                    "--rpc-url",
+                    "https://node.example:443",
+                    "--rpc-cacert",
+                    "/etc/ssl/node ca.pem",
+                ][..],
+                "https",
+                " --cacert \"$RPC_CACERT\"",
+            ),
+            (
+                &["--rpc-url", "https://node.example:443", "--rpc-insecure"][..],
+                "https",
+                " --insecure",
+            ),
+        ] {
+            let script = script_with_rpc_opts(args, CurlOpts::default());
+            let host = if url == "http" {
+                "127.0.0.1:18443"
+            } else {
+                "node.example"
+            };
+            assert!(
+                script.contains(&format!(
+                    "RPC_URL=${{RPC_URL:-'{url}://{host}/'}}\n"
+                )),
+                "{script}"
+            );
+            assert!(
+                script.contains(&format!("{curl}{tls} \\\n")),
+                "{args:?}:\n{script}"
+            );
+            assert_eq!(
+                script.contains(
+                    "RPC_CACERT=${RPC_CACERT:-'/etc/ssl/node ca.pem'}\n"
+                ),
+                args.contains(&"--rpc-cacert"),
+                "{script}"
+            );
+        }
+    }
+    #[test]
+    fn curl_flags() {
+        let args = ["--rpc-url", "http://127.0.0.1:18443"];
+        let rpc_post = |curl_opts| {
+            let script = script_with_rpc_opts(&args, curl_opts);
+            let start = script.find("rpc_post() {").unwrap();
+            let end = start + script[start..].find("\n}\n").unwrap();
+            script[start..end].to_owned()
+        };
+        let expected = |fail_with_body| {
+            format!(
+                "    rpc_output=$(curl \"$1\" --silent --show-error \\\n        \
+                 --connect-timeout 10 --retry 3 \
+                 --retry-connrefused{fail_with_body} \\\n        \
+                 -H 'Content-Type: application/json' \\\n        \
+                 --user \"$RPC_AUTH\" \\\n        \
+                 --max-time 120 \\\n        \
+                 --write-out '\\n%{{http_code}}' \\\n        \
+                 --data-binary \"$2\") || rpc_status=$?\n"
+            )
+        };
+        let rendered = rpc_post(CurlOpts::default());
+        assert!(
+            rendered.contains(&expected(" --fail-with-body")),
+            "{rendered}"
+        );
+        let rendered = rpc_post(CurlOpts {
+            no_curl_fail_with_body: true,
+            ..CurlOpts::default()
+        });
+        assert!(rendered.contains(&expected("")), "{rendered}");
+    }
+
+    /// An HTTP error, eg. a 401 for incorrect credentials, fails `rpc_post`
+    /// with curl's exit code, printing the status and response body
+    #[test]
+    fn rpc_post_http_error() {
+        let script = script_with_rpc_opts(
+            &["--rpc-url", "http://127.0.0.1:18443"],
+            CurlOpts::default(),
+        );
+        let start = script.find("rpc_post() {").unwrap();
+        let end = start + script[start..].find("\n}\n").unwrap() + 2;
+        // curl with `--fail-with-body` prints the body, then the status from
+        // `--write-out`, and exits with 22
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "curl() {{ printf 'Unauthorized\\n401'; return 22; }}\n\
+                 {}\n\
+                 rpc_post http://127.0.0.1:18443/ '{{}}'",
+                &script[start..end]
+            ))
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(22), "{output:?}");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(
+            stderr,
+            "HTTP status 401 from http://127.0.0.1:18443/\n\
+             Response body: Unauthorized\n"
+        );
+    }
+
+    /// Hash of the regtest genesis block, as returned in the fixtures
+    const GENESIS_HASH: &str =
+        "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+
+    /// Bitcoin Core response in `testdata/core_responses`, without the
+    /// trailing newline, which command substitution strips
+    fn core_response(name: &str) -> String {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/core_responses")
+            .join(name);
+        std::fs::read_to_string(path).unwrap().trim_end().to_owned()
+    }
+
+    /// Run `call` with a POSIX shell, with the shell function `function`
+    /// defined, and `RESPONSE` set to `response`. If `hide_jq` is `true`,
+    /// `command -v` fails, so that the grep/sed fallback is used.
+    /// Returns the exit code and stdout.
+    fn run_with_response(
+        function: &str,
+        call: &str,
+        response: &str,
+        hide_jq: bool,
+    ) -> (i32, String) {
+        let hide_jq = if hide_jq {
+            "command() { return 1; }\n"
+        } else {
+            ""
+        };
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "{hide_jq}{}\n{call}",
+                FailureClass::substitute(function)
+            ))
+            .env("RESPONSE", response)
+            .output()
+            .unwrap();
+        (
+            output.status.code().unwrap(),
+            String::from_utf8(output.stdout).unwrap(),
         )
     }
+
+    /// Outcomes of `call` with the jq-only function, and with the function
+    /// with a fallback, both with jq and with the fallback, which must agree
+    fn run_all_paths(
+        jq_fn: &str,
+        fallback_fn: &str,
+        call: &str,
+        response: &str,
+    ) -> (i32, String) {
+        let outcome = run_with_response(jq_fn, call, response, false);
+        for hide_jq in [false, true] {
+            assert_eq!(
+                run_with_response(fallback_fn, call, response, hide_jq),
+                outcome,
+                "{call} with {response}, jq hidden: {hide_jq}"
+            );
+        }
+        outcome
+    }
+
+    #[test]
+    fn rpc_result_core_responses() {
+        let exit_rpc_error = FailureClass::RpcError.exit_code().into();
+        for (name, expected) in [
+            ("submitblock_accept.json", (0, "")),
+            ("submitblock_accept.v2.json", (0, "")),
+            ("submitblock_reject.json", (0, "high-hash")),
+            ("submitblock_reject.v2.json", (0, "bad-txnmrklroot")),
+            ("submitblock_duplicate.json", (0, "duplicate")),
+            ("submitblock_error.json", (exit_rpc_error, "")),
+            ("submitblock_error.v2.json", (exit_rpc_error, "")),
+            ("getbestblockhash.json", (0, GENESIS_HASH)),
+        ] {
+            let (code, stdout) = run_all_paths(
+                RPC_RESULT_JQ_FN,
+                RPC_RESULT_FN,
+                "rpc_result \"$RESPONSE\" 'block 0'",
+                &core_response(name),
+            );
+            assert_eq!((code, stdout.as_str()), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn block_known_core_responses() {
+        for (name, expected) in [
+            ("getblockheader_known.json", 0),
+            ("getblockheader_unknown.json", 1),
+        ] {
+            let (code, _) = run_all_paths(
+                BLOCK_KNOWN_JQ_FN,
+                BLOCK_KNOWN_FN,
+                "block_known \"$RESPONSE\"",
+                &core_response(name),
+            );
+            assert_eq!(code, expected, "{name}");
+        }
+    }
 }
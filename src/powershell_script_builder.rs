@@ -0,0 +1,1341 @@
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use bitcoin::BlockHash;
+
+use crate::{
+    cli::{wallet_endpoint_path, RpcCredentials, RpcOpts, Verdict},
+    enforcer_checks::{
+        EnforcerCheck, ENFORCER_CHECK_ATTEMPTS, VALIDATOR_SERVICE,
+    },
+    script_builder::{
+        indent, render_items, verdict_arg, Arg, BatchedSubmitblock,
+        BlockSubmission, Command, Comment, FailureClass, HexFilesDir,
+        RenderOpts, RpcCall, RpcIds, ScriptBuilder, ScriptItem, ScriptMeta,
+        ScriptVar, SubmissionCounts, WALLET_METHODS,
+    },
+};
+
+/// Characters that PowerShell treats as single quotes
+const SINGLE_QUOTES: &[char] =
+    &['\'', '\u{2018}', '\u{2019}', '\u{201a}', '\u{201b}'];
+
+/// Quote a string as a PowerShell single-quoted string literal, within
+/// which no characters are special. Single quotes, including the
+/// typographic quotes that PowerShell also treats as single quotes, are
+/// escaped by doubling them.
+fn quote(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('\'');
+    for c in s.chars() {
+        if SINGLE_QUOTES.contains(&c) {
+            res.push(c);
+        }
+        res.push(c);
+    }
+    res.push('\'');
+    res
+}
+
+/// Comment with line breaks normalized. PowerShell also ends comments at
+/// carriage returns, which would otherwise end a comment line early.
+fn comment_lines(comment: String) -> Comment {
+    Comment(comment.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// Render a JSON value as a PowerShell expression. Arrays are rendered
+/// with the unary comma operator, so that they are not flattened into the
+/// enclosing array.
+fn json_literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "$null".to_owned(),
+        serde_json::Value::Bool(true) => "$true".to_owned(),
+        serde_json::Value::Bool(false) => "$false".to_owned(),
+        serde_json::Value::Number(number) => number.to_string(),
+        serde_json::Value::String(s) => quote(s),
+        serde_json::Value::Array(values) => {
+            let values: Vec<_> = values.iter().map(json_literal).collect();
+            format!(",@({})", values.join(", "))
+        }
+        serde_json::Value::Object(_) => {
+            format!(
+                "(ConvertFrom-Json -InputObject {})",
+                quote(&value.to_string())
+            )
+        }
+    }
+}
+
+/// Render a command argument as a PowerShell argument. JSON values are
+/// rendered as expressions, and arrays at the top level are rendered
+/// without the unary comma operator.
+fn render_arg(arg: &Arg) -> String {
+    match arg {
+        Arg::Literal(arg) => arg.clone(),
+        Arg::Quoted(arg) => quote(arg),
+        Arg::Variable(name) => format!("${name}"),
+        Arg::FilePath { dir_var, file_name } => {
+            format!("(Join-Path ${dir_var} {})", quote(file_name))
+        }
+        Arg::Body(serde_json::Value::Array(values)) => {
+            let values: Vec<_> = values.iter().map(json_literal).collect();
+            format!("@({})", values.join(", "))
+        }
+        Arg::Body(value) => json_literal(value),
+        Arg::Opt { name, value } => format!("-{name}:{}", render_arg(value)),
+    }
+}
+
+/// Render a command. PowerShell commands are not wrapped.
+fn render_command(command: &Command) -> String {
+    command.render(0, render_arg)
+}
+
+/// Function that sends a JSON-RPC request body to a URL, and returns the
+/// parsed response, including for error responses.
+/// `{timeout_sec}` is replaced by the RPC timeout, and `{tls}` by TLS
+/// options.
+/// PowerShell 6+ refuses to send credentials over HTTP unless explicitly
+/// allowed.
+/// Usage: `Send-Rpc <uri> <body>`
+const SEND_RPC_FN: &str = r#"function Send-Rpc {
+    param([string] $Uri, [string] $Body)
+    $IrmArgs = @{
+        Uri = $Uri
+        Method = 'Post'
+        ContentType = 'application/json'
+        Credential = $RpcCredential
+        TimeoutSec = {timeout_sec}
+        Body = $Body
+    }{tls}
+    if ($PSVersionTable.PSVersion.Major -ge 6) {
+        $IrmArgs.AllowUnencryptedAuthentication = $true
+    }
+    try {
+        Invoke-RestMethod @IrmArgs
+    } catch {
+        if ($_.ErrorDetails -and $_.ErrorDetails.Message) {
+            ConvertFrom-Json -InputObject $_.ErrorDetails.Message
+        } else {
+            Stop-Script {exit_connection} $_
+        }
+    }
+}"#;
+
+/// Function that sends a JSON-RPC request to the node, and returns the
+/// parsed response. Requests are sent to `$RPC_URL`, or to `-Path`,
+/// relative to `$RPC_URL`.
+/// `{id}` is replaced by the default request id.
+const INVOKE_RPC_FN: &str = r#"function Invoke-Rpc {
+    param(
+        [Parameter(Mandatory)] [string] $Method,
+        [object[]] $Params = @(),
+        [string] $Path,
+        [string] $Id = {id}
+    )
+    $Uri = if ($Path) { $RPC_URL.TrimEnd('/') + $Path } else { $RPC_URL }
+    $Request = @{
+        jsonrpc = '2.0'
+        id = $Id
+        method = $Method
+        params = $Params
+    }
+    Send-Rpc $Uri (ConvertTo-Json -InputObject $Request -Compress -Depth 16)
+}"#;
+
+/// Function that sends a batch of JSON-RPC requests for the same method to
+/// the node, and returns the parsed responses. Each element of `-Params` is
+/// the params for one request, which are numbered from 0, in order.
+const INVOKE_RPC_BATCH_FN: &str = r#"function Invoke-RpcBatch {
+    param(
+        [Parameter(Mandatory)] [string] $Method,
+        [object[]] $Params = @()
+    )
+    $Requests = @(for ($Id = 0; $Id -lt $Params.Count; $Id++) {
+        @{
+            jsonrpc = '2.0'
+            id = $Id
+            method = $Method
+            params = @($Params[$Id])
+        }
+    })
+    Send-Rpc $RPC_URL (ConvertTo-Json -InputObject $Requests -Compress -Depth 16)
+}"#;
+
+/// Function that returns the result of the JSON-RPC response with the
+/// specified id in a batch. Fails if there is no such response, or if the
+/// response is an error.
+/// Usage: `Get-BatchResult <responses> <id> <description>`
+const GET_BATCH_RESULT_FN: &str = r#"function Get-BatchResult {
+    param($Responses, [int] $Id, [string] $Description)
+    $Response = $Responses | Where-Object { $_.id -eq $Id }
+    if ($null -eq $Response) {
+        Stop-Script {exit_rpc_error} "no RPC response for $Description"
+    }
+    Get-RpcResult $Response $Description
+}"#;
+
+/// Function that returns the result of a JSON-RPC response. Fails if the
+/// response is an error.
+/// Usage: `Get-RpcResult <response> <description>`
+const GET_RPC_RESULT_FN: &str = r#"function Get-RpcResult {
+    param($Response, [string] $Description)
+    if ($null -ne $Response.error) {
+        $RpcError = ConvertTo-Json -InputObject $Response.error -Compress
+        Stop-Script {exit_rpc_error} "RPC request failed for ${Description}: $RpcError"
+    }
+    $Response.result
+}"#;
+
+/// Function that checks the result of a `submitblock` request, which is
+/// normalized as by `SubmitBlockResult::parse`. Invalid blocks on a side
+/// branch are not fully validated, so `inconclusive` is not a failure for
+/// blocks that are expected to be rejected.
+/// Usage: `Assert-SubmitBlock <result> <accept|reject|inconclusive>
+/// <description>`
+const ASSERT_SUBMITBLOCK_FN: &str = r#"function Assert-SubmitBlock {
+    param($Result, [string] $Expected, [string] $Description)
+    $SubmitResult = switch -exact ([string] $Result) {
+        '' { 'accepted' }
+        'duplicate' { 'duplicate' }
+        'duplicate-inconclusive' { 'duplicate-inconclusive' }
+        'inconclusive' { 'inconclusive' }
+        default { 'rejected' }
+    }
+    switch -wildcard ("${Expected}:$SubmitResult") {
+        'accept:accepted' {}
+        'reject:rejected' {}
+        'reject:inconclusive' {}
+        'reject:duplicate-inconclusive' {}
+        'inconclusive:inconclusive' {}
+        'accept:rejected' {
+            Stop-Script {exit_unexpected_reject} "expected $Description to be accepted, but it was rejected: $Result"
+        }
+        'accept:inconclusive' {
+            Stop-Script {exit_unexpected_reject} "expected $Description to be accepted, but it was stored on a side branch"
+        }
+        'accept:duplicate*' {
+            Stop-Script {exit_unexpected_reject} "expected $Description to be submitted for the first time, but the node already had it: $Result"
+        }
+        'inconclusive:duplicate*' {
+            Stop-Script {exit_unexpected_reject} "expected $Description to be submitted for the first time, but the node already had it: $Result"
+        }
+        'reject:accepted' {
+            Stop-Script {exit_unexpected_accept} "expected $Description to be rejected, but it was accepted"
+        }
+        'reject:duplicate' {
+            Stop-Script {exit_unexpected_accept} "expected $Description to be rejected, but the node already had it as a valid block"
+        }
+        'inconclusive:accepted' {
+            Stop-Script {exit_unexpected_accept} "expected $Description to be stored on a side branch, but it became the tip"
+        }
+        'inconclusive:rejected' {
+            Stop-Script {exit_unexpected_reject} "expected $Description to be stored on a side branch, but it was rejected: $Result"
+        }
+    }
+}"#;
+
+/// Function that checks the tip after submitting a block. Accepted blocks
+/// must become the tip, and rejected or side branch blocks must not.
+/// Usage: `Assert-Tip <tip> <accept|reject|inconclusive> <block hash>
+/// <description>`
+const ASSERT_TIP_FN: &str = r#"function Assert-Tip {
+    param($Tip, [string] $Expected, [string] $BlockHash, [string] $Description)
+    switch ($Expected) {
+        'accept' {
+            if ($Tip -ne $BlockHash) {
+                Stop-Script {exit_tip_mismatch} "expected $Description to become the tip, but the tip is $Tip"
+            }
+        }
+        'reject' {
+            if ($Tip -eq $BlockHash) {
+                Stop-Script {exit_tip_mismatch} "expected $Description to be rejected, but it became the tip"
+            }
+        }
+        'inconclusive' {
+            if ($Tip -eq $BlockHash) {
+                Stop-Script {exit_tip_mismatch} "expected $Description to be stored on a side branch, but it became the tip"
+            }
+        }
+    }
+}"#;
+
+/// Function that checks a value captured by an earlier step.
+/// Usage: `Assert-Captured <value> <expected> <description>`
+const ASSERT_CAPTURED_FN: &str = r#"function Assert-Captured {
+    param($Value, [string] $Expected, [string] $Description)
+    if ($Value -ne $Expected) {
+        Stop-Script {exit_check_failed} "expected $Description to be $Expected, but it is $Value"
+    }
+}"#;
+
+/// Function that sends a request to the enforcer's gRPC-gateway endpoint,
+/// and returns the parsed response.
+/// `{service}` is replaced by the gRPC service, and `{timeout_sec}` by the
+/// RPC timeout.
+/// Usage: `Invoke-EnforcerRpc <method>`
+const INVOKE_ENFORCER_RPC_FN: &str = r#"function Invoke-EnforcerRpc {
+    param([Parameter(Mandatory)] [string] $Method)
+    $IrmArgs = @{
+        Uri = $ENFORCER_URL.TrimEnd('/') + '/{service}/' + $Method
+        Method = 'Post'
+        ContentType = 'application/json'
+        TimeoutSec = {timeout_sec}
+        Body = '{}'
+    }
+    try {
+        Invoke-RestMethod @IrmArgs
+    } catch {
+        Stop-Script {exit_connection} $_
+    }
+}"#;
+
+/// Function that checks that the enforcer has a pending sidechain proposal
+/// with the expected number of acks, retrying while the enforcer catches
+/// up. Skipped if `$ENFORCER_CHECKS` is `0`.
+/// `{attempts}` is replaced by the number of attempts.
+/// Usage: `Assert-EnforcerProposal <slot> <description hash> <acks> <description>`
+const ASSERT_ENFORCER_PROPOSAL_FN: &str = r#"function Assert-EnforcerProposal {
+    param([int] $Slot, [string] $DescriptionHash, [int] $Acks, [string] $Description)
+    if ($ENFORCER_CHECKS -eq '0') {
+        return
+    }
+    for ($Attempt = 1; ; $Attempt++) {
+        $Response = Invoke-EnforcerRpc 'GetSidechainProposals'
+        $Proposal = @($Response.sidechainProposals) | Where-Object {
+            $_.sidechainNumber -eq $Slot -and $_.descriptionSha256dHash.hex -eq $DescriptionHash
+        } | Select-Object -First 1
+        $ProposalAcks = if ($null -eq $Proposal) { $null } elseif ($Proposal.voteCount) { [int] $Proposal.voteCount } else { 0 }
+        if ($ProposalAcks -eq $Acks) {
+            return
+        }
+        if ($Attempt -ge {attempts}) {
+            $Found = if ($null -eq $ProposalAcks) { 'no proposal' } else { $ProposalAcks }
+            Stop-Script {exit_enforcer_mismatch} "expected the enforcer to have a proposal for sidechain $Slot with $Acks acks after $Description, but found: $Found"
+        }
+        Start-Sleep -Seconds 1
+    }
+}"#;
+
+/// Function that checks whether the sidechain in a slot is active, retrying
+/// while the enforcer catches up. Skipped if `$ENFORCER_CHECKS` is `0`.
+/// `{attempts}` is replaced by the number of attempts.
+/// Usage: `Assert-EnforcerActive <slot> <$true|$false> <description>`
+const ASSERT_ENFORCER_ACTIVE_FN: &str = r#"function Assert-EnforcerActive {
+    param([int] $Slot, [bool] $Active, [string] $Description)
+    if ($ENFORCER_CHECKS -eq '0') {
+        return
+    }
+    for ($Attempt = 1; ; $Attempt++) {
+        $Response = Invoke-EnforcerRpc 'GetSidechains'
+        $Sidechain = @($Response.sidechains) | Where-Object {
+            [int] $_.sidechainNumber -eq $Slot
+        } | Select-Object -First 1
+        $IsActive = $null -ne $Sidechain
+        if ($IsActive -eq $Active) {
+            return
+        }
+        if ($Attempt -ge {attempts}) {
+            Stop-Script {exit_enforcer_mismatch} "expected sidechain $Slot to be active: $Active after $Description, but found: $IsActive"
+        }
+        Start-Sleep -Seconds 1
+    }
+}"#;
+
+/// Function that checks that a sidechain's treasury has no CTIP. Skipped if
+/// `$ENFORCER_CHECKS` is `0`. An HTTP error response counts as no CTIP,
+/// since the enforcer may have no treasury for an inactive sidechain.
+/// `{timeout_sec}` is replaced by the RPC timeout.
+/// Usage: `Assert-EnforcerNoCtip <slot> <description>`
+const ASSERT_ENFORCER_NO_CTIP_FN: &str = r#"function Assert-EnforcerNoCtip {
+    param([int] $Slot, [string] $Description)
+    if ($ENFORCER_CHECKS -eq '0') {
+        return
+    }
+    $IrmArgs = @{
+        Uri = $ENFORCER_URL.TrimEnd('/') + '/{service}/GetCtip'
+        Method = 'Post'
+        ContentType = 'application/json'
+        TimeoutSec = {timeout_sec}
+        Body = "{""sidechainNumber"": $Slot}"
+    }
+    try {
+        $Response = Invoke-RestMethod @IrmArgs
+    } catch {
+        # Set for HTTP error responses, but not for connection failures
+        if ($null -ne $_.Exception.Response) {
+            return
+        }
+        Stop-Script {exit_connection} $_
+    }
+    if ($null -ne $Response.ctip) {
+        Stop-Script {exit_enforcer_mismatch} "expected sidechain $Slot to have no CTIP after $Description, but found: $($Response.ctip.txid.hex):$($Response.ctip.vout)"
+    }
+}"#;
+
+/// Function that checks that a rejected block is not the enforcer's chain
+/// tip. Skipped if `$ENFORCER_CHECKS` is `0`.
+/// Usage: `Assert-EnforcerNotTip <block hash> <description>`
+const ASSERT_ENFORCER_NOT_TIP_FN: &str = r#"function Assert-EnforcerNotTip {
+    param([string] $BlockHash, [string] $Description)
+    if ($ENFORCER_CHECKS -eq '0') {
+        return
+    }
+    $Response = Invoke-EnforcerRpc 'GetChainTip'
+    if ($Response.blockHeaderInfo.blockHash.hex -eq $BlockHash) {
+        Stop-Script {exit_enforcer_mismatch} "expected $Description to be rejected, but it became the enforcer tip"
+    }
+}"#;
+
+/// Function that prints the enforcer's tip to stderr, as part of the
+/// summary. Skipped if `$ENFORCER_CHECKS` is `0`.
+const WRITE_ENFORCER_TIP_FN: &str = r#"function Write-EnforcerTip {
+    if ($ENFORCER_CHECKS -eq '0') {
+        return
+    }
+    $Response = Invoke-EnforcerRpc 'GetChainTip'
+    [Console]::Error.WriteLine("  Enforcer tip:            $($Response.blockHeaderInfo.blockHash.hex)")
+}"#;
+
+/// Function that prints an error message to stderr, and exits with the
+/// specified exit code.
+/// Usage: `Stop-Script <exit code> <message>`
+const STOP_SCRIPT_FN: &str = r#"function Stop-Script {
+    param([int] $ExitCode, [string] $Message)
+    [Console]::Error.WriteLine($Message)
+    exit $ExitCode
+}"#;
+
+/// Function that prints a progress message to stderr before a step.
+/// `{n_steps}` is replaced by the number of steps.
+/// Usage: `Write-Step <step> <label>`
+const WRITE_STEP_FN: &str = r#"function Write-Step {
+    param([int] $Step, [string] $Label)
+    [Console]::Error.WriteLine(">>> [$Step/{n_steps}] $Label")
+}"#;
+
+#[derive(Debug)]
+pub struct PowerShellScriptBuilder {
+    rpc: RpcOpts,
+    /// Header comment for the preamble. If not set, the script is emitted
+    /// without a shebang or error handling settings.
+    preamble: Option<Comment>,
+    /// Variables assigned at the top of the script, as names and default
+    /// expressions. Each variable can be overridden by the environment
+    /// variable of the same name.
+    variables: Vec<(String, String)>,
+    /// Variables holding the hashes of labelled blocks, assigned after the
+    /// settings variables. Unlike settings, they cannot be overridden.
+    block_hashes: Vec<(ScriptVar, BlockHash)>,
+    script: VecDeque<ScriptItem>,
+    /// Maximum number of `submitblock` requests to send in each JSON-RPC
+    /// batch
+    batch_size: usize,
+    /// `submitblock` requests waiting to be sent
+    batch: Vec<BatchedSubmitblock>,
+    /// `true` if progress messages are not printed before each step
+    quiet: bool,
+    /// Number of steps in the script, if progress messages are printed
+    n_steps: usize,
+    rpc_ids: RpcIds,
+    render_opts: RenderOpts,
+    /// `true` if steps are skipped when `$SkipBlock` is set, ie. the steps
+    /// for a block that the node may already have
+    skip_guard: bool,
+    /// `true` IFF the script uses `Invoke-Rpc`
+    uses_invoke_rpc: bool,
+    /// `true` IFF the script uses `Invoke-RpcBatch`
+    uses_invoke_rpc_batch: bool,
+    /// `true` IFF the script uses `Get-BatchResult`
+    uses_get_batch_result: bool,
+    /// `true` IFF the script uses `Get-RpcResult`
+    uses_get_rpc_result: bool,
+    /// `true` IFF the script uses `Assert-SubmitBlock`
+    uses_assert_submitblock: bool,
+    /// `true` IFF the script uses `Assert-Tip`
+    uses_assert_tip: bool,
+    /// `true` IFF the script uses `Assert-Captured`
+    uses_assert_captured: bool,
+    /// `true` IFF the script uses `Assert-EnforcerProposal`
+    uses_assert_enforcer_proposal: bool,
+    /// `true` IFF the script uses `Assert-EnforcerActive`
+    uses_assert_enforcer_active: bool,
+    /// `true` IFF the script uses `Assert-EnforcerNoCtip`
+    uses_assert_enforcer_no_ctip: bool,
+    /// `true` IFF the script uses `Assert-EnforcerNotTip`
+    uses_assert_enforcer_not_tip: bool,
+    /// `true` IFF the script uses `Write-EnforcerTip`
+    uses_write_enforcer_tip: bool,
+}
+
+impl PowerShellScriptBuilder {
+    pub fn new(rpc: RpcOpts) -> Self {
+        let mut res = Self {
+            rpc,
+            preamble: None,
+            variables: Vec::new(),
+            block_hashes: Vec::new(),
+            script: VecDeque::new(),
+            batch_size: 1,
+            batch: Vec::new(),
+            quiet: false,
+            n_steps: 0,
+            rpc_ids: RpcIds::default(),
+            render_opts: RenderOpts::default(),
+            skip_guard: false,
+            uses_invoke_rpc: false,
+            uses_invoke_rpc_batch: false,
+            uses_get_batch_result: false,
+            uses_get_rpc_result: false,
+            uses_assert_submitblock: false,
+            uses_assert_tip: false,
+            uses_assert_captured: false,
+            uses_assert_enforcer_proposal: false,
+            uses_assert_enforcer_active: false,
+            uses_assert_enforcer_no_ctip: false,
+            uses_assert_enforcer_not_tip: false,
+            uses_write_enforcer_tip: false,
+        };
+        let () = res.declare_rpc_variables();
+        res
+    }
+
+    /// Send up to `batch_size` consecutive `submitblock` requests in each
+    /// JSON-RPC batch request. Batches are split by any other step.
+    pub fn batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Do not print progress messages before each step
+    pub fn quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Set the ids for JSON-RPC requests that are not batched
+    pub fn rpc_ids(&mut self, rpc_ids: RpcIds) {
+        self.rpc_ids = rpc_ids;
+    }
+
+    /// Set whether comments are omitted, and whether the output is compact
+    pub fn render_opts(&mut self, render_opts: RenderOpts) {
+        self.render_opts = render_opts;
+    }
+
+    /// Declare a variable at the top of the script. The default expression
+    /// is evaluated unless the environment variable of the same name is
+    /// set.
+    pub fn variable(&mut self, name: &str, default: String) {
+        debug_assert!(
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid variable name `{name}`"
+        );
+        self.variables.push((name.to_owned(), default));
+    }
+
+    /// Declare variables for the RPC connection settings. `RPC_AUTH` is
+    /// `<user>:<password>`, as in a cookie file.
+    fn declare_rpc_variables(&mut self) {
+        let endpoint = self.rpc.endpoint(None);
+        self.variable("RPC_URL", quote(endpoint.as_str()));
+        let auth = match &self.rpc.rpc_auth.rpc_cookie {
+            Some(cookie_path) => format!(
+                "(Get-Content -Raw -LiteralPath {}).Trim()",
+                quote(&cookie_path.to_string_lossy())
+            ),
+            None if self.rpc.rpc_auth.rpc_auth_env => {
+                "\"${env:RPC_USER}:${env:RPC_PASS}\"".to_owned()
+            }
+            None => {
+                let RpcCredentials { user, pass } = self.rpc.user_pass();
+                quote(&format!("{user}:{pass}"))
+            }
+        };
+        self.variable("RPC_AUTH", auth);
+    }
+
+    /// Add statements to the script as a step. Unless the script is quiet,
+    /// steps are numbered, and the label is printed to stderr before the
+    /// step.
+    fn push_step(&mut self, label: &str, body: String) {
+        let snippet = if self.quiet {
+            body
+        } else {
+            self.n_steps += 1;
+            format!("Write-Step {} {}\n{body}", self.n_steps, quote(label))
+        };
+        let snippet = self.guarded(&snippet);
+        self.script.push_back(ScriptItem::Snippet(snippet));
+    }
+
+    /// Statements that are skipped if `$SkipBlock` is set, if steps are
+    /// guarded
+    fn guarded(&self, body: &str) -> String {
+        if self.skip_guard {
+            format!("if (-not $SkipBlock) {{\n{}\n}}", indent(body))
+        } else {
+            body.to_owned()
+        }
+    }
+
+    /// Wallet to send the RPC request to, if any
+    fn wallet(&self, call: &RpcCall) -> Option<&str> {
+        self.rpc
+            .rpc_wallet
+            .as_deref()
+            .filter(|_| WALLET_METHODS.contains(&call.method.as_str()))
+    }
+
+    /// `Invoke-Rpc` command to send an RPC request to the node.
+    /// Wallet RPC requests are sent to the wallet endpoint, if a wallet was
+    /// specified.
+    fn rpc_command(&mut self, call: &RpcCall) -> Command {
+        self.uses_invoke_rpc = true;
+        let mut args =
+            vec![Arg::literal("-Method"), Arg::quoted(call.method.as_str())];
+        if !call.params.is_empty() {
+            args.push(Arg::literal("-Params"));
+            args.push(Arg::Body(serde_json::Value::Array(call.params.clone())));
+        }
+        if let Some(wallet) = self.wallet(call) {
+            args.push(Arg::literal("-Path"));
+            args.push(Arg::Quoted(wallet_endpoint_path(wallet)));
+        }
+        if let Some(id) = self.rpc_ids.next() {
+            args.push(Arg::literal("-Id"));
+            args.push(Arg::Quoted(id));
+        }
+        Command {
+            command: "Invoke-Rpc".to_owned(),
+            args,
+            stdin: None,
+        }
+    }
+
+    /// `Invoke-Rpc` command to send a `submitblock` request for the block,
+    /// reading the block's hex from its hex file, if any
+    fn submitblock_command(&mut self, submission: &BlockSubmission) -> Command {
+        let Some(hex_file) = submission.hex_file else {
+            return self.rpc_command(&RpcCall::submitblock(submission.block));
+        };
+        let Command {
+            command,
+            mut args,
+            stdin,
+        } = self.rpc_command(&RpcCall::new("submitblock", ()));
+        let hex_file = Arg::FilePath {
+            dir_var: "BLOCKS_DIR".to_owned(),
+            file_name: hex_file.to_owned(),
+        };
+        args.push(Arg::literal("-Params"));
+        args.push(Arg::Literal(format!(
+            "@((Get-Content -Raw -LiteralPath {}))",
+            render_arg(&hex_file)
+        )));
+        Command {
+            command,
+            args,
+            stdin,
+        }
+    }
+
+    /// Statements that send an RPC request to the node, and assign its
+    /// result to the variable `var`, failing if the request fails.
+    /// `description` is used in error messages.
+    fn capture_result(
+        &mut self,
+        command: Command,
+        var: &str,
+        description: &str,
+    ) -> String {
+        self.uses_get_rpc_result = true;
+        format!(
+            "$Response = {}\n\
+             ${var} = Get-RpcResult $Response {}",
+            render_command(&command),
+            quote(description)
+        )
+    }
+
+    /// `submitblock` request, failing the script if the node returns an
+    /// error, or if the block is not accepted or rejected as expected
+    fn submitblock_checked_step(
+        &mut self,
+        submitblock: Command,
+        expected: Verdict,
+        description: &str,
+        summary: &str,
+    ) {
+        let snippet = [
+            self.capture_result(submitblock, "Result", description),
+            format!(
+                "Assert-SubmitBlock $Result {} {}",
+                quote(verdict_arg(expected)),
+                quote(description)
+            ),
+        ]
+        .join("\n");
+        self.uses_assert_submitblock = true;
+        self.push_step(&format!("submitting {summary}"), snippet)
+    }
+
+    /// Add a `submitblock` request to the pending batch, and send the batch
+    /// if it is full
+    fn push_batch(&mut self, batched: BatchedSubmitblock) {
+        self.batch.push(batched);
+        if self.batch.len() >= self.batch_size {
+            let () = self.flush_batch();
+        }
+    }
+
+    /// Send the pending `submitblock` requests. A single pending request is
+    /// sent on its own.
+    fn flush_batch(&mut self) {
+        let batch = std::mem::take(&mut self.batch);
+        if !batch.is_empty() {
+            let contexts: Vec<_> = batch
+                .iter()
+                .map(|batched| batched.context.as_str())
+                .collect();
+            self.script.push_back(ScriptItem::Comment(comment_lines(
+                contexts.join("\n"),
+            )));
+        }
+        match batch.as_slice() {
+            [] => (),
+            [BatchedSubmitblock {
+                call,
+                expected: None,
+                description: _,
+                summary,
+                context: _,
+            }] => {
+                let submitblock = self.rpc_command(call);
+                self.push_step(
+                    &format!("submitting {summary}"),
+                    render_command(&submitblock),
+                )
+            }
+            [BatchedSubmitblock {
+                call,
+                expected: Some(expected),
+                description,
+                summary,
+                context: _,
+            }] => {
+                let submitblock = self.rpc_command(call);
+                self.submitblock_checked_step(
+                    submitblock,
+                    *expected,
+                    description,
+                    summary,
+                )
+            }
+            [first, .., last] => {
+                self.uses_invoke_rpc_batch = true;
+                // Each request's params, as an array
+                let params = batch
+                    .iter()
+                    .map(|batched| {
+                        serde_json::Value::Array(batched.call.params.clone())
+                    })
+                    .collect();
+                let command = Command {
+                    command: "Invoke-RpcBatch".to_owned(),
+                    args: vec![
+                        Arg::literal("-Method"),
+                        Arg::quoted(first.call.method.as_str()),
+                        Arg::literal("-Params"),
+                        Arg::Body(serde_json::Value::Array(params)),
+                    ],
+                    stdin: None,
+                };
+                let checks: Vec<_> = batch
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(id, batched)| {
+                        let expected = batched.expected?;
+                        let description = quote(&batched.description);
+                        Some(format!(
+                            "$Result = Get-BatchResult $Responses {id} \
+                             {description}\n\
+                             Assert-SubmitBlock $Result {} {description}",
+                            quote(verdict_arg(expected))
+                        ))
+                    })
+                    .collect();
+                let label = format!(
+                    "submitting a batch of {} blocks, ending with {}",
+                    batch.len(),
+                    last.description
+                );
+                if checks.is_empty() {
+                    return self.push_step(&label, render_command(&command));
+                }
+                self.uses_get_rpc_result = true;
+                self.uses_get_batch_result = true;
+                self.uses_assert_submitblock = true;
+                let snippet = std::iter::once(format!(
+                    "$Responses = {}",
+                    render_command(&command)
+                ))
+                .chain(checks)
+                .collect::<Vec<_>>()
+                .join("\n");
+                self.push_step(&label, snippet)
+            }
+        }
+    }
+}
+
+impl ScriptBuilder for PowerShellScriptBuilder {
+    /// Prepend a preamble to the script: a shebang for `pwsh`, the
+    /// specified header comment followed by the exit codes, and
+    /// `$ErrorActionPreference = 'Stop'`, so that the script exits on the
+    /// first failure
+    fn preamble(&mut self, meta: &ScriptMeta) {
+        self.preamble =
+            Some(comment_lines(format!("{meta}\n{}", FailureClass::doc())));
+    }
+
+    fn comment<S>(&mut self, comment: S)
+    where
+        String: From<S>,
+    {
+        let () = self.flush_batch();
+        self.script
+            .push_back(ScriptItem::Comment(comment_lines(comment.into())))
+    }
+
+    /// Blocks that are sent in a batch are commented on when the batch is
+    /// sent, so that comments do not split batches.
+    /// Ends the steps that are skipped by [`Self::skip_if_known`].
+    fn block_comment(
+        &mut self,
+        comment: Option<&str>,
+        submission: &BlockSubmission,
+    ) {
+        self.skip_guard = false;
+        if submission.hex_file.is_some() || self.batch_size == 1 {
+            self.comment(submission.comment_with_context(comment))
+        } else if let Some(comment) = comment {
+            self.comment(comment)
+        }
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        let () = self.flush_batch();
+        let snippet = self.guarded(&format!(
+            "Start-Sleep -Milliseconds {}",
+            duration.as_millis()
+        ));
+        self.script.push_back(ScriptItem::Snippet(snippet));
+    }
+
+    fn hex_files_dir(&mut self, dir: &HexFilesDir) {
+        let dir = match dir {
+            HexFilesDir::ScriptRelative(dir) => format!(
+                "(Join-Path $PSScriptRoot {})",
+                quote(&dir.to_string_lossy())
+            ),
+            HexFilesDir::Absolute(dir) => quote(&dir.to_string_lossy()),
+        };
+        self.variable("BLOCKS_DIR", dir);
+    }
+
+    fn enforcer_addr(&mut self, addr: SocketAddr) {
+        self.variable("ENFORCER_URL", quote(&format!("http://{addr}")));
+        self.variable("ENFORCER_CHECKS", quote("1"));
+    }
+
+    fn block_hash_var(&mut self, var: &ScriptVar, block_hash: BlockHash) {
+        self.block_hashes.push((var.clone(), block_hash));
+    }
+
+    fn skip_if_known(&mut self, submission: &BlockSubmission) {
+        let () = self.flush_batch();
+        self.skip_guard = false;
+        let getblockheader = self.rpc_command(&RpcCall::new(
+            "getblockheader",
+            [submission.block.block_hash().to_string()],
+        ));
+        let snippet = format!(
+            "$Response = {}\n\
+             $SkipBlock = $null -ne $Response.result\n\
+             if ($SkipBlock) {{\n    \
+                 [Console]::Error.WriteLine({})\n\
+             }}",
+            render_command(&getblockheader),
+            quote(&format!(
+                "skipping {}, which the node already has",
+                submission.description
+            ))
+        );
+        self.push_step(
+            &format!(
+                "checking whether the node already has {}",
+                submission.description
+            ),
+            snippet,
+        );
+        self.skip_guard = true;
+    }
+
+    fn submitblock(&mut self, submission: &BlockSubmission) {
+        if submission.hex_file.is_some() || self.batch_size == 1 {
+            let () = self.flush_batch();
+            let submitblock = self.submitblock_command(submission);
+            self.push_step(
+                &format!("submitting {}", submission.summary()),
+                render_command(&submitblock),
+            )
+        } else {
+            self.push_batch(BatchedSubmitblock::new(submission, false))
+        }
+    }
+
+    fn submitblock_checked(&mut self, submission: &BlockSubmission) {
+        let BlockSubmission {
+            block: _,
+            height: _,
+            spec: _,
+            expected,
+            description,
+            hex_file,
+        } = *submission;
+        if hex_file.is_some() || self.batch_size == 1 {
+            let () = self.flush_batch();
+            let submitblock = self.submitblock_command(submission);
+            self.submitblock_checked_step(
+                submitblock,
+                expected,
+                description,
+                &submission.summary(),
+            )
+        } else {
+            self.push_batch(BatchedSubmitblock::new(submission, true))
+        }
+    }
+
+    fn check_tip(&mut self, submission: &BlockSubmission) {
+        let () = self.flush_batch();
+        let BlockSubmission {
+            block,
+            height: _,
+            spec: _,
+            expected,
+            description,
+            hex_file: _,
+        } = *submission;
+        let getbestblockhash =
+            self.rpc_command(&RpcCall::new("getbestblockhash", ()));
+        let snippet = [
+            self.capture_result(getbestblockhash, "Tip", description),
+            format!(
+                "Assert-Tip $Tip {} {} {}",
+                quote(verdict_arg(expected)),
+                quote(&block.block_hash().to_string()),
+                quote(description)
+            ),
+        ]
+        .join("\n");
+        self.uses_assert_tip = true;
+        self.push_step(
+            &format!("checking the tip after {description}"),
+            snippet,
+        )
+    }
+
+    fn command_capture(
+        &mut self,
+        var: &ScriptVar,
+        call: &RpcCall,
+        description: &str,
+    ) {
+        let () = self.flush_batch();
+        let command = self.rpc_command(call);
+        let snippet = self.capture_result(command, var.name(), description);
+        self.push_step(&format!("requesting {description}"), snippet)
+    }
+
+    fn check_captured(
+        &mut self,
+        var: &ScriptVar,
+        expected: &str,
+        description: &str,
+    ) {
+        let () = self.flush_batch();
+        let snippet = format!(
+            "Assert-Captured ${} {} {}",
+            var.name(),
+            quote(expected),
+            quote(description)
+        );
+        self.uses_assert_captured = true;
+        self.push_step(&format!("checking {description}"), snippet)
+    }
+
+    fn check_enforcer(&mut self, check: &EnforcerCheck, description: &str) {
+        let () = self.flush_batch();
+        let snippet = match check {
+            EnforcerCheck::Proposal {
+                slot,
+                description_hash,
+                acks,
+            } => {
+                self.uses_assert_enforcer_proposal = true;
+                format!(
+                    "Assert-EnforcerProposal {slot} {} {acks} {}",
+                    quote(description_hash),
+                    quote(description)
+                )
+            }
+            EnforcerCheck::SidechainActive { slot, active } => {
+                self.uses_assert_enforcer_active = true;
+                format!(
+                    "Assert-EnforcerActive {slot} ${active} {}",
+                    quote(description)
+                )
+            }
+            EnforcerCheck::NoCtip { slot } => {
+                self.uses_assert_enforcer_no_ctip = true;
+                format!("Assert-EnforcerNoCtip {slot} {}", quote(description))
+            }
+            EnforcerCheck::NotTip { block_hash } => {
+                self.uses_assert_enforcer_not_tip = true;
+                format!(
+                    "Assert-EnforcerNotTip {} {}",
+                    quote(&block_hash.to_string()),
+                    quote(description)
+                )
+            }
+        };
+        self.push_step(
+            &format!("checking the enforcer state after {description}"),
+            snippet,
+        )
+    }
+
+    fn summary(&mut self, counts: &SubmissionCounts) {
+        let SubmissionCounts {
+            n_blocks,
+            n_expect_accept,
+            n_expect_reject,
+        } = *counts;
+        self.skip_guard = false;
+        let final_tip = ScriptVar::new("FINAL_TIP").unwrap();
+        let final_height = ScriptVar::new("FINAL_HEIGHT").unwrap();
+        self.command_capture(
+            &final_tip,
+            &RpcCall::new("getbestblockhash", ()),
+            "the node's tip",
+        );
+        self.command_capture(
+            &final_height,
+            &RpcCall::new("getblockcount", ()),
+            "the node's block count",
+        );
+        let mut lines = vec![
+            "[Console]::Error.WriteLine('Summary:')".to_owned(),
+            format!(
+                "[Console]::Error.WriteLine('  Blocks submitted:        \
+                 {n_blocks}')"
+            ),
+            format!(
+                "[Console]::Error.WriteLine('  Expected to be accepted: \
+                 {n_expect_accept}')"
+            ),
+            format!(
+                "[Console]::Error.WriteLine('  Expected to be rejected: \
+                 {n_expect_reject}')"
+            ),
+            format!(
+                "[Console]::Error.WriteLine(\"  Node tip:                \
+                 ${} (height ${})\")",
+                final_tip.name(),
+                final_height.name()
+            ),
+        ];
+        if self
+            .variables
+            .iter()
+            .any(|(var_name, _)| var_name == "ENFORCER_URL")
+        {
+            self.uses_write_enforcer_tip = true;
+            lines.push("Write-EnforcerTip".to_owned());
+        }
+        self.push_step("printing a summary", lines.join("\n"))
+    }
+
+    fn finalize(mut self) -> String {
+        let () = self.flush_batch();
+        let blank_line = self.render_opts.blank_line();
+        let mut res = match &self.preamble {
+            Some(comment) => format!(
+                "#!/usr/bin/env pwsh\n{}$ErrorActionPreference = 'Stop'\n\
+                 {blank_line}",
+                self.render_opts.comment_line(comment)
+            ),
+            None => "".to_owned(),
+        };
+        if !self.variables.is_empty() {
+            res.push_str(&self.render_opts.comment_line(&Comment(
+                "Settings, which can be overridden by setting these variables \
+                 in the\nenvironment"
+                    .to_owned(),
+            )));
+            for (name, default) in &self.variables {
+                res.push_str(&format!(
+                    "${name} = if ($env:{name}) {{ $env:{name} }} else {{ \
+                     {default} }}\n"
+                ));
+            }
+            res.push_str(
+                "$RpcUser, $RpcPass = $RPC_AUTH -split ':', 2\n\
+                 $RpcSecurePass = [System.Net.NetworkCredential]::new(\
+                 '', $RpcPass).SecurePassword\n\
+                 $RpcCredential = New-Object \
+                 System.Management.Automation.PSCredential \
+                 -ArgumentList $RpcUser, $RpcSecurePass\n",
+            );
+            res.push_str(blank_line);
+        }
+        if !self.block_hashes.is_empty() {
+            res.push_str(&self.render_opts.comment_line(&Comment(
+                "Hashes of labelled blocks".to_owned(),
+            )));
+            for (var, block_hash) in &self.block_hashes {
+                res.push_str(&format!(
+                    "${} = {}\n",
+                    var.name(),
+                    quote(&block_hash.to_string())
+                ));
+            }
+            res.push_str(blank_line);
+        }
+        let mut functions = Vec::new();
+        if self.uses_invoke_rpc || self.uses_invoke_rpc_batch {
+            functions.push((
+                "Send a JSON-RPC request body, and return the response",
+                SEND_RPC_FN
+                    .replace("{timeout_sec}", &self.rpc.rpc_timeout.to_string())
+                    .replace(
+                        "{tls}",
+                        if self.rpc.rpc_insecure {
+                            "\n    # Requires PowerShell 6+\n    \
+                             $IrmArgs.SkipCertificateCheck = $true"
+                        } else {
+                            ""
+                        },
+                    ),
+            ));
+        }
+        if self.uses_invoke_rpc {
+            functions.push((
+                "Send a JSON-RPC request to the node, and return the response",
+                INVOKE_RPC_FN
+                    .replace("{id}", &quote(self.rpc_ids.default_id())),
+            ));
+        }
+        if self.uses_invoke_rpc_batch {
+            functions.push((
+                "Send a batch of JSON-RPC requests to the node, and return the \
+                 responses",
+                INVOKE_RPC_BATCH_FN.to_owned(),
+            ));
+        }
+        if self.uses_get_rpc_result {
+            functions.push((
+                "Return the result of a JSON-RPC response",
+                GET_RPC_RESULT_FN.to_owned(),
+            ));
+        }
+        if self.uses_get_batch_result {
+            functions.push((
+                "Return the result of a JSON-RPC response in a batch",
+                GET_BATCH_RESULT_FN.to_owned(),
+            ));
+        }
+        if self.uses_assert_submitblock {
+            functions.push((
+                "Check the result of a `submitblock` request",
+                ASSERT_SUBMITBLOCK_FN.to_owned(),
+            ));
+        }
+        if self.uses_assert_tip {
+            functions.push((
+                "Check that a submitted block did or did not become the tip",
+                ASSERT_TIP_FN.to_owned(),
+            ));
+        }
+        if self.uses_assert_captured {
+            functions.push((
+                "Check a value captured by an earlier step",
+                ASSERT_CAPTURED_FN.to_owned(),
+            ));
+        }
+        if self.uses_assert_enforcer_proposal
+            || self.uses_assert_enforcer_active
+            || self.uses_assert_enforcer_not_tip
+            || self.uses_write_enforcer_tip
+        {
+            functions.push((
+                "Send a request to the enforcer, and return the response",
+                INVOKE_ENFORCER_RPC_FN
+                    .replace("{service}", VALIDATOR_SERVICE)
+                    .replace(
+                        "{timeout_sec}",
+                        &self.rpc.rpc_timeout.to_string(),
+                    ),
+            ));
+        }
+        if self.uses_assert_enforcer_proposal {
+            functions.push((
+                "Check that the enforcer has a sidechain proposal with the \
+                 expected acks",
+                ASSERT_ENFORCER_PROPOSAL_FN.replace(
+                    "{attempts}",
+                    &ENFORCER_CHECK_ATTEMPTS.to_string(),
+                ),
+            ));
+        }
+        if self.uses_assert_enforcer_active {
+            functions.push((
+                "Check whether a sidechain is active",
+                ASSERT_ENFORCER_ACTIVE_FN.replace(
+                    "{attempts}",
+                    &ENFORCER_CHECK_ATTEMPTS.to_string(),
+                ),
+            ));
+        }
+        if self.uses_assert_enforcer_no_ctip {
+            functions.push((
+                "Check that a sidechain's treasury has no CTIP",
+                ASSERT_ENFORCER_NO_CTIP_FN
+                    .replace("{service}", VALIDATOR_SERVICE)
+                    .replace(
+                        "{timeout_sec}",
+                        &self.rpc.rpc_timeout.to_string(),
+                    ),
+            ));
+        }
+        if self.uses_assert_enforcer_not_tip {
+            functions.push((
+                "Check that a rejected block is not the enforcer's tip",
+                ASSERT_ENFORCER_NOT_TIP_FN.to_owned(),
+            ));
+        }
+        if self.uses_write_enforcer_tip {
+            functions.push((
+                "Print the enforcer's tip",
+                WRITE_ENFORCER_TIP_FN.to_owned(),
+            ));
+        }
+        // Every other function fails with `Stop-Script`
+        if !functions.is_empty() {
+            functions.push((
+                "Print an error message, and exit with an exit code",
+                STOP_SCRIPT_FN.to_owned(),
+            ));
+        }
+        if self.n_steps > 0 {
+            functions.push((
+                "Print a progress message before a step",
+                WRITE_STEP_FN.replace("{n_steps}", &self.n_steps.to_string()),
+            ));
+        }
+        for (comment, function) in functions.into_iter().rev() {
+            self.script.push_front(ScriptItem::Snippet(
+                FailureClass::substitute(&function),
+            ));
+            self.script
+                .push_front(ScriptItem::Comment(Comment(comment.to_owned())));
+        }
+        res.push_str(&render_items(self.script, self.render_opts));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::render_arg;
+    use crate::script_builder::Arg;
+
+    /// String with single, typographic and double quotes, `$`, spaces and
+    /// newlines
+    const AWKWARD: &str = "it's \u{2019}$HOME\u{2019} \"$x\"\n`$(id)";
+
+    /// [`AWKWARD`] as a single-quoted string literal
+    const AWKWARD_QUOTED: &str =
+        "'it''s \u{2019}\u{2019}$HOME\u{2019}\u{2019} \"$x\"\n`$(id)'";
+
+    #[test]
+    fn literal() {
+        assert_eq!(render_arg(&Arg::literal("-Force")), "-Force");
+        // Literals are not quoted, even if they should be
+        assert_eq!(render_arg(&Arg::literal("$a b")), "$a b");
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(render_arg(&Arg::quoted(AWKWARD)), AWKWARD_QUOTED);
+        assert_eq!(render_arg(&Arg::quoted("")), "''");
+    }
+
+    #[test]
+    fn nested_quotes() {
+        // Single quote inside double quotes, and the reverse. Double quotes
+        // are not special in single-quoted strings.
+        assert_eq!(render_arg(&Arg::quoted("\"it's\"")), "'\"it''s\"'");
+        assert_eq!(
+            render_arg(&Arg::quoted("'say \"hi\"'")),
+            "'''say \"hi\"'''"
+        );
+        assert_eq!(
+            render_arg(&Arg::Body(json!(["\"it's\"", "'say \"hi\"'"]))),
+            "@('\"it''s\"', '''say \"hi\"''')"
+        );
+    }
+
+    #[test]
+    fn variable() {
+        let rendered = render_arg(&Arg::Variable("HexDir".to_owned()));
+        assert_eq!(rendered, "$HexDir");
+    }
+
+    #[test]
+    fn file_path() {
+        let rendered = render_arg(&Arg::FilePath {
+            dir_var: "HexDir".to_owned(),
+            file_name: AWKWARD.to_owned(),
+        });
+        assert_eq!(rendered, format!("(Join-Path $HexDir {AWKWARD_QUOTED})"));
+    }
+
+    #[test]
+    fn body() {
+        // Top-level arrays are not wrapped with the unary comma operator,
+        // but nested arrays are
+        let rendered =
+            render_arg(&Arg::Body(json!([AWKWARD, 1, [true, null], false])));
+        assert_eq!(
+            rendered,
+            format!("@({AWKWARD_QUOTED}, 1, ,@($true, $null), $false)")
+        );
+        let rendered = render_arg(&Arg::Body(json!({"a": "it's $x\n"})));
+        assert_eq!(
+            rendered,
+            r#"(ConvertFrom-Json -InputObject '{"a":"it''s $x\n"}')"#
+        );
+        assert_eq!(render_arg(&Arg::Body(json!("a b"))), "'a b'");
+    }
+
+    #[test]
+    fn opt() {
+        let rendered = render_arg(&Arg::opt("Body", Arg::quoted(AWKWARD)));
+        assert_eq!(rendered, format!("-Body:{AWKWARD_QUOTED}"));
+        let rendered =
+            render_arg(&Arg::opt("Uri", Arg::Variable("RpcUrl".to_owned())));
+        assert_eq!(rendered, "-Uri:$RpcUrl");
+    }
+}
@@ -0,0 +1,16 @@
+//! Messages and the `ValidatorService` client generated from the enforcer's
+//! proto definitions in `proto/`
+
+pub mod cusf {
+    pub mod common {
+        pub mod v1 {
+            tonic::include_proto!("cusf.common.v1");
+        }
+    }
+
+    pub mod mainchain {
+        pub mod v1 {
+            tonic::include_proto!("cusf.mainchain.v1");
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use bitcoin::{Block, BlockHash, CompactTarget, Network};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{BlockSpec, BlocksSpec, SidechainDescription, Verdict},
+    Anchor, Setup, WalletFunding,
+};
+
+/// Version of the recording format. Must be incremented whenever the format
+/// changes in a way that is not backwards-compatible.
+pub const RECORDING_VERSION: u32 = 1;
+
+/// Name of the manifest file in a recording directory
+const MANIFEST_FILE_NAME: &str = "recording.json";
+
+/// Inputs to a recorded run, other than the blocks spec, that are needed to
+/// generate the same blocks again
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingManifest {
+    pub version: u32,
+    pub network: Network,
+    pub seed: u64,
+    /// Height of the first recorded block
+    pub height: u32,
+    /// Block that the recorded blocks build on, which must be the node's
+    /// tip when they are replayed
+    pub prev_blockhash: BlockHash,
+    pub target: CompactTarget,
+    pub setup: Setup,
+    pub wallet_funding: Option<WalletFunding>,
+    pub coinbase_address: Option<String>,
+    pub sidechain_slot: u8,
+    pub sidechain_description: SidechainDescription,
+    pub n_blocks: usize,
+}
+
+impl RecordingManifest {
+    /// Anchor for the first recorded block
+    pub fn anchor(&self) -> Anchor {
+        Anchor {
+            height: self.height,
+            prev_blockhash: self.prev_blockhash,
+            target: self.target,
+        }
+    }
+}
+
+/// Metadata for a recorded block, written alongside its raw bytes
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordedBlockMeta {
+    pub hash: BlockHash,
+    pub height: u32,
+    /// Block spec that the block was generated from. `None` for setup
+    /// blocks.
+    pub spec: Option<BlockSpec>,
+    /// Expected response from the node
+    pub expect: Verdict,
+}
+
+/// Version of a manifest, read before the rest of the manifest, so that
+/// recordings with other versions are reported as such
+#[derive(Deserialize)]
+struct ManifestVersion {
+    version: u32,
+}
+
+/// Blocks recorded with `--record`, in the order they were generated
+pub struct Recording {
+    pub manifest: RecordingManifest,
+    pub blocks: Vec<(Block, RecordedBlockMeta)>,
+}
+
+/// Paths of the raw bytes and metadata of the recorded block at `idx`
+fn block_paths(dir: &Path, idx: usize) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{idx:06}.block")),
+        dir.join(format!("{idx:06}.json")),
+    )
+}
+
+/// Write blocks to a new recording in `dir`, which is created if it does not
+/// exist. Fails if `dir` already contains a recording.
+pub fn record<'a, Blocks>(
+    dir: &Path,
+    manifest: &RecordingManifest,
+    blocks: Blocks,
+) -> anyhow::Result<()>
+where
+    Blocks: IntoIterator<Item = (&'a Block, RecordedBlockMeta)>,
+{
+    let manifest_path = dir.join(MANIFEST_FILE_NAME);
+    if manifest_path.exists() {
+        anyhow::bail!(
+            "`{}` already contains a recording. Delete it, or record to \
+             another directory.",
+            dir.display()
+        );
+    }
+    let () = std::fs::create_dir_all(dir).with_context(|| {
+        format!("failed to create recording directory `{}`", dir.display())
+    })?;
+    for (idx, (block, meta)) in blocks.into_iter().enumerate() {
+        let (block_path, meta_path) = block_paths(dir, idx);
+        std::fs::write(&block_path, bitcoin::consensus::serialize(block))
+            .with_context(|| {
+                format!("failed to write `{}`", block_path.display())
+            })?;
+        let meta_json = serde_json::to_string_pretty(&meta)?;
+        std::fs::write(&meta_path, format!("{meta_json}\n")).with_context(
+            || format!("failed to write `{}`", meta_path.display()),
+        )?;
+    }
+    // Written last, so that an interrupted recording is not replayed
+    let manifest_json = serde_json::to_string_pretty(manifest)?;
+    std::fs::write(&manifest_path, format!("{manifest_json}\n")).with_context(
+        || format!("failed to write `{}`", manifest_path.display()),
+    )
+}
+
+impl Recording {
+    /// Read a recording written by [`record`]
+    pub fn read(dir: &Path) -> anyhow::Result<Self> {
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        let manifest_json = std::fs::read_to_string(&manifest_path)
+            .with_context(|| {
+                format!(
+                    "failed to read `{}`. Is `{}` a directory written by \
+                     `--record`?",
+                    manifest_path.display(),
+                    dir.display()
+                )
+            })?;
+        let invalid = || {
+            format!("invalid recording manifest `{}`", manifest_path.display())
+        };
+        let ManifestVersion { version } =
+            serde_json::from_str(&manifest_json).with_context(invalid)?;
+        if version != RECORDING_VERSION {
+            anyhow::bail!(
+                "recording `{}` has version {version}, but only version \
+                 {RECORDING_VERSION} is supported",
+                dir.display()
+            );
+        }
+        let manifest: RecordingManifest =
+            serde_json::from_str(&manifest_json).with_context(invalid)?;
+        let blocks = (0..manifest.n_blocks)
+            .map(|idx| {
+                let (block_path, meta_path) = block_paths(dir, idx);
+                let block_bytes =
+                    std::fs::read(&block_path).with_context(|| {
+                        format!("failed to read `{}`", block_path.display())
+                    })?;
+                let block: Block =
+                    bitcoin::consensus::deserialize(&block_bytes)
+                        .with_context(|| {
+                            format!(
+                                "invalid recorded block `{}`",
+                                block_path.display()
+                            )
+                        })?;
+                let meta_json = std::fs::read_to_string(&meta_path)
+                    .with_context(|| {
+                        format!("failed to read `{}`", meta_path.display())
+                    })?;
+                let meta: RecordedBlockMeta = serde_json::from_str(&meta_json)
+                    .with_context(|| {
+                        format!(
+                            "invalid recorded block metadata `{}`",
+                            meta_path.display()
+                        )
+                    })?;
+                if block.block_hash() != meta.hash {
+                    anyhow::bail!(
+                        "recorded block `{}` has hash {}, but its metadata \
+                         in `{}` has hash {}",
+                        block_path.display(),
+                        block.block_hash(),
+                        meta_path.display(),
+                        meta.hash
+                    );
+                }
+                Ok((block, meta))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { manifest, blocks })
+    }
+
+    /// Blocks spec that the recorded blocks were generated from
+    pub fn blocks_spec(&self) -> BlocksSpec {
+        BlocksSpec(
+            self.blocks
+                .iter()
+                .filter_map(|(_, meta)| meta.spec.clone())
+                .collect(),
+        )
+    }
+}
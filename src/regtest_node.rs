@@ -0,0 +1,164 @@
+use std::{
+    io::Read as _,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use bip300301::jsonrpsee::core::{client::ClientT as _, params::ArrayParams};
+use url::Url;
+
+use crate::{cli::RpcOpts, rpc_client};
+
+/// RPC user and pass for the node
+const RPC_CREDENTIALS: &str = "selftest";
+
+/// How long to wait for the node's RPC server to become ready
+const RPC_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A throwaway regtest node, spawned in a temporary datadir. The node is
+/// killed, and its datadir removed, when dropped.
+pub struct RegtestNode {
+    child: Child,
+    datadir: PathBuf,
+    rpc_port: u16,
+}
+
+impl RegtestNode {
+    /// Spawn `bitcoind`, which is looked up on the `PATH` if it is a bare
+    /// file name
+    pub fn spawn(bitcoind_path: &Path) -> anyhow::Result<Self> {
+        let datadir = std::env::temp_dir().join(format!(
+            "bip300-enforcer-demo-selftest-{}",
+            std::process::id()
+        ));
+        // Left over by a previous process with the same id
+        if datadir.exists() {
+            let () = std::fs::remove_dir_all(&datadir).with_context(|| {
+                format!("failed to remove `{}`", datadir.display())
+            })?;
+        }
+        let () = std::fs::create_dir_all(&datadir).with_context(|| {
+            format!("failed to create `{}`", datadir.display())
+        })?;
+        // The port is released before the node binds it, so another process
+        // could take it in between, in which case the node fails to start
+        let rpc_port = TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| listener.local_addr())
+            .context("failed to find a free port for the node's RPC server")?
+            .port();
+        let child = Command::new(bitcoind_path)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.display()))
+            .args([
+                "-server",
+                "-listen=0",
+                "-disablewallet",
+                "-printtoconsole=0",
+                "-rpcbind=127.0.0.1",
+                "-rpcallowip=127.0.0.1",
+            ])
+            .arg(format!("-rpcport={rpc_port}"))
+            .arg(format!("-rpcuser={RPC_CREDENTIALS}"))
+            .arg(format!("-rpcpassword={RPC_CREDENTIALS}"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                let _: std::io::Result<()> = std::fs::remove_dir_all(&datadir);
+                return Err(err).with_context(|| {
+                    format!(
+                        "failed to run `{}`. Pass `--bitcoind-path`, or add \
+                         bitcoind to the `PATH`.",
+                        bitcoind_path.display()
+                    )
+                });
+            }
+        };
+        tracing::info!(
+            pid = child.id(),
+            datadir = %datadir.display(),
+            rpc_port,
+            "Spawned regtest node"
+        );
+        Ok(Self {
+            child,
+            datadir,
+            rpc_port,
+        })
+    }
+
+    /// Point the RPC options at the node
+    pub fn apply(&self, rpc: &mut RpcOpts) {
+        rpc.rpc_url =
+            Url::parse(&format!("http://127.0.0.1:{}", self.rpc_port)).unwrap();
+        rpc.rpc_addr = None;
+        rpc.rpc_auth.rpc_auth_env = false;
+        rpc.rpc_auth.rpc_cookie = None;
+        rpc.rpc_auth.rpc_user = RPC_CREDENTIALS.to_owned();
+        rpc.rpc_auth.rpc_pass = RPC_CREDENTIALS.to_owned();
+        rpc.rpc_wallet = None;
+        rpc.rpc_cacert = None;
+        rpc.rpc_insecure = false;
+        rpc.bitcoin_datadir = None;
+    }
+
+    /// Wait for the node's RPC server to respond, failing if the node exits
+    /// first
+    pub async fn wait_for_rpc(&mut self, rpc: &RpcOpts) -> anyhow::Result<()> {
+        let client = rpc_client(rpc, None)?;
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                let mut stderr = String::new();
+                if let Some(child_stderr) = &mut self.child.stderr {
+                    let _: usize = child_stderr.read_to_string(&mut stderr)?;
+                }
+                anyhow::bail!(
+                    "regtest node exited with {status} before its RPC server \
+                     was ready: {}",
+                    stderr.trim()
+                );
+            }
+            match client
+                .request::<serde_json::Value, _>(
+                    "getblockchaininfo",
+                    ArrayParams::new(),
+                )
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(err) if start.elapsed() >= RPC_READY_TIMEOUT => {
+                    return Err(err).with_context(|| {
+                        format!(
+                            "regtest node's RPC server was not ready after \
+                             {RPC_READY_TIMEOUT:?}"
+                        )
+                    })
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+}
+
+impl Drop for RegtestNode {
+    fn drop(&mut self) {
+        // Killed rather than stopped, since the datadir is thrown away
+        if let Err(err) = self.child.kill() {
+            tracing::warn!("failed to kill regtest node: {err}");
+        }
+        let _: std::io::Result<_> = self.child.wait();
+        if let Err(err) = std::fs::remove_dir_all(&self.datadir) {
+            tracing::warn!(
+                "failed to remove regtest node datadir `{}`: {err}",
+                self.datadir.display()
+            );
+        }
+    }
+}
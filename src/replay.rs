@@ -0,0 +1,75 @@
+use crate::{
+    chain_params::ChainParams, cli::GenerateArgs, recording::RecordingManifest,
+};
+
+/// Check the args for `replay` against the recording, and take the
+/// recorded run's seed, coinbase address and sidechain
+pub fn check_replay_args(
+    args: &mut GenerateArgs,
+    manifest: &RecordingManifest,
+    chain_params: &ChainParams,
+) -> anyhow::Result<()> {
+    if args.state_file.is_some() {
+        anyhow::bail!("`--state-file` is not supported with `replay`");
+    }
+    if args.setup_cache.is_some() {
+        anyhow::bail!(
+            "`--setup-cache` is not supported with `replay`, which \
+             replays the recorded setup blocks"
+        );
+    }
+    if args.setup_via_generatetoaddress || args.fund_node_wallet.is_some() {
+        anyhow::bail!(
+            "`--setup-via-generatetoaddress` and `--fund-node-wallet` are \
+             not supported with `replay`, which replays the recorded \
+             setup blocks"
+        );
+    }
+    if args.coinbase_address.is_some()
+        || args.sidechain_slot.is_some()
+        || args.sidechain_description.is_some()
+        || args.sidechain_description_hex.is_some()
+    {
+        anyhow::bail!(
+            "`--coinbase-address` and the sidechain options are not \
+             supported with `replay`, which uses those of the recorded run"
+        );
+    }
+    if let Some(seed) = args.seed.filter(|seed| *seed != manifest.seed) {
+        anyhow::bail!(
+            "`--seed {seed}` was specified, but the recording was \
+             generated with seed {}",
+            manifest.seed
+        );
+    }
+    if manifest.network != chain_params.network {
+        anyhow::bail!(
+            "the recording is for network `{}`, rather than `{}`",
+            manifest.network.to_core_arg(),
+            chain_params.network.to_core_arg()
+        );
+    }
+    let recorded_anchor = manifest.anchor();
+    if args
+        .height
+        .is_some_and(|height| height != recorded_anchor.height)
+        || args.prev_blockhash.is_some_and(|prev_blockhash| {
+            prev_blockhash != recorded_anchor.prev_blockhash
+        })
+        || args.bits.is_some_and(|bits| bits != recorded_anchor.target)
+    {
+        anyhow::bail!(
+            "the specified anchor does not match the recording, whose \
+             first block builds on {} at height {}, with target {:08x}",
+            recorded_anchor.prev_blockhash,
+            recorded_anchor.height - 1,
+            recorded_anchor.target.to_consensus()
+        );
+    }
+    args.seed = Some(manifest.seed);
+    args.coinbase_address.clone_from(&manifest.coinbase_address);
+    args.sidechain_slot = Some(manifest.sidechain_slot);
+    args.sidechain_description_hex =
+        Some(manifest.sidechain_description.clone());
+    Ok(())
+}
@@ -0,0 +1,511 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use bitcoin::BlockHash;
+use serde::Serialize;
+
+use crate::{
+    cli::{BlockSpec, Verdict},
+    enforcer_checks::EnforcerClient,
+    recording::{RecordedBlockMeta, Recording},
+};
+
+/// Version of the comparison report. Must be incremented whenever the
+/// report changes in a way that is not backwards-compatible.
+pub const REPORT_VERSION: u32 = 1;
+
+/// Version of the failure report. Must be incremented whenever the report
+/// changes in a way that is not backwards-compatible.
+pub const FAILURE_REPORT_VERSION: u32 = 1;
+
+/// Identifiers that the enforcer reports BIP300 failures with, for each
+/// block spec field that makes a block invalid. Must be kept up to date with
+/// the enforcer, and with [`BlockSpec::reasons_invalid`]. The identifiers
+/// are not taken from the enforcer's source, so they must be checked against
+/// the enforcer version under test, as for
+/// [`crate::enforcer_checks::BLOCK_FAILURES_METHOD`].
+const ENFORCER_FAILURE_IDS: &[(&str, &str)] =
+    &[("duplicate_m2", "DuplicateM2")];
+
+/// Render rows as a plain text table, with left-aligned columns
+pub fn render_table<const N: usize>(
+    header: [&str; N],
+    rows: &[[String; N]],
+) -> String {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    let header = header.map(str::to_owned);
+    for row in std::iter::once(&header).chain(rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Name of a block in tables
+fn block_name(label: Option<&str>, height: u32) -> String {
+    match label {
+        Some(label) => format!("\"{label}\" ({height})"),
+        None => height.to_string(),
+    }
+}
+
+/// Verdicts of the node and enforcer for a block submitted directly
+#[derive(Debug, Serialize)]
+pub struct BlockComparison {
+    /// Position of the block in the submission order, starting at 1
+    pub index: usize,
+    pub hash: BlockHash,
+    pub height: u32,
+    pub label: Option<String>,
+    pub expected_verdict: Verdict,
+    pub node_verdict: Verdict,
+    /// Reason that the node rejected the block, if it did
+    pub node_reject_reason: Option<String>,
+    /// `Accept` if the block became the enforcer's tip. `None` if the node
+    /// rejected the block or stored it on a side branch, so that the
+    /// enforcer never saw it.
+    pub enforcer_verdict: Option<Verdict>,
+}
+
+impl BlockComparison {
+    /// `true` if the node accepted the block, but the enforcer did not
+    pub fn caught_by_enforcer(&self) -> bool {
+        self.node_verdict == Verdict::Accept
+            && self.enforcer_verdict == Some(Verdict::Reject)
+    }
+
+    /// `true` if the block was accepted or rejected as expected, by the
+    /// enforcer if it saw the block, and by the node otherwise
+    pub fn as_expected(&self) -> bool {
+        self.enforcer_verdict.unwrap_or(self.node_verdict)
+            == self.expected_verdict
+    }
+}
+
+/// Report comparing the node's and enforcer's verdicts for each block
+#[derive(Debug, Serialize)]
+pub struct ComparisonReport {
+    pub version: u32,
+    /// Seed used for randomness in the run
+    pub seed: u64,
+    pub blocks: Vec<BlockComparison>,
+    /// Number of blocks that the node accepted, but the enforcer did not
+    pub n_caught_by_enforcer: usize,
+    /// Number of blocks that were not accepted or rejected as expected
+    pub n_unexpected: usize,
+}
+
+impl ComparisonReport {
+    pub fn new(seed: u64, blocks: Vec<BlockComparison>) -> Self {
+        let n_caught_by_enforcer = blocks
+            .iter()
+            .filter(|block| block.caught_by_enforcer())
+            .count();
+        let n_unexpected =
+            blocks.iter().filter(|block| !block.as_expected()).count();
+        Self {
+            version: REPORT_VERSION,
+            seed,
+            blocks,
+            n_caught_by_enforcer,
+            n_unexpected,
+        }
+    }
+
+    /// Render the report as a plain text table, with a row for each block
+    pub fn table(&self) -> String {
+        const HEADER: [&str; 6] =
+            ["#", "Block", "Expected", "Node", "Enforcer", "Note"];
+        let verdict = |verdict: Option<Verdict>| match verdict {
+            Some(Verdict::Accept) => "accept",
+            Some(Verdict::Reject) => "reject",
+            Some(Verdict::Inconclusive) => "inconclusive",
+            None => "-",
+        };
+        let rows: Vec<[String; 6]> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let name = block_name(block.label.as_deref(), block.height);
+                let note = if !block.as_expected() {
+                    "UNEXPECTED"
+                } else if block.caught_by_enforcer() {
+                    "caught by enforcer"
+                } else {
+                    ""
+                };
+                [
+                    block.index.to_string(),
+                    name,
+                    verdict(Some(block.expected_verdict)).to_owned(),
+                    verdict(Some(block.node_verdict)).to_owned(),
+                    verdict(block.enforcer_verdict).to_owned(),
+                    note.to_owned(),
+                ]
+            })
+            .collect();
+        let mut table = render_table(HEADER, &rows);
+        table.push_str(&format!(
+            "\n{} blocks, {} caught by the enforcer, {} unexpected\n",
+            self.blocks.len(),
+            self.n_caught_by_enforcer,
+            self.n_unexpected
+        ));
+        table
+    }
+}
+
+/// BIP300 failures expected for a recorded block, and reported for it by
+/// the enforcer
+#[derive(Debug, Serialize)]
+pub struct BlockFailures {
+    /// Position of the block in the recording, starting at 1
+    pub index: usize,
+    pub hash: BlockHash,
+    pub height: u32,
+    pub label: Option<String>,
+    /// Failures expected from the block spec, as enforcer identifiers.
+    /// Block spec fields with no known identifier are kept as is.
+    pub expected: Vec<String>,
+    /// Failures reported by the enforcer. `None` if the enforcer has no
+    /// record of the block.
+    pub reported: Option<Vec<String>>,
+    /// Expected failures that the enforcer did not report
+    pub missing: Vec<String>,
+    /// Reported failures that were not expected
+    pub unexpected: Vec<String>,
+    /// Expected or reported failures that are not in the mapping between
+    /// block spec fields and enforcer identifiers
+    pub unknown: Vec<String>,
+}
+
+impl BlockFailures {
+    /// Compare the failures expected from a block spec, or none for setup
+    /// blocks, with those reported by the enforcer
+    pub fn new(
+        index: usize,
+        hash: BlockHash,
+        height: u32,
+        block_spec: Option<&BlockSpec>,
+        reported: Option<Vec<String>>,
+    ) -> Self {
+        let mut unknown = Vec::new();
+        let expected: Vec<String> = block_spec
+            .map(BlockSpec::reasons_invalid)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|reason| {
+                match ENFORCER_FAILURE_IDS
+                    .iter()
+                    .find(|(field, _)| *field == reason)
+                {
+                    Some((_, failure_id)) => (*failure_id).to_owned(),
+                    None => {
+                        unknown.push(reason.to_owned());
+                        reason.to_owned()
+                    }
+                }
+            })
+            .collect();
+        let reported_failures = reported.as_deref().unwrap_or_default();
+        let missing = match &reported {
+            Some(reported) => expected
+                .iter()
+                .filter(|failure| !reported.contains(failure))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let unexpected = reported_failures
+            .iter()
+            .filter(|failure| !expected.contains(failure))
+            .cloned()
+            .collect();
+        unknown.extend(
+            reported_failures
+                .iter()
+                .filter(|failure| {
+                    !ENFORCER_FAILURE_IDS
+                        .iter()
+                        .any(|(_, failure_id)| failure_id == failure)
+                })
+                .cloned(),
+        );
+        Self {
+            index,
+            hash,
+            height,
+            label: block_spec.and_then(|block_spec| block_spec.label.clone()),
+            expected,
+            reported,
+            missing,
+            unexpected,
+            unknown,
+        }
+    }
+
+    /// `true` if the enforcer reported exactly the expected failures, all
+    /// of which are known
+    pub fn as_expected(&self) -> bool {
+        self.reported.is_some()
+            && self.missing.is_empty()
+            && self.unexpected.is_empty()
+            && self.unknown.is_empty()
+    }
+}
+
+/// Report comparing the BIP300 failures expected for each recorded block
+/// with those reported by the enforcer
+#[derive(Debug, Serialize)]
+pub struct FailureReport {
+    pub version: u32,
+    pub blocks: Vec<BlockFailures>,
+    /// Number of blocks whose failures were not reported as expected
+    pub n_unexpected: usize,
+}
+
+impl FailureReport {
+    pub fn new(blocks: Vec<BlockFailures>) -> Self {
+        let n_unexpected =
+            blocks.iter().filter(|block| !block.as_expected()).count();
+        Self {
+            version: FAILURE_REPORT_VERSION,
+            blocks,
+            n_unexpected,
+        }
+    }
+
+    /// Render the report as a plain text table, with a row for each block.
+    /// Discrepancies are highlighted in the last column.
+    pub fn table(&self) -> String {
+        const HEADER: [&str; 5] =
+            ["#", "Block", "Expected", "Reported", "Note"];
+        let failures = |failures: &[String]| match failures {
+            [] => "-".to_owned(),
+            failures => failures.join(", "),
+        };
+        let rows: Vec<[String; 5]> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let mut notes = Vec::new();
+                if block.reported.is_none() {
+                    notes.push("NOT FOUND".to_owned());
+                }
+                if !block.missing.is_empty() {
+                    notes.push(format!("MISSING {}", block.missing.join(", ")));
+                }
+                if !block.unexpected.is_empty() {
+                    notes.push(format!(
+                        "UNEXPECTED {}",
+                        block.unexpected.join(", ")
+                    ));
+                }
+                if !block.unknown.is_empty() {
+                    notes.push(format!("UNKNOWN {}", block.unknown.join(", ")));
+                }
+                [
+                    block.index.to_string(),
+                    block_name(block.label.as_deref(), block.height),
+                    failures(&block.expected),
+                    block
+                        .reported
+                        .as_deref()
+                        .map_or("not found".to_owned(), failures),
+                    notes.join("; "),
+                ]
+            })
+            .collect();
+        let mut table = render_table(HEADER, &rows);
+        table.push_str(&format!(
+            "\n{} blocks, {} not as expected\n",
+            self.blocks.len(),
+            self.n_unexpected
+        ));
+        table
+    }
+}
+
+/// Query the enforcer for the BIP300 failures that it recorded for each
+/// recorded block, and compare them with the expected failures
+pub async fn failure_report<'a>(
+    enforcer_client: &EnforcerClient,
+    blocks: impl IntoIterator<Item = &'a RecordedBlockMeta>,
+) -> anyhow::Result<FailureReport> {
+    let mut block_failures = Vec::new();
+    for (idx, meta) in blocks.into_iter().enumerate() {
+        let RecordedBlockMeta {
+            hash,
+            height,
+            spec,
+            expect: _,
+        } = meta;
+        let reported = enforcer_client.block_failures(*hash).await?;
+        block_failures.push(BlockFailures::new(
+            idx + 1,
+            *hash,
+            *height,
+            spec.as_ref(),
+            reported,
+        ));
+    }
+    Ok(FailureReport::new(block_failures))
+}
+
+/// Compare the BIP300 failures expected for each recorded block with those
+/// that the enforcer reports for it, and print the report
+pub async fn report_failures(
+    recording_dir: &Path,
+    enforcer_addr: SocketAddr,
+    json: bool,
+) -> anyhow::Result<()> {
+    /// Timeout for each request to the enforcer
+    const ENFORCER_TIMEOUT: Duration = Duration::from_secs(30);
+    let Recording {
+        manifest: _,
+        blocks,
+    } = Recording::read(recording_dir)?;
+    let enforcer_client = EnforcerClient::new(enforcer_addr, ENFORCER_TIMEOUT);
+    let report = failure_report(
+        &enforcer_client,
+        blocks.iter().map(|(_block, meta)| meta),
+    )
+    .await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.table());
+    }
+    if report.n_unexpected > 0 {
+        anyhow::bail!(
+            "the enforcer's failures for {} of {} blocks were not as expected",
+            report.n_unexpected,
+            report.blocks.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bitcoin::{hashes::Hash as _, BlockHash};
+    use serde_json::json;
+
+    use super::failure_report;
+    use crate::{
+        cli::{BlockSpec, Verdict},
+        enforcer_checks::{EnforcerClient, BLOCK_FAILURES_METHOD},
+        mock_rpc::MockEnforcerServer,
+        recording::RecordedBlockMeta,
+    };
+
+    #[tokio::test]
+    async fn failure_report_from_mock_enforcer() {
+        let enforcer = MockEnforcerServer::start(|method, request| {
+            assert_eq!(method, BLOCK_FAILURES_METHOD);
+            let block_hash: BlockHash =
+                request["blockHash"]["hex"].as_str()?.parse().unwrap();
+            let reasons: &[&str] = match block_hash.to_byte_array()[0] {
+                1 | 3 => &[],
+                2 => &["DuplicateM2"],
+                4 => &["NewFailure"],
+                _ => return None,
+            };
+            let failures: Vec<_> = reasons
+                .iter()
+                .map(|reason| json!({ "reason": reason }))
+                .collect();
+            Some(json!({ "failures": failures }))
+        })
+        .await;
+        let duplicate_m2 = BlockSpec {
+            duplicate_m2: true,
+            ..BlockSpec::default()
+        };
+        let specs = [
+            None,
+            Some(duplicate_m2.clone()),
+            Some(duplicate_m2),
+            Some(BlockSpec::default()),
+            Some(BlockSpec::default()),
+        ];
+        let blocks: Vec<_> = specs
+            .into_iter()
+            .zip(1..)
+            .map(|(spec, n)| RecordedBlockMeta {
+                hash: BlockHash::from_byte_array([n; 32]),
+                height: n.into(),
+                spec,
+                expect: Verdict::Accept,
+            })
+            .collect();
+        let enforcer_client =
+            EnforcerClient::new(enforcer.addr(), Duration::from_secs(5));
+        let report = failure_report(&enforcer_client, &blocks).await.unwrap();
+        assert_eq!(enforcer.methods().len(), 5);
+        assert_eq!(report.n_unexpected, 3);
+        let summary: Vec<_> = report
+            .blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.expected.clone(),
+                    block.reported.clone(),
+                    block.missing.clone(),
+                    block.unexpected.clone(),
+                    block.unknown.clone(),
+                )
+            })
+            .collect();
+        let ids = |ids: &[&str]| -> Vec<String> {
+            ids.iter().map(|id| (*id).to_owned()).collect()
+        };
+        assert_eq!(
+            summary,
+            [
+                (ids(&[]), Some(ids(&[])), ids(&[]), ids(&[]), ids(&[])),
+                (
+                    ids(&["DuplicateM2"]),
+                    Some(ids(&["DuplicateM2"])),
+                    ids(&[]),
+                    ids(&[]),
+                    ids(&[]),
+                ),
+                (
+                    ids(&["DuplicateM2"]),
+                    Some(ids(&[])),
+                    ids(&["DuplicateM2"]),
+                    ids(&[]),
+                    ids(&[]),
+                ),
+                (
+                    ids(&[]),
+                    Some(ids(&["NewFailure"])),
+                    ids(&[]),
+                    ids(&["NewFailure"]),
+                    ids(&["NewFailure"]),
+                ),
+                (ids(&[]), None, ids(&[]), ids(&[]), ids(&[])),
+            ]
+        );
+        let table = report.table();
+        assert!(table.contains("MISSING DuplicateM2"), "{table}");
+        assert!(
+            table.contains("UNEXPECTED NewFailure; UNKNOWN NewFailure"),
+            "{table}"
+        );
+        assert!(table.contains("NOT FOUND"), "{table}");
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+
+use bip300301::jsonrpsee::{
+    core::{client::ClientT as _, params::ArrayParams},
+    http_client::HttpClient,
+};
+use bitcoin::BlockHash;
+use url::Url;
+
+use crate::{
+    check_node, cli::RpcOpts, errors::RpcError, fetch_block_header, retry_rpc,
+    rpc_client, BlockHeaderInfo,
+};
+
+/// Roll the node's chain back to `target`, by invalidating the child of
+/// `target` on the node's active chain with `invalidateblock`, until
+/// `target` is the tip. Blocks on side branches of `target` may become the
+/// tip once the active chain is invalidated, and are invalidated in turn.
+/// If `own_blocks` is specified, refuses to invalidate blocks that are not
+/// in it, ie. if another party extended the chain.
+/// Returns the invalidated blocks.
+pub async fn rollback(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    target: BlockHash,
+    own_blocks: Option<&HashSet<BlockHash>>,
+) -> anyhow::Result<Vec<BlockHash>> {
+    let BlockHeaderInfo {
+        height: target_height,
+        prev_blockhash: _,
+        bits: _,
+    } = fetch_block_header(client, rpc_url, rpc_retries, target).await?;
+    let mut invalidated = Vec::new();
+    loop {
+        let tip: BlockHash =
+            retry_rpc(rpc_retries, rpc_url, "getbestblockhash", |_| {
+                client.request("getbestblockhash", ArrayParams::new())
+            })
+            .await
+            .map_err(|err| RpcError::new(rpc_url, "getbestblockhash", err))?;
+        if tip == target {
+            return Ok(invalidated);
+        }
+        // Walk back from the tip to the child of `target`
+        let mut block_hash = tip;
+        let child = loop {
+            let BlockHeaderInfo {
+                height,
+                prev_blockhash,
+                bits: _,
+            } = fetch_block_header(client, rpc_url, rpc_retries, block_hash)
+                .await?;
+            if let Some(own_blocks) = own_blocks {
+                if !own_blocks.contains(&block_hash) {
+                    anyhow::bail!(
+                        "block {block_hash} at height {height}, which was not \
+                         generated in this run, was mined on top of \
+                         {target}: refusing to roll back. Use the \
+                         `rollback` subcommand to roll back anyway."
+                    );
+                }
+            }
+            match prev_blockhash {
+                Some(prev_blockhash) if prev_blockhash == target => {
+                    break block_hash
+                }
+                Some(prev_blockhash) if height > target_height + 1 => {
+                    block_hash = prev_blockhash
+                }
+                Some(_) | None => anyhow::bail!(
+                    "block {target} is not an ancestor of the node's tip \
+                     {tip}"
+                ),
+            }
+        };
+        if invalidated.contains(&child) {
+            anyhow::bail!(
+                "the node's tip is still {tip} after invalidating {child}"
+            );
+        }
+        let () =
+            retry_rpc(rpc_retries, rpc_url, "invalidateblock", |_| async {
+                let mut params = ArrayParams::new();
+                let () = params.insert(child)?;
+                client.request("invalidateblock", params).await
+            })
+            .await
+            .map_err(|err| RpcError::new(rpc_url, "invalidateblock", err))?;
+        tracing::info!(%child, "Invalidated block");
+        invalidated.push(child);
+    }
+}
+
+/// Roll the node's chain back to `target`, for the `rollback` subcommand
+pub async fn rollback_node(
+    rpc: &RpcOpts,
+    network: bitcoin::Network,
+    target: BlockHash,
+) -> anyhow::Result<()> {
+    let _: String = check_node(rpc, network).await?;
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(rpc, None)?;
+    let invalidated =
+        rollback(&client, &rpc_url, rpc.rpc_retries, target, None).await?;
+    for block_hash in &invalidated {
+        println!("Invalidated {block_hash}");
+    }
+    if invalidated.is_empty() {
+        println!("The node's tip is already {target}");
+    } else {
+        println!("Rolled back to {target}");
+    }
+    Ok(())
+}
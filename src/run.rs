@@ -0,0 +1,382 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use bitcoin::{BlockHash, ScriptBuf};
+use clap::ArgMatches;
+
+use crate::{
+    chain_params::{ChainParams, ChainParamsFile},
+    check_mainnet_credentials, check_mainnet_network,
+    cli::{
+        BlocksSpec, GenerateArgs, MineArgs, Network, SpecFileBoundary,
+        SubmitArgs,
+    },
+    config::Config,
+    datadir::Datadir,
+    dry_run, fetch_blockchain_info,
+    generate::{
+        check_output_args, gen_script, preamble_meta, OutputOpts, ScriptOpts,
+    },
+    metrics::Metrics,
+    mine::{check_mine_args, mine_blocks},
+    preflight,
+    recording::Recording,
+    regtest_node::RegtestNode,
+    replay::check_replay_args,
+    rollback::rollback_node,
+    script_builder::{FailureClass, RenderOpts, RpcIds},
+    selftest::{check_selftest_args, spawn_node},
+    setup_cache::SetupCache,
+    state_file::StateFile,
+    submit::{submit_blocks, SubmitOpts},
+    Anchor, AnchorSource, BlockGenCtx, Clock, Mining, Sidechain,
+    DEMO_SIDECHAIN_DESCRIPTION, DEMO_SIDECHAIN_SLOT,
+};
+
+/// What to do with the generated blocks
+pub enum Mode {
+    /// Render them as a script, or another output format
+    Generate,
+    Submit(SubmitArgs),
+    Mine(MineArgs),
+    /// Roll the node's chain back to a block, rather than generating blocks
+    Rollback(BlockHash),
+}
+
+/// Drop the blocks that the run saved to the state file already submitted.
+/// `false` if the saved run already finished.
+fn resume(
+    state_file: &StateFile,
+    blocks_spec: &mut BlocksSpec,
+    spec_file_boundaries: &mut Vec<SpecFileBoundary>,
+) -> bool {
+    let Some(resumed) = state_file.resumed() else {
+        return true;
+    };
+    if resumed.next_block == blocks_spec.0.len() {
+        eprintln!(
+            "The run saved to the state file already finished, at height {}",
+            resumed.height - 1
+        );
+        return false;
+    }
+    eprintln!(
+        "Resuming from block {} of {} in the blocks spec, at height {}",
+        resumed.next_block + 1,
+        blocks_spec.0.len(),
+        resumed.height
+    );
+    blocks_spec.0.drain(..resumed.next_block);
+    // Keep the boundary of the spec file that the run resumes in
+    let first_boundary = spec_file_boundaries
+        .iter()
+        .rposition(|boundary| boundary.index <= resumed.next_block)
+        .unwrap_or(0);
+    spec_file_boundaries.drain(..first_boundary);
+    for boundary in spec_file_boundaries {
+        boundary.index = boundary.index.saturating_sub(resumed.next_block);
+    }
+    true
+}
+
+/// Print the metrics and write them to `metrics_json`, if set, then exit
+/// with the failure's exit code, if any
+fn report_outcome(
+    failure: Option<FailureClass>,
+    metrics_json: Option<&Path>,
+    regtest_node: Option<RegtestNode>,
+) -> anyhow::Result<()> {
+    let metrics = Metrics::get();
+    eprint!("{}", metrics.table());
+    if let Some(metrics_json) = metrics_json {
+        let metrics_json_str = serde_json::to_string_pretty(&metrics)?;
+        std::fs::write(metrics_json, format!("{metrics_json_str}\n"))
+            .with_context(|| {
+                format!("failed to write `{}`", metrics_json.display())
+            })?;
+    }
+    // Torn down before exiting, since exiting skips destructors
+    if let Some(regtest_node) = regtest_node {
+        drop(regtest_node);
+        if failure.is_none() {
+            eprintln!("Selftest passed");
+        }
+    }
+    if let Some(failure) = failure {
+        eprintln!(
+            "Exit code {}: {}",
+            failure.exit_code(),
+            failure.description()
+        );
+        std::process::exit(failure.exit_code().into());
+    }
+    Ok(())
+}
+
+/// Generate blocks, submit them, mine, or roll back, as `mode` says, once
+/// the args are merged with the config file and datadir. `recording_dir` is
+/// set when replaying, and `bitcoind_path` for `selftest`.
+pub async fn run(
+    mut args: GenerateArgs,
+    matches: &ArgMatches,
+    mode: Mode,
+    recording_dir: Option<PathBuf>,
+    bitcoind_path: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let config = Config::load(args.config.as_deref())?;
+    let () = config.apply(&mut args, matches);
+    let () = args.rpc.apply_deprecated_rpc_addr();
+    if bitcoind_path.is_some() {
+        let () = check_selftest_args(&mut args)?;
+    }
+    if let Some(datadir) = &args.rpc.bitcoin_datadir {
+        let datadir = Datadir::discover(datadir, args.network)?;
+        let () = datadir.apply(&mut args, matches)?;
+    }
+    if (args.rpc.rpc_cacert.is_some() || args.rpc.rpc_insecure)
+        && args.rpc.rpc_url.scheme() != "https"
+    {
+        anyhow::bail!(
+            "`--rpc-cacert` and `--rpc-insecure` require an `https://` RPC URL"
+        );
+    }
+    if args.print_config {
+        print!("{}", toml::to_string(&Config::effective(&args).redacted())?);
+        return Ok(());
+    }
+    let () =
+        check_mainnet_network(args.network, args.rpc.i_really_want_mainnet)?;
+    if args.dry_run {
+        return dry_run(&args.rpc, args.network.into(), args.enforcer_addr)
+            .await;
+    }
+    if let Mode::Rollback(target) = mode {
+        return rollback_node(&args.rpc, args.network.into(), target).await;
+    }
+    let recording =
+        recording_dir.as_deref().map(Recording::read).transpose()?;
+    // Required unless a subcommand, `--print-config`, or `--dry-run` is
+    // present. Optional when mining, and read from the recording when
+    // replaying.
+    let (mut blocks_spec, mut spec_file_boundaries) = match &recording {
+        Some(recording) => {
+            if args.read_blocks_spec()?.is_some() {
+                anyhow::bail!(
+                    "a blocks spec is not supported with `replay`, which \
+                     replays the recorded blocks spec"
+                );
+            }
+            (recording.blocks_spec(), Vec::new())
+        }
+        None => args.read_blocks_spec()?.unwrap_or_default(),
+    };
+    if let Mode::Mine(mine_args) = &mode {
+        let () = check_mine_args(&args, mine_args, &blocks_spec)?;
+    }
+    // Setup blocks are only known to be accepted when submitted directly
+    if let (Mode::Generate | Mode::Mine(_), Some(_)) =
+        (&mode, &args.setup_cache)
+    {
+        anyhow::bail!("`--setup-cache` is only supported by `submit`");
+    }
+    let chain_params_file = args
+        .chain_params
+        .as_deref()
+        .map(ChainParamsFile::read)
+        .transpose()?;
+    let chain_params = ChainParams::new(
+        args.network.into(),
+        args.signet_challenge.clone(),
+        chain_params_file,
+    )?;
+    if let Some(Recording { manifest, .. }) = &recording {
+        let () = check_replay_args(&mut args, manifest, &chain_params)?;
+    }
+    if let Some(coinbase_address) = &args.coinbase_address {
+        let _: ScriptBuf = chain_params
+            .script_pubkey(coinbase_address)
+            .context("invalid `--coinbase-address`")?;
+    }
+    let () = blocks_spec.validate(&chain_params)?;
+    let () = check_output_args(&args)?;
+    // Spawned once the options are checked, since the node takes a while
+    // to start
+    let regtest_node = match &bitcoind_path {
+        Some(bitcoind_path) => {
+            Some(spawn_node(bitcoind_path, &mut args.rpc).await?)
+        }
+        None => None,
+    };
+    let mut mainnet = matches!(args.network, Network::Mainnet);
+    let anchor_source = if let (Some(Recording { manifest, .. }), true) =
+        (&recording, args.offline)
+    {
+        AnchorSource::Recorded {
+            anchor: manifest.anchor(),
+            setup: manifest.setup,
+            offline: true,
+        }
+    } else if args.offline {
+        // Required by `--offline`, unless replaying
+        AnchorSource::Offline(Anchor {
+            height: args.height.unwrap(),
+            prev_blockhash: args.prev_blockhash.unwrap(),
+            target: args.bits.unwrap(),
+        })
+    } else {
+        // The mainnet check is not skipped with the preflight checks
+        let chain = if args.skip_preflight {
+            fetch_blockchain_info(&args.rpc).await?.chain
+        } else {
+            preflight(&args.rpc, chain_params.network, args.enforcer_addr)
+                .await?
+                .chain
+        };
+        mainnet |= chain == bitcoin::Network::Bitcoin.to_core_arg();
+        match &recording {
+            Some(Recording { manifest, .. }) => AnchorSource::Recorded {
+                anchor: manifest.anchor(),
+                setup: manifest.setup,
+                offline: false,
+            },
+            None => AnchorSource::Node {
+                setup_via_generatetoaddress: args.setup_via_generatetoaddress,
+                fund_node_wallet: args.fund_node_wallet,
+            },
+        }
+    };
+    let () = check_mainnet_credentials(mainnet, &mode, args.format, &args.rpc)?;
+    let seed = match args.seed {
+        Some(seed) => seed,
+        None => {
+            let seed = rand::random();
+            eprintln!("Seed: {seed}");
+            seed
+        }
+    };
+    let clock = match args.start_time {
+        Some(start_time) => Clock::Deterministic {
+            next_time: start_time,
+        },
+        None => Clock::Now,
+    };
+    let sidechain = Sidechain {
+        slot: args.sidechain_slot.unwrap_or(DEMO_SIDECHAIN_SLOT),
+        description: args
+            .sidechain_description
+            .or(args.sidechain_description_hex)
+            .map_or(DEMO_SIDECHAIN_DESCRIPTION.to_vec(), |description| {
+                description.as_bytes().to_vec()
+            }),
+    };
+    let preamble = if args.no_preamble {
+        None
+    } else {
+        Some(preamble_meta(
+            args.network,
+            args.deterministic,
+            seed,
+            &blocks_spec,
+        )?)
+    };
+    let script_opts = ScriptOpts {
+        preamble,
+        checked: !args.unchecked,
+        check_tip: !args.unchecked && !args.no_tip_check,
+        check_anchor: args.check_anchor,
+        skip_known_blocks: args.skip_known_blocks,
+        summary: args.summary,
+        rpc_client: args.rpc_client,
+        network: args.network.into(),
+        delay_between_blocks: args.delay_between_blocks,
+        batch_size: args.batch_size.get(),
+        enforcer_addr: args.enforcer_addr,
+        exec_wrapper: args.exec_wrapper,
+        bash: args.bash,
+        quiet: args.quiet_script,
+        rpc_ids: RpcIds::new(args.rpc_id, args.rpc_id_counter),
+        curl: args.curl,
+        heredoc_bodies: args.heredoc_bodies,
+        assume_jq: args.assume_jq,
+        wrap_width: args.wrap_width,
+        render_opts: RenderOpts {
+            no_comments: args.no_comments,
+            compact: args.compact,
+        },
+    };
+    let output_opts = OutputOpts {
+        format: args.format,
+        path: args.output,
+        hex_files_dir: args.hex_files,
+        dump_requests_dir: args.dump_requests,
+    };
+    let state_file = match &args.state_file {
+        Some(path) => Some(StateFile::open(path, &blocks_spec)?),
+        None => None,
+    };
+    if let Some(state_file) = &state_file {
+        if !resume(state_file, &mut blocks_spec, &mut spec_file_boundaries) {
+            return Ok(());
+        }
+    }
+    let mut ctx = BlockGenCtx::new(
+        chain_params,
+        clock,
+        args.coinbase_address,
+        sidechain,
+        seed,
+    );
+    if let Some(mining_threads) = args.mining_threads {
+        ctx.mining_threads = mining_threads;
+    }
+    ctx.state_file = state_file;
+    ctx.record_dir = args.record;
+    ctx.setup_cache = args
+        .setup_cache
+        .as_deref()
+        .map(SetupCache::open)
+        .transpose()?;
+    if let Some(Recording { manifest, blocks }) = recording {
+        ctx.wallet_funding = manifest.wallet_funding;
+        ctx.mining = Mining::Replay(
+            blocks.into_iter().map(|(block, _)| block).collect(),
+        );
+    }
+    let failure = match mode {
+        Mode::Generate => {
+            let () = gen_script(
+                ctx,
+                anchor_source,
+                args.rpc,
+                blocks_spec,
+                &spec_file_boundaries,
+                output_opts,
+                script_opts,
+            )
+            .await?;
+            None
+        }
+        Mode::Submit(submit_args) => {
+            submit_blocks(
+                ctx,
+                anchor_source,
+                args.rpc,
+                blocks_spec,
+                SubmitOpts {
+                    delay_between_blocks: args.delay_between_blocks,
+                    check_tip: !args.no_tip_check,
+                    enforcer_addr: args.enforcer_addr,
+                    submit_args,
+                },
+            )
+            .await?
+        }
+        Mode::Mine(mine_args) => {
+            mine_blocks(ctx, anchor_source, args.rpc, blocks_spec, mine_args)
+                .await?
+        }
+        // Handled before the blocks spec is read
+        Mode::Rollback(_) => unreachable!(),
+    };
+    report_outcome(failure, args.metrics_json.as_deref(), regtest_node)
+}
@@ -0,0 +1,1144 @@
+use std::{
+    collections::VecDeque, fmt::Display, net::SocketAddr, path::PathBuf,
+    time::Duration,
+};
+
+use bitcoin::{
+    hashes::{sha256, Hash as _},
+    Block, BlockHash,
+};
+use serde::Serialize;
+
+use crate::{
+    cli::{BlockSpec, Verdict},
+    enforcer_checks::EnforcerCheck,
+    errors::ScriptError,
+    metrics::{self, MetricsSummary},
+};
+
+/// RPC methods that are sent to the wallet endpoint, if a wallet is
+/// specified
+pub const WALLET_METHODS: &[&str] = &[
+    "getbalance",
+    "getbalances",
+    "getnewaddress",
+    "getreceivedbyaddress",
+    "getwalletinfo",
+    "listunspent",
+    "sendmany",
+    "sendtoaddress",
+];
+
+/// Default id for JSON-RPC requests sent by generated scripts
+pub const DEFAULT_RPC_ID: &str = "bip300-enforcer-demo";
+
+/// Ids for the JSON-RPC requests sent by a generated script, other than
+/// batched requests, which are numbered within each batch
+#[derive(Clone, Debug)]
+pub struct RpcIds {
+    id: String,
+    /// If `true`, each request's id is suffixed with `-N`, counting
+    /// requests from 1
+    counter: bool,
+    n_requests: usize,
+}
+
+impl RpcIds {
+    pub fn new(id: String, counter: bool) -> Self {
+        Self {
+            id,
+            counter,
+            n_requests: 0,
+        }
+    }
+
+    /// Id used by the script's RPC functions if none is specified
+    pub fn default_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Id for the next request, if it differs from the default id
+    pub fn next(&mut self) -> Option<String> {
+        if !self.counter {
+            return None;
+        }
+        self.n_requests += 1;
+        Some(format!("{}-{}", self.id, self.n_requests))
+    }
+}
+
+impl Default for RpcIds {
+    fn default() -> Self {
+        Self::new(DEFAULT_RPC_ID.to_owned(), false)
+    }
+}
+
+/// Names of the settings variables declared by generated scripts, which
+/// cannot be used as [`ScriptVar`]s
+const SETTINGS_VARIABLES: &[&str] = &[
+    "BLOCKS_DIR",
+    "ENFORCER_CHECKS",
+    "ENFORCER_URL",
+    "RPC_AUTH",
+    "RPC_CACERT",
+    "RPC_COOKIE_FILE",
+    "RPC_HOST",
+    "RPC_PASS",
+    "RPC_PORT",
+    "RPC_URL",
+    "RPC_USER",
+];
+
+/// Prefix of the names of the script variables that hold the hashes of
+/// labelled blocks
+const BLOCK_HASH_VAR_PREFIX: &str = "BLOCK_HASH_";
+
+/// `true` IFF `s` is a valid variable name in POSIX shell and PowerShell
+/// scripts: ASCII letters, digits, and underscores, not starting with a
+/// digit
+pub fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Script variable that the result of an RPC request is captured in, for
+/// later steps to reference. Names are upper-case ASCII letters, digits,
+/// and underscores, starting with a letter and containing at least one
+/// underscore, so that they do not clash with variables used internally
+/// by scripts, which are lower-case in POSIX scripts and camel-case in
+/// PowerShell scripts. The hashes of labelled blocks are held in variables
+/// named after their labels, with an upper-case prefix.
+#[derive(Clone, Debug, Serialize)]
+#[serde(transparent)]
+pub struct ScriptVar(String);
+
+impl ScriptVar {
+    pub fn new(name: &str) -> Result<Self, ScriptError> {
+        let valid = name.starts_with(|c: char| c.is_ascii_uppercase())
+            && name.contains('_')
+            && name.chars().all(|c| {
+                c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'
+            });
+        if !valid {
+            return Err(ScriptError::InvalidVarName {
+                name: name.to_owned(),
+            });
+        }
+        if SETTINGS_VARIABLES.contains(&name) {
+            return Err(ScriptError::SettingsVarClash {
+                name: name.to_owned(),
+            });
+        }
+        Ok(Self(name.to_owned()))
+    }
+
+    /// Variable holding the hash of the block with the label, eg.
+    /// `BLOCK_HASH_double_ack`. The label must be an identifier, as checked
+    /// by [`crate::cli::BlocksSpec::validate`].
+    pub fn block_hash(label: &str) -> Self {
+        debug_assert!(is_identifier(label), "invalid block label `{label}`");
+        Self(format!("{BLOCK_HASH_VAR_PREFIX}{label}"))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Delimiter for heredocs in generated scripts, unless a line of the
+/// heredoc consists of it
+const HEREDOC_DELIMITER: &str = "EOF";
+
+/// Argument of a command in a generated script. Arguments are quoted when
+/// the command is rendered, as appropriate for the script's language.
+#[derive(Clone, Debug)]
+pub enum Arg {
+    /// Rendered as-is, eg. an option or RPC method name. Must be a single
+    /// word in the script's language.
+    Literal(String),
+    /// String, rendered as a quoted string
+    Quoted(String),
+    /// Value of a script variable, eg. `RPC_HOST`
+    Variable(String),
+    /// File in the directory in a script variable
+    FilePath { dir_var: String, file_name: String },
+    /// JSON-encoded value, eg. an RPC param, rendered as a quoted string
+    Body(serde_json::Value),
+    /// Option with a value, rendered as `-name=value`, eg. for bitcoin-cli
+    Opt { name: String, value: Box<Arg> },
+}
+
+impl Arg {
+    pub fn literal<S>(s: S) -> Self
+    where
+        String: From<S>,
+    {
+        Self::Literal(s.into())
+    }
+
+    pub fn quoted<S>(s: S) -> Self
+    where
+        String: From<S>,
+    {
+        Self::Quoted(s.into())
+    }
+
+    pub fn opt(name: &str, value: Self) -> Self {
+        Self::Opt {
+            name: name.to_owned(),
+            value: Box::new(value),
+        }
+    }
+}
+
+/// Quoted heredoc, with a delimiter that no line of the heredoc consists
+/// of
+#[derive(Debug)]
+pub struct Heredoc {
+    body: String,
+    delimiter: String,
+}
+
+impl Heredoc {
+    /// The delimiter is [`HEREDOC_DELIMITER`], or if a line of `body`
+    /// consists of it, the first of `EOF_1`, `EOF_2`, ... that none does
+    pub fn new(body: String) -> Self {
+        let mut delimiter = HEREDOC_DELIMITER.to_owned();
+        let mut suffix = 0;
+        while body.lines().any(|line| line == delimiter) {
+            suffix += 1;
+            delimiter = format!("{HEREDOC_DELIMITER}_{suffix}");
+        }
+        Self { body, delimiter }
+    }
+}
+
+/// Delimiter of the quoted heredoc that a line starts, if any
+fn heredoc_delimiter(line: &str) -> Option<&str> {
+    let (_, delimiter) = line.strip_suffix('\'')?.rsplit_once("<<'")?;
+    let is_delimiter = !delimiter.is_empty()
+        && delimiter
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+    is_delimiter.then_some(delimiter)
+}
+
+/// Input for a command
+#[derive(Debug)]
+pub enum Stdin {
+    Heredoc(Heredoc),
+    /// Redirected from a file
+    File(Arg),
+}
+
+#[derive(Debug)]
+pub struct Command {
+    /// Rendered as-is
+    pub command: String,
+    pub args: Vec<Arg>,
+    pub stdin: Option<Stdin>,
+}
+
+impl Command {
+    /// Render the command, with each argument rendered by `render_arg`.
+    /// Lines longer than `width` are wrapped with POSIX line
+    /// continuations. Lines are only broken between arguments, and
+    /// continuation lines are indented by four spaces. A width of 0
+    /// disables wrapping.
+    /// Commands with a heredoc are rendered across lines, ending with a
+    /// newline after the delimiter, so that they can be followed by `)` in
+    /// a command substitution.
+    pub fn render<F>(&self, width: usize, render_arg: F) -> String
+    where
+        F: Fn(&Arg) -> String,
+    {
+        let redirect = match &self.stdin {
+            Some(Stdin::File(file)) => Some(format!("<{}", render_arg(file))),
+            Some(Stdin::Heredoc(_)) | None => None,
+        };
+        let mut lines = vec![self.command.clone()];
+        for arg in self.args.iter().map(&render_arg).chain(redirect) {
+            let line = lines.last_mut().unwrap();
+            // Leave space for the continuation
+            if width != 0 && line.len() + arg.len() + 3 > width {
+                line.push_str(" \\");
+                lines.push(format!("    {arg}"));
+            } else {
+                line.push(' ');
+                line.push_str(&arg);
+            }
+        }
+        let command = lines.join("\n");
+        match &self.stdin {
+            Some(Stdin::Heredoc(Heredoc { body, delimiter })) => {
+                format!("{command} <<'{delimiter}'\n{body}\n{delimiter}\n")
+            }
+            Some(Stdin::File(_)) | None => command,
+        }
+    }
+}
+
+/// Indent each line of a snippet by four spaces, except for the bodies
+/// and delimiters of heredocs, which must be left as-is
+pub fn indent(snippet: &str) -> String {
+    // Delimiter of the heredoc that the current line is in, if any
+    let mut heredoc: Option<&str> = None;
+    snippet
+        .lines()
+        .map(|line| {
+            if let Some(delimiter) = heredoc {
+                if line == delimiter {
+                    heredoc = None;
+                }
+                return line.to_owned();
+            }
+            heredoc = heredoc_delimiter(line);
+            if line.is_empty() {
+                String::new()
+            } else {
+                format!("    {line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Line comment, with each line prefixed by `# `.
+/// Empty lines are rendered as a bare `#`, so that paragraphs stay separated
+/// without trailing whitespace. Trailing newlines are ignored.
+#[derive(Debug)]
+pub struct Comment(pub String);
+
+impl Display for Comment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = self.0.trim_end_matches(['\n', '\r']);
+        if text.is_empty() {
+            return "#".fmt(f);
+        }
+        text.lines()
+            .map(|line| {
+                if line.trim_end().is_empty() {
+                    "#".to_owned()
+                } else {
+                    format!("# {line}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+            .fmt(f)
+    }
+}
+
+#[derive(Debug)]
+pub enum ScriptItem {
+    Comment(Comment),
+    /// Command or multi-line snippet
+    Snippet(String),
+}
+
+/// Options for rendering scripts
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderOpts {
+    /// Omit comments
+    pub no_comments: bool,
+    /// Separate items and sections with a single newline, rather than a
+    /// blank line
+    pub compact: bool,
+}
+
+impl RenderOpts {
+    /// `comment` followed by a newline, or nothing if comments are omitted
+    pub fn comment_line(&self, comment: &Comment) -> String {
+        if self.no_comments {
+            String::new()
+        } else {
+            format!("{comment}\n")
+        }
+    }
+
+    /// Blank line separating sections, or nothing if the output is compact
+    pub fn blank_line(&self) -> &'static str {
+        if self.compact {
+            ""
+        } else {
+            "\n"
+        }
+    }
+}
+
+/// Render script items. Comments are attached to the following item, and
+/// other items are separated by blank lines, or by single newlines if the
+/// output is compact. The output is empty if there are no items, and
+/// otherwise ends with exactly one newline, including after a trailing
+/// comment.
+pub fn render_items(
+    items: VecDeque<ScriptItem>,
+    render_opts: RenderOpts,
+) -> String {
+    let mut item_renderer = ItemRenderer::new(render_opts);
+    let mut res = String::new();
+    for script_item in items {
+        res.push_str(&item_renderer.push(script_item));
+    }
+    res.push_str(&item_renderer.finish());
+    res
+}
+
+/// Renders script items incrementally, as [`render_items`] does. The
+/// separator after an item depends on the item that follows it, so each
+/// item is rendered once the next item is pushed, or once rendering is
+/// finished.
+#[derive(Debug)]
+pub struct ItemRenderer {
+    render_opts: RenderOpts,
+    /// Last item pushed, which is not rendered yet
+    pending: Option<ScriptItem>,
+}
+
+impl ItemRenderer {
+    pub fn new(render_opts: RenderOpts) -> Self {
+        Self {
+            render_opts,
+            pending: None,
+        }
+    }
+
+    /// Render `item`, followed by the separator before `next`
+    fn render(&self, item: ScriptItem, next: Option<&ScriptItem>) -> String {
+        let separator = format!("\n{}", self.render_opts.blank_line());
+        match item {
+            ScriptItem::Comment(comment) => {
+                let end = match next {
+                    // Keep consecutive comments as separate paragraphs,
+                    // even if the output is compact
+                    Some(ScriptItem::Comment(_))
+                        if self.render_opts.compact =>
+                    {
+                        "\n#\n"
+                    }
+                    Some(ScriptItem::Comment(_)) => &separator,
+                    Some(ScriptItem::Snippet(_)) | None => "\n",
+                };
+                format!("{comment}{end}")
+            }
+            ScriptItem::Snippet(snippet) => {
+                let end = if next.is_some() { &separator } else { "\n" };
+                format!("{snippet}{end}")
+            }
+        }
+    }
+
+    /// Push an item, and return the rendering of the previous item, if any
+    pub fn push(&mut self, item: ScriptItem) -> String {
+        if self.render_opts.no_comments
+            && matches!(item, ScriptItem::Comment(_))
+        {
+            return String::new();
+        }
+        match self.pending.replace(item) {
+            Some(prev) => self.render(prev, self.pending.as_ref()),
+            None => String::new(),
+        }
+    }
+
+    /// Render the last item, if any
+    pub fn finish(mut self) -> String {
+        match self.pending.take() {
+            Some(item) => self.render(item, None),
+            None => String::new(),
+        }
+    }
+}
+
+/// Classes of failure, each with a distinct exit code, so that callers can
+/// tell why a generated script failed without parsing its output. Other
+/// failures, eg. a missing tool, exit with status 1.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    /// The node or enforcer could not be reached
+    Connection,
+    /// The node returned an error for an RPC request
+    RpcError,
+    /// A block was accepted, but was expected to be rejected
+    UnexpectedAccept,
+    /// A block was rejected, but was expected to be accepted
+    UnexpectedReject,
+    /// The node's tip after submitting a block is not as expected
+    TipMismatch,
+    /// A value captured by an earlier step is not as expected
+    CheckFailed,
+    /// The enforcer's state is not as expected
+    EnforcerMismatch,
+}
+
+impl FailureClass {
+    pub const ALL: [Self; 7] = [
+        Self::Connection,
+        Self::RpcError,
+        Self::UnexpectedAccept,
+        Self::UnexpectedReject,
+        Self::TipMismatch,
+        Self::CheckFailed,
+        Self::EnforcerMismatch,
+    ];
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Connection => 10,
+            Self::RpcError => 11,
+            Self::UnexpectedAccept => 20,
+            Self::UnexpectedReject => 21,
+            Self::TipMismatch => 30,
+            Self::CheckFailed => 31,
+            Self::EnforcerMismatch => 40,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Connection => "the node or enforcer could not be reached",
+            Self::RpcError => "the node returned an error for an RPC request",
+            Self::UnexpectedAccept => {
+                "a block was accepted, but was expected to be rejected"
+            }
+            Self::UnexpectedReject => {
+                "a block was rejected, but was expected to be accepted"
+            }
+            Self::TipMismatch => {
+                "the node's tip after submitting a block is not as expected"
+            }
+            Self::CheckFailed => "a checked value is not as expected",
+            Self::EnforcerMismatch => "the enforcer's state is not as expected",
+        }
+    }
+
+    /// Placeholder for the exit code in script templates
+    fn placeholder(self) -> &'static str {
+        match self {
+            Self::Connection => "{exit_connection}",
+            Self::RpcError => "{exit_rpc_error}",
+            Self::UnexpectedAccept => "{exit_unexpected_accept}",
+            Self::UnexpectedReject => "{exit_unexpected_reject}",
+            Self::TipMismatch => "{exit_tip_mismatch}",
+            Self::CheckFailed => "{exit_check_failed}",
+            Self::EnforcerMismatch => "{exit_enforcer_mismatch}",
+        }
+    }
+
+    /// Replace the exit code placeholders in a script template
+    pub fn substitute(template: &str) -> String {
+        Self::ALL.iter().fold(template.to_owned(), |res, class| {
+            res.replace(class.placeholder(), &class.exit_code().to_string())
+        })
+    }
+
+    /// Documentation of the exit codes, for script header comments
+    pub fn doc() -> String {
+        let mut res = "Exit codes:".to_owned();
+        for class in Self::ALL {
+            res.push_str(&format!(
+                "\n  {:<3} {}",
+                class.exit_code(),
+                class.description()
+            ));
+        }
+        res.push_str("\n  1   any other failure, eg. a missing tool");
+        res
+    }
+}
+
+/// Argument for the script functions that check results
+pub fn verdict_arg(verdict: Verdict) -> &'static str {
+    match verdict {
+        Verdict::Accept => "accept",
+        Verdict::Reject => "reject",
+        Verdict::Inconclusive => "inconclusive",
+    }
+}
+
+/// RPC request, rendered as a command for the selected RPC client
+#[derive(Clone, Debug, Serialize)]
+pub struct RpcCall {
+    pub method: String,
+    pub params: Vec<serde_json::Value>,
+}
+
+impl RpcCall {
+    pub fn new<Params>(method: &str, params: Params) -> Self
+    where
+        Params: Serialize,
+    {
+        let params = match serde_json::to_value(params).unwrap() {
+            serde_json::Value::Array(params) => params,
+            serde_json::Value::Null => Vec::new(),
+            param => vec![param],
+        };
+        Self {
+            method: method.to_owned(),
+            params,
+        }
+    }
+
+    /// RPC request for `submitblock`
+    pub fn submitblock(block: &Block) -> Self {
+        Self::new("submitblock", [metrics::block_hex(block)])
+    }
+}
+
+/// JSON-RPC request body
+#[derive(Debug, Serialize)]
+pub struct RpcRequest<'a> {
+    pub jsonrpc: &'static str,
+    pub id: serde_json::Value,
+    #[serde(flatten)]
+    pub call: &'a RpcCall,
+}
+
+impl<'a> RpcRequest<'a> {
+    pub fn new<Id>(id: Id, call: &'a RpcCall) -> Self
+    where
+        serde_json::Value: From<Id>,
+    {
+        Self {
+            jsonrpc: "2.0",
+            id: id.into(),
+            call,
+        }
+    }
+}
+
+/// JSON-RPC request body sent by a generated script, as written by
+/// `--dump-requests`
+#[derive(Clone, Debug)]
+pub struct RequestBody {
+    /// Method of the request, or of each request in a batch
+    pub method: String,
+    /// Body, exactly as sent to the node
+    pub body: String,
+}
+
+/// Block to submit, with metadata used by the output formats
+#[derive(Clone, Copy, Debug)]
+pub struct BlockSubmission<'a> {
+    pub block: &'a Block,
+    pub height: u32,
+    /// `None` for setup blocks
+    pub spec: Option<&'a BlockSpec>,
+    /// Expected response from the node
+    pub expected: Verdict,
+    /// Identifies the block in error messages
+    pub description: &'a str,
+    /// Name of the file in the hex files directory that contains the
+    /// block's hex, if it is not embedded in the script
+    pub hex_file: Option<&'a str>,
+}
+
+impl BlockSubmission<'_> {
+    /// Number of coinbase outputs in the block
+    pub fn n_coinbase_outputs(&self) -> usize {
+        self.block
+            .txdata
+            .first()
+            .map_or(0, |coinbase| coinbase.output.len())
+    }
+
+    /// `comment`, if any, followed by the block's context
+    pub fn comment_with_context(&self, comment: Option<&str>) -> String {
+        match comment {
+            Some(comment) => format!("{comment}\n{}", self.context()),
+            None => self.context(),
+        }
+    }
+
+    /// Metadata derived from the block, for comments, eg.
+    /// `Block 1a2b… at height 215: 1 transactions, 3 coinbase outputs`
+    pub fn context(&self) -> String {
+        format!(
+            "Block {} at height {}: {} transactions, {} coinbase outputs",
+            self.block.block_hash(),
+            self.height,
+            self.block.txdata.len(),
+            self.n_coinbase_outputs(),
+        )
+    }
+
+    /// One-line summary of the block, shared by progress messages and the
+    /// JSON plan, eg. `block 1a2b… (height 215, expect: reject — duplicate_m2)`
+    pub fn summary(&self) -> String {
+        let label = self
+            .spec
+            .and_then(|spec| spec.label.as_ref())
+            .map(|label| format!(" \"{label}\""))
+            .unwrap_or_default();
+        let reasons_invalid = self
+            .spec
+            .map(BlockSpec::reasons_invalid)
+            .unwrap_or_default();
+        let reasons_invalid = if reasons_invalid.is_empty() {
+            String::new()
+        } else {
+            format!(" — {}", reasons_invalid.join(", "))
+        };
+        format!(
+            "block{label} {} (height {}, expect: {}{reasons_invalid})",
+            self.block.block_hash(),
+            self.height,
+            verdict_arg(self.expected),
+        )
+    }
+}
+
+/// Numbers of blocks submitted by a script, for its summary. Blocks that
+/// are expected to be `inconclusive` are valid, so they are counted as
+/// accepted.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct SubmissionCounts {
+    pub n_blocks: usize,
+    pub n_expect_accept: usize,
+    pub n_expect_reject: usize,
+}
+
+impl SubmissionCounts {
+    pub fn add(&mut self, expected: Verdict) {
+        self.n_blocks += 1;
+        match expected {
+            Verdict::Accept | Verdict::Inconclusive => {
+                self.n_expect_accept += 1
+            }
+            Verdict::Reject => self.n_expect_reject += 1,
+        }
+    }
+}
+
+/// Block at the node's tip
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct NodeTip {
+    pub hash: BlockHash,
+    pub height: u32,
+}
+
+/// Metadata about how a script was generated, for its header
+#[derive(Clone, Debug, Serialize)]
+pub struct ScriptMeta {
+    pub tool: &'static str,
+    pub version: &'static str,
+    /// Git commit that the tool was built from, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_hash: Option<&'static str>,
+    /// Unix time at which the script was generated. Omitted in
+    /// deterministic mode, so that output is reproducible.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated_at: Option<u64>,
+    pub network: String,
+    pub seed: u64,
+    /// SHA-256 of the normalized blocks spec JSON
+    pub spec_sha256: sha256::Hash,
+    /// Normalized blocks spec JSON
+    #[serde(skip)]
+    pub spec_json: String,
+    /// The node's tip when the script was generated. `None` in offline
+    /// mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_tip: Option<NodeTip>,
+    /// Mining and RPC timings for generating the script. Only included in
+    /// JSON plans, and omitted in deterministic mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsSummary>,
+}
+
+impl ScriptMeta {
+    pub fn new(
+        network: String,
+        seed: u64,
+        generated_at: Option<u64>,
+        spec_json: String,
+    ) -> Self {
+        Self {
+            tool: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("GIT_HASH"),
+            generated_at,
+            network,
+            seed,
+            spec_sha256: sha256::Hash::hash(spec_json.as_bytes()),
+            spec_json,
+            node_tip: None,
+            metrics: None,
+        }
+    }
+}
+
+/// Header comment, with one field per line
+impl Display for ScriptMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            tool,
+            version,
+            git_hash,
+            generated_at,
+            network,
+            seed,
+            spec_sha256,
+            spec_json,
+            node_tip,
+            metrics: _,
+        } = self;
+        writeln!(f, "Generated by {tool}")?;
+        writeln!(f, "Version:      {version}")?;
+        if let Some(git_hash) = git_hash {
+            writeln!(f, "Git commit:   {git_hash}")?;
+        }
+        if let Some(generated_at) = generated_at {
+            writeln!(f, "Generated at: Unix time {generated_at}")?;
+        }
+        writeln!(f, "Network:      {network}")?;
+        writeln!(f, "Seed:         {seed}")?;
+        match node_tip {
+            Some(NodeTip { hash, height }) => {
+                writeln!(f, "Node tip:     {hash} (height {height})")?
+            }
+            None => writeln!(f, "Node tip:     none, generated offline")?,
+        }
+        writeln!(f, "Spec SHA-256: {spec_sha256}")?;
+        write!(f, "Blocks spec:  {spec_json}")
+    }
+}
+
+/// Directory containing block hex files, as referenced by generated scripts
+#[derive(Clone, Debug)]
+pub enum HexFilesDir {
+    /// Relative to the directory containing the script
+    ScriptRelative(PathBuf),
+    Absolute(PathBuf),
+}
+
+/// `submitblock` request waiting to be sent in a JSON-RPC batch
+#[derive(Debug)]
+pub struct BatchedSubmitblock {
+    pub call: RpcCall,
+    /// Expected response, if the response is checked
+    pub expected: Option<Verdict>,
+    /// Identifies the block in error messages
+    pub description: String,
+    /// See [`BlockSubmission::summary`]
+    pub summary: String,
+    /// See [`BlockSubmission::context`]
+    pub context: String,
+}
+
+impl BatchedSubmitblock {
+    pub fn new(submission: &BlockSubmission, checked: bool) -> Self {
+        Self {
+            call: RpcCall::submitblock(submission.block),
+            expected: checked.then_some(submission.expected),
+            description: submission.description.to_owned(),
+            summary: submission.summary(),
+            context: submission.context(),
+        }
+    }
+}
+
+/// Operations used to render a script that submits generated blocks,
+/// implemented for each script output format
+pub trait ScriptBuilder {
+    /// Prepend a preamble to the script, with a header comment describing
+    /// how it was generated, and settings so that the script exits on the
+    /// first failure
+    fn preamble(&mut self, meta: &ScriptMeta);
+
+    fn comment<S>(&mut self, comment: S)
+    where
+        String: From<S>;
+
+    /// Comment on a block before it is submitted, composed of `comment`, if
+    /// any, and metadata derived from the block
+    fn block_comment(
+        &mut self,
+        comment: Option<&str>,
+        submission: &BlockSubmission,
+    ) {
+        self.comment(submission.comment_with_context(comment))
+    }
+
+    /// Set the directory containing block hex files, for blocks that are
+    /// submitted from files
+    fn hex_files_dir(&mut self, dir: &HexFilesDir);
+
+    /// Set the address of the enforcer to check the state of
+    fn enforcer_addr(&mut self, addr: SocketAddr);
+
+    /// Declare the script variable `var`, holding the hash of a labelled
+    /// block, so that any step can reference it. Must be called before any
+    /// steps are added.
+    fn block_hash_var(&mut self, var: &ScriptVar, block_hash: BlockHash);
+
+    /// Pause the script
+    fn sleep(&mut self, duration: Duration);
+
+    /// Check whether the node already has the block, before submitting it.
+    /// If it does, the script skips the block's delay, submission, and
+    /// checks, up to the next block.
+    fn skip_if_known(&mut self, submission: &BlockSubmission);
+
+    /// RPC request for `submitblock`
+    fn submitblock(&mut self, submission: &BlockSubmission);
+
+    /// RPC request for `submitblock`, failing the script if the node
+    /// returns an error, or if the block is not accepted or rejected as
+    /// expected
+    fn submitblock_checked(&mut self, submission: &BlockSubmission);
+
+    /// RPC request for `getbestblockhash` after submitting a block, failing
+    /// the script if an accepted block did not become the tip, or if a
+    /// rejected block did
+    fn check_tip(&mut self, submission: &BlockSubmission);
+
+    /// RPC request whose result is assigned to the script variable `var`,
+    /// for later steps to reference. Fails the script if the request fails.
+    /// `description` describes the result in error messages.
+    fn command_capture(
+        &mut self,
+        var: &ScriptVar,
+        call: &RpcCall,
+        description: &str,
+    );
+
+    /// Fail the script unless the value captured in `var` is `expected`.
+    /// `description` describes the value in error messages.
+    fn check_captured(
+        &mut self,
+        var: &ScriptVar,
+        expected: &str,
+        description: &str,
+    );
+
+    /// Request the enforcer's state after submitting a block, failing the
+    /// script if it does not match the expected state. `description`
+    /// identifies the block in error messages.
+    fn check_enforcer(&mut self, check: &EnforcerCheck, description: &str);
+
+    /// Print a summary at the end of the script: the numbers of blocks
+    /// submitted, the node's tip and block count, and the enforcer's tip, if
+    /// the enforcer's state is checked
+    fn summary(&mut self, counts: &SubmissionCounts);
+
+    /// Take the JSON-RPC request bodies sent by the script so far, in the
+    /// order they are sent. Empty if the builder does not track them.
+    fn take_request_bodies(&mut self) -> Vec<RequestBody> {
+        Vec::new()
+    }
+
+    fn finalize(self) -> String;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, path::Path};
+
+    use clap::Parser as _;
+
+    use super::{
+        indent, render_items, Arg, BlockSubmission, Command, Comment, Heredoc,
+        RenderOpts, ScriptBuilder as _, ScriptItem, Stdin,
+    };
+    use crate::{
+        cli::{RpcClient, RpcOpts, Verdict},
+        posix_script_builder::OutputPosixScriptBuilder,
+    };
+
+    /// Check `actual` against the golden file `testdata/script_builder/{name}`.
+    /// If `UPDATE_GOLDEN` is set, the golden file is overwritten instead.
+    fn check_golden(name: &str, actual: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/script_builder")
+            .join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            panic!(
+                "failed to read `{}` ({err}): run with UPDATE_GOLDEN=1 to \
+                 create it",
+                path.display()
+            )
+        });
+        assert!(
+            actual == expected,
+            "output differs from `{}`: run with UPDATE_GOLDEN=1 to update \
+             it, and review the diff\n--- expected\n{expected}\n--- actual\n\
+             {actual}",
+            path.display()
+        );
+    }
+
+    fn comment(text: &str) -> ScriptItem {
+        ScriptItem::Comment(Comment(text.to_owned()))
+    }
+
+    fn snippet(text: &str) -> ScriptItem {
+        ScriptItem::Snippet(text.to_owned())
+    }
+
+    /// Render `items` with the default, compact, and comment-free options,
+    /// checking each against its golden file
+    fn check_items_golden<F>(name: &str, items: F)
+    where
+        F: Fn() -> Vec<ScriptItem>,
+    {
+        let variants = [
+            ("", RenderOpts::default()),
+            (
+                ".compact",
+                RenderOpts {
+                    compact: true,
+                    ..RenderOpts::default()
+                },
+            ),
+            (
+                ".no_comments",
+                RenderOpts {
+                    no_comments: true,
+                    ..RenderOpts::default()
+                },
+            ),
+        ];
+        for (suffix, render_opts) in variants {
+            let rendered = render_items(VecDeque::from(items()), render_opts);
+            check_golden(&format!("{name}{suffix}.sh"), &rendered);
+        }
+    }
+
+    #[test]
+    fn comment_followed_by_comment() {
+        check_items_golden("comment_comment", || {
+            vec![
+                comment("First comment"),
+                comment("Second comment"),
+                snippet("echo done"),
+            ]
+        })
+    }
+
+    #[test]
+    fn comment_followed_by_command() {
+        check_items_golden("comment_command", || {
+            vec![
+                snippet("set -eu"),
+                comment("Submit the blocks"),
+                snippet("echo one"),
+                snippet("echo two"),
+            ]
+        })
+    }
+
+    #[test]
+    fn trailing_comment() {
+        check_items_golden("trailing_comment", || {
+            vec![snippet("echo one"), comment("Trailing comment")]
+        })
+    }
+
+    #[test]
+    fn empty_script() {
+        check_items_golden("empty", Vec::new);
+    }
+
+    #[test]
+    fn multi_line_comments() {
+        check_items_golden("multi_line_comment", || {
+            vec![
+                comment("First line\nsecond line\n\nNew paragraph\n\n"),
+                snippet("if true; then\n    echo one\nfi"),
+                comment("  indented\n\t\nlast\r\n"),
+                comment(""),
+                snippet("echo two"),
+            ]
+        })
+    }
+
+    /// Script that submits the regtest genesis block with the RPC client,
+    /// expecting it to be accepted, with commands wrapped at `wrap_width`
+    fn submitblock_script(rpc_client: RpcClient, wrap_width: usize) -> String {
+        let rpc = RpcOpts::try_parse_from([
+            "bip300-enforcer-demo",
+            "--rpc-url",
+            "http://127.0.0.1:18443",
+            "--rpc-user",
+            "user",
+            "--rpc-pass",
+            "pass",
+        ])
+        .unwrap();
+        let block =
+            bitcoin::constants::genesis_block(bitcoin::Network::Regtest);
+        let mut script_builder = OutputPosixScriptBuilder::new(
+            rpc,
+            rpc_client,
+            bitcoin::Network::Regtest,
+        );
+        let () = script_builder.wrap_width(wrap_width);
+        let () = script_builder.comment("Submit the genesis block");
+        let () = script_builder.submitblock_checked(&BlockSubmission {
+            block: &block,
+            height: 0,
+            spec: None,
+            expected: Verdict::Accept,
+            description: "the genesis block",
+            hex_file: None,
+        });
+        script_builder.finalize()
+    }
+
+    #[test]
+    fn submitblock_unwrapped() {
+        check_golden("submitblock.sh", &submitblock_script(RpcClient::Curl, 0));
+    }
+
+    /// The `bitcoin-cli` command, with its connection and auth options and
+    /// the block hex, is wrapped across lines, with the hex on a line of its
+    /// own
+    #[test]
+    fn submitblock_wrapped() {
+        check_golden(
+            "submitblock.wrapped.sh",
+            &submitblock_script(RpcClient::BitcoinCli, 80),
+        );
+    }
+
+    fn heredoc_command(body: &str) -> Command {
+        Command {
+            command: "rpc_post".to_owned(),
+            args: vec![Arg::literal("@-")],
+            stdin: Some(Stdin::Heredoc(Heredoc::new(body.to_owned()))),
+        }
+    }
+
+    #[test]
+    fn heredoc_delimiter() {
+        let render = |body| heredoc_command(body).render(80, |_| "@-".into());
+        assert_eq!(render("{}"), "rpc_post @- <<'EOF'\n{}\nEOF\n");
+        // Lines that only contain the delimiter do not end the heredoc
+        assert_eq!(
+            render(" EOF\nEOF \nEOFX"),
+            "rpc_post @- <<'EOF'\n EOF\nEOF \nEOFX\nEOF\n"
+        );
+        assert_eq!(
+            render("EOF\nEOF_1\nx"),
+            "rpc_post @- <<'EOF_2'\nEOF\nEOF_1\nx\nEOF_2\n"
+        );
+    }
+
+    #[test]
+    fn indent_skips_heredocs() {
+        let command = heredoc_command("EOF\n  {}").render(80, |_| "@-".into());
+        let snippet = format!("a\n{command}b\n\nc <<'X'");
+        assert_eq!(
+            indent(&snippet),
+            "    a\n    rpc_post @- <<'EOF_1'\nEOF\n  {}\nEOF_1\n    b\n\n\
+             \x20   c <<'X'"
+        );
+    }
+}
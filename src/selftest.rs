@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use crate::{
+    cli::{GenerateArgs, Network, RpcOpts},
+    regtest_node::RegtestNode,
+};
+
+/// Check the args for `selftest`, which runs against a node that it spawns
+pub fn check_selftest_args(args: &mut GenerateArgs) -> anyhow::Result<()> {
+    if !matches!(args.network, Network::Regtest) {
+        anyhow::bail!("`selftest` only supports `--network regtest`");
+    }
+    if args.offline || args.no_tip_check {
+        anyhow::bail!(
+            "`--offline` and `--no-tip-check` are not supported with \
+             `selftest`"
+        );
+    }
+    // The RPC options are replaced with the spawned node's
+    args.rpc.bitcoin_datadir = None;
+    Ok(())
+}
+
+/// Spawn the node to run against, and point the RPC options at it once its
+/// RPC server is ready
+pub async fn spawn_node(
+    bitcoind_path: &Path,
+    rpc: &mut RpcOpts,
+) -> anyhow::Result<RegtestNode> {
+    let mut regtest_node = RegtestNode::spawn(bitcoind_path)?;
+    let () = regtest_node.apply(rpc);
+    let () = regtest_node.wait_for_rpc(rpc).await?;
+    Ok(regtest_node)
+}
@@ -0,0 +1,179 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use bitcoin::{hashes::sha256d, Amount, BlockHash, Network};
+use serde::{Deserialize, Serialize};
+
+/// Version of the setup cache. Must be incremented whenever the cache
+/// changes in a way that is not backwards-compatible.
+pub const SETUP_CACHE_VERSION: u32 = 1;
+
+/// What the setup blocks for a blocks spec provide, other than the plain
+/// setup block, which every run requires
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetupRequirements {
+    /// Slot and SHA256D hash of the description of the sidechain proposed
+    /// by the M1 setup block, if one is required
+    pub m1: Option<(u8, sha256d::Hash)>,
+    /// Minimum amount to fund the node's wallet with, if any
+    #[serde(with = "bitcoin::amount::serde::as_btc::opt")]
+    pub wallet_funding: Option<Amount>,
+}
+
+/// Setup block submitted by a previous run
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachedBlock {
+    pub hash: BlockHash,
+    pub height: u32,
+}
+
+/// Setup blocks submitted by a previous run, which later runs with the same
+/// requirements can build on instead of generating their own
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachedSetup {
+    pub network: Network,
+    /// Block that the setup blocks build on
+    pub prev_blockhash: BlockHash,
+    pub requirements: SetupRequirements,
+    /// Address from the node's wallet that the setup blocks funded, if any
+    pub funded_address: Option<String>,
+    /// Setup blocks, in the order they were submitted
+    pub blocks: Vec<CachedBlock>,
+}
+
+/// Contents of a setup cache file
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SetupCacheFile {
+    version: u32,
+    entries: Vec<CachedSetup>,
+}
+
+/// Version of a setup cache file, read before the rest of the file, so that
+/// files with other versions are reported as such
+#[derive(Deserialize)]
+struct SetupCacheVersion {
+    version: u32,
+}
+
+/// File that setup blocks are cached in across runs, keyed by network and
+/// requirements
+#[derive(Clone, Debug)]
+pub struct SetupCache {
+    path: PathBuf,
+    entries: Vec<CachedSetup>,
+    /// Setup blocks generated by this run, cached once they are submitted
+    pending: Option<CachedSetup>,
+}
+
+impl SetupCache {
+    /// Open the setup cache file, which is created when setup blocks are
+    /// first cached, if it does not exist
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(cache_json) => Self::parse(path, &cache_json)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Vec::new()
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to read setup cache `{}`", path.display())
+                })
+            }
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            entries,
+            pending: None,
+        })
+    }
+
+    fn parse(
+        path: &Path,
+        cache_json: &str,
+    ) -> anyhow::Result<Vec<CachedSetup>> {
+        let corrupt = || {
+            format!(
+                "setup cache `{}` is corrupt. Delete it to generate setup \
+                 blocks again.",
+                path.display()
+            )
+        };
+        let SetupCacheVersion { version } =
+            serde_json::from_str(cache_json).with_context(corrupt)?;
+        if version != SETUP_CACHE_VERSION {
+            anyhow::bail!(
+                "setup cache `{}` has version {version}, but only version \
+                 {SETUP_CACHE_VERSION} is supported. Delete it to generate \
+                 setup blocks again.",
+                path.display()
+            );
+        }
+        let SetupCacheFile {
+            version: _,
+            entries,
+        } = serde_json::from_str(cache_json).with_context(corrupt)?;
+        Ok(entries)
+    }
+
+    /// Setup blocks cached for the network and requirements, if any
+    pub fn get(
+        &self,
+        network: Network,
+        requirements: &SetupRequirements,
+    ) -> Option<&CachedSetup> {
+        self.entries.iter().find(|entry| {
+            entry.network == network && entry.requirements == *requirements
+        })
+    }
+
+    /// Remove the setup blocks cached for the network and requirements, eg.
+    /// because the node's chain reorged past them. Saved by [`Self::save`].
+    pub fn remove(
+        &mut self,
+        network: Network,
+        requirements: &SetupRequirements,
+    ) {
+        self.entries.retain(|entry| {
+            entry.network != network || entry.requirements != *requirements
+        });
+    }
+
+    /// Set the setup blocks generated by this run, to cache with
+    /// [`Self::commit`] once they are submitted
+    pub fn set_pending(&mut self, setup: CachedSetup) {
+        self.pending = Some(setup);
+    }
+
+    /// Cache the setup blocks generated by this run, replacing any cached
+    /// for the same network and requirements, and save the file
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        let Some(setup) = self.pending.take() else {
+            return Ok(());
+        };
+        let () = self.remove(setup.network, &setup.requirements);
+        self.entries.push(setup);
+        self.save()
+    }
+
+    /// Write the cache to the file, replacing it atomically, so that an
+    /// interrupted write does not corrupt it
+    pub fn save(&self) -> anyhow::Result<()> {
+        let cache_file = SetupCacheFile {
+            version: SETUP_CACHE_VERSION,
+            entries: self.entries.clone(),
+        };
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let cache_json = serde_json::to_string_pretty(&cache_file)?;
+        std::fs::write(&tmp_path, format!("{cache_json}\n")).with_context(
+            || format!("failed to write `{}`", Path::new(&tmp_path).display()),
+        )?;
+        std::fs::rename(&tmp_path, &self.path).with_context(|| {
+            format!("failed to write setup cache `{}`", self.path.display())
+        })
+    }
+}
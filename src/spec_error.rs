@@ -0,0 +1,349 @@
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Write as _},
+};
+
+use serde_path_to_error::Segment;
+
+use crate::cli::{BlockSpec, BLOCKS_SPEC_VERSION};
+
+/// Number of lines of context to show before the offending line
+const CONTEXT_LINES: usize = 2;
+
+/// Maximum number of chars to show either side of the offending column
+const MAX_HALF_WIDTH: usize = 40;
+
+#[derive(Debug)]
+enum SpecParseErrorKind {
+    Json {
+        err: serde_path_to_error::Error<serde_json::Error>,
+        snippet: String,
+    },
+    /// The spec is in an envelope with an unsupported version
+    UnsupportedVersion {
+        version: u32,
+        /// Fields that are not known to this version
+        unknown_fields: Vec<String>,
+    },
+}
+
+/// Error parsing a blocks spec. Displays the location, a snippet of the
+/// surrounding input with a caret under the offending token, and the path to
+/// the offending field.
+#[derive(Debug)]
+pub struct SpecParseError {
+    /// Where the spec was read from, eg. a file path
+    origin: String,
+    kind: SpecParseErrorKind,
+}
+
+/// Render the lines preceding and including the offending line, with a caret
+/// under the offending column. Long lines are truncated around the column.
+fn render_snippet(input: &str, line: usize, column: usize) -> String {
+    let lines: Vec<&str> = input.lines().collect();
+    if line == 0 || line > lines.len() {
+        return String::new();
+    }
+    let gutter_width = line.to_string().len();
+    let mut res = format!("{:gutter_width$} |\n", "");
+    let first_line = line.saturating_sub(CONTEXT_LINES).max(1);
+    for line_number in first_line..=line {
+        let chars: Vec<char> = lines[line_number - 1].chars().collect();
+        // Column of the caret, 0-indexed
+        let caret_column = column.saturating_sub(1).min(chars.len());
+        let start = caret_column.saturating_sub(MAX_HALF_WIDTH);
+        let end = (caret_column + MAX_HALF_WIDTH).min(chars.len());
+        let prefix = if start > 0 { "..." } else { "" };
+        let suffix = if end < chars.len() { "..." } else { "" };
+        let text: String = chars[start..end].iter().collect();
+        let _ = writeln!(
+            res,
+            "{line_number:>gutter_width$} | {prefix}{text}{suffix}"
+        );
+        if line_number == line {
+            let padding = prefix.len() + caret_column - start;
+            let _ = writeln!(res, "{:gutter_width$} | {:padding$}^", "", "");
+        }
+    }
+    res
+}
+
+/// Suggest the known field name closest to an unknown field name, if any is
+/// close enough
+fn suggest_field(unknown_field: &str) -> Option<String> {
+    let max_distance = (unknown_field.len() / 3).max(2);
+    BlockSpec::field_names()
+        .into_iter()
+        .map(|field| (strsim::levenshtein(unknown_field, &field), field))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, field)| field)
+}
+
+/// Fields in a blocks spec envelope that are not known to this version
+fn unknown_fields(input: &str) -> Vec<String> {
+    let Ok(serde_json::Value::Object(envelope)) = serde_json::from_str(input)
+    else {
+        return Vec::new();
+    };
+    let block_fields = BlockSpec::field_names();
+    let mut res = BTreeSet::new();
+    for (key, value) in &envelope {
+        match (key.as_str(), value) {
+            ("version", _) => (),
+            ("blocks", serde_json::Value::Array(blocks)) => {
+                for block in blocks {
+                    let serde_json::Value::Object(block) = block else {
+                        continue;
+                    };
+                    res.extend(
+                        block
+                            .keys()
+                            .filter(|field| !block_fields.contains(field))
+                            .map(|field| format!("blocks[].{field}")),
+                    );
+                }
+            }
+            ("blocks", _) => (),
+            (key, _) => {
+                res.insert(key.to_owned());
+            }
+        }
+    }
+    res.into_iter().collect()
+}
+
+impl SpecParseError {
+    pub fn new(
+        input: &str,
+        origin: &str,
+        err: serde_path_to_error::Error<serde_json::Error>,
+    ) -> Self {
+        let snippet =
+            render_snippet(input, err.inner().line(), err.inner().column());
+        Self {
+            origin: origin.to_owned(),
+            kind: SpecParseErrorKind::Json { err, snippet },
+        }
+    }
+
+    /// Error for a blocks spec envelope with an unsupported version
+    pub fn unsupported_version(
+        input: &str,
+        origin: &str,
+        version: u32,
+    ) -> Self {
+        Self {
+            origin: origin.to_owned(),
+            kind: SpecParseErrorKind::UnsupportedVersion {
+                version,
+                unknown_fields: unknown_fields(input),
+            },
+        }
+    }
+
+    fn json_err(
+        &self,
+    ) -> Option<&serde_path_to_error::Error<serde_json::Error>> {
+        match &self.kind {
+            SpecParseErrorKind::Json { err, .. } => Some(err),
+            SpecParseErrorKind::UnsupportedVersion { .. } => None,
+        }
+    }
+
+    /// Short description of the kind of error
+    pub fn headline(&self) -> &'static str {
+        let Some(err) = self.json_err() else {
+            return "blocks spec version is not supported";
+        };
+        match err.inner().classify() {
+            serde_json::error::Category::Syntax
+            | serde_json::error::Category::Eof => {
+                "blocks spec is not valid JSON"
+            }
+            serde_json::error::Category::Data => "blocks spec is invalid",
+            serde_json::error::Category::Io => "failed to read blocks spec",
+        }
+    }
+
+    /// Index of the block spec in which the error occurred, if any
+    pub fn block_index(&self) -> Option<usize> {
+        let mut segments = self.json_err()?.path().iter().peekable();
+        // Skip the `blocks` field of an envelope
+        let _: Option<&Segment> = segments.next_if(|segment| {
+            matches!(segment, Segment::Map { key } if key == "blocks")
+        });
+        match segments.next()? {
+            Segment::Seq { index } => Some(*index),
+            Segment::Map { .. } | Segment::Enum { .. } | Segment::Unknown => {
+                None
+            }
+        }
+    }
+
+    /// Suggestion for a misspelled field name, if applicable
+    fn suggestion(&self) -> Option<String> {
+        let inner = self.json_err()?.inner().to_string();
+        let unknown_field = inner
+            .strip_prefix("unknown field `")?
+            .split_once('`')?
+            .0
+            .to_owned();
+        suggest_field(&unknown_field)
+    }
+}
+
+impl Display for SpecParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (err, snippet) = match &self.kind {
+            SpecParseErrorKind::Json { err, snippet } => (err, snippet),
+            SpecParseErrorKind::UnsupportedVersion {
+                version,
+                unknown_fields,
+            } => {
+                writeln!(
+                    f,
+                    "blocks spec version {version} is not supported: \
+                     supported versions are 1 to {BLOCKS_SPEC_VERSION}"
+                )?;
+                write!(f, "  --> {}", self.origin)?;
+                if !unknown_fields.is_empty() {
+                    write!(
+                        f,
+                        "\n  = fields not understood by this version: {}",
+                        unknown_fields.join(", ")
+                    )?;
+                }
+                return Ok(());
+            }
+        };
+        let inner = err.inner().to_string();
+        // Location is displayed separately
+        let msg = match inner.rsplit_once(" at line ") {
+            Some((msg, _)) => msg,
+            None => &inner,
+        };
+        if let Some(block_index) = self.block_index() {
+            write!(f, "block {block_index}: ")?;
+        }
+        write!(f, "{msg}")?;
+        match self.suggestion() {
+            Some(suggestion) => {
+                writeln!(f, " (did you mean `{suggestion}`?)")?;
+            }
+            None => writeln!(f)?,
+        }
+        writeln!(
+            f,
+            "  --> {}:{}:{}",
+            self.origin,
+            err.inner().line(),
+            err.inner().column()
+        )?;
+        write!(f, "{snippet}")?;
+        write!(f, "  = path: {}", err.path())
+    }
+}
+
+impl std::error::Error for SpecParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.json_err().map(|err| err.inner() as _)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_snippet;
+    use crate::cli::BlocksSpec;
+
+    /// Error displayed for a blocks spec read from `spec.json`
+    fn parse_err(input: &str) -> String {
+        BlocksSpec::parse(input, "spec.json")
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn misspelled_field() {
+        let input = "[\n  {\"label\": \"a\"},\n  {\"duplicat_m2\": true}\n]";
+        assert_eq!(
+            parse_err(input),
+            "block 1: unknown field `duplicat_m2`, expected one of `label`, \
+             `description`, `payout_address`, `expect`, `delay_ms`, \
+             `fork_from`, `post_actions`, `duplicate_m2` (did you mean \
+             `duplicate_m2`?)\n  \
+             --> spec.json:3:16\n  \
+               |\n\
+             1 | [\n\
+             2 |   {\"label\": \"a\"},\n\
+             3 |   {\"duplicat_m2\": true}\n  \
+               |                ^\n  \
+               = path: [1].duplicat_m2"
+        );
+    }
+
+    #[test]
+    fn extra_nested_object() {
+        let input = r#"[{}, {"label": "a", "extra": {"nested": {}}}]"#;
+        assert_eq!(
+            parse_err(input),
+            "block 1: unknown field `extra`, expected one of `label`, \
+             `description`, `payout_address`, `expect`, `delay_ms`, \
+             `fork_from`, `post_actions`, `duplicate_m2`\n  \
+             --> spec.json:1:27\n  \
+               |\n\
+             1 | [{}, {\"label\": \"a\", \"extra\": {\"nested\": {}}}]\n  \
+               |                           ^\n  \
+               = path: [1].extra"
+        );
+    }
+
+    #[test]
+    fn wrong_type() {
+        let input =
+            "[\n  {\n    \"label\": \"a\",\n    \"duplicate_m2\": \"yes\"\n  }\n]";
+        assert_eq!(
+            parse_err(input),
+            "block 0: invalid type: string \"yes\", expected a boolean\n  \
+             --> spec.json:4:25\n  \
+               |\n\
+             2 |   {\n\
+             3 |     \"label\": \"a\",\n\
+             4 |     \"duplicate_m2\": \"yes\"\n  \
+               |                         ^\n  \
+               = path: [0].duplicate_m2"
+        );
+    }
+
+    #[test]
+    fn snippet_caret() {
+        // The caret is under the column, counted in chars from 1
+        assert_eq!(
+            render_snippet("abc\ndéf", 2, 3),
+            "  |\n1 | abc\n2 | déf\n  |   ^\n"
+        );
+        // Columns past the end of the line put the caret after it
+        assert_eq!(render_snippet("ab", 1, 9), "  |\n1 | ab\n  |   ^\n");
+        // Long lines are truncated around the column, and the caret is
+        // shifted by the elision
+        let line = format!("{}^{}", "a".repeat(100), "b".repeat(100));
+        assert_eq!(
+            render_snippet(&line, 1, 101),
+            format!(
+                "  |\n1 | ...{}^{}...\n  | {}^\n",
+                "a".repeat(40),
+                "b".repeat(39),
+                " ".repeat(43)
+            )
+        );
+        // The gutter is as wide as the last line number
+        let input = "x\n".repeat(10);
+        assert_eq!(
+            render_snippet(&input, 10, 1),
+            "   |\n 8 | x\n 9 | x\n10 | x\n   | ^\n"
+        );
+        // Lines outside of the input render nothing
+        assert_eq!(render_snippet("a", 0, 1), "");
+        assert_eq!(render_snippet("a", 2, 1), "");
+    }
+}
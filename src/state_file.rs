@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use bitcoin::{
+    hashes::{sha256, Hash as _},
+    BlockHash, CompactTarget, OutPoint,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::BlocksSpec, Anchor};
+
+/// Version of the state file. Must be incremented whenever the state changes
+/// in a way that is not backwards-compatible.
+pub const STATE_VERSION: u32 = 1;
+
+/// Progress of a run, from which it can be resumed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunState {
+    pub version: u32,
+    /// SHA-256 of the normalized blocks spec JSON that the run was started
+    /// with
+    pub spec_sha256: sha256::Hash,
+    /// Index of the next block in the blocks spec
+    pub next_block: usize,
+    /// Height of the next block
+    pub height: u32,
+    pub prev_blockhash: BlockHash,
+    pub target: CompactTarget,
+    /// Coinbase outputs that anyone can spend once they mature, in blocks
+    /// that were accepted
+    pub spendable_coinbases: Vec<OutPoint>,
+}
+
+impl RunState {
+    /// Anchor for the next block
+    pub fn anchor(&self) -> Anchor {
+        Anchor {
+            height: self.height,
+            prev_blockhash: self.prev_blockhash,
+            target: self.target,
+        }
+    }
+}
+
+/// Progress made by a generated block, once it is accepted or rejected as
+/// expected
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    /// Number of blocks from the blocks spec that were generated by the run
+    /// up to and including the block
+    pub n_blocks: usize,
+    /// Anchor for the next block
+    pub anchor: Anchor,
+    /// Coinbase outputs in the block that anyone can spend
+    pub spendable_coinbases: Vec<OutPoint>,
+}
+
+/// Version of a state file, read before the rest of the state, so that
+/// files with other versions are reported as such
+#[derive(Deserialize)]
+struct StateVersion {
+    version: u32,
+}
+
+/// File that a run's progress is saved to, so that it can be resumed if it
+/// is interrupted
+#[derive(Clone, Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    spec_sha256: sha256::Hash,
+    /// State that the run resumed from, if the file existed
+    resumed: Option<RunState>,
+    /// State to save
+    state: Option<RunState>,
+}
+
+impl StateFile {
+    /// Open the state file for a run with the blocks spec. If the file
+    /// exists, the run resumes from its state, which must be for the same
+    /// blocks spec.
+    pub fn open(path: &Path, blocks_spec: &BlocksSpec) -> anyhow::Result<Self> {
+        let spec_json = serde_json::to_string(blocks_spec)?;
+        let spec_sha256 = sha256::Hash::hash(spec_json.as_bytes());
+        let resumed = match std::fs::read_to_string(path) {
+            Ok(state_json) => {
+                Some(Self::parse(path, &state_json, spec_sha256, blocks_spec)?)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to read state file `{}`", path.display())
+                })
+            }
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            spec_sha256,
+            state: resumed.clone(),
+            resumed,
+        })
+    }
+
+    fn parse(
+        path: &Path,
+        state_json: &str,
+        spec_sha256: sha256::Hash,
+        blocks_spec: &BlocksSpec,
+    ) -> anyhow::Result<RunState> {
+        let corrupt = || {
+            format!(
+                "state file `{}` is corrupt. Delete it to start the run over.",
+                path.display()
+            )
+        };
+        let StateVersion { version } =
+            serde_json::from_str(state_json).with_context(corrupt)?;
+        if version != STATE_VERSION {
+            anyhow::bail!(
+                "state file `{}` has version {version}, but only version \
+                 {STATE_VERSION} is supported. Delete it to start the run \
+                 over.",
+                path.display()
+            );
+        }
+        let state: RunState =
+            serde_json::from_str(state_json).with_context(corrupt)?;
+        if state.spec_sha256 != spec_sha256 {
+            anyhow::bail!(
+                "state file `{}` is for a different blocks spec, with \
+                 SHA-256 {}, rather than {spec_sha256}. Resume with the \
+                 original blocks spec, or delete the state file to start the \
+                 run over.",
+                path.display(),
+                state.spec_sha256
+            );
+        }
+        if state.next_block > blocks_spec.0.len() {
+            anyhow::bail!(
+                "{} The next block is {}, but the blocks spec has {} blocks.",
+                corrupt(),
+                state.next_block,
+                blocks_spec.0.len()
+            );
+        }
+        Ok(state)
+    }
+
+    /// State that the previous run stopped at, if the run is resumed
+    pub fn resumed(&self) -> Option<&RunState> {
+        self.resumed.as_ref()
+    }
+
+    /// Record the progress made by a block. Saved by [`Self::save`].
+    pub fn advance(&mut self, checkpoint: &Checkpoint) {
+        let Checkpoint {
+            n_blocks,
+            anchor,
+            spendable_coinbases,
+        } = checkpoint;
+        let first_block = self
+            .resumed
+            .as_ref()
+            .map_or(0, |resumed| resumed.next_block);
+        let mut all_spendable_coinbases = self
+            .state
+            .take()
+            .map(|state| state.spendable_coinbases)
+            .unwrap_or_default();
+        all_spendable_coinbases.extend(spendable_coinbases);
+        self.state = Some(RunState {
+            version: STATE_VERSION,
+            spec_sha256: self.spec_sha256,
+            next_block: first_block + n_blocks,
+            height: anchor.height,
+            prev_blockhash: anchor.prev_blockhash,
+            target: anchor.target,
+            spendable_coinbases: all_spendable_coinbases,
+        });
+    }
+
+    /// Write the state to the file, replacing it atomically, so that an
+    /// interrupted write does not corrupt it. If no progress was made or
+    /// resumed from, the file is removed.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(state) = &self.state else {
+            return match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(err).with_context(|| {
+                        format!(
+                            "failed to remove state file `{}`",
+                            self.path.display()
+                        )
+                    })
+                }
+                Ok(()) | Err(_) => Ok(()),
+            };
+        };
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let state_json = serde_json::to_string_pretty(state)?;
+        std::fs::write(&tmp_path, format!("{state_json}\n")).with_context(
+            || format!("failed to write `{}`", Path::new(&tmp_path).display()),
+        )?;
+        std::fs::rename(&tmp_path, &self.path).with_context(|| {
+            format!("failed to write state file `{}`", self.path.display())
+        })
+    }
+}
@@ -0,0 +1,1080 @@
+use std::{
+    collections::HashSet,
+    io::IsTerminal as _,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use bip300301::jsonrpsee::{
+    self,
+    core::{client::ClientT as _, params::ArrayParams},
+    http_client::HttpClient,
+};
+use bitcoin::{Block, BlockHash};
+use url::Url;
+
+use crate::{
+    block_description, check_node, check_wallet_funding,
+    cli::{BlockSpec, BlocksSpec, RpcOpts, SubmitArgs, Verdict},
+    enforcer_checks::{EnforcerCheck, EnforcerClient},
+    errors::RpcError,
+    fetch_block_header, gen_anchored_blocks,
+    json_output::{ActionResult, SecondaryResult, SubmitOutcome, SubmitResult},
+    metrics,
+    report::{BlockComparison, ComparisonReport},
+    retry_rpc,
+    rollback::rollback,
+    rpc_client,
+    script_builder::{verdict_arg, FailureClass},
+    submit_block_result::SubmitBlockResult,
+    tip_notifications::TipNotifications,
+    AnchorSource, BlockGenCtx, BlockHeaderInfo, GeneratedAction,
+};
+
+/// Response to the prompt before each block in interactive mode
+enum PromptResponse {
+    Submit,
+    Skip,
+    /// Submit the remaining blocks without prompting
+    SubmitAll,
+    Quit,
+}
+
+/// Prompt on stderr before submitting a block, and read the response from
+/// stdin. Prompts again if the response is not recognized. End of input is
+/// treated as quitting.
+fn prompt_before_submitting(
+    description: &str,
+    comment: Option<&str>,
+    expected: Verdict,
+) -> anyhow::Result<PromptResponse> {
+    eprintln!();
+    eprintln!("Next: {description}");
+    for line in comment.into_iter().flat_map(str::lines) {
+        eprintln!("  {line}");
+    }
+    eprintln!("  Expect: {}", verdict_arg(expected));
+    loop {
+        eprint!("[Enter] submit, [s] skip, [a] submit all, [q] quit: ");
+        let mut response = String::new();
+        if std::io::stdin().read_line(&mut response)? == 0 {
+            eprintln!();
+            return Ok(PromptResponse::Quit);
+        }
+        match response.trim() {
+            "" => return Ok(PromptResponse::Submit),
+            "s" => return Ok(PromptResponse::Skip),
+            "a" => return Ok(PromptResponse::SubmitAll),
+            "q" => return Ok(PromptResponse::Quit),
+            _ => (),
+        }
+    }
+}
+
+/// Class of failure for an RPC request that failed
+fn rpc_failure_class(err: &jsonrpsee::core::client::Error) -> FailureClass {
+    match err {
+        jsonrpsee::core::client::Error::Call(_) => FailureClass::RpcError,
+        _ => FailureClass::Connection,
+    }
+}
+
+/// Poll the node's tip until it is `block_hash`, with a short backoff, for up
+/// to `timeout`, and return the last tip. The node may connect a block
+/// shortly after `submitblock` returns.
+async fn poll_tip(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    block_hash: BlockHash,
+    timeout: Duration,
+) -> Result<BlockHash, jsonrpsee::core::client::Error> {
+    const MAX_BACKOFF: Duration = Duration::from_millis(500);
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(25);
+    loop {
+        let tip: BlockHash =
+            retry_rpc(rpc_retries, rpc_url, "getbestblockhash", |_| {
+                client.request("getbestblockhash", ArrayParams::new())
+            })
+            .await?;
+        let now = Instant::now();
+        if tip == block_hash || now >= deadline {
+            return Ok(tip);
+        }
+        tokio::time::sleep(backoff.min(deadline - now)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Submit a block to the node directly
+pub async fn submitblock(
+    client: &HttpClient,
+    block: &Block,
+) -> Result<SubmitBlockResult, jsonrpsee::core::client::Error> {
+    let mut params = ArrayParams::new();
+    let () = params.insert(metrics::block_hex(block))?;
+    let response = client.request("submitblock", params).await?;
+    Ok(SubmitBlockResult::parse(response))
+}
+
+/// Class of failure if a block was not accepted or rejected as expected,
+/// printing why to stderr
+pub fn unexpected_outcome(
+    expected: Verdict,
+    description: &str,
+    res: &SubmitBlockResult,
+) -> Option<FailureClass> {
+    match (expected, res) {
+        (Verdict::Accept, SubmitBlockResult::Accepted)
+        | (Verdict::Inconclusive, SubmitBlockResult::Inconclusive) => None,
+        // Invalid blocks on a side branch are not fully validated, so the
+        // node may respond `inconclusive`
+        (
+            Verdict::Reject,
+            SubmitBlockResult::Rejected { .. }
+            | SubmitBlockResult::Inconclusive
+            | SubmitBlockResult::Duplicate { inconclusive: true },
+        ) => None,
+        (Verdict::Accept, SubmitBlockResult::Rejected { .. }) => {
+            eprintln!(
+                "expected {description} to be accepted, but it was {res}"
+            );
+            Some(FailureClass::UnexpectedReject)
+        }
+        (Verdict::Accept, SubmitBlockResult::Inconclusive) => {
+            eprintln!(
+                "expected {description} to be accepted, but it was stored on \
+                 a side branch"
+            );
+            Some(FailureClass::UnexpectedReject)
+        }
+        // Generated blocks are new to the node, so a duplicate is neither
+        // the expected acceptance nor a rejection
+        (Verdict::Accept, SubmitBlockResult::Duplicate { .. }) => {
+            eprintln!(
+                "expected {description} to be accepted, but the node already \
+                 had it ({res})"
+            );
+            Some(FailureClass::UnexpectedReject)
+        }
+        (Verdict::Reject, SubmitBlockResult::Accepted) => {
+            eprintln!(
+                "expected {description} to be rejected, but it was accepted"
+            );
+            Some(FailureClass::UnexpectedAccept)
+        }
+        (
+            Verdict::Reject,
+            SubmitBlockResult::Duplicate {
+                inconclusive: false,
+            },
+        ) => {
+            eprintln!(
+                "expected {description} to be rejected, but the node already \
+                 had it as a valid block"
+            );
+            Some(FailureClass::UnexpectedAccept)
+        }
+        (Verdict::Inconclusive, SubmitBlockResult::Accepted) => {
+            eprintln!(
+                "expected {description} to be stored on a side branch, but \
+                 it became the tip"
+            );
+            Some(FailureClass::UnexpectedAccept)
+        }
+        (Verdict::Inconclusive, SubmitBlockResult::Rejected { .. }) => {
+            eprintln!(
+                "expected {description} to be stored on a side branch, but \
+                 it was {res}"
+            );
+            Some(FailureClass::UnexpectedReject)
+        }
+        (Verdict::Inconclusive, SubmitBlockResult::Duplicate { .. }) => {
+            eprintln!(
+                "expected {description} to be stored on a side branch, but \
+                 the node already had it ({res})"
+            );
+            Some(FailureClass::UnexpectedReject)
+        }
+    }
+}
+
+/// Submit a block with [`submitblock`], retrying transient failures. If the
+/// node already has the block when retrying, an earlier attempt reached the
+/// node, so its response is reported instead.
+async fn submitblock_retrying(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    block: &Block,
+) -> Result<SubmitBlockResult, jsonrpsee::core::client::Error> {
+    let start = Instant::now();
+    let res =
+        retry_rpc(rpc_retries, rpc_url, "submitblock", |attempt| async move {
+            let res = submitblock(client, block).await?;
+            if attempt > 1 {
+                Ok(res.retried())
+            } else {
+                Ok(res)
+            }
+        })
+        .await;
+    let () = metrics::record_submitted(&block.block_hash(), start.elapsed());
+    res
+}
+
+/// Apply an action to the node after submitting a block directly, and print
+/// the result. If `tip_timeout` is specified, the node's tip must become the
+/// expected tip within it. If an enforcer client is specified, the
+/// enforcer's state is also checked.
+async fn apply_post_action(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    post_action: &GeneratedAction,
+    tip_timeout: Option<Duration>,
+    enforcer_client: Option<&EnforcerClient>,
+    json: bool,
+) -> anyhow::Result<Option<FailureClass>> {
+    let GeneratedAction {
+        action,
+        block_hash,
+        description,
+        expected_tip,
+        comment: _,
+        enforcer_checks,
+    } = post_action;
+    let method = action.method();
+    let description = format!("{method} {description}");
+    let mut params = ArrayParams::new();
+    let () = params.insert(block_hash)?;
+    if let Err(err) = client.request::<(), _>(method, params).await {
+        let failure = rpc_failure_class(&err);
+        let err = RpcError::new(rpc_url, method, err);
+        eprintln!("RPC request failed for {description}: {err:#}");
+        return Ok(Some(failure));
+    }
+    let mut result = ActionResult {
+        action: *action,
+        hash: *block_hash,
+        tip: None,
+        enforcer_mismatches: Vec::new(),
+        failure: None,
+    };
+    if let Some(tip_timeout) = tip_timeout {
+        let tip = match poll_tip(
+            client,
+            rpc_url,
+            rpc_retries,
+            *expected_tip,
+            tip_timeout,
+        )
+        .await
+        {
+            Ok(tip) => tip,
+            Err(err) => {
+                let failure = rpc_failure_class(&err);
+                let err = RpcError::new(rpc_url, "getbestblockhash", err);
+                eprintln!("RPC request failed after {description}: {err:#}");
+                return Ok(Some(failure));
+            }
+        };
+        result.tip = Some(tip);
+        if tip != *expected_tip {
+            eprintln!(
+                "expected the node's tip to be {expected_tip} after \
+                 {description}, but it is {tip}"
+            );
+            result.failure = Some(FailureClass::TipMismatch);
+        }
+    }
+    if let Some(enforcer_client) = enforcer_client {
+        for check in enforcer_checks {
+            if result.failure.is_some() {
+                break;
+            }
+            let mismatches = match enforcer_client.check(check).await {
+                Ok(mismatches) => mismatches,
+                Err(err) => {
+                    eprintln!(
+                        "Enforcer request failed after {description}: {err:#}"
+                    );
+                    return Ok(Some(FailureClass::Connection));
+                }
+            };
+            if !mismatches.is_empty() {
+                eprintln!(
+                    "the enforcer's state is not as expected after \
+                     {description}:"
+                );
+                for mismatch in &mismatches {
+                    eprintln!("  {mismatch}");
+                }
+                result.enforcer_mismatches = mismatches;
+                result.failure = Some(FailureClass::EnforcerMismatch);
+            }
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string(&result)?);
+    } else {
+        match result.tip {
+            Some(tip) => println!("  {description}: tip is {tip}"),
+            None => println!("  {description}: done"),
+        }
+    }
+    Ok(result.failure)
+}
+
+/// Check the node's notified tips, printing an error if the tip
+/// changed to a block that was not generated in this run. Returns the class
+/// of failure if it did.
+async fn check_tips(
+    client: &HttpClient,
+    rpc_url: &Url,
+    rpc_retries: u32,
+    tips: &[BlockHash],
+    generated_hashes: &HashSet<BlockHash>,
+) -> anyhow::Result<Option<FailureClass>> {
+    let Some(tip) = tips.iter().find(|tip| !generated_hashes.contains(*tip))
+    else {
+        return Ok(None);
+    };
+    let BlockHeaderInfo {
+        height,
+        prev_blockhash: _,
+        bits: _,
+    } = fetch_block_header(client, rpc_url, rpc_retries, *tip).await?;
+    eprintln!(
+        "the chain moved underneath us at height {height}: the node's tip \
+         changed to {tip}, which was not generated in this run"
+    );
+    Ok(Some(FailureClass::TipMismatch))
+}
+
+/// Options for submitting blocks directly
+pub struct SubmitOpts {
+    pub delay_between_blocks: u64,
+    /// Check that accepted blocks become the node's tip, and rejected
+    /// blocks do not
+    pub check_tip: bool,
+    /// Enforcer to check the state of after submitting blocks, if any
+    pub enforcer_addr: Option<SocketAddr>,
+    pub submit_args: SubmitArgs,
+}
+
+/// Submit blocks with [`submit_blocks_inner`]. With `--rollback-on-failure`,
+/// the node's chain is rolled back to its tip from before the run if the run
+/// fails.
+pub async fn submit_blocks(
+    ctx: BlockGenCtx,
+    anchor_source: AnchorSource,
+    rpc: RpcOpts,
+    blocks_spec: BlocksSpec,
+    submit_opts: SubmitOpts,
+) -> anyhow::Result<Option<FailureClass>> {
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(&rpc, None)?;
+    let rpc_retries = rpc.rpc_retries;
+    // Fetched before anything is mined or submitted
+    let pre_run_tip: Option<BlockHash> = if submit_opts
+        .submit_args
+        .rollback_on_failure
+    {
+        let tip = retry_rpc(rpc_retries, &rpc_url, "getbestblockhash", |_| {
+            client.request("getbestblockhash", ArrayParams::new())
+        })
+        .await
+        .map_err(|err| RpcError::new(&rpc_url, "getbestblockhash", err))?;
+        Some(tip)
+    } else {
+        None
+    };
+    // Restored if the run is rolled back
+    let initial_state_file = ctx.state_file.clone();
+    let mut own_blocks = HashSet::new();
+    let res = submit_blocks_inner(
+        ctx,
+        anchor_source,
+        rpc,
+        blocks_spec,
+        submit_opts,
+        &mut own_blocks,
+    )
+    .await;
+    if let (Some(pre_run_tip), Ok(Some(_)) | Err(_)) = (pre_run_tip, &res) {
+        match rollback(
+            &client,
+            &rpc_url,
+            rpc_retries,
+            pre_run_tip,
+            Some(&own_blocks),
+        )
+        .await
+        {
+            Ok(invalidated) if invalidated.is_empty() => (),
+            Ok(invalidated) => {
+                eprintln!(
+                    "Rolled back to {pre_run_tip}, invalidating {}",
+                    invalidated
+                        .iter()
+                        .map(BlockHash::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                if let Some(initial_state_file) = initial_state_file {
+                    let () = initial_state_file.save()?;
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to roll back to {pre_run_tip}: {err:#}")
+            }
+        }
+    }
+    res
+}
+
+/// Block to submit, with what is expected of it
+struct Submission<'a> {
+    block: &'a Block,
+    height: u32,
+    /// `None` for setup blocks
+    block_spec: Option<&'a BlockSpec>,
+    expected: Verdict,
+    comment: &'a str,
+    /// Enforcer state expected after submitting the block
+    enforcer_checks: &'a [EnforcerCheck],
+    /// Actions on the node after submitting the block
+    post_actions: &'a [GeneratedAction],
+}
+
+/// Generate blocks, and submit them to the node directly, printing each
+/// block's result. Unless `check_tip` is false, the node's tip is checked
+/// after each block: accepted blocks must become the tip, and rejected
+/// blocks must not. If an enforcer address was specified, the enforcer's
+/// state is also checked after relevant blocks. Stops at the first block
+/// that is not accepted or rejected as expected, unless `--keep-going` was
+/// specified.
+/// With `--compare-enforcer`, the node's and enforcer's verdicts are instead
+/// recorded for every block, and reported at the end.
+/// Blocks that may be connected by the run, ie. the generated blocks, and
+/// the setup block mined by `--setup-via-generatetoaddress`, are added to
+/// `own_blocks` once generated.
+/// Returns the class of the first failure, if any.
+async fn submit_blocks_inner(
+    mut ctx: BlockGenCtx,
+    anchor_source: AnchorSource,
+    rpc: RpcOpts,
+    blocks_spec: BlocksSpec,
+    submit_opts: SubmitOpts,
+    own_blocks: &mut HashSet<BlockHash>,
+) -> anyhow::Result<Option<FailureClass>> {
+    let SubmitOpts {
+        delay_between_blocks,
+        check_tip,
+        enforcer_addr,
+        submit_args,
+    } = submit_opts;
+    let SubmitArgs {
+        keep_going,
+        mut interactive,
+        tip_timeout,
+        json,
+        compare_enforcer,
+        report_out,
+        secondary_rpc_url,
+        zmq_block,
+        no_longpoll,
+        rollback_on_failure: _,
+    } = submit_args;
+    // Checked before generating blocks, so that nothing is mined by
+    // `--setup-via-generatetoaddress`
+    if interactive && !std::io::stdin().is_terminal() {
+        anyhow::bail!("`--interactive` requires stdin to be a terminal");
+    }
+    // Subscribed before the block template is fetched, so that no tip
+    // changes are missed
+    let mut tip_notifications = match &zmq_block {
+        Some(endpoint) => Some(TipNotifications::zmq(endpoint).await?),
+        None => None,
+    };
+    let (generated_blocks, _node_tip) =
+        gen_anchored_blocks(&mut ctx, anchor_source, &rpc, blocks_spec).await?;
+    let rpc_url = rpc.endpoint(None);
+    let client = rpc_client(&rpc, None)?;
+    // Started once the blocks are generated, since the first template
+    // notified is the node's tip at that point, which must be the anchor
+    // unless the chain moved. Not started if the blocks were anchored on the
+    // node's tip because the node would not return a template.
+    if tip_notifications.is_none()
+        && !no_longpoll
+        && ctx.template_constraints.is_some()
+    {
+        tip_notifications =
+            Some(TipNotifications::longpoll(client.clone(), rpc_url.clone()));
+    }
+    let mut generated_hashes: HashSet<BlockHash> = generated_blocks
+        .setup_blocks
+        .iter()
+        .map(|setup_block| &setup_block.block)
+        .chain(
+            generated_blocks
+                .blocks
+                .iter()
+                .map(|generated_block| &generated_block.block),
+        )
+        .map(Block::block_hash)
+        .collect();
+    let anchor_hash = generated_blocks
+        .setup_blocks
+        .first()
+        .map(|setup_block| &setup_block.block)
+        .or_else(|| {
+            generated_blocks
+                .blocks
+                .first()
+                .map(|generated_block| &generated_block.block)
+        })
+        .map(|block| block.header.prev_blockhash);
+    own_blocks.clone_from(&generated_hashes);
+    if let AnchorSource::Node {
+        setup_via_generatetoaddress: true,
+        ..
+    } = anchor_source
+    {
+        own_blocks.extend(anchor_hash);
+    }
+    if let Some(tip_notifications) = &mut tip_notifications {
+        // Tips notified up to the anchor preceded the block template
+        let tips = tip_notifications.drain()?;
+        let tips = match tips.iter().rposition(|tip| Some(*tip) == anchor_hash)
+        {
+            Some(idx) => &tips[idx + 1..],
+            None => &tips[..],
+        };
+        generated_hashes.extend(anchor_hash);
+        if let Some(failure) = check_tips(
+            &client,
+            &rpc_url,
+            rpc.rpc_retries,
+            tips,
+            &generated_hashes,
+        )
+        .await?
+        {
+            return Ok(Some(failure));
+        }
+    }
+    let mut secondary_clients = Vec::new();
+    for secondary_rpc_url in secondary_rpc_url {
+        let secondary_rpc = rpc.with_rpc_url(secondary_rpc_url);
+        let _: String =
+            check_node(&secondary_rpc, ctx.chain_params.network).await?;
+        secondary_clients.push((
+            secondary_rpc.endpoint(None),
+            rpc_client(&secondary_rpc, None)?,
+        ));
+    }
+    let tip_timeout = Duration::from_millis(tip_timeout);
+    let enforcer_client = enforcer_addr.map(|addr| {
+        EnforcerClient::new(addr, Duration::from_secs(rpc.rpc_timeout))
+    });
+    let compare_client = match (compare_enforcer, &enforcer_client) {
+        (false, _) => None,
+        (true, Some(enforcer_client)) => Some(enforcer_client),
+        (true, None) => {
+            anyhow::bail!("`--compare-enforcer` requires `--enforcer-addr`")
+        }
+    };
+    let mut comparisons = Vec::new();
+    // Setup blocks have no block spec, and must be accepted
+    let setup_blocks =
+        generated_blocks
+            .setup_blocks
+            .iter()
+            .map(|setup_block| Submission {
+                block: &setup_block.block,
+                height: setup_block.height,
+                block_spec: None,
+                expected: Verdict::Accept,
+                comment: &generated_blocks.setup_comment,
+                enforcer_checks: &setup_block.enforcer_checks,
+                post_actions: &[],
+            });
+    let blocks =
+        generated_blocks
+            .blocks
+            .iter()
+            .map(|generated_block| Submission {
+                block: &generated_block.block,
+                height: generated_block.height,
+                block_spec: Some(&generated_block.block_spec),
+                expected: generated_block.expected_verdict,
+                comment: &generated_block.comment,
+                enforcer_checks: &generated_block.enforcer_checks,
+                post_actions: &generated_block.post_actions,
+            });
+    let submissions: Vec<_> = setup_blocks.chain(blocks).collect();
+    let checkpoints = generated_blocks.checkpoints();
+    // `false` once a block is skipped, since later blocks build on it
+    let mut resumable = true;
+    let n_blocks = submissions.len();
+    let n_setup_blocks = generated_blocks.setup_blocks.len();
+    let funded_wallet = generated_blocks.funded_wallet.as_ref();
+    if enforcer_client.is_none()
+        && !compare_enforcer
+        && submissions
+            .iter()
+            .any(|submission| !submission.enforcer_checks.is_empty())
+    {
+        tracing::warn!(
+            "The enforcer's state will not be checked, since \
+             `--enforcer-addr` was not specified"
+        );
+    }
+    let print_result =
+        |result: &SubmitResult, description: &str| -> anyhow::Result<()> {
+            if json {
+                println!("{}", serde_json::to_string(result)?);
+            } else {
+                let SubmitResult {
+                    index,
+                    outcome,
+                    secondary_nodes,
+                    ..
+                } = result;
+                let mut line =
+                    format!("[{index}/{n_blocks}] {description}: {outcome}");
+                for secondary_result in secondary_nodes {
+                    line.push_str(&format!(" | {secondary_result}"));
+                }
+                println!("{line}");
+            }
+            Ok(())
+        };
+    let mut first_failure = None;
+    for (idx, submission) in submissions.into_iter().enumerate() {
+        let Submission {
+            block,
+            height,
+            block_spec,
+            expected,
+            comment,
+            enforcer_checks,
+            post_actions,
+        } = submission;
+        // Checked once the setup blocks are submitted
+        if let Some(funded_wallet) =
+            funded_wallet.filter(|_| idx == n_setup_blocks)
+        {
+            let () = check_wallet_funding(
+                &rpc,
+                &funded_wallet.address,
+                funded_wallet.amount,
+            )
+            .await?;
+        }
+        let block_hash = block.block_hash();
+        let description = block_description(height, block_hash, block_spec);
+        let mut result = SubmitResult {
+            index: idx + 1,
+            hash: block_hash,
+            height,
+            label: block_spec
+                .and_then(|block_spec| block_spec.label.as_deref()),
+            expected_verdict: expected,
+            outcome: SubmitOutcome::Skipped,
+            tip: None,
+            enforcer_mismatches: Vec::new(),
+            failure: None,
+            secondary_nodes: Vec::new(),
+        };
+        if interactive {
+            let comment = Some(comment).filter(|comment| !comment.is_empty());
+            match prompt_before_submitting(&description, comment, expected)? {
+                PromptResponse::Submit => (),
+                PromptResponse::Skip => {
+                    let () = print_result(&result, &description)?;
+                    resumable = false;
+                    continue;
+                }
+                PromptResponse::SubmitAll => interactive = false,
+                PromptResponse::Quit => break,
+            }
+        } else {
+            // Delays are replaced by the prompt in interactive mode
+            let delay = block_spec
+                .and_then(|block_spec| block_spec.delay_ms)
+                .unwrap_or(delay_between_blocks);
+            if idx > 0 && delay > 0 {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+        }
+        // Checked after any delay or prompt, so that the block is not
+        // submitted on a stale parent
+        if let Some(tip_notifications) = &mut tip_notifications {
+            let tips = tip_notifications.drain()?;
+            if let Some(failure) = check_tips(
+                &client,
+                &rpc_url,
+                rpc.rpc_retries,
+                &tips,
+                &generated_hashes,
+            )
+            .await?
+            {
+                return Ok(Some(failure));
+            }
+        }
+        let submit_result = match submitblock_retrying(
+            &client,
+            &rpc_url,
+            rpc.rpc_retries,
+            block,
+        )
+        .await
+        {
+            Ok(submit_result) => submit_result,
+            Err(err) => {
+                let failure = rpc_failure_class(&err);
+                let err = RpcError::new(&rpc_url, "submitblock", err);
+                eprintln!("RPC request failed for {description}: {err:#}");
+                return Ok(Some(first_failure.unwrap_or(failure)));
+            }
+        };
+        result.outcome = SubmitOutcome::Submitted(submit_result.clone());
+        for (secondary_url, secondary_client) in &secondary_clients {
+            let (outcome, error) = match submitblock_retrying(
+                secondary_client,
+                secondary_url,
+                rpc.rpc_retries,
+                block,
+            )
+            .await
+            {
+                Ok(res) => (Some(SubmitOutcome::Submitted(res)), None),
+                Err(err) => {
+                    let err = RpcError::new(secondary_url, "submitblock", err);
+                    (None, Some(format!("{err:#}")))
+                }
+            };
+            result.secondary_nodes.push(SecondaryResult {
+                node: secondary_url.to_string(),
+                outcome,
+                error,
+            });
+        }
+        // Blocks are not expected to be rejected by the node when comparing
+        // verdicts, so no other checks apply
+        if let Some(compare_client) = compare_client {
+            let (node_verdict, node_reject_reason) = match &submit_result {
+                SubmitBlockResult::Accepted
+                | SubmitBlockResult::Duplicate {
+                    inconclusive: false,
+                } => (Verdict::Accept, None),
+                SubmitBlockResult::Inconclusive
+                | SubmitBlockResult::Duplicate { inconclusive: true } => {
+                    (Verdict::Inconclusive, None)
+                }
+                SubmitBlockResult::Rejected { reason, known: _ } => {
+                    (Verdict::Reject, Some(reason.clone()))
+                }
+            };
+            let enforcer_verdict = match node_verdict {
+                Verdict::Accept => {
+                    match compare_client.block_verdict(block_hash).await {
+                        Ok(verdict) => Some(verdict),
+                        Err(err) => {
+                            eprintln!(
+                                "Enforcer request failed after submitting \
+                                 {description}: {err:#}"
+                            );
+                            return Ok(Some(FailureClass::Connection));
+                        }
+                    }
+                }
+                Verdict::Reject | Verdict::Inconclusive => None,
+            };
+            let () = print_result(&result, &description)?;
+            comparisons.push(BlockComparison {
+                index: result.index,
+                hash: block_hash,
+                height,
+                label: result.label.map(str::to_owned),
+                expected_verdict: expected,
+                node_verdict,
+                node_reject_reason,
+                enforcer_verdict,
+            });
+            // Actions still apply, since they affect the verdicts of later
+            // blocks, but are not checked
+            for post_action in post_actions {
+                if let Some(failure) = apply_post_action(
+                    &client,
+                    &rpc_url,
+                    rpc.rpc_retries,
+                    post_action,
+                    None,
+                    None,
+                    json,
+                )
+                .await?
+                {
+                    return Ok(Some(failure));
+                }
+            }
+            if let (Some(state_file), Some(checkpoint), true) =
+                (&mut ctx.state_file, &checkpoints[idx], resumable)
+            {
+                let () = state_file.advance(checkpoint);
+                let () = state_file.save()?;
+            }
+            continue;
+        }
+        result.failure =
+            unexpected_outcome(expected, &description, &submit_result);
+        if check_tip && result.failure.is_none() {
+            // Rejected and side branch blocks are not waited for, as they
+            // should never become the tip
+            let timeout = match expected {
+                Verdict::Accept => tip_timeout,
+                Verdict::Reject | Verdict::Inconclusive => Duration::ZERO,
+            };
+            let tip = match poll_tip(
+                &client,
+                &rpc_url,
+                rpc.rpc_retries,
+                block_hash,
+                timeout,
+            )
+            .await
+            {
+                Ok(tip) => tip,
+                Err(err) => {
+                    let failure = rpc_failure_class(&err);
+                    let err = RpcError::new(&rpc_url, "getbestblockhash", err);
+                    eprintln!(
+                        "RPC request failed after submitting {description}: \
+                         {err:#}"
+                    );
+                    return Ok(Some(first_failure.unwrap_or(failure)));
+                }
+            };
+            result.tip = Some(tip);
+            match expected {
+                Verdict::Accept if tip != block_hash => {
+                    eprintln!(
+                        "expected {description} to become the tip, but the \
+                         tip is {tip}"
+                    );
+                    result.failure = Some(FailureClass::TipMismatch);
+                }
+                Verdict::Reject if tip == block_hash => {
+                    eprintln!(
+                        "expected {description} to be rejected, but it \
+                         became the tip"
+                    );
+                    result.failure = Some(FailureClass::TipMismatch);
+                }
+                Verdict::Inconclusive if tip == block_hash => {
+                    eprintln!(
+                        "expected {description} to be stored on a side \
+                         branch, but it became the tip"
+                    );
+                    result.failure = Some(FailureClass::TipMismatch);
+                }
+                Verdict::Accept | Verdict::Reject | Verdict::Inconclusive => (),
+            }
+        }
+        if let Some(enforcer_client) = &enforcer_client {
+            for check in enforcer_checks {
+                if result.failure.is_some() {
+                    break;
+                }
+                let mismatches = match enforcer_client.check(check).await {
+                    Ok(mismatches) => mismatches,
+                    Err(err) => {
+                        eprintln!(
+                            "Enforcer request failed after submitting \
+                             {description}: {err:#}"
+                        );
+                        return Ok(Some(
+                            first_failure.unwrap_or(FailureClass::Connection),
+                        ));
+                    }
+                };
+                if !mismatches.is_empty() {
+                    eprintln!(
+                        "the enforcer's state is not as expected after \
+                         {description}:"
+                    );
+                    for mismatch in &mismatches {
+                        eprintln!("  {mismatch}");
+                    }
+                    result.enforcer_mismatches = mismatches;
+                    result.failure = Some(FailureClass::EnforcerMismatch);
+                }
+            }
+        }
+        let () = print_result(&result, &description)?;
+        if let Some(failure) = result.failure {
+            let failure = *first_failure.get_or_insert(failure);
+            if !keep_going {
+                return Ok(Some(failure));
+            }
+        }
+        for post_action in post_actions {
+            let failure = apply_post_action(
+                &client,
+                &rpc_url,
+                rpc.rpc_retries,
+                post_action,
+                check_tip.then_some(tip_timeout),
+                enforcer_client.as_ref(),
+                json,
+            )
+            .await?;
+            let Some(failure) = failure else {
+                continue;
+            };
+            let failure = *first_failure.get_or_insert(failure);
+            if !keep_going {
+                return Ok(Some(failure));
+            }
+        }
+        // Blocks after a failure are not resumed from, so that the failed
+        // block is submitted again
+        if let (Some(state_file), Some(checkpoint), true, None) = (
+            &mut ctx.state_file,
+            &checkpoints[idx],
+            resumable,
+            first_failure,
+        ) {
+            let () = state_file.advance(checkpoint);
+            let () = state_file.save()?;
+        }
+        // Cached once every setup block is accepted
+        if let (Some(setup_cache), true, true, None) = (
+            &mut ctx.setup_cache,
+            idx + 1 == n_setup_blocks,
+            resumable,
+            first_failure,
+        ) {
+            let () = setup_cache.commit()?;
+        }
+    }
+    if let Some(funded_wallet) =
+        funded_wallet.filter(|_| n_blocks == n_setup_blocks)
+    {
+        let () = check_wallet_funding(
+            &rpc,
+            &funded_wallet.address,
+            funded_wallet.amount,
+        )
+        .await?;
+    }
+    if compare_enforcer {
+        let report = ComparisonReport::new(ctx.seed, comparisons);
+        if !json {
+            println!();
+            print!("{}", report.table());
+        }
+        if let Some(report_out) = report_out {
+            let report_json = serde_json::to_string_pretty(&report)?;
+            std::fs::write(&report_out, format!("{report_json}\n"))
+                .with_context(|| {
+                    format!("failed to write `{}`", report_out.display())
+                })?;
+            tracing::info!("Wrote report to `{}`", report_out.display());
+        }
+        if report.n_unexpected > 0 {
+            first_failure = Some(FailureClass::EnforcerMismatch);
+        }
+    }
+    Ok(first_failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use bitcoin::{hashes::Hash as _, BlockHash};
+    use clap::Parser as _;
+    use serde_json::json;
+
+    use super::check_tips;
+    use crate::{
+        cli::RpcOpts, mock_rpc::MockRpcServer, rpc_client,
+        script_builder::FailureClass, tip_notifications::TipNotifications,
+    };
+
+    /// Hash that is distinct for each `n`
+    fn block_hash(n: u8) -> BlockHash {
+        BlockHash::from_byte_array([n; 32])
+    }
+
+    #[tokio::test]
+    async fn template_change_is_tip_mismatch() {
+        let anchor = block_hash(1);
+        let other_tip = block_hash(2);
+        // The template changes once, in response to the first long poll
+        let node = MockRpcServer::start_pending(move |method, params| {
+            let response = match method {
+                "getblocktemplate" => {
+                    let (prev_blockhash, longpollid) =
+                        match params[0]["longpollid"].as_str() {
+                            None => (anchor, "1"),
+                            Some("1") => (other_tip, "2"),
+                            Some(_) => return None,
+                        };
+                    Ok(json!({
+                        "previousblockhash": prev_blockhash,
+                        "height": 101,
+                        "bits": "207fffff",
+                        "longpollid": longpollid,
+                        "transactions": [],
+                    }))
+                }
+                "getblockheader" => Ok(json!({
+                    "height": 101,
+                    "previousblockhash": anchor,
+                    "bits": "207fffff",
+                })),
+                _ => Err((-32601, "Method not found".to_owned())),
+            };
+            Some(response)
+        })
+        .await;
+        let rpc = RpcOpts::try_parse_from([
+            "bip300-enforcer-demo",
+            "--rpc-url",
+            node.url().as_str(),
+        ])
+        .unwrap();
+        let client = rpc_client(&rpc, None).unwrap();
+        let mut tip_notifications =
+            TipNotifications::longpoll(client.clone(), node.url());
+        let mut tips = Vec::new();
+        while tips.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tips.extend(tip_notifications.drain().unwrap());
+        }
+        assert_eq!(tips, [anchor, other_tip]);
+        let generated_hashes = HashSet::from([anchor, block_hash(3)]);
+        // Only tips that were not generated are failures
+        let failure =
+            check_tips(&client, &node.url(), 0, &tips[..1], &generated_hashes)
+                .await
+                .unwrap();
+        assert_eq!(failure, None);
+        let failure =
+            check_tips(&client, &node.url(), 0, &tips, &generated_hashes)
+                .await
+                .unwrap();
+        assert_eq!(failure, Some(FailureClass::TipMismatch));
+        let methods = node.methods();
+        assert_eq!(methods.last().unwrap(), "getblockheader");
+    }
+}
@@ -0,0 +1,287 @@
+use serde::Serialize;
+
+/// Response to `submitblock` for a valid block that was stored, but not
+/// connected
+const INCONCLUSIVE: &str = "inconclusive";
+
+/// Response to `submitblock` for a block that the node already has, and
+/// fully validated
+const DUPLICATE: &str = "duplicate";
+
+/// Response to `submitblock` for a block that the node already has, and
+/// stored without fully validating it
+const DUPLICATE_INCONCLUSIVE: &str = "duplicate-inconclusive";
+
+/// Reasons that nodes give for rejecting blocks with `submitblock`, and
+/// what they mean. Reasons that end with `(`, eg. `bad-version(`, are
+/// matched as prefixes, since the node appends details to them.
+const KNOWN_REJECT_REASONS: &[(&str, &str)] = &[
+    ("bad-blk-length", "the block is too large"),
+    ("bad-blk-weight", "the block exceeds the weight limit"),
+    ("bad-blk-sigops", "the block exceeds the sigop limit"),
+    ("bad-cb-missing", "the first transaction is not a coinbase"),
+    ("bad-cb-multiple", "the block has more than one coinbase"),
+    (
+        "bad-cb-length",
+        "the coinbase scriptSig is too short or too long",
+    ),
+    (
+        "bad-cb-height",
+        "the coinbase does not commit to the block height",
+    ),
+    (
+        "bad-cb-amount",
+        "the coinbase pays more than the block subsidy and fees",
+    ),
+    (
+        "bad-txnmrklroot",
+        "the merkle root does not match the transactions",
+    ),
+    (
+        "bad-txns-duplicate",
+        "the block has a duplicate transaction",
+    ),
+    (
+        "bad-txns-inputs-missingorspent",
+        "a transaction spends a missing or spent output",
+    ),
+    (
+        "bad-txns-premature-spend-of-coinbase",
+        "a transaction spends an immature coinbase output",
+    ),
+    ("bad-txns-nonfinal", "the block has a non-final transaction"),
+    (
+        "bad-txns-BIP30",
+        "a transaction overwrites an unspent transaction",
+    ),
+    ("bad-diffbits", "the target is not the one required"),
+    ("high-hash", "the header hash does not meet the target"),
+    (
+        "time-too-old",
+        "the timestamp is not after the median time past",
+    ),
+    ("time-too-new", "the timestamp is too far in the future"),
+    ("bad-version(", "the block version is obsolete"),
+    (
+        "bad-witness-nonce-size",
+        "the coinbase witness reserved value is not 32 bytes",
+    ),
+    (
+        "bad-witness-merkle-match",
+        "the witness commitment does not match the witnesses",
+    ),
+    (
+        "unexpected-witness",
+        "the block has witnesses, but no commitment",
+    ),
+    ("bad-prevblk", "the previous block is invalid"),
+    (
+        "prev-blk-not-found",
+        "the previous block is not known to the node",
+    ),
+    (
+        "duplicate-invalid",
+        "the node already has the block, as invalid",
+    ),
+    (
+        "rejected",
+        "the block was rejected, without a more specific reason",
+    ),
+];
+
+/// Response to `submitblock`, normalized across node versions
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "outcome")]
+pub enum SubmitBlockResult {
+    /// `null`: the block was valid, and stored
+    Accepted,
+    /// `duplicate` or `duplicate-inconclusive`: the node already had the
+    /// block. `inconclusive` if the node had not fully validated it.
+    Duplicate { inconclusive: bool },
+    /// `inconclusive`: the block was stored, but not connected, since its
+    /// branch has no more work than the node's tip
+    Inconclusive,
+    /// Any other response. `known` is `false` for reasons that are not in
+    /// [`KNOWN_REJECT_REASONS`], eg. those added by patched nodes.
+    Rejected { reason: String, known: bool },
+}
+
+impl SubmitBlockResult {
+    /// Normalize a `submitblock` response, which is `None` for `null`
+    pub fn parse(response: Option<String>) -> Self {
+        let Some(reason) = response else {
+            return Self::Accepted;
+        };
+        match reason.as_str() {
+            DUPLICATE => Self::Duplicate {
+                inconclusive: false,
+            },
+            DUPLICATE_INCONCLUSIVE => Self::Duplicate { inconclusive: true },
+            INCONCLUSIVE => Self::Inconclusive,
+            _ => {
+                let known = reject_reason_description(&reason).is_some();
+                Self::Rejected { reason, known }
+            }
+        }
+    }
+
+    /// Normalize the result of a retried submission. A duplicate means that
+    /// an earlier attempt reached the node, so the block was new to it.
+    pub fn retried(self) -> Self {
+        match self {
+            Self::Duplicate {
+                inconclusive: false,
+            } => Self::Accepted,
+            Self::Duplicate { inconclusive: true } => Self::Inconclusive,
+            res @ (Self::Accepted
+            | Self::Inconclusive
+            | Self::Rejected { .. }) => res,
+        }
+    }
+}
+
+impl std::fmt::Display for SubmitBlockResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Accepted => write!(f, "accepted"),
+            Self::Duplicate {
+                inconclusive: false,
+            } => write!(f, "duplicate"),
+            Self::Duplicate { inconclusive: true } => {
+                write!(f, "duplicate, inconclusive")
+            }
+            Self::Inconclusive => write!(f, "inconclusive"),
+            Self::Rejected { reason, known: _ } => {
+                match reject_reason_description(reason) {
+                    Some(description) => {
+                        write!(f, "rejected: {reason} ({description})")
+                    }
+                    None => write!(f, "rejected: {reason} (unknown reason)"),
+                }
+            }
+        }
+    }
+}
+
+/// What a reason for rejecting a block means, if it is in
+/// [`KNOWN_REJECT_REASONS`]
+fn reject_reason_description(reason: &str) -> Option<&'static str> {
+    KNOWN_REJECT_REASONS
+        .iter()
+        .find(|(known, _)| match known.strip_suffix('(') {
+            Some(_) => reason.starts_with(known),
+            None => reason == *known,
+        })
+        .map(|(_, description)| *description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SubmitBlockResult, KNOWN_REJECT_REASONS};
+
+    /// `submitblock` response for each known reason, with details appended
+    /// to reasons that are matched as prefixes
+    fn known_responses() -> impl Iterator<Item = (String, &'static str)> {
+        KNOWN_REJECT_REASONS.iter().map(|(reason, description)| {
+            let response = match reason.strip_suffix('(') {
+                Some(_) => format!("{reason}0x00000001)"),
+                None => (*reason).to_owned(),
+            };
+            (response, *description)
+        })
+    }
+
+    #[test]
+    fn parse() {
+        let cases = [
+            (None, SubmitBlockResult::Accepted),
+            (
+                Some("duplicate"),
+                SubmitBlockResult::Duplicate {
+                    inconclusive: false,
+                },
+            ),
+            (
+                Some("duplicate-inconclusive"),
+                SubmitBlockResult::Duplicate { inconclusive: true },
+            ),
+            (Some("inconclusive"), SubmitBlockResult::Inconclusive),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(
+                SubmitBlockResult::parse(response.map(str::to_owned)),
+                expected,
+                "{response:?}"
+            );
+        }
+        for (response, _) in known_responses() {
+            assert_eq!(
+                SubmitBlockResult::parse(Some(response.clone())),
+                SubmitBlockResult::Rejected {
+                    reason: response,
+                    known: true,
+                }
+            );
+        }
+        // Unknown reasons, including known reasons with the wrong case,
+        // extra details, or a missing suffix
+        for response in [
+            "bad-drivechain-m2",
+            "",
+            "High-hash",
+            "high-hash(1)",
+            "bad-version",
+        ] {
+            assert_eq!(
+                SubmitBlockResult::parse(Some(response.to_owned())),
+                SubmitBlockResult::Rejected {
+                    reason: response.to_owned(),
+                    known: false,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn display() {
+        for (response, description) in known_responses() {
+            assert_eq!(
+                SubmitBlockResult::parse(Some(response.clone())).to_string(),
+                format!("rejected: {response} ({description})")
+            );
+        }
+        assert_eq!(
+            SubmitBlockResult::parse(Some("bad-drivechain-m2".to_owned()))
+                .to_string(),
+            "rejected: bad-drivechain-m2 (unknown reason)"
+        );
+        assert_eq!(SubmitBlockResult::parse(None).to_string(), "accepted");
+    }
+
+    #[test]
+    fn retried() {
+        let cases = [
+            (Some("duplicate"), SubmitBlockResult::Accepted),
+            (
+                Some("duplicate-inconclusive"),
+                SubmitBlockResult::Inconclusive,
+            ),
+            (None, SubmitBlockResult::Accepted),
+            (Some("inconclusive"), SubmitBlockResult::Inconclusive),
+            (
+                Some("high-hash"),
+                SubmitBlockResult::Rejected {
+                    reason: "high-hash".to_owned(),
+                    known: true,
+                },
+            ),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(
+                SubmitBlockResult::parse(response.map(str::to_owned)).retried(),
+                expected,
+                "{response:?}"
+            );
+        }
+    }
+}
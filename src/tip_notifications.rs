@@ -0,0 +1,195 @@
+use anyhow::Context as _;
+use bip300301::jsonrpsee::http_client::HttpClient;
+use bitcoin::{hex::DisplayHex as _, BlockHash};
+use tokio::sync::mpsc;
+use url::Url;
+use zeromq::{Socket as _, SocketRecv as _, ZmqMessage};
+
+use crate::mine::fetch_long_poll_template;
+
+/// ZMQ topic for notifications of the node's new tip
+const HASHBLOCK_TOPIC: &str = "hashblock";
+
+/// Notifications of a node's new tips, received in a background task, so
+/// that none are missed between checks
+pub struct TipNotifications {
+    /// Where notifications are received from, for error messages
+    source: String,
+    rx: mpsc::UnboundedReceiver<anyhow::Result<BlockHash>>,
+}
+
+impl TipNotifications {
+    /// Subscribe to the node's `hashblock` ZMQ notifications, which are sent
+    /// whenever the node's tip changes, at its `-zmqpubhashblock` endpoint,
+    /// eg. `tcp://127.0.0.1:28332`
+    pub async fn zmq(endpoint: &str) -> anyhow::Result<Self> {
+        let mut socket = zeromq::SubSocket::new();
+        let () = socket.connect(endpoint).await.with_context(|| {
+            format!("failed to connect to ZMQ endpoint `{endpoint}`")
+        })?;
+        let () = socket.subscribe(HASHBLOCK_TOPIC).await.with_context(|| {
+            format!("failed to subscribe to `{HASHBLOCK_TOPIC}` at `{endpoint}`")
+        })?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let notification = match socket.recv().await {
+                    Ok(message) => parse_hashblock(message),
+                    Err(err) => Err(err.into()),
+                };
+                let failed = notification.is_err();
+                if tx.send(notification).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            source: format!("ZMQ endpoint `{endpoint}`"),
+            rx,
+        })
+    }
+
+    /// Long poll the node's block template with `getblocktemplate`, which
+    /// responds whenever the template changes. The first notification is the
+    /// node's tip when polling starts. Tips may be skipped if the node's tip
+    /// changes several times within one request.
+    pub fn longpoll(client: HttpClient, rpc_url: Url) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let source = format!("`getblocktemplate` long polling at `{rpc_url}`");
+        tokio::spawn(async move {
+            let mut longpollid = None;
+            loop {
+                let template = match fetch_long_poll_template(
+                    &client,
+                    &rpc_url,
+                    longpollid.as_deref(),
+                )
+                .await
+                {
+                    Ok(template) => template,
+                    Err(err) => {
+                        let _ = tx.send(Err(err.into()));
+                        break;
+                    }
+                };
+                if tx.send(Ok(template.prev_blockhash)).is_err() {
+                    break;
+                }
+                longpollid = Some(template.longpollid);
+            }
+        });
+        Self { source, rx }
+    }
+
+    /// Hashes of the new tips notified since the last call, without
+    /// waiting. Fails if the subscription failed.
+    pub fn drain(&mut self) -> anyhow::Result<Vec<BlockHash>> {
+        let mut block_hashes = Vec::new();
+        loop {
+            match self.rx.try_recv() {
+                Ok(notification) => {
+                    let block_hash = notification.with_context(|| {
+                        format!(
+                            "failed to receive a tip notification from {}",
+                            self.source
+                        )
+                    })?;
+                    block_hashes.push(block_hash);
+                }
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    return Ok(block_hashes)
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    anyhow::bail!(
+                        "tip notifications from {} ended",
+                        self.source
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Parse a `hashblock` message: the topic, the block hash in display order,
+/// and a sequence number
+fn parse_hashblock(message: ZmqMessage) -> anyhow::Result<BlockHash> {
+    let frames = message.into_vec();
+    let [topic, body, _sequence] = frames.as_slice() else {
+        anyhow::bail!(
+            "expected 3 frames in a `{HASHBLOCK_TOPIC}` message, got {}",
+            frames.len()
+        );
+    };
+    if topic.as_ref() != HASHBLOCK_TOPIC.as_bytes() {
+        anyhow::bail!(
+            "unexpected ZMQ topic `{}`",
+            String::from_utf8_lossy(topic)
+        );
+    }
+    body.to_lower_hex_string().parse().with_context(|| {
+        format!("invalid block hash in `{HASHBLOCK_TOPIC}` message")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bitcoin::{hashes::Hash as _, BlockHash};
+    use zeromq::{Socket as _, SocketSend as _, ZmqMessage};
+
+    use super::{parse_hashblock, TipNotifications};
+
+    /// `hashblock` message for `block_hash`, as sent by the node
+    fn hashblock_message(topic: &str, block_hash: BlockHash) -> ZmqMessage {
+        let mut body = block_hash.to_byte_array();
+        body.reverse();
+        let mut message = ZmqMessage::from(vec![0, 0, 0, 0]);
+        message.prepend(&ZmqMessage::from(body.to_vec()));
+        message.prepend(&ZmqMessage::from(topic));
+        message
+    }
+
+    #[test]
+    fn parse_hashblock_display_order() {
+        let block_hash =
+            BlockHash::from_byte_array(std::array::from_fn(|i| i as u8));
+        let message = hashblock_message("hashblock", block_hash);
+        assert_eq!(parse_hashblock(message).unwrap(), block_hash);
+        let message = hashblock_message("hashtx", block_hash);
+        assert!(parse_hashblock(message).is_err());
+        assert!(parse_hashblock(ZmqMessage::from("hashblock")).is_err());
+    }
+
+    #[tokio::test]
+    async fn zmq_notifies_tips() {
+        let mut publisher = zeromq::PubSocket::new();
+        let endpoint = publisher.bind("tcp://127.0.0.1:0").await.unwrap();
+        let mut tip_notifications =
+            TipNotifications::zmq(&endpoint.to_string()).await.unwrap();
+        let block_hash = BlockHash::from_byte_array([7; 32]);
+        // Messages published before the subscription reaches the publisher
+        // are dropped, so publish until one is received
+        let mut tips = Vec::new();
+        for _ in 0..500 {
+            let message = hashblock_message("hashblock", block_hash);
+            let () = publisher.send(message).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tips.extend(tip_notifications.drain().unwrap());
+            if !tips.is_empty() {
+                break;
+            }
+        }
+        assert!(!tips.is_empty());
+        assert!(tips.iter().all(|tip| *tip == block_hash));
+        // Other topics are not subscribed to
+        let message = hashblock_message("hashtx", block_hash);
+        let () = publisher.send(message).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(tip_notifications
+            .drain()
+            .unwrap()
+            .iter()
+            .all(|tip| *tip == block_hash));
+    }
+}
@@ -0,0 +1,19 @@
+#!/bin/sh
+# Generated by bip300-enforcer-demo
+# Version:      0.1.0
+# Git commit:   <git hash>
+# Generated at: <timestamp>
+# Network:      regtest
+# Seed:         1
+# Node tip:     none, generated offline
+# Spec SHA-256: 0074e48b0ac3ad15d28e0890dca37b9ca0b58d5d11f5d4265ff28561ba56f2dc
+# Blocks spec:  [{"label":"a","duplicate_m2":true}]
+# Exit codes:
+#   10  the node or enforcer could not be reached
+#   11  the node returned an error for an RPC request
+#   20  a block was accepted, but was expected to be rejected
+#   21  a block was rejected, but was expected to be accepted
+#   30  the node's tip after submitting a block is not as expected
+#   31  a checked value is not as expected
+#   40  the enforcer's state is not as expected
+#   1   any other failure, eg. a missing tool
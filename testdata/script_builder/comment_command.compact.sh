@@ -0,0 +1,4 @@
+set -eu
+# Submit the blocks
+echo one
+echo two
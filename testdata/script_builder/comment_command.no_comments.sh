@@ -0,0 +1,5 @@
+set -eu
+
+echo one
+
+echo two
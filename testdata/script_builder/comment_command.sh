@@ -0,0 +1,6 @@
+set -eu
+
+# Submit the blocks
+echo one
+
+echo two
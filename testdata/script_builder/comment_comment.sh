@@ -0,0 +1,4 @@
+# First comment
+
+# Second comment
+echo done
@@ -0,0 +1,13 @@
+# First line
+# second line
+#
+# New paragraph
+if true; then
+    echo one
+fi
+#   indented
+#
+# last
+#
+#
+echo two
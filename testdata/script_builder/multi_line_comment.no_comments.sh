@@ -0,0 +1,5 @@
+if true; then
+    echo one
+fi
+
+echo two
@@ -0,0 +1,123 @@
+# Settings, which can be overridden by setting these variables in the
+# environment
+RPC_URL=${RPC_URL:-'http://127.0.0.1:18443/'}
+RPC_AUTH=${RPC_AUTH:-'user:pass'}
+
+# Send a JSON-RPC request body, and print the response
+rpc_post() {
+    rpc_status=0
+    rpc_output=$(curl "$1" --silent --show-error \
+        --connect-timeout 10 --retry 3 --retry-connrefused --fail-with-body \
+        -H 'Content-Type: application/json' \
+        --user "$RPC_AUTH" \
+        --max-time 120 \
+        --write-out '\n%{http_code}' \
+        --data-binary "$2") || rpc_status=$?
+    rpc_http_status=$(printf '%s\n' "$rpc_output" | tail -n 1)
+    rpc_body=$(printf '%s\n' "$rpc_output" | sed '$d')
+    case "$rpc_http_status" in
+        2??|000) ;;
+        *)
+            case "$rpc_body" in
+                *'"error":{'*) rpc_status=0 ;;
+                *)
+                    printf 'HTTP status %s from %s\n' "$rpc_http_status" "$1" >&2
+                    if [ -n "$rpc_body" ]; then
+                        printf 'Response body: %s\n' "$rpc_body" >&2
+                    fi
+                    if [ "$rpc_status" -eq 0 ]; then
+                        rpc_status=22
+                    fi
+                    ;;
+            esac
+            ;;
+    esac
+    printf '%s\n' "$rpc_body"
+    return "$rpc_status"
+}
+
+# Send a JSON-RPC request to the node, and print the response
+rpc() {
+    rpc_request_id='bip300-enforcer-demo'
+    if [ "$1" = --id ]; then
+        rpc_request_id=$2
+        shift 2
+    fi
+    rpc_url=$RPC_URL
+    if [ "$1" = --path ]; then
+        rpc_url="${RPC_URL%/}$2"
+        shift 2
+    fi
+    rpc_method=$1
+    shift
+    rpc_params=
+    for rpc_param in "$@"; do
+        rpc_params="${rpc_params:+$rpc_params,}$rpc_param"
+    done
+    rpc_post "$rpc_url" "$(printf '{"jsonrpc":"2.0","id":"%s","method":"%s","params":[%s]}' "$rpc_request_id" "$rpc_method" "$rpc_params")"
+}
+
+# Print the result of a JSON-RPC response
+rpc_result() {
+    if command -v jq >/dev/null 2>&1; then
+        error=$(printf '%s' "$1" | jq -c '.error // empty')
+        result=$(printf '%s' "$1" | jq -r '.result // empty')
+    else
+        error=$(printf '%s' "$1" | grep -o '"error": *{[^}]*}' || true)
+        result=$(printf '%s' "$1" | sed -n -e 's/.*"result": *"\([^"]*\)".*/\1/p' -e 's/.*"result": *\(-\{0,1\}[0-9][0-9.]*\).*/\1/p')
+    fi
+    if [ -n "$error" ]; then
+        printf 'RPC request failed for %s: %s\n' "$2" "$error" >&2
+        exit 11
+    fi
+    printf '%s' "$result"
+}
+
+# Check the result of a `submitblock` request
+check_submitblock() {
+    case "$1" in
+        '') submit_result=accepted ;;
+        duplicate) submit_result=duplicate ;;
+        duplicate-inconclusive) submit_result=duplicate-inconclusive ;;
+        inconclusive) submit_result=inconclusive ;;
+        *) submit_result=rejected ;;
+    esac
+    case "$2:$submit_result" in
+        accept:accepted|reject:rejected|reject:inconclusive|reject:duplicate-inconclusive|inconclusive:inconclusive)
+            ;;
+        accept:rejected)
+            printf 'expected %s to be accepted, but it was rejected: %s\n' "$3" "$1" >&2
+            exit 21
+            ;;
+        accept:inconclusive)
+            printf 'expected %s to be accepted, but it was stored on a side branch\n' "$3" >&2
+            exit 21
+            ;;
+        accept:duplicate*|inconclusive:duplicate*)
+            printf 'expected %s to be submitted for the first time, but the node already had it: %s\n' "$3" "$1" >&2
+            exit 21
+            ;;
+        reject:accepted)
+            printf 'expected %s to be rejected, but it was accepted\n' "$3" >&2
+            exit 20
+            ;;
+        reject:duplicate)
+            printf 'expected %s to be rejected, but the node already had it as a valid block\n' "$3" >&2
+            exit 20
+            ;;
+        inconclusive:accepted)
+            printf 'expected %s to be stored on a side branch, but it became the tip\n' "$3" >&2
+            exit 20
+            ;;
+        inconclusive:rejected)
+            printf 'expected %s to be stored on a side branch, but it was rejected: %s\n' "$3" "$1" >&2
+            exit 21
+            ;;
+    esac
+}
+
+# Submit the genesis block
+printf '>>> %s\n' 'submitting block 0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206 (height 0, expect: accept)' >&2
+response=$(rpc submitblock '"0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f20020000000101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000"') || exit 10
+result=$(rpc_result "$response" 'the genesis block') || exit
+check_submitblock "$result" accept 'the genesis block'
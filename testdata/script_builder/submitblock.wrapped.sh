@@ -0,0 +1,57 @@
+# Settings, which can be overridden by setting these variables in the
+# environment
+RPC_HOST=${RPC_HOST:-'127.0.0.1'}
+RPC_PORT=${RPC_PORT:-18443}
+RPC_USER=${RPC_USER:-'user'}
+RPC_PASS=${RPC_PASS:-'pass'}
+
+# Check the result of a `submitblock` request
+check_submitblock() {
+    case "$1" in
+        '') submit_result=accepted ;;
+        duplicate) submit_result=duplicate ;;
+        duplicate-inconclusive) submit_result=duplicate-inconclusive ;;
+        inconclusive) submit_result=inconclusive ;;
+        *) submit_result=rejected ;;
+    esac
+    case "$2:$submit_result" in
+        accept:accepted|reject:rejected|reject:inconclusive|reject:duplicate-inconclusive|inconclusive:inconclusive)
+            ;;
+        accept:rejected)
+            printf 'expected %s to be accepted, but it was rejected: %s\n' "$3" "$1" >&2
+            exit 21
+            ;;
+        accept:inconclusive)
+            printf 'expected %s to be accepted, but it was stored on a side branch\n' "$3" >&2
+            exit 21
+            ;;
+        accept:duplicate*|inconclusive:duplicate*)
+            printf 'expected %s to be submitted for the first time, but the node already had it: %s\n' "$3" "$1" >&2
+            exit 21
+            ;;
+        reject:accepted)
+            printf 'expected %s to be rejected, but it was accepted\n' "$3" >&2
+            exit 20
+            ;;
+        reject:duplicate)
+            printf 'expected %s to be rejected, but the node already had it as a valid block\n' "$3" >&2
+            exit 20
+            ;;
+        inconclusive:accepted)
+            printf 'expected %s to be stored on a side branch, but it became the tip\n' "$3" >&2
+            exit 20
+            ;;
+        inconclusive:rejected)
+            printf 'expected %s to be stored on a side branch, but it was rejected: %s\n' "$3" "$1" >&2
+            exit 21
+            ;;
+    esac
+}
+
+# Submit the genesis block
+printf '>>> %s\n' 'submitting block 0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206 (height 0, expect: accept)' >&2
+result=$(bitcoin-cli -chain=regtest -rpcconnect="$RPC_HOST" -rpcport="$RPC_PORT" \
+    -rpcuser="$RPC_USER" -rpcpassword="$RPC_PASS" -rpcclienttimeout=120 \
+    submitblock \
+    '0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f20020000000101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000') || exit 10
+check_submitblock "$result" accept 'the genesis block'
@@ -0,0 +1,2 @@
+echo one
+# Trailing comment
@@ -0,0 +1,94 @@
+//! Block labels are declared as script variables holding the blocks' hashes,
+//! and must be valid variable names
+
+mod common;
+
+use serde_json::Value;
+use tempfile::NamedTempFile;
+
+const BLOCKS_SPEC: &str = r#"[{"label": "first"}, {"label": "_Second_2"}]"#;
+
+const LABELS: [&str; 2] = ["first", "_Second_2"];
+
+/// Output of an offline run with the blocks spec, in the format
+fn output(format: &str, blocks_spec: &str) -> String {
+    let config = NamedTempFile::new().unwrap();
+    let mut cmd = common::offline_cmd(config.path());
+    cmd.args(["--format", format, blocks_spec]);
+    common::stdout(&mut cmd)
+}
+
+/// Labels and hashes of the labelled blocks submitted by the JSON plan, in
+/// order
+fn block_hashes() -> Vec<(String, String)> {
+    let plan: Value =
+        serde_json::from_str(&output("json-plan", BLOCKS_SPEC)).unwrap();
+    plan["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|step| {
+            let block = &step["block"];
+            Some((
+                block["spec"]["label"].as_str()?.to_owned(),
+                block["hash"].as_str()?.to_owned(),
+            ))
+        })
+        .collect()
+}
+
+#[test]
+fn all_labels_planned() {
+    let labels: Vec<_> =
+        block_hashes().into_iter().map(|(label, _)| label).collect();
+    assert_eq!(labels, LABELS);
+}
+
+#[test]
+fn posix_script_declares_block_hashes() {
+    let script = output("script", BLOCKS_SPEC);
+    for (label, hash) in block_hashes() {
+        let line = format!("BLOCK_HASH_{label}={hash}");
+        assert!(script.lines().any(|l| l == line), "{line}:\n{script}");
+    }
+}
+
+#[test]
+fn powershell_script_declares_block_hashes() {
+    let script = output("powershell", BLOCKS_SPEC);
+    for (label, hash) in block_hashes() {
+        let line = format!("$BLOCK_HASH_{label} = '{hash}'");
+        assert!(script.lines().any(|l| l == line), "{line}:\n{script}");
+    }
+}
+
+#[test]
+fn json_plan_declares_block_hashes() {
+    let plan: Value =
+        serde_json::from_str(&output("json-plan", BLOCKS_SPEC)).unwrap();
+    for (label, hash) in block_hashes() {
+        assert_eq!(plan["block_hashes"][format!("BLOCK_HASH_{label}")], hash);
+    }
+}
+
+#[test]
+fn invalid_labels_rejected() {
+    for label in ["double-ack", "2nd", "a b", "$x", ""] {
+        let config = NamedTempFile::new().unwrap();
+        let blocks_spec = format!(r#"[{{"label": {label:?}}}]"#);
+        let output = common::offline_cmd(config.path())
+            .arg(&blocks_spec)
+            .assert()
+            .failure()
+            .get_output()
+            .stderr
+            .clone();
+        let stderr = String::from_utf8(output).unwrap();
+        assert!(
+            stderr.contains(&format!(
+                "block 0: label `{label}` is not a valid script variable name"
+            )),
+            "{stderr}"
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Help and usage error output, checked against the golden files in
+//! `testdata/cli/ui`
+
+mod common;
+
+use tempfile::NamedTempFile;
+
+/// Golden file name, and the args of a run that fails with a usage error
+const USAGE_ERRORS: [(&str, &[&str]); 6] = [
+    ("not_json", &["[{"]),
+    ("unknown_field", &[r#"[{"duplicate_m3": true}]"#]),
+    ("wrong_type", &[r#"[{"duplicate_m2": 1}]"#]),
+    ("not_an_array", &["null"]),
+    ("unsupported_version", &[r#"{"version": 9, "blocks": []}"#]),
+    ("quick_flag_conflict", &["--duplicate-m2", "[{}]"]),
+];
+
+#[test]
+fn help() {
+    for (name, args) in
+        [("help", &["-h"][..]), ("submit_help", &["submit", "-h"])]
+    {
+        let output = common::stdout(common::bare_cmd().args(args));
+        common::check_golden(&format!("ui/{name}.txt"), &output);
+    }
+}
+
+#[test]
+fn usage_errors() {
+    for (name, args) in USAGE_ERRORS {
+        let config = NamedTempFile::new().unwrap();
+        let output = common::cmd(None, config.path())
+            .arg("--offline")
+            .args(args)
+            .assert()
+            .code(2)
+            .get_output()
+            .clone();
+        assert!(output.stdout.is_empty(), "{name}: {output:?}");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        common::check_golden(&format!("ui/{name}.txt"), &stderr);
+    }
+}
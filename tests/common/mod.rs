@@ -0,0 +1,99 @@
+//! Helpers for running the binary in integration tests
+
+// Each integration test uses a subset of the helpers
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+use assert_cmd::Command;
+
+/// Hash of the block that offline runs build on
+pub const PREV_BLOCKHASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Environment variables that the binary reads, and that must not leak
+/// into tests from the environment that they are run in
+const ENV_VARS: [&str; 3] =
+    ["BIP300_DEMO_RPC_USER", "BIP300_DEMO_RPC_PASS", "RUST_LOG"];
+
+/// Command running the binary, with the environment variables that it reads
+/// cleared
+pub fn bare_cmd() -> Command {
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+    for var in ENV_VARS {
+        cmd.env_remove(var);
+    }
+    cmd
+}
+
+/// Like [`bare_cmd`], running the subcommand if any, with the config file
+/// at `config`, so that the user's own config file is not read
+pub fn cmd(subcommand: Option<&str>, config: &Path) -> Command {
+    let mut cmd = bare_cmd();
+    cmd.args(subcommand);
+    cmd.arg("--config").arg(config);
+    cmd
+}
+
+/// Like [`cmd`], generating blocks in offline mode, deterministically, on
+/// regtest. The blocks spec and any other args are added by the caller.
+pub fn offline_cmd(config: &Path) -> Command {
+    let mut cmd = cmd(None, config);
+    cmd.args([
+        "--offline",
+        "--prev-blockhash",
+        PREV_BLOCKHASH,
+        "--height",
+        "200",
+        "--bits",
+        "207fffff",
+        "--seed",
+        "1",
+        "--deterministic",
+        "--start-time",
+        "1700000000",
+    ]);
+    cmd
+}
+
+/// Stdout of a command that must succeed
+pub fn stdout(cmd: &mut Command) -> String {
+    let output = cmd.assert().success().get_output().stdout.clone();
+    String::from_utf8(output).unwrap()
+}
+
+/// Check `actual` against the golden file `testdata/cli/{name}`. If
+/// `UPDATE_GOLDEN` is set, the golden file is overwritten instead.
+pub fn check_golden(name: &str, actual: &str) {
+    let path = golden_path(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = read_golden(name);
+    assert!(
+        actual == expected,
+        "output differs from `{}`: run with UPDATE_GOLDEN=1 to update it, \
+         and review the diff\n--- expected\n{expected}\n--- actual\n{actual}",
+        path.display()
+    );
+}
+
+/// Contents of the golden file `testdata/cli/{name}`
+pub fn read_golden(name: &str) -> String {
+    let path = golden_path(name);
+    std::fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "failed to read `{}` ({err}): run with UPDATE_GOLDEN=1 to create \
+             it",
+            path.display()
+        )
+    })
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata/cli")
+        .join(name)
+}
@@ -0,0 +1,66 @@
+//! Precedence of flags, environment variables, and the config file, as
+//! printed by `--print-config`
+
+mod common;
+
+use std::io::Write as _;
+
+use tempfile::NamedTempFile;
+
+/// Config file setting the RPC user and pass
+fn config() -> NamedTempFile {
+    let mut config = NamedTempFile::new().unwrap();
+    writeln!(config, "rpc_user = \"config-user\"").unwrap();
+    writeln!(config, "rpc_pass = \"config-pass\"").unwrap();
+    writeln!(config, "coinbase_address = \"bcrt1qconfig\"").unwrap();
+    writeln!(config, "mining_threads = 3").unwrap();
+    config
+}
+
+/// Effective configuration printed with the args and environment variables
+fn print_config(args: &[&str], env: &[(&str, &str)]) -> String {
+    let config = config();
+    let mut cmd = common::cmd(None, config.path());
+    cmd.arg("--print-config").args(args);
+    for (var, value) in env {
+        cmd.env(var, value);
+    }
+    common::stdout(&mut cmd)
+}
+
+#[test]
+fn config_beats_defaults() {
+    let printed = print_config(&[], &[]);
+    assert!(printed.contains("rpc_user = \"config-user\""), "{printed}");
+    assert!(printed.contains("coinbase_address = \"bcrt1qconfig\""));
+    assert!(printed.contains("mining_threads = 3"), "{printed}");
+}
+
+#[test]
+fn env_beats_config() {
+    let printed = print_config(&[], &[("BIP300_DEMO_RPC_USER", "env-user")]);
+    assert!(printed.contains("rpc_user = \"env-user\""), "{printed}");
+}
+
+#[test]
+fn flag_beats_env() {
+    let printed = print_config(
+        &["--rpc-user", "flag-user", "--mining-threads", "5"],
+        &[("BIP300_DEMO_RPC_USER", "env-user")],
+    );
+    assert!(printed.contains("rpc_user = \"flag-user\""), "{printed}");
+    assert!(printed.contains("mining_threads = 5"), "{printed}");
+}
+
+#[test]
+fn pass_redacted() {
+    for (args, env) in [
+        (&[][..], &[][..]),
+        (&["--rpc-pass", "flag-pass"][..], &[][..]),
+        (&[][..], &[("BIP300_DEMO_RPC_PASS", "env-pass")][..]),
+    ] {
+        let printed = print_config(args, env);
+        assert!(printed.contains("rpc_pass = \"<redacted>\""), "{printed}");
+        assert!(!printed.contains("-pass\""), "{printed}");
+    }
+}
@@ -0,0 +1,148 @@
+//! Submits blocks to a custom signet node, whose challenge is satisfied by
+//! an empty signet solution. Requires `bitcoind`, which is looked up on the
+//! `PATH`, or at `BITCOIND` if set. Run with
+//! `cargo test --features signet-integration --test custom_signet`.
+#![cfg(feature = "signet-integration")]
+
+mod common;
+
+use std::{
+    io::{Read as _, Write as _},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use base64::Engine as _;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+/// `OP_TRUE`, which an empty signet solution satisfies
+const SIGNET_CHALLENGE: &str = "51";
+
+/// RPC user and pass for the node
+const RPC_CREDENTIALS: &str = "signet";
+
+/// How long to wait for the node's RPC server to become ready
+const RPC_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Custom signet node in a temporary datadir. Killed when dropped.
+struct SignetNode {
+    child: Child,
+    rpc_port: u16,
+    _datadir: TempDir,
+}
+
+impl SignetNode {
+    fn spawn() -> Self {
+        let datadir = TempDir::new().unwrap();
+        let rpc_port = TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let bitcoind =
+            std::env::var("BITCOIND").unwrap_or_else(|_| "bitcoind".to_owned());
+        let child = Command::new(&bitcoind)
+            .arg("-signet")
+            .arg(format!("-signetchallenge={SIGNET_CHALLENGE}"))
+            .arg(format!("-datadir={}", datadir.path().display()))
+            .args([
+                "-server",
+                "-listen=0",
+                "-disablewallet",
+                "-printtoconsole=0",
+                "-rpcbind=127.0.0.1",
+                "-rpcallowip=127.0.0.1",
+            ])
+            .arg(format!("-rpcport={rpc_port}"))
+            .arg(format!("-rpcuser={RPC_CREDENTIALS}"))
+            .arg(format!("-rpcpassword={RPC_CREDENTIALS}"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|err| panic!("failed to run `{bitcoind}`: {err}"));
+        let node = Self {
+            child,
+            rpc_port,
+            _datadir: datadir,
+        };
+        let start = Instant::now();
+        while node.try_request("getblockchaininfo").is_none() {
+            assert!(
+                start.elapsed() < RPC_READY_TIMEOUT,
+                "node's RPC server was not ready after {RPC_READY_TIMEOUT:?}"
+            );
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        node
+    }
+
+    fn rpc_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.rpc_port)
+    }
+
+    /// Result of an RPC request without params, or `None` if the node could
+    /// not be reached, or responded with an error, eg. while warming up
+    fn try_request(&self, method: &str) -> Option<Value> {
+        let body =
+            json!({ "jsonrpc": "1.0", "id": 0, "method": method, "params": [] })
+                .to_string();
+        let auth = base64::engine::general_purpose::STANDARD
+            .encode(format!("{RPC_CREDENTIALS}:{RPC_CREDENTIALS}"));
+        let mut stream =
+            TcpStream::connect(("127.0.0.1", self.rpc_port)).ok()?;
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nAuthorization: Basic \
+             {auth}\r\nContent-Type: application/json\r\nContent-Length: \
+             {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .ok()?;
+        let mut response = String::new();
+        let _: usize = stream.read_to_string(&mut response).ok()?;
+        let (_, body) = response.split_once("\r\n\r\n")?;
+        let response: Value = serde_json::from_str(body).ok()?;
+        match response["result"].clone() {
+            Value::Null => None,
+            result => Some(result),
+        }
+    }
+}
+
+impl Drop for SignetNode {
+    fn drop(&mut self) {
+        let _: std::io::Result<()> = self.child.kill();
+        let _: std::io::Result<_> = self.child.wait();
+    }
+}
+
+#[test]
+fn submit_to_custom_signet() {
+    let node = SignetNode::spawn();
+    let config = tempfile::NamedTempFile::new().unwrap();
+    let mut cmd = common::cmd(Some("submit"), config.path());
+    cmd.args([
+        "--network",
+        "signet",
+        "--signet-challenge",
+        SIGNET_CHALLENGE,
+        "--rpc-url",
+        &node.rpc_url(),
+        "--rpc-user",
+        RPC_CREDENTIALS,
+        "--rpc-pass",
+        RPC_CREDENTIALS,
+        "--seed",
+        "1",
+        "--empty-blocks",
+        "2",
+        "[{}]",
+    ]);
+    let _: String = common::stdout(&mut cmd);
+    let info = node.try_request("getblockchaininfo").unwrap();
+    assert_eq!(info["chain"], "signet");
+    // The setup blocks, and the blocks that were submitted
+    assert!(info["blocks"].as_u64().unwrap() >= 3, "{info}");
+}
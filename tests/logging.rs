@@ -0,0 +1,51 @@
+//! Logs are written to stderr only, since stdout carries the generated
+//! output
+
+mod common;
+
+use tempfile::NamedTempFile;
+
+/// Output formats, all of which are written to stdout
+const FORMATS: [&str; 5] =
+    ["script", "powershell", "json", "json-plan", "makefile"];
+
+/// Stdout and stderr of an offline run, with the extra args
+fn run(
+    format: &str,
+    args: &[&str],
+    rust_log: Option<&str>,
+) -> (String, String) {
+    let config = NamedTempFile::new().unwrap();
+    let mut cmd = common::offline_cmd(config.path());
+    cmd.args(["--format", format]).args(args).arg("[{}]");
+    if let Some(rust_log) = rust_log {
+        cmd.env("RUST_LOG", rust_log);
+    }
+    let output = cmd.assert().success().get_output().clone();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn logs_never_reach_stdout() {
+    for format in FORMATS {
+        let (quiet_stdout, _) = run(format, &[], None);
+        for (args, rust_log) in
+            [(&["-vvv"][..], None), (&[][..], Some("trace"))]
+        {
+            let (stdout, stderr) = run(format, args, rust_log);
+            assert!(
+                stderr.contains("DEBUG"),
+                "expected debug logs on stderr for {format}, \
+                 {args:?}, RUST_LOG={rust_log:?}:\n{stderr}"
+            );
+            assert_eq!(
+                stdout, quiet_stdout,
+                "stdout differs with logging enabled for {format}, {args:?}, \
+                 RUST_LOG={rust_log:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Makefiles generated with `--format makefile` are valid for GNU make
+//! (see [`make_dry_run`]), recipe lines start with a tab, and `$` is
+//! escaped as `$$` wherever make would expand it.
+
+mod common;
+
+use std::process::Command;
+
+use tempfile::{NamedTempFile, TempDir};
+
+const BLOCKS_SPEC: &str =
+    r#"[{"label": "first"}, {"label": "double_ack", "duplicate_m2": true}]"#;
+
+fn makefile() -> String {
+    let config = NamedTempFile::new().unwrap();
+    let mut cmd = common::offline_cmd(config.path());
+    cmd.args(["--format", "makefile", BLOCKS_SPEC]);
+    common::stdout(&mut cmd)
+}
+
+#[test]
+fn recipe_lines_start_with_tab() {
+    let makefile = makefile();
+    let mut in_recipe = false;
+    let mut n_recipe_lines = 0;
+    for line in makefile.lines() {
+        let is_target = line.split_once(':').is_some_and(|(target, _)| {
+            !target.is_empty()
+                && target.chars().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'
+                })
+        });
+        if is_target {
+            in_recipe = true;
+        } else if line.is_empty() {
+            in_recipe = false;
+        } else if in_recipe {
+            assert!(line.starts_with('\t'), "{line:?}:\n{makefile}");
+            n_recipe_lines += 1;
+        }
+    }
+    assert!(n_recipe_lines > 0, "no recipes:\n{makefile}");
+}
+
+#[test]
+fn dollars_escaped() {
+    let makefile = makefile();
+    for line in makefile.lines() {
+        // Outside of make's own syntax, every `$` is doubled
+        let unescaped = line
+            .replace("$$", "")
+            .replace("$(patsubst", "")
+            .replace("$(dir", "")
+            .replace("$(abspath", "")
+            .replace("$(lastword", "")
+            .replace("$(MAKEFILE_LIST)", "")
+            .replace("$(if $(NO_DEPS),", "");
+        assert!(!unescaped.contains('$'), "{line:?}:\n{makefile}");
+    }
+    assert!(makefile.contains("\teval \"$$SCRIPT_PRELUDE\"\n"));
+}
+
+/// `make --dry-run` parses the Makefile, and prints each recipe with `$$`
+/// unescaped, without running it
+#[test]
+fn make_dry_run() {
+    let dir = TempDir::new().unwrap();
+    std::fs::write(dir.path().join("Makefile"), makefile()).unwrap();
+    let output = Command::new("make")
+        .arg("--dry-run")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run make");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{stderr}");
+    assert!(stdout.contains("eval \"$SCRIPT_PRELUDE\""), "{stdout}");
+    assert!(!stdout.contains("$$"), "{stdout}");
+}
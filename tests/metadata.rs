@@ -0,0 +1,140 @@
+//! Golden tests for the version and the metadata header of generated
+//! output. The git hash and generation time vary between builds and runs,
+//! so they are masked.
+
+mod common;
+
+use serde_json::Value;
+use tempfile::NamedTempFile;
+
+/// Placeholder for the git hash, if the binary was built from a git checkout
+const GIT_HASH_MASK: &str = "<git hash>";
+
+/// Placeholder for the generation time
+const TIMESTAMP_MASK: &str = "<timestamp>";
+
+/// Replace the git hash in `output` with [`GIT_HASH_MASK`]. If the binary
+/// was built without a git hash, the golden file is expected to have the
+/// mask, so it is added where the git hash would have been, by `add_mask`.
+fn mask_git_hash<F>(output: &str, add_mask: F) -> String
+where
+    F: FnOnce(&str) -> String,
+{
+    match option_env!("GIT_HASH") {
+        Some(git_hash) => {
+            assert!(output.contains(git_hash), "{output}");
+            output.replace(git_hash, GIT_HASH_MASK)
+        }
+        None => add_mask(output),
+    }
+}
+
+/// Mask the generation time in the header line `Generated at: Unix time N`
+fn mask_timestamp(output: &str) -> String {
+    let mut found = false;
+    let res = output
+        .lines()
+        .map(|line| match line.split_once("Generated at: Unix time ") {
+            Some((prefix, timestamp)) => {
+                assert!(timestamp.parse::<u64>().is_ok(), "{line}");
+                found = true;
+                format!("{prefix}Generated at: {TIMESTAMP_MASK}")
+            }
+            None => line.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert!(found, "no generation time in:\n{output}");
+    res
+}
+
+/// Generate output in the format, offline, with the generation time in the
+/// header
+fn generate(format: &str) -> String {
+    let config = NamedTempFile::new().unwrap();
+    let mut cmd = common::cmd(None, config.path());
+    cmd.args([
+        "--offline",
+        "--prev-blockhash",
+        common::PREV_BLOCKHASH,
+        "--height",
+        "200",
+        "--bits",
+        "207fffff",
+        "--seed",
+        "1",
+        "--network",
+        "regtest",
+        "--format",
+        format,
+        r#"[{"label": "a", "duplicate_m2": true}]"#,
+    ]);
+    common::stdout(&mut cmd)
+}
+
+#[test]
+fn version() {
+    let output = common::stdout(common::bare_cmd().arg("--version"));
+    let masked = mask_git_hash(&output, |output| {
+        output.replace('\n', &format!(" ({GIT_HASH_MASK})\n"))
+    });
+    common::check_golden("version.txt", &masked);
+    // The short version omits the git hash
+    let output = common::stdout(common::bare_cmd().arg("-V"));
+    assert_eq!(
+        output,
+        format!("{} {}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn script_header() {
+    let script = generate("script");
+    let header = script
+        .split_inclusive('\n')
+        .take_while(|line| line.starts_with('#'))
+        .collect::<String>();
+    let masked = mask_git_hash(&mask_timestamp(&header), |header| {
+        header.replace(
+            "\n# Generated at:",
+            &format!("\n# Git commit:   {GIT_HASH_MASK}\n# Generated at:"),
+        )
+    });
+    common::check_golden("header.sh", &format!("{masked}\n"));
+}
+
+#[test]
+fn json_plan_meta() {
+    let plan: Value = serde_json::from_str(&generate("json-plan")).unwrap();
+    let mut meta = plan["meta"].clone();
+    let meta_obj = meta.as_object_mut().unwrap();
+    assert!(meta_obj["generated_at"].is_u64(), "{meta}");
+    meta_obj.insert("generated_at".to_owned(), TIMESTAMP_MASK.into());
+    match option_env!("GIT_HASH") {
+        Some(git_hash) => assert_eq!(meta_obj["git_hash"], git_hash),
+        None => assert!(!meta_obj.contains_key("git_hash"), "{meta}"),
+    }
+    meta_obj.insert("git_hash".to_owned(), GIT_HASH_MASK.into());
+    // Timings vary between runs
+    assert!(meta_obj.remove("metrics").is_some(), "{meta}");
+    let mut masked = serde_json::to_string_pretty(&meta).unwrap();
+    masked.push('\n');
+    common::check_golden("meta.json", &masked);
+    // The header has the same metadata as `meta`
+    let header = mask_timestamp(plan["header"].as_str().unwrap());
+    let header = mask_git_hash(&header, |header| {
+        header.replace(
+            "\nGenerated at:",
+            &format!("\nGit commit:   {GIT_HASH_MASK}\nGenerated at:"),
+        )
+    });
+    let script_header = common::read_golden("header.sh")
+        .lines()
+        // Skip the shebang
+        .skip(1)
+        .map_while(|line| line.strip_prefix("# "))
+        .take_while(|line| !line.starts_with("Exit codes:"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert_eq!(header, script_header);
+}
@@ -0,0 +1,39 @@
+//! Quick flags produce the same output as the equivalent blocks spec
+
+mod common;
+
+use tempfile::NamedTempFile;
+
+/// Output formats that blocks are rendered in
+const FORMATS: [&str; 5] =
+    ["script", "powershell", "json", "json-plan", "makefile"];
+
+/// Offline output in the format, with the args specifying the blocks
+fn output(format: &str, args: &[&str]) -> String {
+    let config = NamedTempFile::new().unwrap();
+    let mut cmd = common::offline_cmd(config.path());
+    cmd.args(["--format", format]).args(args);
+    common::stdout(&mut cmd)
+}
+
+#[test]
+fn flags_match_blocks_spec() {
+    let cases = [
+        (&["--duplicate-m2"][..], r#"[{"duplicate_m2": true}]"#),
+        (
+            &["--duplicate-m2", "--repeat", "3"][..],
+            r#"[{"duplicate_m2": true}, {"duplicate_m2": true},
+                {"duplicate_m2": true}]"#,
+        ),
+        (&["--repeat", "2"][..], "[{}, {}]"),
+    ];
+    for format in FORMATS {
+        for (flags, blocks_spec) in cases {
+            assert_eq!(
+                output(format, flags),
+                output(format, &[blocks_spec]),
+                "{format}: {flags:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Runs with the same seed and the same offline anchor produce identical
+//! output
+
+mod common;
+
+use tempfile::NamedTempFile;
+
+/// Output formats that blocks are rendered in
+const FORMATS: [&str; 5] =
+    ["script", "powershell", "json", "json-plan", "makefile"];
+
+/// Blocks spec with several blocks, so that the seed is used more than once
+const BLOCKS_SPEC: &str =
+    r#"[{"label": "first"}, {"duplicate_m2": true}, {"fork_from": "first"}]"#;
+
+/// Stdout and stderr of a deterministic offline run in the format, with
+/// the seed if any
+fn run(format: &str, seed: Option<&str>) -> (Vec<u8>, String) {
+    let config = NamedTempFile::new().unwrap();
+    let mut cmd = common::cmd(None, config.path());
+    cmd.args([
+        "--offline",
+        "--prev-blockhash",
+        common::PREV_BLOCKHASH,
+        "--height",
+        "200",
+        "--bits",
+        "207fffff",
+        "--deterministic",
+        "--start-time",
+        "1700000000",
+        "--format",
+        format,
+    ]);
+    if let Some(seed) = seed {
+        cmd.args(["--seed", seed]);
+    }
+    let output = cmd.arg(BLOCKS_SPEC).assert().success().get_output().clone();
+    (output.stdout, String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn same_seed_same_bytes() {
+    for format in FORMATS {
+        let (first, _) = run(format, Some("42"));
+        let (second, _) = run(format, Some("42"));
+        assert!(first == second, "output differs for {format}");
+        let (other, _) = run(format, Some("43"));
+        assert!(first != other, "seed is unused for {format}");
+    }
+}
+
+#[test]
+fn printed_seed_reproduces_output() {
+    let (first, stderr) = run("script", None);
+    let seed = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("Seed: "))
+        .unwrap_or_else(|| panic!("no seed in:\n{stderr}"));
+    let (second, _) = run("script", Some(seed));
+    assert!(first == second, "output differs with `--seed {seed}`");
+}